@@ -0,0 +1,13 @@
+#!/bin/bash
+# Пропускная способность модели ошибок ОС при разном числе потоков libtorch
+
+THREADS="${@:-1 2 4 8}"
+
+echo "Компиляция проекта..."
+cargo build --release || exit 1
+
+for n in $THREADS; do
+    ips=$(./target/release/bashpic --torch-threads "$n" benchmark --batch-size 8 --iterations 50 --json 2>/dev/null \
+        | grep '"images_per_second"' | sed 's/[^0-9.]//g')
+    echo "Потоков: $n, изображений/с: ${ips:-ошибка}"
+done
@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("proto/inference.proto")?;
+
+    // Версия tch из Cargo.lock для команды doctor
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let tch_version = lock
+        .split("[[package]]")
+        .find(|package| package.contains("\nname = \"tch\"\n"))
+        .and_then(|package| package.lines().find_map(|line| line.strip_prefix("version = ")))
+        .map_or("unknown", |version| version.trim_matches('"'));
+    println!("cargo:rustc-env=TCH_VERSION={}", tch_version);
+    Ok(())
+}
@@ -0,0 +1,30 @@
+// Пример клиента: анализ скриншота запущенным сервером
+// cargo run --example client --features client -- screenshot.png [http://localhost:5000]
+use bashpic::client::{ClientError, ErrorPredictorClient};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let image_path = args.next().ok_or("Использование: client <скриншот> [адрес_сервера]")?;
+    let server_url = args.next().unwrap_or_else(|| "http://localhost:5000".to_string());
+
+    let mut client = ErrorPredictorClient::new(server_url);
+    if let Ok(key) = std::env::var("BASHPIC_API_KEY") {
+        client = client.with_api_key(key);
+    }
+
+    let image = std::fs::read(&image_path)?;
+    match client.predict_os_error(&image).await {
+        Ok(prediction) => {
+            println!("Тип ошибки: {} ({:.2}%)", prediction.error_type, prediction.confidence * 100.0);
+            println!("ОС: {} ({:.2}%)", prediction.os_type, prediction.os_confidence * 100.0);
+            println!("Описание: {}", prediction.description);
+            for solution in &prediction.solutions {
+                println!("  - {}", solution);
+            }
+            Ok(())
+        }
+        Err(ClientError::Api { status: 401, .. }) => Err("Нужен API-ключ в переменной BASHPIC_API_KEY".into()),
+        Err(e) => Err(e.into()),
+    }
+}
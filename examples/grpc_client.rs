@@ -0,0 +1,30 @@
+// Пример gRPC-клиента: анализ скриншота вызовом PredictOsError
+// cargo run --example grpc_client -- screenshot.png [http://localhost:50051]
+mod inference_proto {
+    tonic::include_proto!("bashpic.inference");
+}
+
+use inference_proto::ImageRequest;
+use inference_proto::inference_client::InferenceClient;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let image_path = args.next().ok_or("Использование: grpc_client <скриншот> [адрес_сервиса]")?;
+    let address = args.next().unwrap_or_else(|| "http://localhost:50051".to_string());
+
+    let mut client = InferenceClient::connect(address).await?;
+    let mut request = tonic::Request::new(ImageRequest { image: std::fs::read(&image_path)? });
+    if let Ok(key) = std::env::var("BASHPIC_API_KEY") {
+        request.metadata_mut().insert("x-api-key", key.parse()?);
+    }
+
+    let prediction = client.predict_os_error(request).await?.into_inner();
+    println!("Тип ошибки: {} ({:.2}%)", prediction.error_type, prediction.confidence * 100.0);
+    println!("ОС: {} ({:.2}%)", prediction.os_type, prediction.os_confidence * 100.0);
+    println!("Описание: {}", prediction.description);
+    for solution in &prediction.solutions {
+        println!("  - {}", solution);
+    }
+    Ok(())
+}
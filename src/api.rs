@@ -0,0 +1,57 @@
+// Структуры запросов и ответов REST API, общие для сервера и клиента
+use serde::{Deserialize, Serialize};
+
+// Структура для запроса предсказания
+// Изображение передается либо плоским вектором, либо файлом в base64 (при наличии обоих
+// используется image_base64)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PredictRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<Vec<f32>>,      // Входное изображение как плоский вектор [3, size, size]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_base64: Option<String>, // Закодированный в base64 файл изображения (PNG, JPEG и др.)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_format: Option<InputFormat>, // Порядок значений в image [по умолчанию: nchw]
+}
+
+// Порядок значений плоского вектора image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    #[default]
+    Nchw, // Плоскости каналов [3, H, W], как в PyTorch
+    Nhwc, // Пиксели подряд [H, W, 3], как в TensorFlow
+}
+
+// Запрос /predict-batch: несколько изображений в формате PredictRequest
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PredictBatchRequest {
+    pub images: Vec<PredictRequest>,
+}
+
+// Структура для ответа с предсказанием
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PredictResponse {
+    pub class: i64,
+    pub confidence: f32,
+}
+
+// Структура для ответа с предсказанием ошибок ОС
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OsErrorPredictResponse {
+    pub error_type: String,
+    pub os_type: String,
+    pub confidence: f32,
+    pub os_confidence: f32,
+    pub os_corrected: bool, // Тип ОС заменен, потому что противоречил типу ошибки
+    pub description: String,
+    pub possible_causes: Vec<String>,
+    pub solutions: Vec<String>,
+}
+
+// Тело ответа с ошибкой: { "error": "...", "code": "..." }
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorBody {
+    pub error: String,
+    pub code: String,
+}
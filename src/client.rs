@@ -0,0 +1,123 @@
+// Асинхронный клиент REST API сервера для сервисов на Rust
+use crate::api::{ErrorBody, OsErrorPredictResponse, PredictBatchRequest, PredictRequest, PredictResponse};
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+// Ошибка вызова API: сбой соединения/разбора ответа или ответ сервера со статусом не 2xx
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    Api {
+        status: u16,
+        code: Option<String>, // Код из тела { "error", "code" }; нет, если тело не JSON (например, 401)
+        message: String,
+    },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "Ошибка HTTP-запроса: {}", e),
+            ClientError::Api { status, code: Some(code), message } => {
+                write!(f, "Сервер вернул {} ({}): {}", status, code, message)
+            }
+            ClientError::Api { status, code: None, message } => write!(f, "Сервер вернул {}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::Api { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+// Клиент для /predict и /predict-os-error. Изображение отправляется файлом в поле image_base64
+#[derive(Clone)]
+pub struct ErrorPredictorClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl ErrorPredictorClient {
+    // base_url - адрес сервера, например http://localhost:5000
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(reqwest::Client::new(), base_url)
+    }
+
+    // Клиент с собственными настройками reqwest (таймауты, прокси, TLS)
+    pub fn with_http_client(http: reqwest::Client, base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        ErrorPredictorClient { http, base_url, api_key: None }
+    }
+
+    // API-ключ для заголовка X-API-Key
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    // Общая классификация изображения (PNG, JPEG и др.)
+    pub async fn predict_image(&self, image: &[u8]) -> Result<PredictResponse, ClientError> {
+        self.post_image("/predict", image).await
+    }
+
+    // Анализ скриншота ошибки ОС
+    pub async fn predict_os_error(&self, image: &[u8]) -> Result<OsErrorPredictResponse, ClientError> {
+        self.post_image("/predict-os-error", image).await
+    }
+
+    // Общая классификация нескольких изображений одним запросом; результаты в порядке изображений.
+    // Ответ запрашивается одним JSON-массивом, без потока NDJSON
+    pub async fn predict_batch(&self, images: &[&[u8]]) -> Result<Vec<PredictResponse>, ClientError> {
+        let body = PredictBatchRequest {
+            images: images.iter().map(|image| encode_image(image)).collect(),
+        };
+        let request = self.request("/predict-batch", &body).header("accept", "application/json");
+        Self::send(request).await
+    }
+
+    async fn post_image<T: DeserializeOwned>(&self, path: &str, image: &[u8]) -> Result<T, ClientError> {
+        Self::send(self.request(path, &encode_image(image))).await
+    }
+
+    fn request<B: serde::Serialize>(&self, path: &str, body: &B) -> reqwest::RequestBuilder {
+        let request = self.http.post(format!("{}{}", self.base_url, path)).json(body);
+        match &self.api_key {
+            Some(key) => request.header("x-api-key", key),
+            None => request,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T, ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await?;
+            return Err(match serde_json::from_str::<ErrorBody>(&text) {
+                Ok(body) => ClientError::Api { status: status.as_u16(), code: Some(body.code), message: body.error },
+                Err(_) => ClientError::Api { status: status.as_u16(), code: None, message: text },
+            });
+        }
+        Ok(response.json().await?)
+    }
+}
+
+fn encode_image(image: &[u8]) -> PredictRequest {
+    PredictRequest {
+        image: None,
+        image_base64: Some(base64::engine::general_purpose::STANDARD.encode(image)),
+        input_format: None,
+    }
+}
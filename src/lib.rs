@@ -0,0 +1,5 @@
+// Общие типы REST API и асинхронный клиент (feature "client") для сервисов на Rust
+pub mod api;
+
+#[cfg(feature = "client")]
+pub mod client;
@@ -1,54 +1,411 @@
 
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, Result, HttpRequest};
+use actix_web::{web, App, HttpResponse, HttpServer, Result, HttpRequest};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Condition, Next};
 use actix_web_actors::ws;
-use actix::{Actor, StreamHandler, Handler, Message, Addr};
-use tch::{nn, nn::Module, Device, Tensor, Kind};
+use actix::{Actor, ActorContext, ActorFutureExt, AsyncContext, ContextFutureSpawner, StreamHandler, Handler, Message, Addr, WrapFuture};
+use tch::{nn, nn::Module, nn::ModuleT, nn::OptimizerConfig, Device, Tensor, Kind};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
 use clap::{Parser, Subcommand};
-use image::GenericImageView;
 use std::path::Path;
 use uuid::Uuid;
-use base64;
+use tracing::Instrument;
+use base64::Engine;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use bashpic::api::{ErrorBody, InputFormat, OsErrorPredictResponse, PredictBatchRequest, PredictRequest, PredictResponse};
+
+// Ошибка обработки запроса. Клиент получает JSON вида { "error": "...", "code": "..." }
+// вместо стандартного текстового ответа actix
+#[derive(Debug, Clone)]
+enum AppError {
+    LockPoisoned,            // Блокировка моделей отравлена паникой в другом потоке
+    BadInput(String),        // Некорректный запрос клиента
+    InferenceFailed(String), // Ошибка libtorch во время инференса
+    PayloadTooLarge(usize),  // Тело запроса больше --max-body-size (лимит в байтах)
+    Internal(String),        // Сбой на стороне сервера, не связанный с инференсом (например, запись файла)
+    NotFound(String),        // Запрошенный ресурс (например, модель по имени) не существует
+    Overloaded(std::time::Duration), // Инференс не уложился в --inference-timeout
+    FetchFailed(String),     // Не удалось скачать изображение для /predict-url
+}
 
-// Структура для запроса предсказания
-#[derive(Deserialize)]
-struct PredictRequest {
-    image: Vec<f32>, // Входное изображение как плоский вектор
+impl AppError {
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::LockPoisoned => "lock_poisoned",
+            AppError::BadInput(_) => "bad_input",
+            AppError::InferenceFailed(_) => "inference_failed",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::Internal(_) => "internal",
+            AppError::NotFound(_) => "not_found",
+            AppError::Overloaded(_) => "overloaded",
+            AppError::FetchFailed(_) => "fetch_failed",
+        }
+    }
 }
 
-// Структура для ответа с предсказанием
-#[derive(Serialize)]
-struct PredictResponse {
-    class: i64,
-    confidence: f32,
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::LockPoisoned => write!(f, "Модель недоступна: блокировка повреждена после сбоя"),
+            AppError::BadInput(message) => write!(f, "{}", message),
+            AppError::InferenceFailed(message) => write!(f, "Ошибка инференса: {}", message),
+            AppError::PayloadTooLarge(limit) => write!(
+                f,
+                "Тело запроса больше допустимых {} байт, уменьшите изображение или увеличьте --max-body-size",
+                limit
+            ),
+            AppError::Internal(message) => write!(f, "Внутренняя ошибка сервера: {}", message),
+            AppError::NotFound(message) => write!(f, "{}", message),
+            AppError::Overloaded(timeout) => write!(
+                f,
+                "Сервер перегружен: инференс не уложился в {} с, повторите запрос позже",
+                timeout.as_secs()
+            ),
+            AppError::FetchFailed(message) => write!(f, "Не удалось получить изображение: {}", message),
+        }
+    }
 }
 
-// Структура для ответа с предсказанием ошибок ОС
-#[derive(Serialize)]
-struct OsErrorPredictResponse {
-    error_type: String,
-    os_type: String,
-    confidence: f32,
-    description: String,
+impl std::error::Error for AppError {}
+
+impl AppError {
+    fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.to_string(),
+            code: self.code().to_string(),
+        }
+    }
+}
+
+impl actix_web::ResponseError for AppError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            AppError::BadInput(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            AppError::LockPoisoned => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            AppError::InferenceFailed(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PayloadTooLarge(_) => actix_web::http::StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Internal(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            AppError::Overloaded(_) => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            AppError::FetchFailed(_) => actix_web::http::StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::Overloaded(timeout) = self {
+            response.insert_header((actix_web::http::header::RETRY_AFTER, timeout.as_secs().to_string()));
+        }
+        response.json(self.body())
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for AppError {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        AppError::LockPoisoned
+    }
+}
+
+impl From<AppError> for tonic::Status {
+    fn from(error: AppError) -> Self {
+        match error {
+            AppError::BadInput(message) => tonic::Status::invalid_argument(message),
+            AppError::NotFound(message) => tonic::Status::not_found(message),
+            AppError::PayloadTooLarge(_) => tonic::Status::resource_exhausted(error.to_string()),
+            AppError::LockPoisoned | AppError::Overloaded(_) | AppError::FetchFailed(_) => tonic::Status::unavailable(error.to_string()),
+            AppError::InferenceFailed(_) | AppError::Internal(_) => tonic::Status::internal(error.to_string()),
+        }
+    }
+}
+
+// Ошибка команды командной строки. Сообщение печатается в stderr, а код выхода
+// зависит от категории, чтобы скрипты могли отличить плохой вызов от сбоя модели
+#[derive(Debug)]
+enum CliError {
+    Io(String),     // Чтение или запись файлов, сеть
+    Torch(String),  // Загрузка весов, инференс и прочие ошибки libtorch
+    Image(String),  // Чтение и декодирование изображений
+    Config(String), // Некорректные флаги или файл конфигурации
+    Train(String),  // Обучение не удалось или остановлено проверкой данных
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            // 2, как у clap при ошибке разбора аргументов
+            CliError::Config(_) => 2,
+            CliError::Io(_) => 3,
+            CliError::Image(_) => 4,
+            CliError::Torch(_) => 5,
+            CliError::Train(_) => 6,
+        }
+    }
+
+    // Ошибка команды с контекстом. Категорию определяет тип исходной ошибки, а если он
+    // не известен (например, сообщение из format!), берется категория команды по умолчанию
+    fn command(context: &str, error: Box<dyn std::error::Error>, default: fn(String) -> CliError) -> Self {
+        let message = format!("{}: {}", context, error);
+        if error.is::<std::io::Error>() {
+            CliError::Io(message)
+        } else if error.is::<image::ImageError>() {
+            CliError::Image(message)
+        } else if error.is::<tch::TchError>() {
+            CliError::Torch(message)
+        } else {
+            default(message)
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Io(message)
+            | CliError::Torch(message)
+            | CliError::Image(message)
+            | CliError::Config(message)
+            | CliError::Train(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<std::io::Error> for CliError {
+    fn from(error: std::io::Error) -> Self {
+        CliError::Io(format!("Ошибка ввода-вывода: {}", error))
+    }
+}
+
+impl From<tch::TchError> for CliError {
+    fn from(error: tch::TchError) -> Self {
+        CliError::Torch(format!("Ошибка libtorch: {}", error))
+    }
+}
+
+impl From<image::ImageError> for CliError {
+    fn from(error: image::ImageError) -> Self {
+        CliError::Image(format!("Ошибка изображения: {}", error))
+    }
+}
+
+// Выполнение инференса с перехватом паники: tch паникует при ошибках libtorch
+// (например, несовпадении размеров тензоров), и без перехвата клиент получил бы обрыв соединения
+fn catch_inference<T>(inference: impl FnOnce() -> T) -> Result<T, AppError> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(inference)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|message| message.to_string()))
+            .unwrap_or_else(|| "неизвестная ошибка".to_string());
+        AppError::InferenceFailed(message)
+    })
+}
+
+// Сколько секунд REST-запрос ждет инференса по умолчанию, включая ожидание свободного слота
+const DEFAULT_INFERENCE_TIMEOUT_SECS: u64 = 30;
+
+// Инференс REST-обработчиков выполняется в пуле блокирующих потоков, а не в воркере actix.
+// Одновременно идет не больше forward, чем воркеров: иначе под нагрузкой пул разрастается
+// до сотен потоков, которые делят одни и те же ядра. Запрос, не получивший результат
+// за timeout, завершается 503
+#[derive(Clone)]
+struct InferenceLimits {
+    timeout: std::time::Duration,
+    slots: Arc<tokio::sync::Semaphore>,
+}
+
+impl InferenceLimits {
+    fn new(timeout: std::time::Duration, slots: usize) -> Self {
+        InferenceLimits {
+            timeout,
+            slots: Arc::new(tokio::sync::Semaphore::new(slots)),
+        }
+    }
+
+    // Прервать libtorch посреди forward нельзя: после таймаута он дорабатывает в фоне
+    // и держит слот до конца, так что следующие запросы быстрее получают 503
+    async fn run<T: Send + 'static>(
+        &self,
+        inference: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+    ) -> Result<T, AppError> {
+        let slots = self.slots.clone();
+        let blocking = async move {
+            let slot = slots
+                .acquire_owned()
+                .await
+                .map_err(|e| AppError::Internal(format!("слоты инференса закрыты: {}", e)))?;
+            web::block(move || {
+                let _slot = slot;
+                inference()
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("пул блокирующих потоков: {}", e)))?
+        };
+        tokio::time::timeout(self.timeout, blocking)
+            .await
+            .unwrap_or(Err(AppError::Overloaded(self.timeout)))
+    }
+}
+
+// Ошибка инференса из-за нехватки памяти CUDA ("CUDA out of memory", "CUDA error: out of memory")
+fn is_cuda_out_of_memory(error: &AppError) -> bool {
+    matches!(error, AppError::InferenceFailed(message) if message.contains("out of memory"))
+}
+
+// Инференс на device; если там не хватило памяти CUDA и fallback разрешен, запрос
+// повторяется через on_cpu. Вынесено из OsErrorModelBundle::infer, чтобы путь
+// с нехваткой памяти проверялся без GPU
+fn retry_on_cpu_after_oom<T>(
+    device: Device,
+    fallback: bool,
+    on_device: impl FnOnce() -> Result<T, AppError>,
+    on_cpu: impl FnOnce() -> Result<T, AppError>,
+) -> Result<T, AppError> {
+    match on_device() {
+        Err(e) if fallback && device != Device::Cpu && is_cuda_out_of_memory(&e) => {
+            tracing::warn!("Не хватило памяти {:?}, запрос повторяется на CPU: {}", device, e);
+            on_cpu()
+        }
+        result => result,
+    }
+}
+
+// Ограничение размера тела REST-запроса и сообщения чата по умолчанию, байт.
+// Стандартные 256 КБ actix не вмещают изображение 128x128 плоским массивом float в JSON
+const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+// Лимит размера тела запроса и кадра WebSocket, заданный --max-body-size
+#[derive(Clone, Copy)]
+struct MaxBodySize(usize);
+
+fn parse_max_body_size(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(format!("Размер тела должен быть положительным числом байт, получено {}", value)),
+    }
+}
+
+// Ошибки разбора JSON-тела и строки запроса в том же формате, что и AppError.
+// Тело больше limit отклоняется с 413 до разбора
+fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(move |e, _| match e {
+            actix_web::error::JsonPayloadError::Overflow { .. }
+            | actix_web::error::JsonPayloadError::OverflowKnownLength { .. } => AppError::PayloadTooLarge(limit).into(),
+            e => AppError::BadInput(format!("Некорректное тело запроса: {}", e)).into(),
+        })
+}
+
+fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|e, _| AppError::BadInput(format!("Некорректные параметры запроса: {}", e)).into())
+}
+
+// Декодирование изображения в base64. Некоторые клиенты присылают data URL целиком
+// (data:image/png;base64,...), префикс отбрасывается
+fn decode_base64_image(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let encoded = encoded.trim();
+    let encoded = match encoded.strip_prefix("data:").and_then(|rest| rest.split_once(";base64,")) {
+        Some((_, data)) => data,
+        None => encoded,
+    };
+    base64::engine::general_purpose::STANDARD.decode(encoded)
+}
+
+// Тензор [1, 3, size, size] из запроса предсказания; некорректный ввод - 400 Bad Request
+fn request_to_tensor(req: &PredictRequest, size: i64) -> Result<Tensor, AppError> {
+    if let Some(encoded) = &req.image_base64 {
+        let bytes = decode_base64_image(encoded)
+            .map_err(|e| AppError::BadInput(format!("Некорректный base64: {}", e)))?;
+        let img = detect_and_decode(&bytes).map_err(|e| AppError::BadInput(e.to_string()))?;
+        return Ok(image_to_tensor(&img, size));
+    }
+
+    match &req.image {
+        // Число значений одинаково в обоих порядках, различается только их расположение
+        Some(flat) if flat.len() as i64 == 3 * size * size => {
+            let tensor = Tensor::of_slice(flat).to_device(Device::Cpu);
+            Ok(match req.input_format.unwrap_or_default() {
+                InputFormat::Nchw => tensor.view([1, 3, size, size]),
+                // Пиксели подряд (H, W, C) переставляются в плоскости каналов (C, H, W)
+                InputFormat::Nhwc => tensor.view([1, size, size, 3]).permute(&[0, 3, 1, 2]).contiguous(),
+            })
+        }
+        Some(flat) => Err(AppError::BadInput(format!(
+            "Ожидается {} значений в image, получено {}",
+            3 * size * size,
+            flat.len()
+        ))),
+        None => Err(AppError::BadInput("Требуется поле image или image_base64".to_string())),
+    }
 }
 
 // Структуры для чата
 #[derive(Deserialize)]
 struct ChatMessage {
     message: String,
-    image_data: Option<String>, // Base64 encoded image
+    image_data: Option<ChatImages>,
+}
+
+// Один скриншот строкой base64 или несколько массивом строк
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChatImages {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl ChatImages {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            ChatImages::Single(image) => std::slice::from_ref(image),
+            ChatImages::Multiple(images) => images,
+        }
+    }
 }
 
+// Максимум скриншотов в одном сообщении чата: каждый прогоняется через модель
+const MAX_CHAT_IMAGES: usize = 5;
+
 #[derive(Serialize)]
 struct ChatResponse {
     response: String,
     analysis: Option<ErrorAnalysis>,
+    // Анализы по каждому скриншоту, если в сообщении их несколько
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    analyses: Vec<ErrorAnalysis>,
     suggestions: Vec<String>,
+    // Уверенность ниже --escalation-threshold, случай передан на проверку специалисту
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    escalated: bool,
+}
+
+// Подтверждение приема скриншотов, отправляемое сразу, до инференса.
+// Итоговый ChatResponse с анализом приходит следом в ту же сессию
+#[derive(Serialize)]
+struct ChatAcknowledgement {
+    response: String,
+    pending: usize, // Сколько скриншотов анализируется
 }
 
+// Фрагмент ответа языковой модели, отправляемый по мере генерации.
+// Итоговый ChatResponse приходит после всех фрагментов и заменяет их
 #[derive(Serialize)]
+struct ChatResponseChunk {
+    chunk: String,
+    index: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct ErrorAnalysis {
     error_type: String,
     os_type: String,
@@ -56,6 +413,9 @@ struct ErrorAnalysis {
     detailed_description: String,
     possible_causes: Vec<String>,
     solutions: Vec<String>,
+    // Перцептивный хэш проанализированного скриншота; у анализа текста его нет
+    #[serde(skip)]
+    image_hash: Option<u64>,
 }
 
 // Типы ошибок операционных систем
@@ -74,937 +434,9937 @@ const OS_ERROR_TYPES: &[&str] = &[
 
 const OS_TYPES: &[&str] = &["windows", "linux", "macos", "unknown"];
 
-// WebSocket актор для чата
-struct ChatSession {
-    id: Uuid,
-    addr: Addr<ChatServer>,
+// Типы ОС, на которых возможна ошибка; None - ошибка встречается в любой ОС
+fn plausible_os_types(error_type: &str) -> Option<&'static [&'static str]> {
+    match error_type {
+        "blue_screen_of_death" => Some(&["windows"]),
+        "kernel_panic" => Some(&["linux", "macos"]),
+        _ => None,
+    }
 }
 
-impl Actor for ChatSession {
-    type Context = ws::WebsocketContext<Self>;
-
-    fn started(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address();
-        self.addr.do_send(Connect {
-            id: self.id,
-            addr: addr.recipient(),
-        });
+// Согласование головы ОС с типом ошибки. Головы обучаются независимо, и голова ОС
+// может выдать невозможное сочетание (например, macos для BSOD). Тогда выбирается
+// наиболее вероятная по той же голове допустимая ОС, а уверенностью становится ее
+// вероятность, которая ниже исходной. Возвращает индекс в os_types, уверенность
+// и признак замены
+fn reconcile_os_type(error_type: &str, os_probs: &[f32], os_types: &[String]) -> (usize, f32, bool) {
+    let probability = |idx: usize| os_probs.get(idx).copied().unwrap_or(0.0);
+    let most_likely = |a: &usize, b: &usize| probability(*a).total_cmp(&probability(*b));
+
+    let predicted = (0..os_probs.len()).max_by(most_likely).unwrap_or(0);
+    let predicted_os = os_types.get(predicted).map_or("unknown", String::as_str);
+
+    match plausible_os_types(error_type) {
+        Some(plausible) if !plausible.contains(&predicted_os) => plausible
+            .iter()
+            .filter_map(|os| os_types.iter().position(|known| known == os))
+            .max_by(most_likely)
+            .map_or((predicted, probability(predicted), false), |idx| (idx, probability(idx), true)),
+        _ => (predicted, probability(predicted), false),
     }
+}
 
-    fn stopping(&mut self, _: &mut Self::Context) -> actix::Running {
-        self.addr.do_send(Disconnect { id: self.id });
-        actix::Running::Stop
+// Краткое описание типа ошибки для ответов API и CLI
+fn error_description(error_type: &str) -> &'static str {
+    match error_type {
+        "blue_screen_of_death" => "Критическая системная ошибка Windows (BSOD)",
+        "kernel_panic" => "Критическая ошибка ядра Linux/macOS",
+        "application_crash" => "Неожиданное завершение работы приложения",
+        "memory_error" => "Ошибка доступа к памяти или нехватка RAM",
+        "disk_error" => "Ошибка чтения/записи диска",
+        "network_error" => "Проблемы с сетевым подключением",
+        "permission_denied" => "Недостаточно прав для выполнения операции",
+        "file_not_found" => "Файл или ресурс не найден",
+        "system_overload" => "Перегрузка системы",
+        "driver_error" => "Ошибка драйвера устройства",
+        _ => "Неизвестная ошибка"
     }
 }
 
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
-    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Text(text)) => {
-                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&text) {
-                    self.addr.do_send(ClientMessage {
-                        id: self.id,
-                        msg: chat_msg,
-                        addr: ctx.address().recipient(),
-                    });
-                }
-            }
-            Ok(ws::Message::Binary(_)) => println!("Unexpected binary"),
-            _ => (),
+// Переменная окружения со списком API-ключей через запятую
+const API_KEYS_ENV: &str = "BASHPIC_API_KEYS";
+
+// Набор API-ключей для защищенных эндпоинтов
+struct ApiKeys {
+    keys: Vec<String>,
+}
+
+impl ApiKeys {
+    // Загрузка ключей из переменной окружения и файла (по одному ключу на строку)
+    fn load(path: Option<&str>) -> std::io::Result<Self> {
+        let mut keys: Vec<String> = std::env::var(API_KEYS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect();
+
+        if let Some(path) = path {
+            let content = std::fs::read_to_string(path)?;
+            keys.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
         }
+
+        Ok(ApiKeys { keys })
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    // Проверяются все ключи без раннего выхода, чтобы время ответа не зависело от совпадения
+    fn contains(&self, candidate: &str) -> bool {
+        self.keys
+            .iter()
+            .fold(false, |found, key| found | constant_time_eq(key.as_bytes(), candidate.as_bytes()))
     }
 }
 
-// Сервер чата
-struct ChatServer {
-    sessions: HashMap<Uuid, actix::Recipient<ws::Message>>,
-    models: std::sync::Arc<Mutex<((Box<dyn nn::Module + Send>, Box<dyn nn::Module + Send>), nn::VarStore)>>,
+// Сравнение строк за время, не зависящее от позиции первого различия
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
-impl ChatServer {
-    fn new(models: std::sync::Arc<Mutex<((Box<dyn nn::Module + Send>, Box<dyn nn::Module + Send>), nn::VarStore)>>) -> Self {
-        ChatServer {
-            sessions: HashMap::new(),
-            models,
+// Ключ в ?api_key= для /ws/: браузер не может задать заголовок при открытии WebSocket
+#[derive(Deserialize)]
+struct ApiKeyQuery {
+    api_key: Option<String>,
+}
+
+// API-ключ запроса: заголовок X-API-Key, а с allow_query (WebSocket) также параметр api_key
+fn request_api_key(req: &ServiceRequest, allow_query: bool) -> Option<String> {
+    let header = req
+        .headers()
+        .get("X-API-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    header.or_else(|| {
+        if !allow_query {
+            return None;
         }
-    }
+        web::Query::<ApiKeyQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().api_key)
+    })
 }
 
-impl Actor for ChatServer {
-    type Context = actix::Context<Self>;
+// Проверка ключа, если ключи заданы. Какие маршруты ее проходят, решает регистрация
+// маршрутов в configure_routes, а не сравнение пути: роутер actix сопоставляет путь
+// после percent-декодирования, и /predict%2Dos-error попадает в тот же обработчик
+fn check_api_key(req: &ServiceRequest, allow_query: bool) -> Result<(), actix_web::Error> {
+    match req.app_data::<web::Data<ApiKeys>>() {
+        Some(api_keys) if api_keys.is_enabled() && !request_api_key(req, allow_query).is_some_and(|key| api_keys.contains(&key)) => {
+            Err(actix_web::error::ErrorUnauthorized("Отсутствует или неверный API-ключ"))
+        }
+        _ => Ok(()),
+    }
 }
 
-// Сообщения для актора
-#[derive(Message)]
-#[rtype(result = "()")]
-struct Connect {
-    id: Uuid,
-    addr: actix::Recipient<ws::Message>,
+// Проверка источника для --cors-allow-origin: "*" или схема, хост и необязательный порт
+fn parse_cors_origin(value: &str) -> Result<String, String> {
+    if value == "*" {
+        return Ok(value.to_string());
+    }
+    let host = value
+        .strip_prefix("http://")
+        .or_else(|| value.strip_prefix("https://"))
+        .ok_or_else(|| format!("Источник {} должен начинаться с http:// или https://", value))?;
+    if host.is_empty() || host.contains('/') {
+        return Err(format!("Источник {} должен состоять из схемы, хоста и порта без пути", value));
+    }
+    Ok(value.to_string())
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-struct Disconnect {
-    id: Uuid,
+// CORS для REST-маршрутов: разрешенные источники, методы и заголовки API.
+// Без разрешенных источников middleware не подключается, и браузер допускает
+// только запросы с того же источника
+fn build_cors(allowed_origins: &[String]) -> actix_cors::Cors {
+    let cors = actix_cors::Cors::default()
+        .allowed_methods(vec!["GET", "POST"])
+        .allowed_headers(vec![
+            actix_web::http::header::CONTENT_TYPE,
+            HeaderName::from_static("x-api-key"),
+            HeaderName::from_static("x-request-id"),
+        ])
+        .expose_headers(vec![HeaderName::from_static("x-request-id"), HeaderName::from_static("x-response-time-ms")])
+        .max_age(3600);
+
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        allowed_origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin))
+    }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-struct ClientMessage {
-    id: Uuid,
-    msg: ChatMessage,
-    addr: actix::Recipient<ws::Message>,
+// Загрузка TLS-конфигурации из PEM-файлов сертификата и приватного ключа
+fn load_rustls_config(cert_path: &str, key_path: &str) -> std::io::Result<rustls::ServerConfig> {
+    let mut cert_reader = std::io::BufReader::new(std::fs::File::open(cert_path)?);
+    let mut key_reader = std::io::BufReader::new(std::fs::File::open(key_path)?);
+
+    let cert_chain = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    if cert_chain.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("В файле {} не найдено сертификатов", cert_path),
+        ));
+    }
+
+    let key = rustls_pemfile::private_key(&mut key_reader)?.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("В файле {} не найден приватный ключ", key_path),
+        )
+    })?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
 
-impl Handler<Connect> for ChatServer {
-    type Result = ();
+// Счетчик обрабатываемых HTTP-запросов, нужен для отчета при завершении работы
+#[derive(Default)]
+struct InFlightRequests(AtomicUsize);
 
-    fn handle(&mut self, msg: Connect, _: &mut Self::Context) {
-        self.sessions.insert(msg.id, msg.addr);
+// Уменьшает счетчик при завершении обработки, в том числе при ошибке
+struct InFlightGuard(web::Data<InFlightRequests>);
+
+impl InFlightGuard {
+    fn new(counter: web::Data<InFlightRequests>) -> Self {
+        counter.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(counter)
     }
 }
 
-impl Handler<Disconnect> for ChatServer {
-    type Result = ();
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
-        self.sessions.remove(&msg.id);
+// Middleware, присваивающий каждому запросу идентификатор и замеряющий время обработки.
+// Идентификатор попадает в заголовок X-Request-Id и в tracing-span запроса. Если клиент
+// сам передал UUID в X-Request-Id, используется он, чтобы запрос прослеживался сквозь сервисы
+async fn request_id_and_timing(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let _in_flight = req.app_data::<web::Data<InFlightRequests>>().cloned().map(InFlightGuard::new);
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Uuid::parse_str(value.trim()).ok())
+        .unwrap_or_else(Uuid::new_v4);
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+    );
+    let http_request = req.request().clone();
+    let started = std::time::Instant::now();
+
+    // Ошибки внутренних middleware (например, 401) тоже получают заголовки
+    let mut res = match next.call(req).instrument(span.clone()).await {
+        Ok(res) => res.map_into_boxed_body(),
+        Err(e) => ServiceResponse::from_err(e, http_request),
+    };
+
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    span.in_scope(|| tracing::info!(status = res.status().as_u16(), elapsed_ms, "запрос обработан"));
+
+    let headers = res.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&request_id.to_string()) {
+        headers.insert(HeaderName::from_static("x-request-id"), value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.2}", elapsed_ms)) {
+        headers.insert(HeaderName::from_static("x-response-time-ms"), value);
     }
+
+    Ok(res)
 }
 
-impl Handler<ClientMessage> for ChatServer {
-    type Result = ();
+// Middleware проверки API-ключа (заголовок X-API-Key или ?api_key= для WebSocket)
+async fn api_key_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    check_api_key(&req, false)?;
+    next.call(req).await
+}
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Self::Context) {
-        let response = self.process_chat_message(&msg.msg);
-        let response_json = serde_json::to_string(&response).unwrap();
+// Браузер не может задать заголовок при открытии WebSocket, поэтому чат принимает и ?api_key=
+async fn websocket_api_key_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    check_api_key(&req, true)?;
+    next.call(req).await
+}
 
-        if let Some(addr) = self.sessions.get(&msg.id) {
-            let _ = addr.do_send(ws::Message::Text(response_json.into()));
-        }
+// Без ключей /admin/* закрыт: сервер по умолчанию слушает 0.0.0.0, и иначе любой
+// в сети мог бы подменить обслуживаемую модель или запустить переобучение.
+// С ключами сам ключ уже проверен api_key_auth снаружи
+async fn admin_enabled(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if !req.app_data::<web::Data<ApiKeys>>().is_some_and(|api_keys| api_keys.is_enabled()) {
+        return Err(actix_web::error::ErrorForbidden(
+            "Администрирование отключено: задайте API-ключи через BASHPIC_API_KEYS или --api-keys",
+        ));
     }
+    next.call(req).await
 }
 
-impl ChatServer {
-    fn process_chat_message(&self, msg: &ChatMessage) -> ChatResponse {
-        if let Some(image_data) = &msg.image_data {
-            // Обработка изображения
-            if let Ok(analysis) = self.analyze_screenshot(image_data) {
-                let suggestions = self.generate_suggestions(&analysis);
+// Обертка над моделями для параллельного инференса под RwLock.
+// forward только читает веса, поэтому одновременные вызовы из разных потоков безопасны,
+// но tch::Tensor не реализует Sync, и это обещание приходится давать явно — и только
+// для конкретных типов моделей ниже, а не для любого T.
+// Изменение весов (перезагрузка модели) выполняется только под блокировкой на запись.
+struct InferenceModels<T>(T);
+
+// SAFETY: модуль базовой модели собирается в simple_cnn из слоев nn, которые держат
+// только тензоры весов, а VarStore и метаданные через &self не изменяются. Под
+// блокировкой на чтение libtorch лишь читает эти тензоры, что потокобезопасно;
+// запись (замена модели) идет только через &mut под блокировкой на запись.
+// Импл общий по времени жизни модуля: иначе async-обработчики, держащие модель,
+// требуют Sync для любого времени жизни и не компилируются
+unsafe impl<'a> Sync for InferenceModels<(Box<dyn nn::Module + Send + 'a>, nn::VarStore, ModelMetadata)> {}
+
+// SAFETY: головы модели ошибок ОС — слои os_error_cnn_with (свертки, линейные и
+// квантованные слои с неизменяемыми тензорами), forward_t вызывается с train = false
+// и ничего не пишет. Единственное изменяемое через &self поле, cpu_copy, — OnceLock,
+// который сам синхронизирует инициализацию
+unsafe impl Sync for InferenceModels<OsErrorModelBundle> {}
+
+impl<T> Deref for InferenceModels<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
 
-                ChatResponse {
-                    response: format!("Я проанализировал ваш скриншот. Обнаружена ошибка типа '{}' в системе {}.",
-                                    analysis.error_type, analysis.os_type),
-                    analysis: Some(analysis),
-                    suggestions,
-                }
-            } else {
-                ChatResponse {
-                    response: "Не удалось проанализировать изображение. Убедитесь, что это скриншот с ошибкой.".to_string(),
-                    analysis: None,
-                    suggestions: vec![
-                        "Загрузите четкий скриншот ошибки".to_string(),
-                        "Убедитесь, что изображение содержит текст ошибки".to_string(),
-                    ],
-                }
-            }
-        } else {
-            // Обработка текстового сообщения
-            self.process_text_query(&msg.message)
-        }
+impl<T> DerefMut for InferenceModels<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
     }
+}
 
-    fn analyze_screenshot(&self, image_data: &str) -> Result<ErrorAnalysis, Box<dyn std::error::Error>> {
-        // Декодирование base64 изображения
-        let image_bytes = base64::decode(image_data)?;
-        let img = image::load_from_memory(&image_bytes)?;
-        let img = img.resize_exact(128, 128, image::imageops::FilterType::Lanczos3);
+// Модель общей классификации, ее веса и метаданные
+type LoadedGenericModel = (Box<dyn nn::Module + Send>, nn::VarStore, ModelMetadata);
+type GenericModel = RwLock<InferenceModels<LoadedGenericModel>>;
+
+// Загрузка весов по именам переменных архитектуры. В отличие от vs.load, сразу сообщает
+// все недостающие имена, а не первое, и проверяет размеры каждого тензора
+fn load_named_weights(vs: &nn::VarStore, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut saved: HashMap<String, Tensor> = Tensor::load_multi(path)?.into_iter().collect();
+    let mut variables: Vec<(String, Tensor)> = vs.variables().into_iter().collect();
+    variables.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let missing: Vec<&str> = variables
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| !saved.contains_key(*name))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "В {} нет весов {}: файл сохранен для другой архитектуры или поврежден",
+            path,
+            missing.join(", ")
+        ).into());
+    }
 
-        let mut flat = Vec::with_capacity(3 * 128 * 128);
-        for y in 0..128 {
-            for x in 0..128 {
-                let pixel = img.get_pixel(x, y);
-                flat.push(pixel[0] as f32 / 255.0);
-                flat.push(pixel[1] as f32 / 255.0);
-                flat.push(pixel[2] as f32 / 255.0);
-            }
+    for (name, mut variable) in variables {
+        let Some(value) = saved.remove(&name) else { continue };
+        if value.size() != variable.size() {
+            return Err(format!(
+                "Размер веса {} в {} {:?} не совпадает с архитектурой {:?}",
+                name, path, value.size(), variable.size()
+            ).into());
         }
+        tch::no_grad(|| variable.copy_(&value));
+    }
+    if !saved.is_empty() {
+        let mut extra: Vec<String> = saved.into_keys().collect();
+        extra.sort();
+        eprintln!("Внимание: в {} есть тензоры, которых нет в архитектуре: {}", path, extra.join(", "));
+    }
+    Ok(())
+}
 
-        let image_tensor = Tensor::of_slice(&flat).view([1, 3, 128, 128]).to_device(Device::Cpu);
+// Сохранение только именованных весов модели. Состояние оптимизатора (.optim), которое
+// при полном сохранении вдвое больше самих весов, не пишется, а оставшееся от прошлого
+// полного сохранения удаляется, чтобы --resume не подхватил чужие моменты Adam
+fn save_weights_only(vs: &nn::VarStore, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let variables = vs.variables();
+    let named: Vec<(&str, Tensor)> = variables
+        .iter()
+        .map(|(name, variable)| (name.as_str(), variable.detach().to_device(Device::Cpu)))
+        .collect();
+    Tensor::save_multi(&named, path)?;
+    match std::fs::remove_file(ResumableAdam::path_for(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
 
-        let ((error_model, os_model), _) = &*self.models.lock().unwrap();
+// Загруженная модель для ошибок ОС: классификатор ошибки, классификатор ОС, их веса и метаданные
+struct OsErrorModelBundle {
+    error_model: Box<dyn nn::ModuleT + Send>,
+    os_model: Box<dyn nn::ModuleT + Send>,
+    vs: nn::VarStore,
+    metadata: ModelMetadata,
+    precision: Precision,
+    cpu_fallback: bool,                                 // Повторять на CPU запрос, которому не хватило памяти CUDA
+    cpu_copy: std::sync::OnceLock<Box<OsErrorModelBundle>>, // Копия весов в CPU, создается при первом повторе
+}
 
-        let error_output = error_model.forward(&image_tensor);
-        let error_probs = error_output.softmax(-1, Kind::Float);
-        let (error_confidence, error_class) = error_probs.max_dim(-1, false);
+impl OsErrorModelBundle {
+    // Построение архитектуры по метаданным (веса инициализируются случайно).
+    // Размеры выходов голов берутся из словаря меток в метаданных
+    fn new(metadata: ModelMetadata, device: Device) -> Self {
+        let vs = nn::VarStore::new(device);
+        let (error_model, os_model) = os_error_cnn(&vs.root(), metadata.num_classes, metadata.os_types.len() as i64);
+
+        OsErrorModelBundle {
+            error_model: Box::new(error_model),
+            os_model: Box::new(os_model),
+            vs,
+            metadata,
+            precision: Precision::Fp32,
+            cpu_fallback: false,
+            cpu_copy: std::sync::OnceLock::new(),
+        }
+    }
 
-        let os_output = os_model.forward(&image_tensor);
-        let os_probs = os_output.softmax(-1, Kind::Float);
-        let (_, os_class) = os_probs.max_dim(-1, false);
+    // Загрузка модели с архитектурой, восстановленной из метаданных рядом с файлом весов
+    fn load(model_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        OsErrorModelBundle::load_on(model_path, Device::Cpu, &Labels::builtin())
+    }
 
-        let error_idx = i64::from(&error_class.get(0)) as usize;
-        let os_idx = i64::from(&os_class.get(0)) as usize;
+    // Загрузка весов сразу на указанное устройство; выходы модели именуются по labels
+    fn load_on(model_path: &str, device: Device, labels: &Labels) -> Result<Self, Box<dyn std::error::Error>> {
+        let metadata = resolve_os_error_metadata(model_path, labels)?;
+        if metadata.quantization.is_some() {
+            return OsErrorModelBundle::load_quantized(model_path, metadata, device);
+        }
+        let bundle = OsErrorModelBundle::new(metadata, device);
+        load_named_weights(&bundle.vs, model_path)?;
+        Ok(bundle)
+    }
 
-        let error_type = OS_ERROR_TYPES.get(error_idx).unwrap_or(&"unknown").to_string();
-        let os_type = OS_TYPES.get(os_idx).unwrap_or(&"unknown").to_string();
+    // Квантованная модель выполняется только на CPU: сверточные слои загружаются в VarStore,
+    // полносвязные собираются из тензоров int8
+    fn load_quantized(model_path: &str, metadata: ModelMetadata, device: Device) -> Result<Self, Box<dyn std::error::Error>> {
+        if metadata.quantization.as_deref() != Some(QUANTIZATION_DYNAMIC_INT8) {
+            return Err(format!("Неизвестная схема квантования модели {}: {:?}", model_path, metadata.quantization).into());
+        }
+        if device != Device::Cpu {
+            eprintln!("Внимание: квантованная модель {} выполняется только на CPU", model_path);
+        }
 
-        let (detailed_description, possible_causes, solutions) = self.get_detailed_error_info(&error_type, &os_type);
+        let tensors: HashMap<String, Tensor> = Tensor::load_multi(model_path)?.into_iter().collect();
+        let mut vs = nn::VarStore::new(Device::Cpu);
+        let mut fbgemm = true;
+        let (error_model, os_model) = os_error_cnn_with(
+            &vs.root(),
+            metadata.num_classes,
+            metadata.os_types.len() as i64,
+            &mut |_, name, in_dim, out_dim| {
+                let layer = QuantizedLinear::from_checkpoint(&tensors, name, in_dim, out_dim)?;
+                fbgemm &= layer.uses_fbgemm();
+                Ok(layer)
+            },
+        )?;
+        vs.load(model_path)?;
+        if !fbgemm {
+            eprintln!("Внимание: FBGEMM недоступен, веса {} восстанавливаются в float32 без ускорения", model_path);
+        }
 
-        Ok(ErrorAnalysis {
-            error_type: error_type.clone(),
-            os_type: os_type.clone(),
-            confidence: f32::from(&error_confidence.get(0)),
-            detailed_description,
-            possible_causes,
-            solutions,
+        Ok(OsErrorModelBundle {
+            error_model: Box::new(error_model),
+            os_model: Box::new(os_model),
+            vs,
+            metadata,
+            precision: Precision::Fp32,
+            cpu_fallback: false,
+            cpu_copy: std::sync::OnceLock::new(),
         })
     }
 
-    fn get_detailed_error_info(&self, error_type: &str, os_type: &str) -> (String, Vec<String>, Vec<String>) {
-        match error_type {
-            "blue_screen_of_death" => (
-                "Синий экран смерти (BSOD) - критическая системная ошибка Windows, при которой операционная система не может продолжить работу и принудительно перезагружается.".to_string(),
-                vec![
-                    "Неисправность оборудования (RAM, жесткий диск)".to_string(),
-                    "Несовместимые или поврежденные драйверы".to_string(),
-                    "Перегрев процессора или видеокарты".to_string(),
-                    "Поврежденные системные файлы".to_string(),
-                ],
-                vec![
-                    "Проверьте код ошибки на экране и найдите его в документации Microsoft".to_string(),
-                    "Запустите проверку памяти Windows (mdsched.exe)".to_string(),
-                    "Обновите или откатите драйверы устройств".to_string(),
-                    "Запустите sfc /scannow для проверки системных файлов".to_string(),
-                    "Проверьте температуру компонентов".to_string(),
-                ]
-            ),
-            "kernel_panic" => (
-                "Паника ядра - критическая ошибка в ядре операционной системы Linux/macOS, после которой система не может продолжить безопасную работу.".to_string(),
-                vec![
-                    "Ошибки в модулях ядра".to_string(),
-                    "Неисправность оборудования".to_string(),
-                    "Несовместимые драйверы".to_string(),
-                    "Переполнение стека ядра".to_string(),
-                ],
-                vec![
-                    "Проанализируйте журналы системы (dmesg, /var/log/kern.log)".to_string(),
-                    "Загрузитесь с предыдущего стабильного ядра".to_string(),
-                    "Отключите недавно установленные модули".to_string(),
-                    "Проверьте оборудование с помощью memtest86+".to_string(),
-                ]
-            ),
-            "memory_error" => (
-                "Ошибка памяти указывает на проблемы с доступом к оперативной памяти или её нехватку.".to_string(),
-                vec![
-                    "Физическая неисправность модулей RAM".to_string(),
-                    "Нехватка оперативной памяти".to_string(),
-                    "Ошибки в управлении памятью приложением".to_string(),
-                ],
-                vec![
-                    "Запустите тест памяти (MemTest86, Windows Memory Diagnostic)".to_string(),
-                    "Закройте ненужные приложения".to_string(),
-                    "Увеличьте размер файла подкачки".to_string(),
-                    "Переустановите или замените модули RAM".to_string(),
-                ]
-            ),
-            _ => (
-                "Общая системная ошибка, требующая дополнительной диагностики.".to_string(),
-                vec!["Различные факторы могут вызывать эту ошибку".to_string()],
-                vec![
-                    "Перезагрузите систему".to_string(),
-                    "Проверьте журналы событий".to_string(),
-                    "Обратитесь к документации системы".to_string(),
-                ]
-            )
-        }
+    fn input_size(&self) -> i64 {
+        self.metadata.input_size()
     }
 
-    fn process_text_query(&self, message: &str) -> ChatResponse {
-        let message_lower = message.to_lowercase();
+    fn device(&self) -> Device {
+        self.vs.device()
+    }
 
-        if message_lower.contains("помощь") || message_lower.contains("help") {
-            ChatResponse {
-                response: "Я помогу вам диагностировать ошибки операционной системы! Загрузите скриншот ошибки, и я проанализирую её тип, определю ОС и предложу решения.".to_string(),
-                analysis: None,
-                suggestions: vec![
-                    "Загрузите скриншот с ошибкой для анализа".to_string(),
-                    "Спросите о конкретном типе ошибки".to_string(),
-                    "Опишите симптомы проблемы".to_string(),
-                ],
-            }
-        } else if message_lower.contains("bsod") || message_lower.contains("синий экран") {
-            ChatResponse {
-                response: "BSOD (Blue Screen of Death) - критическая ошибка Windows. Обычно вызвана проблемами с драйверами, оборудованием или системными файлами.".to_string(),
-                analysis: None,
-                suggestions: vec![
-                    "Запишите код ошибки с синего экрана".to_string(),
-                    "Проверьте последние установленные драйверы".to_string(),
-                    "Запустите тест памяти".to_string(),
-                ],
-            }
-        } else if message_lower.contains("kernel panic") || message_lower.contains("паника ядра") {
-            ChatResponse {
-                response: "Kernel Panic - критическая ошибка ядра в Linux/macOS. Система не может продолжить работу и перезагружается.".to_string(),
-                analysis: None,
-                suggestions: vec![
-                    "Проверьте журналы системы (/var/log/kern.log)".to_string(),
-                    "Загрузитесь с предыдущего ядра".to_string(),
-                    "Отключите проблемные модули ядра".to_string(),
-                ],
-            }
-        } else {
-            ChatResponse {
-                response: "Опишите вашу проблему подробнее или загрузите скриншот ошибки для анализа.".to_string(),
-                analysis: None,
-                suggestions: vec![
-                    "Загрузите скриншот ошибки".to_string(),
-                    "Укажите тип операционной системы".to_string(),
-                    "Опишите когда возникла ошибка".to_string(),
-                ],
-            }
+    fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    fn with_cpu_fallback(mut self, cpu_fallback: bool) -> Self {
+        self.cpu_fallback = cpu_fallback;
+        self
+    }
+
+    // Копия модели в CPU в fp32. Создается один раз и живет, пока модель не перезагружена
+    fn cpu_copy(&self) -> Result<&OsErrorModelBundle, AppError> {
+        if let Some(copy) = self.cpu_copy.get() {
+            return Ok(copy);
         }
+        let mut copy = OsErrorModelBundle::new(self.metadata.clone(), Device::Cpu);
+        copy.vs
+            .copy(&self.vs)
+            .map_err(|e| AppError::Internal(format!("Не удалось скопировать модель в CPU: {}", e)))?;
+        Ok(self.cpu_copy.get_or_init(|| Box::new(copy)))
     }
 
-    fn generate_suggestions(&self, analysis: &ErrorAnalysis) -> Vec<String> {
-        let mut suggestions = vec![
-            "Сохраните скриншот ошибки для дальнейшего анализа".to_string(),
-            "Запишите код ошибки, если он есть".to_string(),
-        ];
+    // Инференс без графа градиентов с перехватом паники libtorch. Если на GPU не хватило
+    // памяти, запрос повторяется на копии модели в CPU (отключается --no-cpu-fallback)
+    fn infer<T>(&self, input: &Tensor, inference: impl Fn(&OsErrorModelBundle, &Tensor) -> T) -> Result<T, AppError> {
+        retry_on_cpu_after_oom(
+            self.device(),
+            self.cpu_fallback,
+            || catch_inference(|| tch::no_grad(|| inference(self, &input.to_device(self.device())))),
+            || {
+                let cpu = self.cpu_copy()?;
+                catch_inference(|| tch::no_grad(|| inference(cpu, &input.to_device(Device::Cpu))))
+            },
+        )
+    }
 
-        suggestions.extend(analysis.solutions.clone());
-        suggestions
+    // Логиты головы ошибок и головы ОС. При fp16 свертки и полносвязные слои
+    // выполняются в половинной точности через autocast: VarStore в tch не умеет
+    // менять тип весов на месте, а autocast приводит к Half и веса, и вход
+    fn forward(&self, input: &Tensor) -> (Tensor, Tensor) {
+        let input = self.metadata.normalize(input);
+        tch::autocast(self.precision == Precision::Fp16, || {
+            (self.error_model.forward_t(&input, false), self.os_model.forward_t(&input, false))
+        })
     }
 }
 
-// Упрощенная модель CNN для демонстрации
-fn simple_cnn(p: &nn::Path, num_classes: i64) -> impl nn::Module {
-    let conv1 = nn::conv2d(p / "conv1", 3, 32, 3, nn::ConvConfig { padding: 1, ..Default::default() });
-    let conv2 = nn::conv2d(p / "conv2", 32, 64, 3, nn::ConvConfig { padding: 1, ..Default::default() });
-    let fc1 = nn::linear(p / "fc1", 64 * 8 * 8, 128, Default::default());
-    let fc2 = nn::linear(p / "fc2", 128, num_classes, Default::default());
+// Модели для ошибок ОС
+type OsErrorModels = RwLock<InferenceModels<OsErrorModelBundle>>;
+
+// Ключевые слова для определения типа ошибки по тексту: (тип ошибки, приоритет, ключевые слова).
+// Если совпало несколько типов, выбирается тип с наибольшим приоритетом.
+const ERROR_KEYWORDS: &[(&str, u8, &[&str])] = &[
+    ("blue_screen_of_death", 10, &["bsod", "blue screen", "синий экран", "stop code", "stop-код"]),
+    ("kernel_panic", 10, &["kernel panic", "паника ядра", "kernel oops"]),
+    ("memory_error", 8, &["out of memory", "oom-killer", "memory", "памят", "оперативн"]),
+    ("disk_error", 7, &["disk", "диск", "i/o error", "bad sector", "ошибка чтения", "ошибка записи"]),
+    ("driver_error", 7, &["driver", "драйвер"]),
+    ("permission_denied", 6, &["permission denied", "access denied", "отказано в доступе", "нет доступа", "нет прав"]),
+    ("file_not_found", 6, &["file not found", "no such file", "не найден", "not found"]),
+    ("network_error", 5, &["network", "connection refused", "dns", "сеть", "сетев", "подключени"]),
+    ("system_overload", 4, &["overload", "high cpu", "перегруз", "тормозит", "зависает"]),
+    ("application_crash", 3, &["crash", "краш", "вылет", "not responding", "не отвечает", "аварийно"]),
+];
 
-    nn::seq()
-        .add(conv1)
-        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
-        .add(conv2)
-        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
-        .add_fn(|xs| xs.flatten(1, -1))
-        .add(fc1)
-        .add_fn(|xs| xs.relu())
-        .add(fc2)
+// Ключевые слова для определения ОС по тексту
+const OS_KEYWORDS: &[(&str, &[&str])] = &[
+    ("windows", &["windows", "винд", "bsod", "синий экран"]),
+    ("linux", &["linux", "линукс", "ubuntu", "debian", "fedora", "dmesg"]),
+    ("macos", &["macos", "mac os", "macbook", "мак"]),
+];
+
+// Тип ошибки с наибольшим приоритетом среди совпавших и число совпавших ключевых слов
+fn classify_text(message_lower: &str) -> Option<(&'static str, usize)> {
+    ERROR_KEYWORDS
+        .iter()
+        .filter_map(|(error_type, priority, keywords)| {
+            let matches = keywords.iter().filter(|keyword| message_lower.contains(*keyword)).count();
+            (matches > 0).then_some((*error_type, *priority, matches))
+        })
+        .max_by_key(|(_, priority, matches)| (*priority, *matches))
+        .map(|(error_type, _, matches)| (error_type, matches))
 }
 
-// Специализированная модель CNN для анализа ошибок ОС с более крупными изображениями
-fn os_error_cnn(p: &nn::Path, num_error_types: i64, num_os_types: i64) -> (impl nn::Module, impl nn::Module) {
-    // Общие сверточные слои для извлечения признаков
-    let conv1 = nn::conv2d(p / "conv1", 3, 64, 5, nn::ConvConfig { padding: 2, ..Default::default() });
-    let conv2 = nn::conv2d(p / "conv2", 64, 128, 5, nn::ConvConfig { padding: 2, ..Default::default() });
-    let conv3 = nn::conv2d(p / "conv3", 128, 256, 3, nn::ConvConfig { padding: 1, ..Default::default() });
+fn detect_os_from_text(message_lower: &str) -> &'static str {
+    OS_KEYWORDS
+        .iter()
+        .find(|(_, keywords)| keywords.iter().any(|keyword| message_lower.contains(*keyword)))
+        .map(|(os_type, _)| *os_type)
+        .unwrap_or("unknown")
+}
 
-    let shared_features = nn::seq()
-        .add(conv1)
-        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
-        .add(conv2)
-        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
-        .add(conv3)
-        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
-        .add_fn(|xs| xs.flatten(1, -1));
+// Эвристическая уверенность текстового анализа: растет с числом совпадений, но не выше 0.8
+fn text_match_confidence(matches: usize) -> f32 {
+    (0.5 + 0.1 * (matches as f32 - 1.0)).min(0.8)
+}
 
-    // Классификатор типа ошибки
-    let error_classifier = nn::seq()
-        .add(nn::linear(p / "error_fc1", 256 * 16 * 16, 512, Default::default()))
-        .add_fn(|xs| xs.relu())
-        .add(nn::dropout(p / "error_dropout", 0.5))
-        .add(nn::linear(p / "error_fc2", 512, 256, Default::default()))
-        .add_fn(|xs| xs.relu())
-        .add(nn::linear(p / "error_out", 256, num_error_types, Default::default()));
+// Проверка живости сессий чата: сервер сам шлет ping и закрывает сессию,
+// от которой слишком долго нет pong. Иначе полуоткрытые соединения навсегда
+// остаются в ChatServer.sessions
+#[derive(Clone, Copy)]
+struct ChatHeartbeat {
+    interval: std::time::Duration, // Как часто отправлять ping
+    timeout: std::time::Duration,  // Сколько ждать ответа, прежде чем закрыть сессию
+}
 
-    // Классификатор типа ОС
-    let os_classifier = nn::seq()
-        .add(nn::linear(p / "os_fc1", 256 * 16 * 16, 256, Default::default()))
-        .add_fn(|xs| xs.relu())
-        .add(nn::dropout(p / "os_dropout", 0.3))
-        .add(nn::linear(p / "os_out", 256, num_os_types, Default::default()));
-
-    (
-        nn::seq().add(shared_features).add(error_classifier),
-        nn::seq().add_fn(move |xs| {
-            let features = xs.apply(&shared_features);
-            features.apply(&os_classifier)
-        })
-    )
+impl Default for ChatHeartbeat {
+    fn default() -> Self {
+        ChatHeartbeat {
+            interval: std::time::Duration::from_secs(5),
+            timeout: std::time::Duration::from_secs(15),
+        }
+    }
 }
 
-// Создание тестовых данных для демонстрации
-fn create_dummy_data() -> (Tensor, Tensor) {
-    let device = Device::Cpu;
-    let train_images = Tensor::randn(&[100, 3, 32, 32], (Kind::Float, device));
-    let train_labels = Tensor::randint(10, &[100], (Kind::Int64, device));
-    (train_images, train_labels)
+fn parse_heartbeat_secs(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(secs),
+        _ => Err(format!("Ожидалось положительное число секунд, получено {}", value)),
+    }
 }
 
-// Обучение модели
-fn train_model(model: &dyn nn::Module, train_images: &Tensor, train_labels: &Tensor, vs: &nn::VarStore) {
-    let mut optimizer = nn::Adam::default().build(vs, 1e-3).unwrap();
+// Сколько двоичных кадров сессия получает в ответ ошибку, прежде чем соединение закрывается
+const MAX_BINARY_FRAMES: usize = 3;
 
-    for epoch in 1..=5 {
-        let output = model.forward(train_images);
-        let loss = output.cross_entropy_for_logits(train_labels);
-        optimizer.backward_step(&loss);
-        println!("Epoch: {}, Loss: {:.4}", epoch, f64::from(&loss));
+// WebSocket актор для чата
+struct ChatSession {
+    id: Uuid,
+    token: Option<String>, // Токен возобновления из ?session=, если клиент его передал
+    addr: Addr<ChatServer>,
+    max_message_size: usize, // Лимит кадра WebSocket, байт
+    heartbeat: ChatHeartbeat,
+    last_heartbeat: std::time::Instant, // Когда клиент последний раз ответил на ping или прислал свой
+    binary_frames: usize,               // Сколько двоичных кадров уже отвергнуто
+}
+
+impl ChatSession {
+    // Отказ в виде обычного ответа чата; с close_code соединение затем закрывается
+    fn reject(&self, ctx: &mut ws::WebsocketContext<Self>, response: String, close_code: Option<ws::CloseCode>) {
+        tracing::warn!(session = %self.id, "Сообщение чата отвергнуто: {}", response);
+        let response = ChatResponse {
+            response,
+            analysis: None,
+            analyses: Vec::new(),
+            suggestions: Vec::new(),
+            escalated: false,
+        };
+        if let Ok(json) = serde_json::to_string(&response) {
+            ctx.text(json);
+        }
+        if let Some(code) = close_code {
+            ctx.close(Some(code.into()));
+            ctx.stop();
+        }
     }
 
-    vs.save("model.pt").unwrap();
-    println!("Модель сохранена в model.pt");
+    fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.heartbeat.interval, |session, ctx| {
+            if session.last_heartbeat.elapsed() > session.heartbeat.timeout {
+                tracing::info!(session = %session.id, "Сессия чата не отвечает на ping, соединение закрывается");
+                // stopping() отправит Disconnect серверу чата
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
 }
 
-// Веб-обработчик для предсказания
-async fn predict(
-    req: web::Json<PredictRequest>,
-    model_data: web::Data<Mutex<(Box<dyn nn::Module + Send>, nn::VarStore)>>,
-) -> Result<impl Responder> {
-    let image = Tensor::of_slice(&req.image)
-        .to_device(Device::Cpu)
-        .view([1, 3, 32, 32]);
-
-    let (model, _) = &*model_data.lock().unwrap();
-    let output = model.forward(&image);
-    let probs = output.softmax(-1, Kind::Float);
-    let (confidence, class) = probs.max_dim(-1, false);
+impl Actor for ChatSession {
+    type Context = ws::WebsocketContext<Self>;
 
-    Ok(HttpResponse::Ok().json(PredictResponse {
-        class: i64::from(&class.get(0)),
-        confidence: f32::from(&confidence.get(0)),
-    }))
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.start_heartbeat(ctx);
+        let addr = ctx.address();
+        // Пока сервер чата не ответил, сообщения сессии не обрабатываются
+        self.addr
+            .send(Connect {
+                id: self.id,
+                token: self.token.clone(),
+                addr: addr.recipient(),
+            })
+            .into_actor(self)
+            .then(|accepted, session, ctx| {
+                if !accepted.unwrap_or(false) {
+                    session.reject(
+                        ctx,
+                        "Сервер занят: слишком много открытых сессий чата. Попробуйте подключиться позже.".to_string(),
+                        Some(ws::CloseCode::Again),
+                    );
+                }
+                actix::fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> actix::Running {
+        self.addr.do_send(Disconnect { id: self.id });
+        actix::Running::Stop
+    }
 }
 
-// Веб-обработчик для предсказания ошибок ОС
-async fn predict_os_error(
-    req: web::Json<PredictRequest>,
-    model_data: web::Data<Mutex<((Box<dyn nn::Module + Send>, Box<dyn nn::Module + Send>), nn::VarStore)>>,
-) -> Result<impl Responder> {
-    let image = Tensor::of_slice(&req.image)
-        .to_device(Device::Cpu)
-        .view([1, 3, 128, 128]);
+impl Handler<SessionText> for ChatSession {
+    type Result = ();
 
-    let ((error_model, os_model), _) = &*model_data.lock().unwrap();
+    fn handle(&mut self, msg: SessionText, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
 
-    // Предсказание типа ошибки
-    let error_output = error_model.forward(&image);
-    let error_probs = error_output.softmax(-1, Kind::Float);
-    let (error_confidence, error_class) = error_probs.max_dim(-1, false);
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = std::time::Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => self.last_heartbeat = std::time::Instant::now(),
+            Ok(ws::Message::Text(text)) => {
+                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&text) {
+                    self.addr.do_send(ClientMessage {
+                        id: self.id,
+                        msg: chat_msg,
+                    });
+                }
+            }
+            // Клиент, упорно шлющий двоичные кадры, отключается
+            Ok(ws::Message::Binary(_)) => {
+                self.binary_frames += 1;
+                let close_code = (self.binary_frames >= MAX_BINARY_FRAMES).then_some(ws::CloseCode::Unsupported);
+                self.reject(
+                    ctx,
+                    "Двоичные кадры не поддерживаются. Отправьте JSON текстовым кадром, скриншот - строкой base64 в image_data.".to_string(),
+                    close_code,
+                );
+            }
+            // Фрагменты не собираются: иначе из кадров по --max-body-size складывалось бы
+            // сообщение любого размера. Браузеры отправляют сообщения чата одним кадром
+            Ok(ws::Message::Continuation(_)) => self.reject(
+                ctx,
+                "Фрагментированные сообщения не поддерживаются. Отправьте сообщение одним кадром.".to_string(),
+                Some(ws::CloseCode::Unsupported),
+            ),
+            // Кадр больше --max-body-size: клиент получает понятный ответ, соединение закрывается
+            Err(ws::ProtocolError::Overflow) => self.reject(
+                ctx,
+                format!(
+                    "Сообщение больше допустимых {} байт. Уменьшите скриншот или отправьте меньше изображений за раз.",
+                    self.max_message_size
+                ),
+                Some(ws::CloseCode::Size),
+            ),
+            _ => (),
+        }
+    }
+}
 
-    // Предсказание типа ОС
-    let os_output = os_model.forward(&image);
-    let os_probs = os_output.softmax(-1, Kind::Float);
-    let (_, os_class) = os_probs.max_dim(-1, false);
+// Сколько сессий чата может быть открыто одновременно по умолчанию
+const DEFAULT_MAX_SESSIONS: usize = 1000;
 
-    let error_idx = i64::from(&error_class.get(0)) as usize;
-    let os_idx = i64::from(&os_class.get(0)) as usize;
+// Сколько скриншотов чата выполняется одним forward
+const CHAT_INFERENCE_BATCH: usize = 16;
 
-    let error_type = OS_ERROR_TYPES.get(error_idx).unwrap_or(&"unknown").to_string();
-    let os_type = OS_TYPES.get(os_idx).unwrap_or(&"unknown").to_string();
+// Результат модели ошибок ОС для одного скриншота чата
+struct ScreenshotPrediction {
+    error_type: String,
+    os_type: String,
+    confidence: f32,
+}
 
-    let description = match error_type.as_str() {
-        "blue_screen_of_death" => "Критическая системная ошибка Windows (BSOD)",
-        "kernel_panic" => "Критическая ошибка ядра Linux/macOS",
-        "application_crash" => "Неожиданное завершение работы приложения",
-        "memory_error" => "Ошибка доступа к памяти или нехватка RAM",
-        "disk_error" => "Ошибка чтения/записи диска",
-        "network_error" => "Проблемы с сетевым подключением",
-        "permission_denied" => "Недостаточно прав для выполнения операции",
-        "file_not_found" => "Файл или ресурс не найден",
-        "system_overload" => "Перегрузка системы",
-        "driver_error" => "Ошибка драйвера устройства",
-        _ => "Неизвестная ошибка"
-    }.to_string();
+type PredictionReceiver = tokio::sync::oneshot::Receiver<Result<ScreenshotPrediction, AppError>>;
 
-    Ok(HttpResponse::Ok().json(OsErrorPredictResponse {
-        error_type,
-        os_type,
-        confidence: f32::from(&error_confidence.get(0)),
-        description,
-    }))
+// Скриншот, ожидающий модели, и канал для ответа
+struct InferenceJob {
+    image: Tensor, // [1, 3, H, W] на CPU
+    reply: tokio::sync::oneshot::Sender<Result<ScreenshotPrediction, AppError>>,
 }
 
-// Определение аргументов командной строки
-#[derive(Parser)]
-#[clap(name = "image-classifier")]
-#[clap(about = "Утилита для классификации изображений")]
-struct Cli {
-    #[clap(subcommand)]
-    command: Commands,
+// Очередь инференса скриншотов чата. Модель выполняется в отдельном потоке, а не в
+// обработчике актора ChatServer, поэтому сессии не ждут друг друга. Скриншоты,
+// пришедшие во время очередного forward, выполняются следующим батчем
+#[derive(Clone)]
+struct InferenceQueue {
+    jobs: std::sync::mpsc::Sender<InferenceJob>,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Запустить веб-сервер
-    Server,
-    /// Обучить модель
-    Train,
-    /// Предсказать класс изображения
-    Predict {
-        /// Путь к обученной модели
-        #[clap(short, long, default_value = "model.pt")]
-        model: String,
-        /// Путь к изображению для предсказания
-        #[clap(short, long)]
-        image: String,
-    },
-    /// Предсказать ошибку операционной системы по скриншоту
-    PredictOsError {
-        /// Путь к модели для предсказания ошибок ОС
-        #[clap(short, long, default_value = "os_error_model.pt")]
-        model: String,
-        /// Путь к скриншоту с ошибкой
-        #[clap(short, long)]
-        screenshot: String,
-    },
-    /// Обучить модель для предсказания ошибок ОС
-    TrainOsError,
+impl InferenceQueue {
+    fn start(models: Arc<OsErrorModels>, max_batch: usize) -> std::io::Result<Self> {
+        let (jobs, queue) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("chat-inference".to_string())
+            .spawn(move || run_inference_queue(&models, &queue, max_batch))?;
+        Ok(InferenceQueue { jobs })
+    }
+
+    fn submit(&self, image: Tensor) -> PredictionReceiver {
+        let (reply, prediction) = tokio::sync::oneshot::channel();
+        // Если поток очереди завершился, задание пропадет, а получатель увидит закрытый канал
+        let _ = self.jobs.send(InferenceJob { image, reply });
+        prediction
+    }
 }
 
-// Функция для загрузки и предобработки изображения
-fn load_image<P: AsRef<Path>>(path: P) -> Result<Tensor, Box<dyn std::error::Error>> {
-    let img = image::open(path)?;
-    let img = img.resize_exact(32, 32, image::imageops::FilterType::Lanczos3);
-    let mut flat = Vec::with_capacity(3 * 32 * 32);
+fn run_inference_queue(models: &OsErrorModels, queue: &std::sync::mpsc::Receiver<InferenceJob>, max_batch: usize) {
+    while let Ok(first) = queue.recv() {
+        let mut pending = vec![first];
+        while pending.len() < max_batch
+            && let Ok(job) = queue.try_recv()
+        {
+            pending.push(job);
+        }
 
-    for y in 0..32 {
-        for x in 0..32 {
-            let pixel = img.get_pixel(x, y);
-            flat.push(pixel[0] as f32 / 255.0);
-            flat.push(pixel[1] as f32 / 255.0);
-            flat.push(pixel[2] as f32 / 255.0);
+        // Чат сохраняет разрешение скриншота, поэтому в батч попадают только тензоры одного размера
+        let mut batches: Vec<Vec<InferenceJob>> = Vec::new();
+        for job in pending {
+            match batches.iter_mut().find(|batch| batch[0].image.size() == job.image.size()) {
+                Some(batch) => batch.push(job),
+                None => batches.push(vec![job]),
+            }
+        }
+        for batch in batches {
+            let images: Vec<&Tensor> = batch.iter().map(|job| &job.image).collect();
+            match predict_screenshots(models, &Tensor::cat(&images, 0)) {
+                Ok(predictions) => {
+                    for (job, prediction) in batch.into_iter().zip(predictions) {
+                        let _ = job.reply.send(Ok(prediction));
+                    }
+                }
+                Err(e) => {
+                    for job in batch {
+                        let _ = job.reply.send(Err(e.clone()));
+                    }
+                }
+            }
         }
     }
+}
 
-    Ok(Tensor::of_slice(&flat).view([1, 3, 32, 32]).to_device(Device::Cpu))
+// Один forward для батча скриншотов [N, 3, H, W]
+fn predict_screenshots(models: &OsErrorModels, images: &Tensor) -> Result<Vec<ScreenshotPrediction>, AppError> {
+    let models = models.read()?;
+
+    let ((error_confidence, error_class), os_probs) = models.infer(images, |models, images| {
+        let temperature = models.metadata.temperature;
+        let (error_logits, os_logits) = models.forward(images);
+        let error_probs = softmax_with_temperature(&error_logits, temperature);
+        let os_probs = softmax_with_temperature(&os_logits, temperature);
+        (error_probs.max_dim(-1, false), os_probs)
+    })?;
+
+    Ok((0..images.size()[0])
+        .map(|i| {
+            let error_idx = i64::from(&error_class.get(i)) as usize;
+            let error_type = models.metadata.error_label(error_idx).to_string();
+            let (os_idx, _, _) = reconcile_os_type(&error_type, &Vec::<f32>::from(&os_probs.get(i)), &models.metadata.os_types);
+            ScreenshotPrediction {
+                os_type: models.metadata.os_label(os_idx).to_string(),
+                error_type,
+                confidence: f32::from(&error_confidence.get(i)),
+            }
+        })
+        .collect())
 }
 
-// Функция для предсказания с использованием утилиты командной строки
-fn predict_from_cli(model_path: &str, image_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let device = Device::Cpu;
-    let vs = nn::VarStore::new(device);
-    let model = simple_cnn(&vs.root(), 10);
+// Скриншот сообщения чата после декодирования: анализ уже готов (из кэша сессии
+// или ошибка декодирования) либо ждет очереди инференса
+enum PendingScreenshot {
+    Ready(Result<ErrorAnalysis, Box<dyn std::error::Error>>),
+    Queued { hash: u64, prediction: PredictionReceiver },
+}
 
-    vs.load(model_path)?;
+enum ScreenshotOutcome {
+    Ready(Result<ErrorAnalysis, Box<dyn std::error::Error>>),
+    Predicted { hash: u64, prediction: Result<ScreenshotPrediction, AppError> },
+}
 
-    let image_tensor = load_image(image_path)?;
-    let output = model.forward(&image_tensor);
-    let probs = output.softmax(-1, Kind::Float);
-    let (confidence, class) = probs.max_dim(-1, false);
+impl PendingScreenshot {
+    async fn resolve(self) -> ScreenshotOutcome {
+        match self {
+            PendingScreenshot::Ready(analysis) => ScreenshotOutcome::Ready(analysis),
+            PendingScreenshot::Queued { hash, prediction } => ScreenshotOutcome::Predicted {
+                hash,
+                prediction: prediction
+                    .await
+                    .unwrap_or_else(|_| Err(AppError::Internal("очередь инференса чата остановлена".to_string()))),
+            },
+        }
+    }
+}
 
-    println!("Предсказанный класс: {}", i64::from(&class.get(0)));
-    println!("Уверенность: {:.2}%", f32::from(&confidence.get(0)) * 100.0);
+// Сколько секунд хранится состояние отключившейся сессии чата с токеном по умолчанию
+const DEFAULT_SESSION_TTL_SECS: u64 = 300;
 
-    Ok(())
-}
+// Как часто удалять истекшие состояния, если --session-ttl не меньше
+const SESSION_SWEEP_INTERVAL_SECS: u64 = 30;
 
-// Функция для загрузки изображения большего размера для анализа ошибок ОС
-fn load_screenshot<P: AsRef<Path>>(path: P) -> Result<Tensor, Box<dyn std::error::Error>> {
-    let img = image::open(path)?;
-    let img = img.resize_exact(128, 128, image::imageops::FilterType::Lanczos3);
-    let mut flat = Vec::with_capacity(3 * 128 * 128);
+// Сколько состояний разговоров хранится; при переполнении вытесняется давно отключившееся
+const RESUMABLE_SESSIONS_CAPACITY: usize = 10_000;
 
-    for y in 0..128 {
-        for x in 0..128 {
-            let pixel = img.get_pixel(x, y);
-            flat.push(pixel[0] as f32 / 255.0);
-            flat.push(pixel[1] as f32 / 255.0);
-            flat.push(pixel[2] as f32 / 255.0);
-        }
-    }
+// Длина токена возобновления: короткие токены легко подобрать
+const SESSION_TOKEN_LEN: std::ops::RangeInclusive<usize> = 16..=128;
 
-    Ok(Tensor::of_slice(&flat).view([1, 3, 128, 128]).to_device(Device::Cpu))
+// Разговор, переживающий обрыв соединения. Клиент передает токен в ?session=, и новая
+// сессия WebSocket с тем же токеном продолжает его вместо того, чтобы начинать заново
+struct ResumableSession {
+    session: Uuid,                           // Сессия WebSocket, к которой сейчас привязан токен
+    analyses: Vec<ErrorAnalysis>,            // Анализы последнего ответа с анализом
+    escalated: bool,                         // Передавался ли случай из разговора на проверку
+    detached_at: Option<std::time::Instant>, // Когда сессия отключилась; None, пока она подключена
 }
 
-// Создание тестовых данных для ошибок ОС
-fn create_os_error_dummy_data() -> (Tensor, Tensor, Tensor) {
-    let device = Device::Cpu;
-    let train_images = Tensor::randn(&[200, 3, 128, 128], (Kind::Float, device));
-    let error_labels = Tensor::randint(OS_ERROR_TYPES.len() as i64, &[200], (Kind::Int64, device));
-    let os_labels = Tensor::randint(OS_TYPES.len() as i64, &[200], (Kind::Int64, device));
-    (train_images, error_labels, os_labels)
+// Сервер чата
+struct ChatServer {
+    sessions: HashMap<Uuid, actix::Recipient<SessionText>>,
+    resumable: HashMap<String, ResumableSession>, // Состояния разговоров по токену
+    tokens: HashMap<Uuid, String>,                // Токен каждой подключенной сессии, которая его передала
+    session_ttl: std::time::Duration,             // Сколько хранится состояние после отключения
+    max_sessions: usize, // Новые сессии сверх лимита закрываются с кодом 1013
+    inference: InferenceQueue,
+    screenshot_cache: ScreenshotCache,
+    recent: std::sync::Arc<RecentPredictions>,
+    preprocessor: Box<dyn Preprocessor>, // Предобработка скриншотов из чата
+    llm: Option<LlmClient>, // Генеративные объяснения вместо шаблонного ответа, если задан --llm-endpoint
+    escalations: std::sync::Arc<EscalationQueue>,
+    animated_images: AnimatedImages,
 }
 
-// Обучение модели для ошибок ОС
-fn train_os_error_model() -> Result<(), Box<dyn std::error::Error>> {
-    let device = Device::Cpu;
-    let vs = nn::VarStore::new(device);
-    let (error_model, os_model) = os_error_cnn(&vs.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
-
-    let mut optimizer = nn::Adam::default().build(&vs, 1e-4)?;
-    let (train_images, error_labels, os_labels) = create_os_error_dummy_data();
+// Настройки сервера чата из командной строки и файла конфигурации
+struct ChatServerConfig {
+    screenshot_cache: ScreenshotCacheConfig,
+    max_sessions: usize,              // Сколько сессий обслуживается одновременно
+    session_ttl: std::time::Duration, // Сколько хранится состояние после отключения
+    animated_images: AnimatedImages,
+}
 
-    for epoch in 1..=10 {
-        let error_output = error_model.forward(&train_images);
-        let os_output = os_model.forward(&train_images);
+impl ChatServer {
+    fn new(
+        inference: InferenceQueue,
+        recent: std::sync::Arc<RecentPredictions>,
+        llm: Option<LlmClient>,
+        escalations: std::sync::Arc<EscalationQueue>,
+        config: ChatServerConfig,
+    ) -> Self {
+        ChatServer {
+            sessions: HashMap::new(),
+            resumable: HashMap::new(),
+            tokens: HashMap::new(),
+            session_ttl: config.session_ttl,
+            max_sessions: config.max_sessions,
+            inference,
+            screenshot_cache: ScreenshotCache::new(config.screenshot_cache),
+            recent,
+            // Модель не зависит от разрешения: скриншот только увеличивается до минимального размера
+            preprocessor: Box::new(StandardPreprocessor::native()),
+            llm,
+            escalations,
+            animated_images: config.animated_images,
+        }
+    }
 
-        let error_loss = error_output.cross_entropy_for_logits(&error_labels);
-        let os_loss = os_output.cross_entropy_for_logits(&os_labels);
-        let total_loss = error_loss + os_loss * 0.5; // Взвешенная потеря
+    // Единственный путь ответа клиенту: сессия отправляет JSON текстовым кадром
+    fn send_to_session(&self, session_id: Uuid, json: String) {
+        if let Some(addr) = self.sessions.get(&session_id) {
+            addr.do_send(SessionText(json));
+        }
+    }
 
-        optimizer.backward_step(&total_loss);
+    // Привязка сессии к разговору с токеном. Если разговор уже был, к новой сессии
+    // переходят его кэш скриншотов и состояние, а клиент получает последний анализ
+    fn resume_session(&mut self, id: Uuid, token: String) {
+        self.tokens.insert(id, token.clone());
+        let Some(state) = self.resumable.get_mut(&token) else {
+            self.evict_resumable();
+            self.resumable.insert(
+                token,
+                ResumableSession {
+                    session: id,
+                    analyses: Vec::new(),
+                    escalated: false,
+                    detached_at: None,
+                },
+            );
+            return;
+        };
+
+        // Прежняя сессия могла еще не заметить обрыва: ее Disconnect уже не тронет разговор
+        let previous = std::mem::replace(&mut state.session, id);
+        state.detached_at = None;
+        self.tokens.remove(&previous);
+        self.screenshot_cache.rebind(previous, id);
+        tracing::info!(session = %id, previous = %previous, "Сессия чата возобновила разговор");
+
+        let mut analyses = state.analyses.clone();
+        let response = ChatResponse {
+            response: match analyses.as_slice() {
+                [analysis] => format!(
+                    "Соединение восстановлено. Последний анализ: ошибка типа '{}' в системе {}.",
+                    analysis.error_type, analysis.os_type
+                ),
+                [] => "Соединение восстановлено.".to_string(),
+                _ => format!("Соединение восстановлено. Последний ответ содержал {} анализа.", analyses.len()),
+            },
+            analysis: (analyses.len() == 1).then(|| analyses.remove(0)),
+            analyses,
+            suggestions: Vec::new(),
+            escalated: state.escalated,
+        };
+        self.send_to_session(id, serde_json::to_string(&response).unwrap());
+    }
 
-        if epoch % 2 == 0 {
-            println!("Epoch: {}, Error Loss: {:.4}, OS Loss: {:.4}, Total Loss: {:.4}",
-                epoch, f64::from(&error_loss), f64::from(&os_loss), f64::from(&total_loss));
+    // Место под новый разговор: вытесняется тот, чья сессия отключилась раньше всех.
+    // Разговоры подключенных сессий не вытесняются, их не больше --max-sessions
+    fn evict_resumable(&mut self) {
+        if self.resumable.len() < RESUMABLE_SESSIONS_CAPACITY {
+            return;
+        }
+        let oldest = self
+            .resumable
+            .iter()
+            .filter_map(|(token, state)| state.detached_at.map(|detached_at| (detached_at, token.clone())))
+            .min();
+        if let Some((_, token)) = oldest
+            && let Some(state) = self.resumable.remove(&token)
+        {
+            self.screenshot_cache.remove_session(state.session);
         }
     }
 
-    vs.save("os_error_model.pt")?;
-    println!("Модель для предсказания ошибок ОС сохранена в os_error_model.pt");
-    Ok(())
-}
+    // Разговоры, чьи сессии отключились дольше --session-ttl назад, удаляются вместе с кэшем скриншотов
+    fn expire_resumable(&mut self) {
+        let ttl = self.session_ttl;
+        let screenshot_cache = &mut self.screenshot_cache;
+        self.resumable.retain(|_, state| {
+            let expired = state.detached_at.is_some_and(|detached_at| detached_at.elapsed() >= ttl);
+            if expired {
+                screenshot_cache.remove_session(state.session);
+            }
+            !expired
+        });
+    }
 
-// Функция для предсказания ошибок ОС
-fn predict_os_error_from_cli(model_path: &str, screenshot_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let device = Device::Cpu;
-    let vs = nn::VarStore::new(device);
-    let (error_model, os_model) = os_error_cnn(&vs.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
+    // Анализы ответа запоминаются в разговоре сессии, если она передала токен
+    fn remember_analyses(&mut self, id: Uuid, response: &ChatResponse) {
+        let Some(state) = self.tokens.get(&id).and_then(|token| self.resumable.get_mut(token)) else {
+            return;
+        };
+        if response.analysis.is_some() || !response.analyses.is_empty() {
+            state.analyses = response.analysis.iter().chain(&response.analyses).cloned().collect();
+        }
+        state.escalated |= response.escalated;
+    }
+}
 
-    vs.load(model_path)?;
+// Модель по умолчанию для --llm-endpoint
+const DEFAULT_LLM_MODEL: &str = "gpt-3.5-turbo-instruct";
 
-    let image_tensor = load_screenshot(screenshot_path)?;
+// Сколько секунд ждать весь ответ языковой модели в одной попытке, включая поток фрагментов
+const DEFAULT_LLM_TIMEOUT_SECS: u64 = 30;
 
-    // Предсказание типа ошибки
-    let error_output = error_model.forward(&image_tensor);
-    let error_probs = error_output.softmax(-1, Kind::Float);
-    let (error_confidence, error_class) = error_probs.max_dim(-1, false);
+// Сколько раз повторять неудачный запрос к языковой модели до шаблонного ответа
+const DEFAULT_LLM_RETRIES: u32 = 2;
 
-    // Предсказание типа ОС
-    let os_output = os_model.forward(&image_tensor);
-    let os_probs = os_output.softmax(-1, Kind::Float);
-    let (os_confidence, os_class) = os_probs.max_dim(-1, false);
+// Пауза перед первым повтором; удваивается с каждой попыткой, плюс случайная добавка до той же величины
+const LLM_RETRY_BASE_DELAY_MS: u64 = 250;
 
-    let error_idx = i64::from(&error_class.get(0)) as usize;
-    let os_idx = i64::from(&os_class.get(0)) as usize;
+// Ограничение длины объяснения в токенах
+const LLM_MAX_TOKENS: u32 = 512;
 
-    let error_type = OS_ERROR_TYPES.get(error_idx).unwrap_or(&"unknown");
-    let os_type = OS_TYPES.get(os_idx).unwrap_or(&"unknown");
+// Переменная окружения с ключом API языковой модели (заголовок Authorization: Bearer)
+const LLM_API_KEY_ENV: &str = "BASHPIC_LLM_API_KEY";
 
-    // Описания ошибок
-    let description = match *error_type {
-        "blue_screen_of_death" => "Критическая системная ошибка Windows (BSOD)",
-        "kernel_panic" => "Критическая ошибка ядра Linux/macOS",
-        "application_crash" => "Неожиданное завершение работы приложения",
-        "memory_error" => "Ошибка доступа к памяти или нехватка RAM",
-        "disk_error" => "Ошибка чтения/записи диска",
-        "network_error" => "Проблемы с сетевым подключением",
-        "permission_denied" => "Недостаточно прав для выполнения операции",
-        "file_not_found" => "Файл или ресурс не найден",
-        "system_overload" => "Перегрузка системы",
-        "driver_error" => "Ошибка драйвера устройства",
-        _ => "Неизвестная ошибка"
-    };
+// Клиент OpenAI-совместимого API completions с потоковой выдачей (stream: true)
+#[derive(Clone)]
+struct LlmClient {
+    http: reqwest::Client,
+    endpoint: String,
+    model: String,
+    api_key: Option<String>,
+    timeout: std::time::Duration, // На одну попытку
+    retries: u32,
+}
 
-    println!("=== Анализ ошибки операционной системы ===");
-    println!("Тип ошибки: {}", error_type);
-    println!("Операционная система: {}", os_type);
-    println!("Уверенность (ошибка): {:.2}%", f32::from(&error_confidence.get(0)) * 100.0);
-    println!("Уверенность (ОС): {:.2}%", f32::from(&os_confidence.get(0)) * 100.0);
-    println!("Описание: {}", description);
+#[derive(Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChoice>,
+}
 
-    Ok(())
+#[derive(Deserialize)]
+struct CompletionChoice {
+    #[serde(default)]
+    text: String,
 }
 
-// WebSocket обработчик
-async fn websocket_handler(
-    req: HttpRequest,
-    stream: web::Payload,
-    srv: web::Data<Addr<ChatServer>>,
-) -> Result<HttpResponse, actix_web::Error> {
-    let chat_session = ChatSession {
-        id: Uuid::new_v4(),
-        addr: srv.get_ref().clone(),
+impl LlmClient {
+    fn new(endpoint: String, model: String, timeout: std::time::Duration, retries: u32) -> Result<Self, reqwest::Error> {
+        Ok(LlmClient {
+            http: reqwest::Client::builder().build()?,
+            endpoint,
+            model,
+            api_key: std::env::var(LLM_API_KEY_ENV).ok().filter(|key| !key.is_empty()),
+            timeout,
+            retries,
+        })
+    }
+
+    // stream_completion с таймаутом на попытку и повторами с экспоненциальной паузой и джиттером.
+    // Повтор возможен, только пока клиенту не отправлено ни одного фрагмента, иначе текст задвоится
+    async fn stream_completion_with_retries(
+        &self,
+        prompt: &str,
+        mut on_text: impl FnMut(String),
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut attempt = 0;
+        loop {
+            let mut streamed = false;
+            let completion = self.stream_completion(prompt, |chunk| {
+                streamed = true;
+                on_text(chunk);
+            });
+            let result = match tokio::time::timeout(self.timeout, completion).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("нет ответа за {} с", self.timeout.as_secs()).into()),
+            };
+            match result {
+                Err(e) if !streamed && attempt < self.retries => {
+                    let base = LLM_RETRY_BASE_DELAY_MS << attempt.min(10);
+                    let delay = std::time::Duration::from_millis(base + rand::thread_rng().gen_range(0..=base));
+                    tracing::warn!(
+                        "Попытка {} запроса к языковой модели не удалась ({}), повтор через {} мс",
+                        attempt + 1,
+                        e,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Запрос с разбором потока server-sent events: on_text получает каждый фрагмент,
+    // результат - весь сгенерированный текст
+    async fn stream_completion(
+        &self,
+        prompt: &str,
+        mut on_text: impl FnMut(String),
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = self.http.post(&self.endpoint).json(&serde_json::json!({
+            "model": self.model,
+            "prompt": prompt,
+            "max_tokens": LLM_MAX_TOKENS,
+            "stream": true,
+        }));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let mut response = request.send().await?.error_for_status()?;
+
+        let mut buffer = Vec::new();
+        let mut text = String::new();
+        while let Some(bytes) = response.chunk().await? {
+            buffer.extend_from_slice(&bytes);
+            // Событие может прийти разрезанным между чанками: разбираются только полные строки
+            while let Some(newline) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    return Ok(text);
+                }
+                let chunk: CompletionChunk = serde_json::from_str(data)?;
+                if let Some(fragment) = chunk.choices.into_iter().next().map(|choice| choice.text)
+                    && !fragment.is_empty()
+                {
+                    text.push_str(&fragment);
+                    on_text(fragment);
+                }
+            }
+        }
+        Ok(text)
+    }
+}
+
+// Запрос к языковой модели: результат классификатора и текст пользователя
+fn llm_prompt(message: &str, analysis: &ErrorAnalysis) -> String {
+    format!(
+        "Ты помощник по диагностике ошибок операционных систем. Объясни пользователю, что произошло, \
+         простыми словами и предложи конкретные шаги по исправлению.\n\n\
+         Сообщение пользователя: {}\n\
+         Тип ошибки: {}\n\
+         Операционная система: {}\n\
+         Уверенность классификатора: {:.0}%\n\
+         Описание: {}\n\
+         Возможные причины: {}\n\
+         Известные решения: {}\n\n\
+         Объяснение:",
+        message,
+        analysis.error_type,
+        analysis.os_type,
+        analysis.confidence * 100.0,
+        analysis.detailed_description,
+        analysis.possible_causes.join("; "),
+        analysis.solutions.join("; "),
+    )
+}
+
+// Генерация объяснения вне актора ChatServer: пока ждется сеть, актор обрабатывает
+// другие сообщения. Фрагменты и итоговый ответ доставляются через SessionReply.
+// Когда повторы исчерпаны, клиент получает шаблонный ответ
+async fn stream_llm_explanation(
+    llm: LlmClient,
+    prompt: String,
+    template: ChatResponse,
+    session_id: Uuid,
+    server: Addr<ChatServer>,
+) {
+    let mut index = 0;
+    let result = llm.stream_completion_with_retries(&prompt, |chunk| {
+        if let Ok(json) = serde_json::to_string(&ChatResponseChunk { chunk, index }) {
+            server.do_send(SessionReply { id: session_id, json });
+        }
+        index += 1;
+    }).await;
+
+    let response = match result {
+        Ok(explanation) if !explanation.trim().is_empty() => {
+            let mut response = explanation.trim().to_string();
+            if template.escalated {
+                response.push_str(ESCALATION_NOTE);
+            }
+            ChatResponse { response, ..template }
+        }
+        Ok(_) => {
+            tracing::warn!(session = %session_id, "Языковая модель вернула пустой ответ, используется шаблонный");
+            template
+        }
+        Err(e) => {
+            tracing::warn!(session = %session_id, "Ошибка запроса к языковой модели, используется шаблонный ответ: {}", e);
+            template
+        }
     };
+    if let Ok(json) = serde_json::to_string(&response) {
+        server.do_send(SessionReply { id: session_id, json });
+    }
+}
 
-    ws::start(chat_session, &req, stream)
+// Параметры кэша анализов скриншотов в сессии чата
+struct ScreenshotCacheConfig {
+    capacity: usize,   // Сколько последних скриншотов помнить на сессию; 0 отключает кэш
+    max_distance: u32, // Максимальное расстояние Хэмминга между хэшами "одинаковых" скриншотов
 }
 
-// Страница чата
-async fn chat_page() -> Result<HttpResponse, actix_web::Error> {
-    let html = r#"
-<!DOCTYPE html>
-<html>
-<head>
-    <title>AI Error Analysis Chat</title>
-    <meta charset="utf-8">
-    <style>
-        body { font-family: Arial, sans-serif; margin: 0; padding: 20px; background-color: #f5f5f5; }
-        .container { max-width: 800px; margin: 0 auto; background: white; border-radius: 10px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); }
-        .header { background: #007bff; color: white; padding: 20px; border-radius: 10px 10px 0 0; text-align: center; }
-        .chat-area { height: 400px; overflow-y: auto; padding: 20px; border-bottom: 1px solid #eee; }
-        .message { margin: 10px 0; padding: 10px; border-radius: 5px; }
-        .user-message { background: #e3f2fd; margin-left: 20%; }
-        .bot-message { background: #f1f8e9; margin-right: 20%; }
-        .error-analysis { background: #fff3e0; border-left: 4px solid #ff9800; padding: 15px; margin: 10px 0; }
-        .suggestions { background: #f3e5f5; border-left: 4px solid #9c27b0; padding: 15px; margin: 10px 0; }
-        .input-area { padding: 20px; display: flex; gap: 10px; }
-        .input-area input { flex: 1; padding: 10px; border: 1px solid #ddd; border-radius: 5px; }
-        .input-area button { padding: 10px 20px; background: #007bff; color: white; border: none; border-radius: 5px; cursor: pointer; }
-        .file-input { margin: 10px 0; }
-        .status { padding: 5px 20px; background: #e8f5e8; border-bottom: 1px solid #ddd; font-size: 14px; }
-        .error-type { font-weight: bold; color: #d32f2f; }
-        .os-type { font-weight: bold; color: #1976d2; }
-        .confidence { font-weight: bold; color: #388e3c; }
-    </style>
-</head>
-<body>
-    <div class="container">
-        <div class="header">
-            <h1>🤖 AI Assistant для анализа ошибок ОС</h1>
-            <p>Загрузите скриншот ошибки или задайте вопрос</p>
-        </div>
-        <div class="status" id="status">Подключение к серверу...</div>
-        <div class="chat-area" id="chatArea"></div>
-        <div class="input-area">
-            <input type="text" id="messageInput" placeholder="Введите ваш вопрос или опишите проблему..." onkeypress="handleKeyPress(event)">
-            <input type="file" id="imageInput" accept="image/*" class="file-input" onchange="handleImageUpload(event)">
-            <button onclick="sendMessage()">Отправить</button>
-        </div>
-    </div>
+impl Default for ScreenshotCacheConfig {
+    fn default() -> Self {
+        ScreenshotCacheConfig {
+            capacity: 8,
+            max_distance: 5,
+        }
+    }
+}
 
-    <script>
-        let socket;
-        let connected = false;
+// Последние анализы скриншотов каждой сессии по перцептивному хэшу.
+// Повторно присланный скриншот не прогоняется через модель
+struct ScreenshotCache {
+    config: ScreenshotCacheConfig,
+    sessions: HashMap<Uuid, VecDeque<(u64, ErrorAnalysis)>>,
+}
+
+impl ScreenshotCache {
+    fn new(config: ScreenshotCacheConfig) -> Self {
+        ScreenshotCache {
+            config,
+            sessions: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, session_id: Uuid, hash: u64) -> Option<ErrorAnalysis> {
+        self.sessions
+            .get(&session_id)?
+            .iter()
+            .find(|(cached, _)| (cached ^ hash).count_ones() <= self.config.max_distance)
+            .map(|(_, analysis)| analysis.clone())
+    }
+
+    // При переполнении вытесняется самая старая запись
+    fn insert(&mut self, session_id: Uuid, hash: u64, analysis: ErrorAnalysis) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let entries = self.sessions.entry(session_id).or_default();
+        if entries.len() == self.config.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((hash, analysis));
+    }
+
+    fn remove_session(&mut self, session_id: Uuid) {
+        self.sessions.remove(&session_id);
+    }
+
+    // Записи отключившейся сессии переходят к сессии, возобновившей разговор
+    fn rebind(&mut self, from: Uuid, to: Uuid) {
+        if let Some(entries) = self.sessions.remove(&from) {
+            self.sessions.insert(to, entries);
+        }
+    }
+}
+
+// Перцептивный хэш (average hash): изображение сжимается до 8x8 в оттенках серого,
+// каждый бит - ярче ли пиксель среднего. Перекодирование и небольшое масштабирование
+// меняют лишь несколько бит
+fn average_hash(img: &image::DynamicImage) -> u64 {
+    let small = img.resize_exact(8, 8, image::imageops::FilterType::Triangle).to_luma8();
+    let mean = small.pixels().map(|pixel| pixel[0] as u32).sum::<u32>() / 64;
+
+    small
+        .pixels()
+        .enumerate()
+        .filter(|(_, pixel)| pixel[0] as u32 > mean)
+        .fold(0, |hash, (i, _)| hash | (1 << i))
+}
+
+impl Actor for ChatServer {
+    type Context = actix::Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let period = self.session_ttl.min(std::time::Duration::from_secs(SESSION_SWEEP_INTERVAL_SECS));
+        ctx.run_interval(period, |server, _| server.expire_resumable());
+    }
+}
+
+// Сообщения для актора
+// Ответ на Connect: false, если достигнут --max-sessions и сессия должна закрыться
+#[derive(Message)]
+#[rtype(result = "bool")]
+struct Connect {
+    id: Uuid,
+    token: Option<String>,
+    addr: actix::Recipient<SessionText>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Disconnect {
+    id: Uuid,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ClientMessage {
+    id: Uuid,
+    msg: ChatMessage,
+}
+
+// Готовый JSON, который сессия чата отправляет клиенту текстовым кадром
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SessionText(String);
+
+// Готовый JSON для сессии из фоновой задачи (потоковый ответ языковой модели)
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SessionReply {
+    id: Uuid,
+    json: String,
+}
+
+// Прогресс обучения, рассылаемый всем подключенным сессиям чата
+#[derive(Message, Serialize, Clone)]
+#[rtype(result = "()")]
+struct TrainingProgress {
+    epoch: i64,
+    total_epochs: i64,
+    loss: f64,
+}
+
+#[derive(Serialize)]
+struct TrainingProgressResponse {
+    response: String,
+    training_progress: TrainingProgress,
+}
+
+impl Handler<Connect> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> bool {
+        if self.sessions.len() >= self.max_sessions {
+            tracing::warn!(session = %msg.id, "Достигнут лимит сессий чата ({}), подключение отклонено", self.max_sessions);
+            return false;
+        }
+        self.sessions.insert(msg.id, msg.addr);
+        if let Some(token) = msg.token {
+            self.resume_session(msg.id, token);
+        }
+        true
+    }
+}
+
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
+        self.sessions.remove(&msg.id);
+        // Разговор с токеном ждет переподключения до --session-ttl вместе с кэшем скриншотов
+        match self.tokens.remove(&msg.id).and_then(|token| self.resumable.get_mut(&token)) {
+            Some(state) => state.detached_at = Some(std::time::Instant::now()),
+            None => self.screenshot_cache.remove_session(msg.id),
+        }
+    }
+}
+
+impl Handler<ClientMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Self::Context) {
+        let images = msg.msg.image_data.as_ref().map(ChatImages::as_slice).unwrap_or_default();
+        if images.len() > MAX_CHAT_IMAGES {
+            let response = ChatResponse {
+                response: format!("Слишком много скриншотов в одном сообщении: {}, максимум {}.", images.len(), MAX_CHAT_IMAGES),
+                analysis: None,
+                analyses: Vec::new(),
+                suggestions: vec!["Отправьте скриншоты несколькими сообщениями".to_string()],
+                escalated: false,
+            };
+            self.finish_chat_message(&msg, response, ctx);
+            return;
+        }
+        if images.is_empty() {
+            // Обработка текстового сообщения
+            let response = process_text_query(&msg.msg.message);
+            self.finish_chat_message(&msg, response, ctx);
+            return;
+        }
+
+        // Скриншоты декодируются в акторе, а модель выполняется в очереди инференса:
+        // пока ждется результат, актор обслуживает остальные сессии
+        let (pending, first_frames): (Vec<PendingScreenshot>, Vec<bool>) = images
+            .iter()
+            .map(|image_data| self.prepare_screenshot(msg.id, image_data))
+            .unzip();
+        let first_frame_only = first_frames.contains(&true);
+
+        // Быстрый ответ уходит в почтовый ящик сессии раньше, чем будет запущен инференс,
+        // поэтому клиент всегда получает его до полного анализа
+        let acknowledgement = ChatAcknowledgement {
+            response: if pending.len() == 1 {
+                "Анализирую ваш скриншот...".to_string()
+            } else {
+                format!("Анализирую скриншоты ({})...", pending.len())
+            },
+            pending: pending.len(),
+        };
+        self.send_to_session(msg.id, serde_json::to_string(&acknowledgement).unwrap());
+
+        async move {
+            let mut outcomes = Vec::with_capacity(pending.len());
+            for screenshot in pending {
+                outcomes.push(screenshot.resolve().await);
+            }
+            outcomes
+        }
+        .into_actor(self)
+        .map(move |outcomes, server, ctx| {
+            let mut analyses: Vec<_> = outcomes
+                .into_iter()
+                .map(|outcome| server.finish_analysis(msg.id, outcome))
+                .collect();
+            let mut response = if analyses.len() == 1 {
+                server.process_screenshot(analyses.remove(0))
+            } else {
+                server.process_screenshots(analyses)
+            };
+            if first_frame_only {
+                response.response.push_str(FIRST_FRAME_NOTE);
+            }
+            server.finish_chat_message(&msg, response, ctx);
+        })
+        .spawn(ctx);
+    }
+}
+
+impl Handler<SessionReply> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionReply, _: &mut Self::Context) {
+        self.send_to_session(msg.id, msg.json);
+    }
+}
+
+impl Handler<TrainingProgress> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: TrainingProgress, _: &mut Self::Context) {
+        let response = TrainingProgressResponse {
+            response: format!("Обучение модели: эпоха {}/{}, потеря {:.4}", msg.epoch, msg.total_epochs, msg.loss),
+            training_progress: msg,
+        };
+        let response_json = serde_json::to_string(&response).unwrap();
+
+        for &session_id in self.sessions.keys() {
+            self.send_to_session(session_id, response_json.clone());
+        }
+    }
+}
+
+// Насколько рискованно решение для пользователя без технической подготовки.
+// Порядок вариантов - от безопасного к разрушительному, по нему работает ?severity_max=
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum SolutionSeverity {
+    Safe,        // Ничего не меняет в системе: посмотреть, перезапустить, закрыть
+    Advanced,    // Меняет настройки, драйверы или ядро; ошибка требует опыта
+    Destructive, // Вскрытие корпуса, замена оборудования, возможна потеря данных
+}
+
+// Решение с меткой риска. В ответы API и чата попадает только текст
+struct Solution {
+    text: String,
+    severity: SolutionSeverity,
+}
+
+impl Solution {
+    fn new(text: &str, severity: SolutionSeverity) -> Self {
+        Solution {
+            text: text.to_string(),
+            severity,
+        }
+    }
+}
+
+// Тексты решений не рискованнее severity_max; None - все решения
+fn solution_texts(solutions: Vec<Solution>, severity_max: Option<SolutionSeverity>) -> Vec<String> {
+    solutions
+        .into_iter()
+        .filter(|solution| severity_max.is_none_or(|max| solution.severity <= max))
+        .map(|solution| solution.text)
+        .collect()
+}
+
+// Подробное описание, возможные причины и решения для типа ошибки
+fn get_detailed_error_info(error_type: &str, _os_type: &str) -> (String, Vec<String>, Vec<Solution>) {
+    use SolutionSeverity::{Advanced, Destructive, Safe};
+
+    match error_type {
+        "blue_screen_of_death" => (
+            "Синий экран смерти (BSOD) - критическая системная ошибка Windows, при которой операционная система не может продолжить работу и принудительно перезагружается.".to_string(),
+            vec![
+                "Неисправность оборудования (RAM, жесткий диск)".to_string(),
+                "Несовместимые или поврежденные драйверы".to_string(),
+                "Перегрев процессора или видеокарты".to_string(),
+                "Поврежденные системные файлы".to_string(),
+            ],
+            vec![
+                Solution::new("Проверьте код ошибки на экране и найдите его в документации Microsoft", Safe),
+                Solution::new("Запустите проверку памяти Windows (mdsched.exe)", Safe),
+                Solution::new("Обновите или откатите драйверы устройств", Advanced),
+                Solution::new("Запустите sfc /scannow для проверки системных файлов", Advanced),
+                Solution::new("Проверьте температуру компонентов", Safe),
+            ]
+        ),
+        "kernel_panic" => (
+            "Паника ядра - критическая ошибка в ядре операционной системы Linux/macOS, после которой система не может продолжить безопасную работу.".to_string(),
+            vec![
+                "Ошибки в модулях ядра".to_string(),
+                "Неисправность оборудования".to_string(),
+                "Несовместимые драйверы".to_string(),
+                "Переполнение стека ядра".to_string(),
+            ],
+            vec![
+                Solution::new("Проанализируйте журналы системы (dmesg, /var/log/kern.log)", Safe),
+                Solution::new("Загрузитесь с предыдущего стабильного ядра", Advanced),
+                Solution::new("Отключите недавно установленные модули", Advanced),
+                Solution::new("Проверьте оборудование с помощью memtest86+", Advanced),
+            ]
+        ),
+        "memory_error" => (
+            "Ошибка памяти указывает на проблемы с доступом к оперативной памяти или её нехватку.".to_string(),
+            vec![
+                "Физическая неисправность модулей RAM".to_string(),
+                "Нехватка оперативной памяти".to_string(),
+                "Ошибки в управлении памятью приложением".to_string(),
+            ],
+            vec![
+                Solution::new("Запустите тест памяти (MemTest86, Windows Memory Diagnostic)", Safe),
+                Solution::new("Закройте ненужные приложения", Safe),
+                Solution::new("Увеличьте размер файла подкачки", Advanced),
+                Solution::new("Переустановите или замените модули RAM", Destructive),
+            ]
+        ),
+        _ => (
+            "Общая системная ошибка, требующая дополнительной диагностики.".to_string(),
+            vec!["Различные факторы могут вызывать эту ошибку".to_string()],
+            vec![
+                Solution::new("Перезагрузите систему", Safe),
+                Solution::new("Проверьте журналы событий", Safe),
+                Solution::new("Обратитесь к документации системы", Safe),
+            ]
+        )
+    }
+}
+
+impl ChatServer {
+    // Отправка готового ответа сессии
+    fn finish_chat_message(&mut self, msg: &ClientMessage, mut response: ChatResponse, ctx: &mut actix::Context<Self>) {
+        // Неуверенные анализы не выдаются за окончательный ответ: случай уходит в очередь на проверку
+        for analysis in response.analysis.iter().chain(&response.analyses) {
+            match self.escalations.escalate(msg.id, &msg.msg.message, analysis) {
+                Ok(escalated) => response.escalated |= escalated,
+                Err(e) => tracing::warn!(session = %msg.id, "Не удалось передать случай на проверку: {}", e),
+            }
+        }
+        if response.escalated {
+            response.response.push_str(ESCALATION_NOTE);
+        }
+        self.remember_analyses(msg.id, &response);
+
+        // Объяснение генерируется только для одиночного анализа; шаблонный ответ остается запасным
+        if let (Some(llm), Some(analysis)) = (&self.llm, &response.analysis) {
+            let prompt = llm_prompt(&msg.msg.message, analysis);
+            actix::spawn(stream_llm_explanation(llm.clone(), prompt, response, msg.id, ctx.address()));
+            return;
+        }
+
+        let response_json = serde_json::to_string(&response).unwrap();
+        self.send_to_session(msg.id, response_json);
+    }
+
+    fn process_screenshot(&self, analysis: Result<ErrorAnalysis, Box<dyn std::error::Error>>) -> ChatResponse {
+        // Обработка изображения
+        match analysis {
+            Ok(analysis) => {
+                let suggestions = generate_suggestions(&analysis);
+
+                ChatResponse {
+                    response: format!("Я проанализировал ваш скриншот. Обнаружена ошибка типа '{}' в системе {}.",
+                                    analysis.error_type, analysis.os_type),
+                    analysis: Some(analysis),
+                    analyses: Vec::new(),
+                    suggestions,
+                    escalated: false,
+                }
+            }
+            // base64 не декодируется: данные повреждены или обрезаны по пути, а не плохой формат
+            Err(e) if e.is::<base64::DecodeError>() => ChatResponse {
+                response: format!("Данные изображения повреждены при передаче: {}. Загрузите скриншот еще раз.", e),
+                analysis: None,
+                analyses: Vec::new(),
+                suggestions: vec![
+                    "Отправьте файл заново, не обрезая строку base64".to_string(),
+                    "Проверьте, что клиент кодирует изображение стандартным алфавитом base64".to_string(),
+                ],
+                escalated: false,
+            },
+            Err(e) if e.is::<AnimatedImageError>() => ChatResponse {
+                response: e.to_string(),
+                analysis: None,
+                analyses: Vec::new(),
+                suggestions: vec![
+                    "Сделайте обычный скриншот экрана с ошибкой".to_string(),
+                    "Сохраните нужный кадр анимации как PNG и загрузите его".to_string(),
+                ],
+                escalated: false,
+            },
+            Err(e) => match e.downcast_ref::<ImageDecodeError>() {
+                Some(decode_error) => ChatResponse {
+                    response: decode_error.to_string(),
+                    analysis: None,
+                    analyses: Vec::new(),
+                    suggestions: vec![
+                        format!("Поддерживаемые форматы: {}", SUPPORTED_IMAGE_FORMATS),
+                        "Сохраните скриншот в формате PNG и загрузите снова".to_string(),
+                    ],
+                    escalated: false,
+                },
+                None => ChatResponse {
+                    response: "Не удалось проанализировать изображение. Убедитесь, что это скриншот с ошибкой.".to_string(),
+                    analysis: None,
+                    analyses: Vec::new(),
+                    suggestions: vec![
+                        "Загрузите четкий скриншот ошибки".to_string(),
+                        "Убедитесь, что изображение содержит текст ошибки".to_string(),
+                    ],
+                    escalated: false,
+                },
+            },
+        }
+    }
+
+    // Анализ нескольких скриншотов одного сообщения со сводкой: согласуются ли они по типу ошибки
+    fn process_screenshots(&self, results: Vec<Result<ErrorAnalysis, Box<dyn std::error::Error>>>) -> ChatResponse {
+        let mut analyses = Vec::with_capacity(results.len());
+        let mut lines = Vec::with_capacity(results.len() + 1);
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(analysis) => {
+                    lines.push(format!("Скриншот {}: '{}' в системе {}", i + 1, analysis.error_type, analysis.os_type));
+                    analyses.push(analysis);
+                }
+                Err(e) => lines.push(format!("Скриншот {}: не удалось проанализировать ({})", i + 1, e)),
+            }
+        }
+
+        let summary = match analyses.first() {
+            None => "Не удалось проанализировать ни один скриншот.".to_string(),
+            Some(first) if analyses.iter().all(|analysis| analysis.error_type == first.error_type) => {
+                format!("Все проанализированные скриншоты ({}) указывают на ошибку типа '{}'.", analyses.len(), first.error_type)
+            }
+            Some(_) => "Скриншоты расходятся в типе ошибки, рекомендации даны по самому уверенному анализу.".to_string(),
+        };
+        lines.insert(0, summary);
+
+        // Рекомендации по самому уверенному анализу
+        let suggestions = analyses
+            .iter()
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+            .map(generate_suggestions)
+            .unwrap_or_else(|| vec![format!("Поддерживаемые форматы: {}", SUPPORTED_IMAGE_FORMATS)]);
+
+        ChatResponse {
+            response: lines.join("\n"),
+            analysis: None,
+            analyses,
+            suggestions,
+            escalated: false,
+        }
+    }
+
+    // Декодирование скриншота; если почти такой же уже анализировался в этой сессии,
+    // анализ берется из кэша, иначе тензор ставится в очередь инференса.
+    // Второе значение - true, если скриншот анимированный и анализируется только первый кадр
+    fn prepare_screenshot(&mut self, session_id: Uuid, image_data: &str) -> (PendingScreenshot, bool) {
+        let animated_images = self.animated_images;
+        let decoded = decode_base64_image(image_data)
+            .map_err(Box::<dyn std::error::Error>::from)
+            .and_then(|image_bytes| {
+                let animated = animated_format(&image_bytes);
+                if let Some(format) = animated
+                    && animated_images == AnimatedImages::Reject
+                {
+                    return Err(AnimatedImageError { format }.into());
+                }
+                Ok((detect_and_decode(&image_bytes)?, animated.is_some()))
+            });
+        let (img, first_frame) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => return (PendingScreenshot::Ready(Err(e)), false),
+        };
+
+        let hash = average_hash(&img);
+        if let Some(analysis) = self.screenshot_cache.lookup(session_id, hash) {
+            let recorded = self.recent.record("chat", &analysis.error_type, &analysis.os_type, analysis.confidence);
+            return (PendingScreenshot::Ready(recorded.map(|()| analysis).map_err(Into::into)), first_frame);
+        }
+
+        let prediction = self.inference.submit(self.preprocessor.to_tensor(&img));
+        (PendingScreenshot::Queued { hash, prediction }, first_frame)
+    }
+
+    // Полный анализ по ответу очереди инференса
+    fn finish_analysis(&mut self, session_id: Uuid, outcome: ScreenshotOutcome) -> Result<ErrorAnalysis, Box<dyn std::error::Error>> {
+        let (hash, prediction) = match outcome {
+            ScreenshotOutcome::Ready(analysis) => return analysis,
+            ScreenshotOutcome::Predicted { hash, prediction } => (hash, prediction?),
+        };
+
+        let (detailed_description, possible_causes, solutions) = get_detailed_error_info(&prediction.error_type, &prediction.os_type);
+        let analysis = ErrorAnalysis {
+            error_type: prediction.error_type,
+            os_type: prediction.os_type,
+            confidence: prediction.confidence,
+            detailed_description,
+            possible_causes,
+            solutions: solution_texts(solutions, None),
+            image_hash: Some(hash),
+        };
+        // Сессия могла отключиться, пока скриншот ждал очереди
+        if self.sessions.contains_key(&session_id) {
+            self.screenshot_cache.insert(session_id, hash, analysis.clone());
+        }
+        self.recent.record("chat", &analysis.error_type, &analysis.os_type, analysis.confidence)?;
+        Ok(analysis)
+    }
+}
+
+// Текстовая диагностика по ключевым словам, общая для чата и POST /analyze-text
+fn process_text_query(message: &str) -> ChatResponse {
+    let message_lower = message.to_lowercase();
+
+    if message_lower.contains("помощь") || message_lower.contains("help") {
+        ChatResponse {
+            response: "Я помогу вам диагностировать ошибки операционной системы! Загрузите скриншот ошибки, и я проанализирую её тип, определю ОС и предложу решения.".to_string(),
+            analysis: None,
+            analyses: Vec::new(),
+            suggestions: vec![
+                "Загрузите скриншот с ошибкой для анализа".to_string(),
+                "Спросите о конкретном типе ошибки".to_string(),
+                "Опишите симптомы проблемы".to_string(),
+            ],
+            escalated: false,
+        }
+    } else if let Some((error_type, matches)) = classify_text(&message_lower) {
+        let os_type = detect_os_from_text(&message_lower);
+        let (detailed_description, possible_causes, solutions) = get_detailed_error_info(error_type, os_type);
+        let analysis = ErrorAnalysis {
+            error_type: error_type.to_string(),
+            os_type: os_type.to_string(),
+            confidence: text_match_confidence(matches),
+            detailed_description,
+            possible_causes,
+            solutions: solution_texts(solutions, None),
+            image_hash: None,
+        };
+        let suggestions = generate_suggestions(&analysis);
+
+        ChatResponse {
+            response: format!("По описанию это похоже на ошибку типа '{}'. Для точного анализа загрузите скриншот ошибки.",
+                            analysis.error_type),
+            analysis: Some(analysis),
+            analyses: Vec::new(),
+            suggestions,
+            escalated: false,
+        }
+    } else {
+        ChatResponse {
+            response: "Опишите вашу проблему подробнее или загрузите скриншот ошибки для анализа.".to_string(),
+            analysis: None,
+            analyses: Vec::new(),
+            suggestions: vec![
+                "Загрузите скриншот ошибки".to_string(),
+                "Укажите тип операционной системы".to_string(),
+                "Опишите когда возникла ошибка".to_string(),
+            ],
+            escalated: false,
+        }
+    }
+}
+
+// Рекомендации к анализу: общие советы и решения для найденной ошибки
+fn generate_suggestions(analysis: &ErrorAnalysis) -> Vec<String> {
+    let mut suggestions = vec![
+        "Сохраните скриншот ошибки для дальнейшего анализа".to_string(),
+        "Запишите код ошибки, если он есть".to_string(),
+    ];
+
+    suggestions.extend(analysis.solutions.clone());
+    suggestions
+}
+
+// Сторона входного изображения базовой модели
+const SIMPLE_CNN_INPUT_SIZE: i64 = 32;
+
+// Размеры слоев базовой модели: меньше каналов - быстрее, но менее точно.
+// Сохраняется в метаданных, чтобы при загрузке собиралась та же архитектура
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct CnnConfig {
+    conv1_channels: i64, // Каналы первой свертки
+    conv2_channels: i64, // Каналы второй свертки
+    hidden: i64,         // Нейроны скрытого полносвязного слоя
+}
+
+impl Default for CnnConfig {
+    fn default() -> Self {
+        CnnConfig {
+            conv1_channels: 32,
+            conv2_channels: 64,
+            hidden: 128,
+        }
+    }
+}
+
+// Разбор размеров слоев: положительное целое
+fn parse_layer_size(value: &str) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(format!("Размер слоя должен быть положительным целым, получено {}", value)),
+    }
+}
+
+// Упрощенная модель CNN для демонстрации
+fn simple_cnn(p: &nn::Path, num_classes: i64, config: &CnnConfig) -> impl nn::Module + use<> {
+    // Два пулинга 2x2 уменьшают сторону карты признаков в 4 раза
+    let pooled_size = SIMPLE_CNN_INPUT_SIZE / 4;
+    let conv1 = nn::conv2d(p / "conv1", 3, config.conv1_channels, 3, nn::ConvConfig { padding: 1, ..Default::default() });
+    let conv2 = nn::conv2d(p / "conv2", config.conv1_channels, config.conv2_channels, 3, nn::ConvConfig { padding: 1, ..Default::default() });
+    let fc1 = nn::linear(p / "fc1", config.conv2_channels * pooled_size * pooled_size, config.hidden, Default::default());
+    let fc2 = nn::linear(p / "fc2", config.hidden, num_classes, Default::default());
+
+    nn::seq()
+        .add(conv1)
+        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
+        .add(conv2)
+        .add_fn(|xs| xs.relu().max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
+        .add_fn(|xs| xs.flatten(1, -1))
+        .add(fc1)
+        .add_fn(|xs| xs.relu())
+        .add(fc2)
+}
+
+// Размер входа модели для ошибок ОС по умолчанию
+const DEFAULT_OS_INPUT_SIZE: i64 = 128;
+
+// Минимальная сторона изображения: после трех пулингов 2x2 должно остаться хотя бы 4x4
+const MIN_OS_INPUT_SIZE: i64 = 32;
+
+// Пространственный размер карты признаков после адаптивного пулинга
+const OS_POOLED_SIZE: i64 = 4;
+
+// Специализированная модель CNN для анализа ошибок ОС с более крупными изображениями.
+// Модули реализуют ModuleT: dropout активен только при forward_t(.., true) во время обучения.
+// Адаптивный пулинг после сверток приводит признаки к 4x4, поэтому модель принимает
+// изображения любого размера не меньше MIN_OS_INPUT_SIZE.
+fn os_error_cnn(p: &nn::Path, num_error_types: i64, num_os_types: i64) -> (impl nn::ModuleT + use<>, impl nn::ModuleT + use<>) {
+    os_error_cnn_with(p, num_error_types, num_os_types, &mut |p, name, in_dim, out_dim| {
+        Ok(nn::linear(p / name, in_dim, out_dim, Default::default()))
+    })
+    .expect("создание обычных полносвязных слоев не завершается ошибкой")
+}
+
+// Полносвязный слой модели ошибок ОС по имени и размерам (входы, выходы):
+// nn::Linear для обучения или QuantizedLinear из квантованной модели
+type LinearFactory<'a, L> = dyn FnMut(&nn::Path, &str, i64, i64) -> Result<L, Box<dyn std::error::Error>> + 'a;
+
+// Архитектура os_error_cnn; полносвязные слои голов создаются через linear
+fn os_error_cnn_with<L: nn::Module + 'static>(
+    p: &nn::Path,
+    num_error_types: i64,
+    num_os_types: i64,
+    linear: &mut LinearFactory<L>,
+) -> Result<(impl nn::ModuleT + use<L>, impl nn::ModuleT + use<L>), Box<dyn std::error::Error>> {
+    let layers = os_error_cnn_layers(p, num_error_types, num_os_types, linear)?;
+    // Каждая голова получает свой модуль сверточных слоев над одними и теми же весами
+    let error_features = layers.conv_features().add_fn(pool_conv_features);
+    let os_features = layers.conv_features().add_fn(pool_conv_features);
+
+    Ok((
+        nn::seq_t().add(error_features).add(layers.error_classifier),
+        nn::seq_t().add(os_features).add(layers.os_classifier),
+    ))
+}
+
+// Части модели ошибок ОС по отдельности: сверточные слои до активации conv3
+// (карта признаков, по которой считается Grad-CAM) и классификаторы голов
+struct OsErrorLayers {
+    convs: [nn::Conv2D; 3], // conv1, conv2, conv3, общие для обеих голов
+    error_classifier: nn::SequentialT,
+    os_classifier: nn::SequentialT,
+}
+
+impl OsErrorLayers {
+    // Сверточные слои до активации conv3. Веса берутся через shallow_clone: модуль
+    // ссылается на те же тензоры VarStore, поэтому таких модулей можно собрать
+    // несколько (по одному на голову), и все они обучаются и загружаются вместе
+    fn conv_features(&self) -> nn::SequentialT {
+        let last = self.convs.len() - 1;
+        self.convs.iter().enumerate().fold(nn::seq_t(), |seq, (i, conv)| {
+            let weight = conv.ws.shallow_clone();
+            let bias = conv.bs.as_ref().map(Tensor::shallow_clone);
+            let padding = weight.size()[2] / 2;
+            let seq = seq.add_fn(move |xs| {
+                xs.conv2d(&weight, bias.as_ref(), &[1, 1], &[padding, padding], &[1, 1], 1).relu()
+            });
+            if i == last {
+                seq
+            } else {
+                seq.add_fn(|xs| xs.max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false))
+            }
+        })
+    }
+}
+
+// Пулинг карты признаков conv3 в вектор признаков для классификаторов
+fn pool_conv_features(xs: &Tensor) -> Tensor {
+    xs.max_pool2d(&[2, 2], &[2, 2], &[0, 0], &[1, 1], false)
+        .adaptive_avg_pool2d(&[OS_POOLED_SIZE, OS_POOLED_SIZE])
+        .flatten(1, -1)
+}
+
+fn os_error_cnn_layers<L: nn::Module + 'static>(
+    p: &nn::Path,
+    num_error_types: i64,
+    num_os_types: i64,
+    linear: &mut LinearFactory<L>,
+) -> Result<OsErrorLayers, Box<dyn std::error::Error>> {
+    let feature_dim = 256 * OS_POOLED_SIZE * OS_POOLED_SIZE;
+
+    // Общие сверточные слои для извлечения признаков
+    let conv1 = nn::conv2d(p / "conv1", 3, 64, 5, nn::ConvConfig { padding: 2, ..Default::default() });
+    let conv2 = nn::conv2d(p / "conv2", 64, 128, 5, nn::ConvConfig { padding: 2, ..Default::default() });
+    let conv3 = nn::conv2d(p / "conv3", 128, 256, 3, nn::ConvConfig { padding: 1, ..Default::default() });
+
+
+    // Классификатор типа ошибки
+    let error_classifier = nn::seq_t()
+        .add(linear(p, "error_fc1", feature_dim, 512)?)
+        .add_fn(|xs| xs.relu())
+        .add_fn_t(|xs, train| xs.dropout(0.5, train))
+        .add(linear(p, "error_fc2", 512, 256)?)
+        .add_fn(|xs| xs.relu())
+        .add(linear(p, "error_out", 256, num_error_types)?);
+
+    // Классификатор типа ОС
+    let os_classifier = nn::seq_t()
+        .add(linear(p, "os_fc1", feature_dim, 256)?)
+        .add_fn(|xs| xs.relu())
+        .add_fn_t(|xs, train| xs.dropout(0.3, train))
+        .add(linear(p, "os_out", 256, num_os_types)?);
+
+    Ok(OsErrorLayers { convs: [conv1, conv2, conv3], error_classifier, os_classifier })
+}
+
+// Общие сверточные слои модели ошибок ОС, которые замораживает --freeze-backbone
+const OS_BACKBONE_LAYERS: &[&str] = &["conv1", "conv2", "conv3"];
+
+// Имена переменных в VarStore имеют вид <слой>.<weight|bias>
+fn is_backbone_variable(name: &str) -> bool {
+    name.split('.').next().is_some_and(|layer| OS_BACKBONE_LAYERS.contains(&layer))
+}
+
+// Полносвязные слои модели ошибок ОС, веса которых квантуются командой quantize
+const OS_LINEAR_LAYERS: &[&str] = &["error_fc1", "error_fc2", "error_out", "os_fc1", "os_out"];
+
+// Схема квантования в метаданных: веса полносвязных слоев int8, активации float32
+const QUANTIZATION_DYNAMIC_INT8: &str = "dynamic_int8";
+
+// Квантование весов по тензору целиком в int8: w ≈ (q - zero_point) * scale.
+// Диапазон расширяется до нуля, чтобы нулевой вес представлялся точно
+fn quantize_weight(weight: &Tensor) -> (Tensor, f64, i64) {
+    let min = f64::from(&weight.min()).min(0.0);
+    let max = f64::from(&weight.max()).max(0.0);
+    let scale = if max > min { (max - min) / 255.0 } else { 1.0 };
+    let zero_point = (-128.0 - min / scale).round().clamp(-128.0, 127.0) as i64;
+    let quantized = ((weight / scale).round() + zero_point as f64)
+        .clamp(-128.0, 127.0)
+        .to_kind(Kind::Int8);
+    (quantized, scale, zero_point)
+}
+
+// Способ умножения квантованного слоя
+#[derive(Debug)]
+enum QuantizedBackend {
+    // int8-умножение FBGEMM: упакованная матрица весов и суммы строк для учета zero_point
+    Fbgemm { packed: Tensor, col_offsets: Tensor },
+    // libtorch собран без FBGEMM или процессор его не поддерживает: веса восстанавливаются в float32
+    Dequantized(Tensor),
+}
+
+// Полносвязный слой с весами int8 (динамическое квантование: активации остаются float32)
+#[derive(Debug)]
+struct QuantizedLinear {
+    weight: Tensor, // int8 [выходы, входы]
+    bias: Tensor,
+    scale: f64,
+    zero_point: i64,
+    backend: QuantizedBackend,
+}
+
+impl QuantizedLinear {
+    // Слой name из тензоров квантованной модели: name.qweight, name.bias, name.scale, name.zero_point
+    fn from_checkpoint(
+        tensors: &HashMap<String, Tensor>,
+        name: &str,
+        in_dim: i64,
+        out_dim: i64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let get = |suffix: &str| {
+            tensors
+                .get(&format!("{}.{}", name, suffix))
+                .ok_or_else(|| format!("В квантованной модели нет тензора {}.{}", name, suffix))
+        };
+
+        let weight = get("qweight")?.contiguous();
+        if weight.kind() != Kind::Int8 || weight.size() != [out_dim, in_dim] {
+            return Err(format!(
+                "Слой {}: ожидаются веса int8 размера [{}, {}], получено {:?} {:?}",
+                name, out_dim, in_dim, weight.kind(), weight.size()
+            ).into());
+        }
+        let bias = get("bias")?.shallow_clone();
+        let scale = f64::from(get("scale")?);
+        let zero_point = i64::from(get("zero_point")?);
+
+        let backend = match weight.f_fbgemm_pack_quantized_matrix() {
+            Ok(packed) => {
+                let col_offsets = (weight.sum_dim_intlist(&[1], false, Kind::Int64) - (zero_point * in_dim) as f64)
+                    .to_kind(Kind::Int)
+                    .contiguous();
+                QuantizedBackend::Fbgemm { packed, col_offsets }
+            }
+            Err(_) => QuantizedBackend::Dequantized((weight.to_kind(Kind::Float) - zero_point as f64) * scale),
+        };
+
+        Ok(QuantizedLinear { weight, bias, scale, zero_point, backend })
+    }
+
+    fn uses_fbgemm(&self) -> bool {
+        matches!(self.backend, QuantizedBackend::Fbgemm { .. })
+    }
+}
+
+impl nn::Module for QuantizedLinear {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        match &self.backend {
+            QuantizedBackend::Fbgemm { packed, col_offsets } => xs.contiguous().fbgemm_linear_int8_weight_fp32_activation(
+                &self.weight,
+                packed,
+                col_offsets,
+                self.scale,
+                self.zero_point as f64,
+                &self.bias,
+            ),
+            QuantizedBackend::Dequantized(weight) => xs.matmul(&weight.tr()) + &self.bias,
+        }
+    }
+}
+
+// Метаданные модели, сохраняемые рядом с весами (model.pt -> model.json)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct ModelMetadata {
+    architecture: String,
+    input_shape: Vec<i64>, // [каналы, высота, ширина]
+    num_classes: i64,
+    #[serde(default)]
+    error_types: Vec<String>,
+    #[serde(default)]
+    os_types: Vec<String>,
+    version: String,
+    // Температура softmax, подобранная для калибровки уверенности (1.0 - без изменений)
+    #[serde(default = "default_temperature")]
+    temperature: f64,
+    // Схема квантования полносвязных слоев (dynamic_int8); нет - обычная модель float32
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quantization: Option<String>,
+    // Размеры слоев simple_cnn; нет - размеры по умолчанию (модели, сохраненные до появления поля)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cnn: Option<CnnConfig>,
+    // Поканальная нормализация входа из --normalize-from; нет - значения 0..1 как есть
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    normalization: Option<Normalization>,
+    // Сохранены только веса (--weights-only), без состояния оптимизатора
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    weights_only: bool,
+}
+
+fn default_temperature() -> f64 {
+    1.0
+}
+
+// Нормализация (x - mean) / std по каналам RGB, посчитанная командой stats
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Normalization {
+    mean: [f64; 3],
+    std: [f64; 3],
+}
+
+impl Normalization {
+    fn validate(self) -> Result<Self, String> {
+        if self.mean.iter().chain(&self.std).any(|value| !value.is_finite()) || self.std.iter().any(|std| *std <= 0.0) {
+            return Err(format!("Некорректная нормализация: mean {:?}, std {:?} (std должно быть положительным)", self.mean, self.std));
+        }
+        Ok(self)
+    }
+
+    fn apply(&self, images: &Tensor) -> Tensor {
+        let channels = |values: &[f64; 3]| {
+            Tensor::of_slice(values).to_kind(Kind::Float).to_device(images.device()).view([1, 3, 1, 1])
+        };
+        (images - channels(&self.mean)) / channels(&self.std)
+    }
+}
+
+// Softmax логитов, деленных на температуру: T > 1 сглаживает распределение, T < 1 заостряет
+// Логиты fp16 приводятся к float до деления, чтобы не терять точность
+fn softmax_with_temperature(logits: &Tensor, temperature: f64) -> Tensor {
+    (logits.to_kind(Kind::Float) / temperature).softmax(-1, Kind::Float)
+}
+
+// Проверка температуры, переданной пользователем
+fn validate_temperature(temperature: f64) -> Result<f64, String> {
+    if temperature.is_finite() && temperature > 0.0 {
+        Ok(temperature)
+    } else {
+        Err(format!("Температура должна быть положительным числом, получено {}", temperature))
+    }
+}
+
+impl ModelMetadata {
+    fn simple_cnn(num_classes: i64, cnn: CnnConfig) -> Self {
+        ModelMetadata {
+            architecture: "simple_cnn".to_string(),
+            input_shape: vec![3, SIMPLE_CNN_INPUT_SIZE, SIMPLE_CNN_INPUT_SIZE],
+            num_classes,
+            error_types: Vec::new(),
+            os_types: Vec::new(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            temperature: default_temperature(),
+            quantization: None,
+            cnn: Some(cnn),
+            normalization: None,
+            weights_only: false,
+        }
+    }
+
+    fn os_error_cnn(input_size: i64) -> Self {
+        ModelMetadata::os_error_cnn_with_labels(input_size, &Labels::builtin())
+    }
+
+    fn os_error_cnn_with_labels(input_size: i64, labels: &Labels) -> Self {
+        ModelMetadata {
+            architecture: "os_error_cnn_pooled".to_string(),
+            input_shape: vec![3, input_size, input_size],
+            num_classes: labels.error_types.len() as i64,
+            error_types: labels.error_types.clone(),
+            os_types: labels.os_types.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            temperature: default_temperature(),
+            quantization: None,
+            cnn: None,
+            normalization: None,
+            weights_only: false,
+        }
+    }
+
+    // Имена выходов голов модели ошибок ОС; индекс вне словаря - "unknown"
+    fn error_label(&self, idx: usize) -> &str {
+        self.error_types.get(idx).map_or("unknown", String::as_str)
+    }
+
+    fn os_label(&self, idx: usize) -> &str {
+        self.os_types.get(idx).map_or("unknown", String::as_str)
+    }
+
+    fn cnn_config(&self) -> CnnConfig {
+        self.cnn.unwrap_or_default()
+    }
+
+    // Вход модели в том виде, в каком она обучалась
+    fn normalize(&self, images: &Tensor) -> Tensor {
+        match &self.normalization {
+            Some(normalization) => normalization.apply(images),
+            None => images.shallow_clone(),
+        }
+    }
+
+    // Сторона квадратного изображения, на котором обучалась модель
+    fn input_size(&self) -> i64 {
+        self.input_shape.last().copied().unwrap_or(DEFAULT_OS_INPUT_SIZE)
+    }
+
+    fn path_for<P: AsRef<Path>>(model_path: P) -> std::path::PathBuf {
+        model_path.as_ref().with_extension("json")
+    }
+
+    fn save<P: AsRef<Path>>(&self, model_path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(Self::path_for(model_path), json)?;
+        Ok(())
+    }
+
+    // Возвращает None, если файла метаданных нет (модели, сохраненные до их появления)
+    fn load<P: AsRef<Path>>(model_path: P) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let path = Self::path_for(model_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = std::fs::read_to_string(&path)?;
+        Ok(Some(serde_json::from_str(&json)?))
+    }
+
+    // Проверка, что сохраненная модель соответствует собираемой архитектуре
+    fn check_compatible(&self, expected: &ModelMetadata) -> Result<(), Box<dyn std::error::Error>> {
+        if self.architecture != expected.architecture {
+            return Err(format!(
+                "Архитектура модели '{}' не совпадает с ожидаемой '{}'",
+                self.architecture, expected.architecture
+            ).into());
+        }
+        if self.input_shape != expected.input_shape {
+            return Err(format!(
+                "Размер входа модели {:?} не совпадает с ожидаемым {:?}",
+                self.input_shape, expected.input_shape
+            ).into());
+        }
+        if self.num_classes != expected.num_classes || self.os_types.len() != expected.os_types.len() {
+            return Err(format!(
+                "Модель обучена на {} классах ошибок и {} типах ОС, ожидается {} и {}",
+                self.num_classes, self.os_types.len(), expected.num_classes, expected.os_types.len()
+            ).into());
+        }
+        if self.cnn_config() != expected.cnn_config() {
+            return Err(format!(
+                "Размеры слоев модели {:?} не совпадают с ожидаемыми {:?}",
+                self.cnn_config(), expected.cnn_config()
+            ).into());
+        }
+        Ok(())
+    }
+}
+
+// Загрузка метаданных рядом с моделью и проверка совместимости.
+// Без файла метаданных возвращаются ожидаемые метаданные
+fn verify_model_metadata(model_path: &str, expected: ModelMetadata) -> Result<ModelMetadata, Box<dyn std::error::Error>> {
+    match ModelMetadata::load(model_path)? {
+        Some(metadata) => {
+            metadata.check_compatible(&expected)?;
+            Ok(metadata)
+        }
+        None => {
+            eprintln!("Внимание: метаданные для {} не найдены, совместимость модели не проверена", model_path);
+            Ok(expected)
+        }
+    }
+}
+
+// Метаданные модели для ошибок ОС: размер входа берется из файла метаданных,
+// а при его отсутствии используется размер по умолчанию. Число выходов модели
+// должно совпадать со словарем меток, имена меток берутся из labels
+fn resolve_os_error_metadata(model_path: &str, labels: &Labels) -> Result<ModelMetadata, Box<dyn std::error::Error>> {
+    match ModelMetadata::load(model_path)? {
+        Some(mut metadata) => {
+            metadata.check_compatible(&ModelMetadata::os_error_cnn_with_labels(metadata.input_size(), labels))
+                .map_err(|e| format!("{}: {} (словарь меток задается --labels)", model_path, e))?;
+            metadata.error_types = labels.error_types.clone();
+            metadata.os_types = labels.os_types.clone();
+            Ok(metadata)
+        }
+        None => {
+            eprintln!("Внимание: метаданные для {} не найдены, используется вход {}x{}",
+                model_path, DEFAULT_OS_INPUT_SIZE, DEFAULT_OS_INPUT_SIZE);
+            Ok(ModelMetadata::os_error_cnn_with_labels(DEFAULT_OS_INPUT_SIZE, labels))
+        }
+    }
+}
+
+// Словарь меток модели ошибок ОС: имена выходов головы ошибок и головы ОС по порядку.
+// По умолчанию встроенные OS_ERROR_TYPES и OS_TYPES, либо JSON-файл из --labels вида
+// { "error_types": [...], "os_types": [...] } для модели, обученной на другом наборе классов
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+struct Labels {
+    error_types: Vec<String>,
+    os_types: Vec<String>,
+}
+
+impl Labels {
+    fn builtin() -> Self {
+        Labels {
+            error_types: OS_ERROR_TYPES.iter().map(|s| s.to_string()).collect(),
+            os_types: OS_TYPES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    // Без пути используются встроенные метки
+    fn load(path: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(path) = path else {
+            return Ok(Labels::builtin());
+        };
+
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("Не удалось прочитать файл меток {}: {}", path, e))?;
+        let labels: Labels = serde_json::from_str(&json)
+            .map_err(|e| format!("Некорректный файл меток {}: {}", path, e))?;
+
+        for (field, names) in [("error_types", &labels.error_types), ("os_types", &labels.os_types)] {
+            if names.is_empty() {
+                return Err(format!("Файл меток {}: список {} пуст", path, field).into());
+            }
+            let unique: std::collections::HashSet<&String> = names.iter().collect();
+            if unique.len() != names.len() {
+                return Err(format!("Файл меток {}: в списке {} есть повторы", path, field).into());
+            }
+        }
+
+        eprintln!("Загружены метки из {}: {} типов ошибок, {} типов ОС",
+            path, labels.error_types.len(), labels.os_types.len());
+        Ok(labels)
+    }
+}
+
+impl From<&ModelMetadata> for Labels {
+    fn from(metadata: &ModelMetadata) -> Self {
+        Labels {
+            error_types: metadata.error_types.clone(),
+            os_types: metadata.os_types.clone(),
+        }
+    }
+}
+
+// Создание тестовых данных для демонстрации
+fn create_dummy_data() -> (Tensor, Tensor) {
+    let device = Device::Cpu;
+    let train_images = Tensor::randn(&[100, 3, 32, 32], (Kind::Float, device));
+    let train_labels = Tensor::randint(10, &[100], (Kind::Int64, device));
+    (train_images, train_labels)
+}
+
+// Запись метрик обучения в CSV: одна строка на эпоху, сброс на диск после каждой строки,
+// чтобы прерванный запуск оставлял частичные данные
+struct MetricsWriter {
+    writer: std::io::BufWriter<std::fs::File>,
+}
+
+impl MetricsWriter {
+    fn create(path: &str, header: &[&str]) -> std::io::Result<Self> {
+        use std::io::Write;
+
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(writer, "{}", header.join(","))?;
+        writer.flush()?;
+        Ok(MetricsWriter { writer })
+    }
+
+    fn write_row(&mut self, epoch: i64, values: &[f64]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let values: Vec<String> = values.iter().map(|value| format!("{:.6}", value)).collect();
+        writeln!(self.writer, "{},{}", epoch, values.join(","))?;
+        self.writer.flush()
+    }
+}
+
+// Параметры обучения базовой модели
+struct TrainConfig {
+    epochs: i64,
+    learning_rate: f64,
+    model_path: String,          // Куда сохранить обученную модель
+    metrics_csv: Option<String>, // CSV-файл для поэпоховых метрик
+    grad_clip: Option<f64>,      // Максимальная общая норма градиентов
+    dry_run: bool,               // Только проверить данные и модель, не обучая и не сохраняя
+    cnn: CnnConfig,              // Размеры слоев модели
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        TrainConfig {
+            epochs: 5,
+            learning_rate: 1e-3,
+            model_path: "model.pt".to_string(),
+            metrics_csv: None,
+            grad_clip: None,
+            dry_run: false,
+            cnn: CnnConfig::default(),
+        }
+    }
+}
+
+// Зерно для libtorch (инициализация весов, случайные данные, перемешивание) и для
+// генератора аугментации и mixup. Без --seed выбирается случайное; оно печатается,
+// чтобы любой запуск можно было повторить
+fn training_seed(seed: Option<u64>) -> u64 {
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().r#gen::<u32>() as u64);
+    tch::manual_seed(seed as i64);
+    println!("Зерно генератора случайных чисел: {} (повторить запуск: --seed {})", seed, seed);
+    seed
+}
+
+// Сколько примеров прогоняется через модель при --dry-run для проверки форм
+const DRY_RUN_BATCH: i64 = 8;
+
+// Метки вне 0..num_classes libtorch отвергает лишь невнятной ошибкой посреди обучения,
+// поэтому они проверяются до первого шага
+fn check_label_range(labels: &Tensor, num_classes: usize, title: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let out_of_range: Vec<(usize, i64)> = Vec::<i64>::from(labels)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, label)| *label < 0 || *label >= num_classes as i64)
+        .collect();
+
+    match out_of_range.first() {
+        None => Ok(()),
+        Some((index, label)) => Err(format!(
+            "{}: {} меток вне диапазона 0..{}, первая - {} у примера {}",
+            title, out_of_range.len(), num_classes, label, index
+        ).into()),
+    }
+}
+
+fn class_counts(labels: &Tensor, num_classes: usize) -> Vec<usize> {
+    let mut counts = vec![0usize; num_classes];
+    for label in Vec::<i64>::from(labels) {
+        if let Some(count) = counts.get_mut(label as usize) {
+            *count += 1;
+        }
+    }
+    counts
+}
+
+fn print_class_distribution<T: std::fmt::Display>(title: &str, names: &[T], labels: &Tensor) {
+    let formatted: Vec<String> = names
+        .iter()
+        .zip(class_counts(labels, names.len()))
+        .map(|(name, count)| format!("{}={}", name, count))
+        .collect();
+    println!("{}: {}", title, formatted.join(", "));
+}
+
+// Ширина самого длинного столбца гистограммы распределения классов
+const CLASS_HISTOGRAM_WIDTH: usize = 40;
+
+// Класс, у которого меньше этой доли от среднего числа примеров на класс, считается
+// недопредставленным (при 10 классах и 200 примерах - меньше 4 примеров)
+const UNDERREPRESENTED_CLASS_SHARE: f64 = 0.2;
+
+// Отчет о распределении меток перед обучением: гистограмма числа примеров по классам
+// и предупреждения о недопредставленных классах. Возвращает классы без примеров:
+// softmax не может научиться их предсказывать
+fn report_class_distribution<'a>(title: &str, names: &[&'a str], labels: &Tensor) -> Vec<&'a str> {
+    let counts = class_counts(labels, names.len());
+    let total: usize = counts.iter().sum();
+    let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+    let name_width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let threshold = total as f64 / names.len() as f64 * UNDERREPRESENTED_CLASS_SHARE;
+
+    println!("{} ({} примеров):", title, total);
+    for (name, &count) in names.iter().zip(&counts) {
+        let bar = "#".repeat((count * CLASS_HISTOGRAM_WIDTH).div_ceil(max_count));
+        println!("  {:<width$} {:>6} {}", name, count, bar, width = name_width);
+    }
+
+    let mut empty = Vec::new();
+    for (name, &count) in names.iter().zip(&counts) {
+        if count == 0 {
+            empty.push(*name);
+        } else if (count as f64) < threshold {
+            eprintln!(
+                "Внимание: {} - всего {} примеров при среднем {:.1} на класс",
+                name, count, total as f64 / names.len() as f64
+            );
+        }
+    }
+    empty
+}
+
+// Классы без примеров прерывают обучение, если не указан --force
+fn check_empty_classes(title: &str, empty: &[&str], force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if empty.is_empty() {
+        return Ok(());
+    }
+    if force {
+        eprintln!("Внимание: {}: нет примеров для {}, обучение продолжено из-за --force", title, empty.join(", "));
+        return Ok(());
+    }
+    Err(format!(
+        "{}: нет примеров для {}, модель не сможет их предсказывать. Добавьте данные или запустите с --force",
+        title, empty.join(", ")
+    ).into())
+}
+
+// Форма выхода головы модели на пробном батче должна быть [batch, num_classes]
+fn check_output_shape(output: &Tensor, batch: i64, num_classes: usize, head: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let shape = output.size();
+    if shape != [batch, num_classes as i64] {
+        return Err(format!("{}: выход формы {:?}, ожидалась [{}, {}]", head, shape, batch, num_classes).into());
+    }
+    println!("{}: выход {:?}", head, shape);
+    Ok(())
+}
+
+// Ограничение градиентов по общей (L2 по всем параметрам) норме: если она больше
+// max_norm, все градиенты пропорционально уменьшаются. Возвращает норму до ограничения
+fn clip_grad_norm(params: &[Tensor], max_norm: f64) -> f64 {
+    tch::no_grad(|| {
+        let grads: Vec<Tensor> = params.iter().map(Tensor::grad).filter(Tensor::defined).collect();
+        let total_norm = grads.iter().map(|grad| f64::from(&grad.norm()).powi(2)).sum::<f64>().sqrt();
+
+        if total_norm > max_norm {
+            let scale = max_norm / (total_norm + 1e-6);
+            for mut grad in grads {
+                let scaled = &grad * scale;
+                grad.copy_(&scaled);
+            }
+        }
+        total_norm
+    })
+}
+
+// Ошибка обучения, при которой продолжать бессмысленно
+#[derive(Debug)]
+enum TrainingError {
+    // Потеря стала NaN или бесконечной; модель не сохраняется, чтобы не затереть рабочий чекпоинт
+    NonFiniteLoss { epoch: i64, loss: f64 },
+}
+
+impl std::fmt::Display for TrainingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrainingError::NonFiniteLoss { epoch, loss } => write!(
+                f,
+                "Потеря стала {} на эпохе {}, обучение прервано, модель не сохранена. \
+                 Уменьшите --learning-rate, задайте --grad-clip или проверьте данные на поврежденные изображения",
+                loss, epoch
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TrainingError {}
+
+// Проверка потери перед шагом оптимизатора, чтобы NaN не попал в веса
+fn check_loss(loss: &Tensor, epoch: i64) -> Result<f64, TrainingError> {
+    let value = f64::from(loss);
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(TrainingError::NonFiniteLoss { epoch, loss: value })
+    }
+}
+
+// Разбор --grad-clip: положительное конечное число
+fn parse_grad_clip(value: &str) -> Result<f64, String> {
+    let max_norm = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if max_norm.is_finite() && max_norm > 0.0 {
+        Ok(max_norm)
+    } else {
+        Err(format!("Норма для ограничения градиентов должна быть положительной, получено {}", max_norm))
+    }
+}
+
+// Обучение модели
+fn train_model(
+    model: &dyn nn::Module,
+    train_images: &Tensor,
+    train_labels: &Tensor,
+    vs: &nn::VarStore,
+    config: &TrainConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_label_range(train_labels, 10, "Метки классов")?;
+
+    if config.dry_run {
+        let classes: Vec<i64> = (0..10).collect();
+        println!("Обучающих примеров: {}, форма входа: {:?}", train_labels.size()[0], train_images.size());
+        print_class_distribution("Распределение классов", &classes, train_labels);
+        let batch = DRY_RUN_BATCH.min(train_images.size()[0]);
+        let output = tch::no_grad(|| model.forward(&train_images.narrow(0, 0, batch)));
+        check_output_shape(&output, batch, classes.len(), "Классификатор")?;
+        println!("Пробный запуск: обучение пропущено, {} не изменен", config.model_path);
+        return Ok(());
+    }
+
+    let mut optimizer = nn::Adam::default().build(vs, config.learning_rate)?;
+    let mut metrics = match &config.metrics_csv {
+        Some(path) => Some(MetricsWriter::create(path, &["epoch", "loss"])?),
+        None => None,
+    };
+    let params = vs.trainable_variables();
+
+    for epoch in 1..=config.epochs {
+        let output = model.forward(train_images);
+        let loss = output.cross_entropy_for_logits(train_labels);
+        let loss_value = check_loss(&loss, epoch)?;
+        optimizer.zero_grad();
+        loss.backward();
+        if let Some(max_norm) = config.grad_clip {
+            let norm = clip_grad_norm(&params, max_norm);
+            if norm > max_norm {
+                println!("Внимание: норма градиентов {:.4} ограничена до {}", norm, max_norm);
+            }
+        }
+        optimizer.step();
+        println!("Epoch: {}, Loss: {:.4}", epoch, loss_value);
+
+        if let Some(metrics) = metrics.as_mut() {
+            metrics.write_row(epoch, &[loss_value])?;
+        }
+    }
+
+    vs.save(&config.model_path)?;
+    ModelMetadata::simple_cnn(10, config.cnn).save(&config.model_path)?;
+    println!("Модель сохранена в {}", config.model_path);
+    Ok(())
+}
+
+// Параметры инференса из строки запроса
+#[derive(Deserialize)]
+struct InferenceQuery {
+    temperature: Option<f64>, // Переопределяет температуру из метаданных модели
+    model: Option<String>,    // Имя модели ошибок ОС из --models-dir; нет - основная
+    severity_max: Option<SolutionSeverity>, // Скрыть решения рискованнее этого уровня
+}
+
+impl InferenceQuery {
+    fn temperature(&self) -> Result<Option<f64>, AppError> {
+        self.temperature
+            .map(validate_temperature)
+            .transpose()
+            .map_err(AppError::BadInput)
+    }
+}
+
+// Размер кэша ответов на одинаковые запросы предсказания по умолчанию
+const DEFAULT_PREDICTION_CACHE_SIZE: usize = 256;
+
+// SHA-256 запроса, см. prediction_cache_key
+type PredictionCacheKey = [u8; 32];
+
+// LRU-кэш ответов на одинаковые запросы предсказания: клиенты иногда повторно
+// присылают то же изображение. Очистка увеличивает поколение, и ответы, посчитанные
+// старой моделью во время перезагрузки, уже не попадают в кэш
+struct PredictionCache<T> {
+    capacity: usize, // 0 отключает кэш
+    state: Mutex<PredictionCacheState<T>>,
+}
+
+struct PredictionCacheState<T> {
+    generation: u64,
+    entries: VecDeque<(PredictionCacheKey, T)>, // От давно использованных к недавним
+}
+
+impl<T: Clone> PredictionCache<T> {
+    fn new(capacity: usize) -> Self {
+        PredictionCache {
+            capacity,
+            state: Mutex::new(PredictionCacheState { generation: 0, entries: VecDeque::new() }),
+        }
+    }
+
+    // Сохраненный ответ (запись становится самой недавней) и текущее поколение для insert
+    fn get(&self, key: PredictionCacheKey) -> Result<(Option<T>, u64), AppError> {
+        let mut state = self.state.lock()?;
+        let cached = state
+            .entries
+            .iter()
+            .position(|(entry_key, _)| *entry_key == key)
+            .and_then(|idx| state.entries.remove(idx))
+            .map(|entry| {
+                let value = entry.1.clone();
+                state.entries.push_back(entry);
+                value
+            });
+        Ok((cached, state.generation))
+    }
+
+    // Ответ сохраняется, только если кэш не очищали с момента get
+    fn insert(&self, key: PredictionCacheKey, value: T, generation: u64) -> Result<(), AppError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut state = self.state.lock()?;
+        if state.generation != generation {
+            return Ok(());
+        }
+        state.entries.retain(|(entry_key, _)| *entry_key != key);
+        if state.entries.len() >= self.capacity {
+            state.entries.pop_front();
+        }
+        state.entries.push_back((key, value));
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), AppError> {
+        let mut state = self.state.lock()?;
+        state.generation += 1;
+        state.entries.clear();
+        Ok(())
+    }
+}
+
+// Кэши ответов REST-обработчиков /predict и /predict-os-error
+struct PredictionCaches {
+    generic: PredictionCache<PredictResponse>,
+    os_error: PredictionCache<OsErrorPredictResponse>,
+}
+
+impl PredictionCaches {
+    fn new(capacity: usize) -> Self {
+        PredictionCaches {
+            generic: PredictionCache::new(capacity),
+            os_error: PredictionCache::new(capacity),
+        }
+    }
+}
+
+// Размер истории последних предсказаний по умолчанию
+const DEFAULT_RECENT_SIZE: usize = 100;
+
+// Сколько записей /recent возвращает без параметра limit
+const DEFAULT_RECENT_LIMIT: usize = 20;
+
+// Запись истории предсказаний модели ошибок ОС
+#[derive(Serialize, Clone)]
+struct RecentPrediction {
+    timestamp: u64,       // Unix-время, мс
+    source: &'static str, // rest или chat
+    error_type: String,
+    os_type: String,
+    confidence: f32,
+}
+
+// Кольцевой буфер последних предсказаний REST API и чата для GET /recent:
+// при заполнении новая запись вытесняет самую старую
+struct RecentPredictions {
+    capacity: usize, // 0 отключает историю
+    entries: Mutex<VecDeque<RecentPrediction>>,
+}
+
+impl RecentPredictions {
+    fn new(capacity: usize) -> Self {
+        RecentPredictions { capacity, entries: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    fn record(&self, source: &'static str, error_type: &str, os_type: &str, confidence: f32) -> Result<(), AppError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        let mut entries = self.entries.lock()?;
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(RecentPrediction {
+            timestamp,
+            source,
+            error_type: error_type.to_string(),
+            os_type: os_type.to_string(),
+            confidence,
+        });
+        Ok(())
+    }
+
+    // Не больше limit последних записей, от новых к старым
+    fn latest(&self, limit: usize) -> Result<Vec<RecentPrediction>, AppError> {
+        Ok(self.entries.lock()?.iter().rev().take(limit).cloned().collect())
+    }
+}
+
+// Параметры GET /recent и GET /escalations
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<usize>, // [по умолчанию: DEFAULT_RECENT_LIMIT]
+}
+
+// Последние предсказания модели ошибок ОС, от новых к старым
+async fn recent(
+    query: web::Query<RecentQuery>,
+    recent: web::Data<RecentPredictions>,
+) -> Result<HttpResponse, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_LIMIT);
+    Ok(HttpResponse::Ok().json(recent.latest(limit)?))
+}
+
+// Размер очереди случаев для проверки специалистом
+const ESCALATION_QUEUE_SIZE: usize = 100;
+
+// Добавляется к ответу чата, если какой-то из скриншотов анимированный
+const FIRST_FRAME_NOTE: &str =
+    "\n\nСкриншот анимированный, проанализирован только его первый кадр. Для точного анализа загрузите статичный скриншот.";
+
+// Добавляется к ответу чата, если анализ передан на проверку
+const ESCALATION_NOTE: &str = "\n\nУверенность анализа низкая, случай передан на проверку специалисту.";
+
+// Случай из чата, анализ которого оказался менее уверенным, чем --escalation-threshold
+#[derive(Serialize, Clone)]
+struct Escalation {
+    timestamp: u64,             // Unix-время, мс
+    session: Uuid,
+    image_hash: Option<String>, // Перцептивный хэш скриншота в hex; нет, если анализировался текст
+    message: String,
+    analysis: ErrorAnalysis,
+}
+
+// Очередь неуверенных анализов чата для GET /escalations: при заполнении
+// новый случай вытесняет самый старый. Без порога очередь не пополняется
+struct EscalationQueue {
+    threshold: Option<f32>,
+    capacity: usize,
+    entries: Mutex<VecDeque<Escalation>>,
+}
+
+impl EscalationQueue {
+    fn new(threshold: Option<f32>, capacity: usize) -> Self {
+        EscalationQueue { threshold, capacity, entries: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    // true, если уверенность анализа ниже порога и случай поставлен в очередь
+    fn escalate(&self, session: Uuid, message: &str, analysis: &ErrorAnalysis) -> Result<bool, AppError> {
+        if !self.threshold.is_some_and(|threshold| analysis.confidence < threshold) {
+            return Ok(false);
+        }
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        let mut entries = self.entries.lock()?;
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Escalation {
+            timestamp,
+            session,
+            image_hash: analysis.image_hash.map(|hash| format!("{:016x}", hash)),
+            message: message.to_string(),
+            analysis: analysis.clone(),
+        });
+        Ok(true)
+    }
+
+    // Не больше limit последних случаев, от новых к старым
+    fn latest(&self, limit: usize) -> Result<Vec<Escalation>, AppError> {
+        Ok(self.entries.lock()?.iter().rev().take(limit).cloned().collect())
+    }
+}
+
+// Запрос POST /analyze-text
+#[derive(Deserialize)]
+struct AnalyzeTextRequest {
+    message: String,
+    #[serde(default)]
+    lang: Option<TextLang>, // Язык ответа; база причин и решений есть только на русском
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TextLang {
+    Ru,
+    En,
+}
+
+// Текстовая диагностика чата без WebSocket: тот же ChatResponse, что и на текстовое сообщение
+async fn analyze_text(req: web::Json<AnalyzeTextRequest>) -> Result<HttpResponse, AppError> {
+    if req.message.trim().is_empty() {
+        return Err(AppError::BadInput("Поле message не должно быть пустым".to_string()));
+    }
+    // Ключевые слова ищутся на обоих языках, но ответ только на русском: клиент, просящий
+    // английский, получает 400, а не молча русский текст
+    if req.lang == Some(TextLang::En) {
+        return Err(AppError::BadInput(
+            "Ответ на английском не поддерживается: lang должен быть \"ru\" или не указан".to_string(),
+        ));
+    }
+    Ok(HttpResponse::Ok().json(process_text_query(&req.message)))
+}
+
+// Случаи, переданные чатом на проверку специалисту, от новых к старым
+async fn escalations(
+    query: web::Query<RecentQuery>,
+    escalations: web::Data<EscalationQueue>,
+) -> Result<HttpResponse, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_LIMIT);
+    Ok(HttpResponse::Ok().json(escalations.latest(limit)?))
+}
+
+// Сколько ссылок на анализы хранится одновременно; при переполнении удаляется самая старая
+const SHARED_ANALYSES_CAPACITY: usize = 1000;
+
+// Время жизни ссылки по умолчанию, секунд (сутки)
+const DEFAULT_SHARE_TTL_SECS: u64 = 24 * 60 * 60;
+
+// Как часто удаляются истекшие ссылки, секунд (чаще, если TTL короче)
+const SHARE_SWEEP_INTERVAL_SECS: u64 = 60;
+
+// Текущее Unix-время в секундах
+fn unix_time_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// Анализ, которым поделились по ссылке /shared/{token}
+struct SharedAnalysis {
+    analysis: ErrorAnalysis,
+    expires_at: std::time::Instant,
+}
+
+// Ссылки на анализы для передачи коллегам. Хранятся в памяти и теряются при перезапуске.
+// Токен подписан: "<случайный id>.<срок действия, Unix-время>.<HMAC-SHA256 первых двух частей>".
+// Ключ создается при запуске, поэтому подделанный токен или токен с продленным сроком
+// отклоняется до поиска в хранилище
+struct SharedAnalyses {
+    ttl: std::time::Duration,
+    capacity: usize,
+    key: ring::hmac::Key,
+    entries: Mutex<HashMap<String, SharedAnalysis>>,
+}
+
+impl SharedAnalyses {
+    fn new(ttl: std::time::Duration, capacity: usize) -> Self {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &rand::thread_rng().r#gen::<[u8; 32]>());
+        SharedAnalyses { ttl, capacity, key, entries: Mutex::new(HashMap::new()) }
+    }
+
+    // Подписанный токен для id, действующий до expires (секунды Unix-времени)
+    fn sign(&self, id: &str, expires: u64) -> String {
+        let payload = format!("{}.{}", id, expires);
+        let signature = ring::hmac::sign(&self.key, payload.as_bytes());
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature.as_ref());
+        format!("{}.{}", payload, signature)
+    }
+
+    // Срок действия из токена, если подпись верна и он еще не истек
+    fn verify(&self, token: &str) -> Option<u64> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(signature).ok()?;
+        ring::hmac::verify(&self.key, payload.as_bytes(), &signature).ok()?;
+        let expires: u64 = payload.split_once('.')?.1.parse().ok()?;
+        (expires > unix_time_secs()).then_some(expires)
+    }
+
+    // Сохранение анализа под новым подписанным токеном
+    fn share(&self, analysis: ErrorAnalysis) -> Result<String, AppError> {
+        let expires = unix_time_secs() + self.ttl.as_secs();
+        let token = self.sign(&Uuid::new_v4().simple().to_string(), expires);
+        let mut entries = self.entries.lock()?;
+        if entries.len() >= self.capacity {
+            // TTL у всех ссылок одинаковый, так что раньше всех истекает самая старая
+            let oldest = entries.iter().min_by_key(|(_, shared)| shared.expires_at).map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let expires_at = std::time::Instant::now() + self.ttl;
+        entries.insert(token.clone(), SharedAnalysis { analysis, expires_at });
+        Ok(token)
+    }
+
+    // Истекшая ссылка не отдается, даже если еще не удалена при очистке
+    fn get(&self, token: &str) -> Result<Option<ErrorAnalysis>, AppError> {
+        if self.verify(token).is_none() {
+            return Ok(None);
+        }
+        let entries = self.entries.lock()?;
+        Ok(entries
+            .get(token)
+            .filter(|shared| shared.expires_at > std::time::Instant::now())
+            .map(|shared| shared.analysis.clone()))
+    }
+
+    // Удаление истекших ссылок, возвращает их число
+    fn sweep(&self) -> Result<usize, AppError> {
+        let now = std::time::Instant::now();
+        let mut entries = self.entries.lock()?;
+        let before = entries.len();
+        entries.retain(|_, shared| shared.expires_at > now);
+        Ok(before - entries.len())
+    }
+
+    // Фоновая очистка на все время работы сервера
+    fn start_sweeper(shared: Arc<SharedAnalyses>) {
+        let period = shared.ttl.min(std::time::Duration::from_secs(SHARE_SWEEP_INTERVAL_SECS));
+        actix_web::rt::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match shared.sweep() {
+                    Ok(0) => {}
+                    Ok(removed) => tracing::debug!("Удалено истекших ссылок на анализы: {}", removed),
+                    Err(e) => tracing::error!("Ошибка очистки ссылок на анализы: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct ShareResponse {
+    token: String,
+    url: String,        // Полная ссылка на страницу анализа
+    expires_in: u64,    // Через сколько секунд ссылка перестанет работать
+}
+
+// Ссылка на анализ (поле analysis ответа чата) для службы поддержки
+async fn share_analysis(
+    http_req: HttpRequest,
+    analysis: web::Json<ErrorAnalysis>,
+    shared: web::Data<SharedAnalyses>,
+) -> Result<HttpResponse, AppError> {
+    let token = shared.share(analysis.into_inner())?;
+    let connection = http_req.connection_info();
+    Ok(HttpResponse::Created().json(ShareResponse {
+        url: format!("{}://{}/shared/{}", connection.scheme(), connection.host(), token),
+        token,
+        expires_in: shared.ttl.as_secs(),
+    }))
+}
+
+// Экранирование текста для вставки в HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn html_list(items: &[String]) -> String {
+    items.iter().map(|item| format!("<li>{}</li>", escape_html(item))).collect()
+}
+
+// Страница анализа по ссылке в оформлении чата. Неизвестная или истекшая ссылка - 404
+async fn shared_analysis_page(
+    token: web::Path<String>,
+    shared: web::Data<SharedAnalyses>,
+) -> Result<HttpResponse, AppError> {
+    let Some(analysis) = shared.get(&token)? else {
+        let page = SHARED_PAGE
+            .replace("{style}", CHAT_STYLE)
+            .replace("{content}", "<p>Ссылка не найдена или срок ее действия истек.</p>");
+        return Ok(HttpResponse::NotFound().content_type("text/html; charset=utf-8").body(page));
+    };
+
+    let content = format!(
+        r#"<div class="error-analysis">
+                <p><span class="error-type">Тип ошибки:</span> {}</p>
+                <p><span class="os-type">Операционная система:</span> {}</p>
+                <p><span class="confidence">Уверенность:</span> {:.1}%</p>
+                <p><strong>Описание:</strong> {}</p>
+                <h5>🔍 Возможные причины:</h5>
+                <ul>{}</ul>
+                <h5>🛠️ Рекомендуемые решения:</h5>
+                <ul>{}</ul>
+            </div>"#,
+        escape_html(&analysis.error_type),
+        escape_html(&analysis.os_type),
+        analysis.confidence * 100.0,
+        escape_html(&analysis.detailed_description),
+        html_list(&analysis.possible_causes),
+        html_list(&analysis.solutions),
+    );
+    let page = SHARED_PAGE.replace("{style}", CHAT_STYLE).replace("{content}", &content);
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page))
+}
+
+const SHARED_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Анализ ошибки ОС</title>
+    <meta charset="utf-8">
+    <style>
+{style}    </style>
+</head>
+<body>
+    <div class="container">
+        <div class="header">
+            <h1>📊 Анализ ошибки</h1>
+        </div>
+        <div class="message bot-message">
+            {content}
+        </div>
+    </div>
+</body>
+</html>
+"#;
+
+// Ключ кэша: SHA-256 изображения в том виде, в каком оно пришло (строка base64 имеет
+// приоритет, как и в request_to_tensor, порядок значений важен только для массива),
+// температуры и имени модели. Криптографический хэш нужен, чтобы подобранная коллизия
+// не отдала клиенту предсказание для чужого изображения; строки идут с длиной,
+// чтобы границы полей были однозначны
+fn prediction_cache_key(req: &PredictRequest, temperature: Option<f64>, model: Option<&str>) -> PredictionCacheKey {
+    let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+    match (&req.image_base64, &req.image) {
+        (Some(encoded), _) => {
+            digest.update(&[0]);
+            digest.update(&(encoded.len() as u64).to_le_bytes());
+            digest.update(encoded.as_bytes());
+        }
+        (None, Some(flat)) => {
+            digest.update(&[1, req.input_format.unwrap_or_default() as u8]);
+            digest.update(&(flat.len() as u64).to_le_bytes());
+            flat.iter().for_each(|value| digest.update(&value.to_le_bytes()));
+        }
+        (None, None) => digest.update(&[2]),
+    }
+    match temperature {
+        Some(temperature) => {
+            digest.update(&[1]);
+            digest.update(&temperature.to_le_bytes());
+        }
+        None => digest.update(&[0]),
+    }
+    match model {
+        Some(model) => {
+            digest.update(&[1]);
+            digest.update(&(model.len() as u64).to_le_bytes());
+            digest.update(model.as_bytes());
+        }
+        None => digest.update(&[0]),
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(digest.finish().as_ref());
+    key
+}
+
+// Ответ с заголовком X-Cache: hit, если результат взят из кэша, иначе miss
+fn prediction_response<T: Serialize>(prediction: &T, cache_hit: bool) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("x-cache", if cache_hit { "hit" } else { "miss" }))
+        .json(prediction)
+}
+
+// Веб-обработчик для предсказания
+async fn predict(
+    req: web::Json<PredictRequest>,
+    query: web::Query<InferenceQuery>,
+    model_data: web::Data<GenericModel>,
+    caches: web::Data<PredictionCaches>,
+    limits: web::Data<InferenceLimits>,
+) -> Result<HttpResponse, AppError> {
+    let temperature = query.temperature()?;
+    let key = prediction_cache_key(&req, temperature, None);
+    let (cached, generation) = caches.generic.get(key)?;
+    if let Some(prediction) = cached {
+        return Ok(prediction_response(&prediction, true));
+    }
+
+    let image = request_to_tensor(&req, 32)?;
+    let prediction = limits.run(move || run_predict(&model_data, &image, temperature)).await?;
+    caches.generic.insert(key, prediction.clone(), generation)?;
+    Ok(prediction_response(&prediction, false))
+}
+
+// Инференс базовой модели, общий для REST и gRPC.
+// Без явной температуры используется сохраненная в метаданных
+fn run_predict(model_data: &GenericModel, image: &Tensor, temperature: Option<f64>) -> Result<PredictResponse, AppError> {
+    let mut predictions = run_predict_batch(model_data, image, temperature)?;
+    Ok(predictions.swap_remove(0))
+}
+
+// Предсказания для батча [N, 3, 32, 32], по одному на изображение
+fn run_predict_batch(model_data: &GenericModel, images: &Tensor, temperature: Option<f64>) -> Result<Vec<PredictResponse>, AppError> {
+    let models = model_data.read()?;
+    let (model, vs, metadata) = &**models;
+    let temperature = temperature.unwrap_or(metadata.temperature);
+    let images = images.to_device(vs.device());
+    let (confidence, class) = catch_inference(|| tch::no_grad(|| {
+        softmax_with_temperature(&model.forward(&images), temperature).max_dim(-1, false)
+    }))?;
+
+    Ok(Vec::<i64>::from(&class)
+        .into_iter()
+        .zip(Vec::<f32>::from(&confidence))
+        .map(|(class, confidence)| PredictResponse { class, confidence })
+        .collect())
+}
+
+// Сколько изображений /predict-batch прогоняет через модель за один проход
+const PREDICT_BATCH_SLICE: usize = 16;
+
+// Объекты по одному на строку (NDJSON)
+fn ndjson_lines<T: Serialize>(items: &[T]) -> web::Bytes {
+    let mut buffer = Vec::new();
+    for item in items {
+        // Сериализация структур ответа в JSON не завершается ошибкой
+        serde_json::to_writer(&mut buffer, item).expect("сериализация ответа");
+        buffer.push(b'\n');
+    }
+    web::Bytes::from(buffer)
+}
+
+// Предсказания для нескольких изображений. По умолчанию ответ - поток NDJSON
+// (application/x-ndjson): строки с результатами отправляются по мере обработки
+// очередного среза, не дожидаясь всего батча. С Accept: application/json весь
+// ответ собирается в один JSON-массив. Все изображения декодируются до начала
+// ответа, так что некорректный вход дает обычный 400; ошибка модели посреди потока
+// приходит последней строкой в формате { "error", "code" }
+async fn predict_batch(
+    http_req: HttpRequest,
+    req: web::Json<PredictBatchRequest>,
+    query: web::Query<InferenceQuery>,
+    model_data: web::Data<GenericModel>,
+    limits: web::Data<InferenceLimits>,
+) -> Result<HttpResponse, AppError> {
+    let temperature = query.temperature()?;
+    if req.images.is_empty() {
+        return Err(AppError::BadInput("Поле images не должно быть пустым".to_string()));
+    }
+
+    let images = req
+        .images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| {
+            request_to_tensor(image, 32).map_err(|e| AppError::BadInput(format!("images[{}]: {}", index, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let slices: Vec<Tensor> = images.chunks(PREDICT_BATCH_SLICE).map(|chunk| Tensor::cat(chunk, 0)).collect();
+
+    let wants_json = http_req
+        .headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") && !accept.contains("application/x-ndjson"));
+    if wants_json {
+        // Таймаут на весь ответ, а не на каждый срез
+        let predictions = limits
+            .run(move || {
+                let mut predictions = Vec::new();
+                for slice in &slices {
+                    predictions.extend(run_predict_batch(&model_data, slice, temperature)?);
+                }
+                Ok(predictions)
+            })
+            .await?;
+        return Ok(HttpResponse::Ok().json(predictions));
+    }
+
+    // Поток ленивый: следующий срез считается, только когда предыдущий отправлен.
+    // Таймаут здесь на каждый срез: строки до него клиент уже получил
+    let stream = futures_util::stream::unfold((slices.into_iter(), false), move |(mut slices, failed)| {
+        let (model_data, limits) = (model_data.clone(), limits.clone());
+        async move {
+            if failed {
+                return None;
+            }
+            let slice = slices.next()?;
+            let (chunk, failed) = match limits.run(move || run_predict_batch(&model_data, &slice, temperature)).await {
+                Ok(predictions) => (ndjson_lines(&predictions), false),
+                Err(e) => {
+                    tracing::error!("Ошибка в потоке /predict-batch: {}", e);
+                    (ndjson_lines(&[e.body()]), true)
+                }
+            };
+            Some((Ok::<_, actix_web::Error>(chunk), (slices, failed)))
+        }
+    });
+
+    Ok(HttpResponse::Ok().content_type("application/x-ndjson").streaming(stream))
+}
+
+// Словарь меток модели для ошибок ОС
+#[derive(Serialize)]
+struct LabelsResponse {
+    error_types: Vec<String>,
+    os_types: Vec<String>,
+    error_descriptions: std::collections::BTreeMap<String, &'static str>,
+}
+
+// Число обучаемых значений в VarStore. У квантованной модели полносвязные слои
+// хранятся вне VarStore, поэтому учитываются только свертки
+fn parameter_count(vs: &nn::VarStore) -> usize {
+    vs.variables().values().map(Tensor::numel).sum()
+}
+
+// Размеры выходов голов модели ошибок ОС
+#[derive(Serialize)]
+struct ModelHeads {
+    error_type: i64,
+    os_type: usize,
+}
+
+// Описание загруженной модели ошибок ОС
+#[derive(Serialize)]
+struct ModelInfoResponse {
+    architecture: String,
+    input_shape: Vec<i64>,
+    parameters: usize,
+    heads: ModelHeads,
+    error_types: Vec<String>,
+    os_types: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantization: Option<String>,
+    temperature: f64,
+    device: String,
+    version: String, // Версия bashpic, которой обучена модель
+}
+
+// Модели ошибок ОС, которые можно выбрать параметром ?model=: основная (--os-error-model)
+// и модели из --models-dir под именами файлов без расширения. Основная хранится
+// тем же Arc, что и у чата и /admin/train, поэтому ее перезагрузка видна и здесь
+struct OsErrorModelRegistry {
+    primary: String,
+    models: HashMap<String, Arc<OsErrorModels>>,
+}
+
+impl OsErrorModelRegistry {
+    fn new(primary: String, primary_models: Arc<OsErrorModels>) -> Self {
+        OsErrorModelRegistry {
+            models: HashMap::from([(primary.clone(), primary_models)]),
+            primary,
+        }
+    }
+
+    // Имя модели ищется только среди загруженных, путь из запроса к файлам не ведет
+    fn get(&self, name: Option<&str>) -> Result<(&str, &Arc<OsErrorModels>), AppError> {
+        let name = name.unwrap_or(&self.primary);
+        match self.models.get_key_value(name) {
+            Some((name, models)) => Ok((name.as_str(), models)),
+            None => Err(AppError::NotFound(format!(
+                "Модель '{}' не найдена, доступны: {}",
+                name,
+                self.names().join(", ")
+            ))),
+        }
+    }
+
+    fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.models.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Загрузка всех .pt из каталога. Словарь меток берется из метаданных каждой модели,
+    // а без него - общий (--labels). Модель, которую не удалось загрузить, пропускается
+    fn load_dir(
+        &mut self,
+        dir: &str,
+        device: Device,
+        precision: Precision,
+        cpu_fallback: bool,
+        labels: &Labels,
+    ) -> std::io::Result<()> {
+        let (mut loaded, mut skipped) = (0, 0);
+        for path in sorted_dir_entries(dir)? {
+            if !path.is_file() || path.extension().is_none_or(|ext| ext != "pt") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string) else {
+                continue;
+            };
+            if self.models.contains_key(&name) {
+                eprintln!("Внимание: модель {} пропущена, имя '{}' уже занято", path.display(), name);
+                continue;
+            }
+
+            // Без метаданных неизвестны размер входа и словарь меток, поэтому такая модель
+            // пропускается (основная в этом случае загружается с размером входа по умолчанию)
+            let path = path.to_string_lossy();
+            let model_labels = match ModelMetadata::load(path.as_ref()) {
+                Ok(Some(metadata)) if !metadata.error_types.is_empty() => Labels::from(&metadata),
+                Ok(Some(_)) => labels.clone(),
+                Ok(None) => {
+                    eprintln!("Внимание: модель {} пропущена: нет файла метаданных {}", path, ModelMetadata::path_for(path.as_ref()).display());
+                    skipped += 1;
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Внимание: модель {} пропущена: некорректные метаданные: {}", path, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            match OsErrorModelBundle::load_on(&path, device, &model_labels) {
+                Ok(bundle) => {
+                    println!("Модель '{}' загружена из {}", name, path);
+                    let models = RwLock::new(InferenceModels(bundle.with_precision(precision).with_cpu_fallback(cpu_fallback)));
+                    self.models.insert(name, Arc::new(models));
+                    loaded += 1;
+                }
+                Err(e) => {
+                    eprintln!("Внимание: модель {} пропущена: {}", path, e);
+                    skipped += 1;
+                }
+            }
+        }
+        println!("Каталог моделей {}: загружено {}, пропущено {}", dir, loaded, skipped);
+        Ok(())
+    }
+}
+
+// Выбор модели ошибок ОС для /labels и /model-info
+#[derive(Deserialize)]
+struct ModelQuery {
+    model: Option<String>, // [по умолчанию: основная модель]
+}
+
+// Архитектура обслуживаемой модели ошибок ОС: помогает разобраться, какая модель
+// на самом деле загружена (например, на 10 классов или на другой набор из --labels)
+async fn model_info(
+    query: web::Query<ModelQuery>,
+    registry: web::Data<OsErrorModelRegistry>,
+) -> Result<HttpResponse, AppError> {
+    let (_, model_data) = registry.get(query.model.as_deref())?;
+    let models = model_data.read()?;
+    let metadata = &models.metadata;
+    Ok(HttpResponse::Ok().json(ModelInfoResponse {
+        architecture: metadata.architecture.clone(),
+        input_shape: metadata.input_shape.clone(),
+        parameters: parameter_count(&models.vs),
+        heads: ModelHeads {
+            error_type: metadata.num_classes,
+            os_type: metadata.os_types.len(),
+        },
+        error_types: metadata.error_types.clone(),
+        os_types: metadata.os_types.clone(),
+        quantization: metadata.quantization.clone(),
+        temperature: metadata.temperature,
+        device: format!("{:?}", models.device()),
+        version: metadata.version.clone(),
+    }))
+}
+
+// Список типов ошибок и ОС обслуживаемой модели для построения клиентских интерфейсов
+async fn labels(
+    query: web::Query<ModelQuery>,
+    registry: web::Data<OsErrorModelRegistry>,
+) -> Result<HttpResponse, AppError> {
+    let (_, model_data) = registry.get(query.model.as_deref())?;
+    let labels = Labels::from(&model_data.read()?.metadata);
+    Ok(HttpResponse::Ok().json(LabelsResponse {
+        error_descriptions: labels
+            .error_types
+            .iter()
+            .map(|error_type| (error_type.clone(), error_description(error_type)))
+            .collect(),
+        error_types: labels.error_types,
+        os_types: labels.os_types,
+    }))
+}
+
+// Веб-обработчик для предсказания ошибок ОС
+async fn predict_os_error(
+    req: web::Json<PredictRequest>,
+    query: web::Query<InferenceQuery>,
+    registry: web::Data<OsErrorModelRegistry>,
+    caches: web::Data<PredictionCaches>,
+    recent: web::Data<RecentPredictions>,
+    limits: web::Data<InferenceLimits>,
+) -> Result<HttpResponse, AppError> {
+    let temperature = query.temperature()?;
+    let (model_name, model_data) = registry.get(query.model.as_deref())?;
+    let key = prediction_cache_key(&req, temperature, Some(model_name));
+    let (cached, generation) = caches.os_error.get(key)?;
+    let cache_hit = cached.is_some();
+    let mut prediction = match cached {
+        Some(prediction) => prediction,
+        None => {
+            let size = model_data.read()?.input_size();
+            let image = request_to_tensor(&req, size)?;
+            let model_data = model_data.clone();
+            let prediction = limits.run(move || run_predict_os_error(&model_data, &image, temperature)).await?;
+            caches.os_error.insert(key, prediction.clone(), generation)?;
+            prediction
+        }
+    };
+
+    // Кэш хранит полный список решений, фильтр применяется к каждому ответу
+    if let Some(severity_max) = query.severity_max {
+        let (_, _, solutions) = get_detailed_error_info(&prediction.error_type, &prediction.os_type);
+        prediction.solutions = solution_texts(solutions, Some(severity_max));
+    }
+
+    recent.record("rest", &prediction.error_type, &prediction.os_type, prediction.confidence)?;
+    Ok(prediction_response(&prediction, cache_hit))
+}
+
+// Сколько секунд по умолчанию ждется ответ сервера, с которого /predict-url скачивает изображение
+const DEFAULT_FETCH_TIMEOUT_SECS: u64 = 10;
+
+// Загрузка изображений по URL для /predict-url. Сервер сам ходит по присланному адресу,
+// поэтому без ограничений через него можно достучаться до внутренних сервисов (SSRF):
+// разрешены только http и https, адреса частных сетей отклоняются, редиректы не выполняются,
+// а соединение идет на тот адрес, который прошел проверку
+struct ImageFetcher {
+    allow_hosts: Vec<String>, // Если не пусто, скачивать можно только с этих хостов, в том числе из частной сети
+    timeout: std::time::Duration,
+    max_bytes: usize,
+}
+
+// Локальные, частные, служебные и зарезервированные адреса, недоступные из интернета
+fn is_private_address(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && (second & 0xc0) == 64) // 100.64.0.0/10, NAT провайдера
+                || first >= 240
+        }
+        std::net::IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00 // fc00::/7, уникальные локальные
+                || (first & 0xffc0) == 0xfe80 // fe80::/10, локальные для канала
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_private_address(std::net::IpAddr::V4(ip)))
+        }
+    }
+}
+
+impl ImageFetcher {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, AppError> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| AppError::BadInput(format!("Некорректный URL {}: {}", url, e)))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(AppError::BadInput(format!("Схема {} не поддерживается, ожидается http или https", parsed.scheme())));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| AppError::BadInput(format!("В URL {} нет хоста", url)))?
+            .to_lowercase();
+        let listed = self.allow_hosts.contains(&host);
+        if !self.allow_hosts.is_empty() && !listed {
+            return Err(AppError::BadInput(format!("Хост {} не входит в --fetch-allow-host", host)));
+        }
+
+        // Адрес проверяется после разрешения имени: имя из интернета может указывать в частную сеть
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let literal = host.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>().ok();
+        let address = match literal {
+            Some(ip) => std::net::SocketAddr::new(ip, port),
+            None => {
+                let addresses: Vec<std::net::SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+                    .await
+                    .map_err(|e| AppError::FetchFailed(format!("не удалось разрешить имя {}: {}", host, e)))?
+                    .collect();
+                if let Some(private) = addresses.iter().find(|address| !listed && is_private_address(address.ip())) {
+                    return Err(AppError::BadInput(format!("Хост {} указывает на адрес частной сети {}", host, private.ip())));
+                }
+                *addresses
+                    .first()
+                    .ok_or_else(|| AppError::FetchFailed(format!("у имени {} нет адресов", host)))?
+            }
+        };
+        if !listed && is_private_address(address.ip()) {
+            return Err(AppError::BadInput(format!("Адрес {} находится в частной сети", address.ip())));
+        }
+
+        // Имя закрепляется за проверенным адресом, чтобы повторное разрешение не увело запрос в другое место
+        let mut client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(self.timeout);
+        if literal.is_none() {
+            client = client.resolve(&host, address);
+        }
+        let client = client.build().map_err(|e| AppError::Internal(format!("HTTP-клиент: {}", e)))?;
+
+        let describe = |e: reqwest::Error| {
+            if e.is_timeout() {
+                AppError::FetchFailed(format!("{} не ответил за {} с", host, self.timeout.as_secs()))
+            } else {
+                AppError::FetchFailed(format!("{}: {}", host, e))
+            }
+        };
+        let mut response = client.get(parsed.clone()).send().await.map_err(describe)?;
+        if response.status().is_redirection() {
+            return Err(AppError::FetchFailed(format!("{} ответил редиректом {}, редиректы не выполняются", host, response.status())));
+        }
+        if !response.status().is_success() {
+            return Err(AppError::FetchFailed(format!("{} ответил {}", host, response.status())));
+        }
+        if response.content_length().is_some_and(|length| length > self.max_bytes as u64) {
+            return Err(AppError::PayloadTooLarge(self.max_bytes));
+        }
+
+        // Content-Length может отсутствовать или не совпадать с телом, поэтому лимит проверяется и по мере чтения
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(describe)? {
+            if bytes.len() + chunk.len() > self.max_bytes {
+                return Err(AppError::PayloadTooLarge(self.max_bytes));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+}
+
+// Модель, которой анализируется скачанное изображение
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum PredictUrlKind {
+    #[default]
+    OsError, // Модель ошибок ОС, ответ как у /predict-os-error
+    Generic, // Базовая модель, ответ как у /predict
+}
+
+#[derive(Deserialize)]
+struct PredictUrlRequest {
+    url: String,
+    #[serde(default)]
+    kind: PredictUrlKind,
+}
+
+// Предсказание по изображению, которое сервер скачивает сам. Кэш предсказаний не используется:
+// по тому же URL со временем может лежать другое изображение
+async fn predict_url(
+    req: web::Json<PredictUrlRequest>,
+    query: web::Query<InferenceQuery>,
+    fetcher: web::Data<ImageFetcher>,
+    model_data: web::Data<GenericModel>,
+    registry: web::Data<OsErrorModelRegistry>,
+    recent: web::Data<RecentPredictions>,
+    limits: web::Data<InferenceLimits>,
+) -> Result<HttpResponse, AppError> {
+    let temperature = query.temperature()?;
+    let bytes = fetcher.fetch(&req.url).await?;
+    let img = detect_and_decode(&bytes).map_err(|e| AppError::BadInput(format!("{}: {}", req.url, e)))?;
+
+    match req.kind {
+        PredictUrlKind::Generic => {
+            let image = image_to_tensor(&img, 32);
+            let prediction = limits.run(move || run_predict(&model_data, &image, temperature)).await?;
+            Ok(HttpResponse::Ok().json(prediction))
+        }
+        PredictUrlKind::OsError => {
+            let (_, model_data) = registry.get(query.model.as_deref())?;
+            let image = image_to_tensor(&img, model_data.read()?.input_size());
+            let model_data = model_data.clone();
+            let mut prediction = limits.run(move || run_predict_os_error(&model_data, &image, temperature)).await?;
+            if let Some(severity_max) = query.severity_max {
+                let (_, _, solutions) = get_detailed_error_info(&prediction.error_type, &prediction.os_type);
+                prediction.solutions = solution_texts(solutions, Some(severity_max));
+            }
+            recent.record("rest", &prediction.error_type, &prediction.os_type, prediction.confidence)?;
+            Ok(HttpResponse::Ok().json(prediction))
+        }
+    }
+}
+
+// Инференс модели для ошибок ОС, общий для REST и gRPC.
+// Без явной температуры используется сохраненная в метаданных
+fn run_predict_os_error(
+    model_data: &OsErrorModels,
+    image: &Tensor,
+    temperature: Option<f64>,
+) -> Result<OsErrorPredictResponse, AppError> {
+    let models = model_data.read()?;
+    let temperature = temperature.unwrap_or(models.metadata.temperature);
+
+    let ((error_confidence, error_class), os_probs) = models.infer(image, |models, image| {
+        let (error_logits, os_logits) = models.forward(image);
+        // Предсказание типа ошибки
+        let error_probs = softmax_with_temperature(&error_logits, temperature);
+        // Предсказание типа ОС
+        let os_probs = softmax_with_temperature(&os_logits, temperature);
+        (error_probs.max_dim(-1, false), Vec::<f32>::from(&os_probs.get(0)))
+    })?;
+
+    let error_idx = i64::from(&error_class.get(0)) as usize;
+    let error_type = models.metadata.error_label(error_idx).to_string();
+
+    let (os_idx, os_confidence, os_corrected) = reconcile_os_type(&error_type, &os_probs, &models.metadata.os_types);
+    let os_type = models.metadata.os_label(os_idx).to_string();
+
+    let description = error_description(&error_type).to_string();
+
+    let (_, possible_causes, solutions) = get_detailed_error_info(&error_type, &os_type);
+    let solutions = solution_texts(solutions, None);
+
+    Ok(OsErrorPredictResponse {
+        error_type,
+        os_type,
+        confidence: f32::from(&error_confidence.get(0)),
+        os_confidence,
+        os_corrected,
+        description,
+        possible_causes,
+        solutions,
+    })
+}
+
+// Ожидание SIGINT (Ctrl+C) или SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                println!("Не удалось подписаться на SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Плавное завершение после сигнала: прекращается прием новых соединений, активные
+// запросы дорабатываются (actix ждет их не дольше shutdown_timeout), затем ожидается
+// окончание переобучения, чтобы модель и CSV с метриками были записаны целиком.
+// Сигнал передается параметром, чтобы логику можно было запустить без реального сигнала.
+async fn graceful_shutdown(
+    signal: impl std::future::Future<Output = ()>,
+    http_handle: actix_web::dev::ServerHandle,
+    grpc_stop: tokio::sync::oneshot::Sender<()>,
+    in_flight: web::Data<InFlightRequests>,
+    training_state: web::Data<TrainingState>,
+    timeout: std::time::Duration,
+) -> std::io::Result<()> {
+    signal.await;
+
+    let pending = in_flight.0.load(Ordering::SeqCst);
+    println!("Получен сигнал завершения, ожидание активных запросов: {}", pending);
+
+    let _ = grpc_stop.send(());
+    http_handle.stop(true).await;
+
+    let remaining = in_flight.0.load(Ordering::SeqCst);
+    println!("Дообработано запросов: {}, прервано: {}", pending.saturating_sub(remaining), remaining);
+
+    let deadline = std::time::Instant::now() + timeout;
+    if training_state.in_progress.load(Ordering::SeqCst) {
+        println!("Ожидание завершения переобучения модели...");
+    }
+    while training_state.in_progress.load(Ordering::SeqCst) {
+        if std::time::Instant::now() >= deadline {
+            println!("Переобучение не завершилось за {} с и будет прервано", timeout.as_secs());
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    println!("Сервер остановлен");
+    Ok(())
+}
+
+// Код gRPC, сгенерированный из proto/inference.proto
+mod inference_proto {
+    tonic::include_proto!("bashpic.inference");
+}
+
+// gRPC-сервис инференса: использует те же модели под RwLock, что и REST API,
+// и те же слоты и таймаут инференса
+struct GrpcInference {
+    model_data: Arc<GenericModel>,
+    os_error_model_data: Arc<OsErrorModels>,
+    limits: InferenceLimits,
+}
+
+// Декодирование присланного файла изображения; ошибка формата - BadInput (INVALID_ARGUMENT)
+fn decode_grpc_image(bytes: &[u8]) -> Result<image::DynamicImage, AppError> {
+    detect_and_decode(bytes).map_err(|e| AppError::BadInput(e.to_string()))
+}
+
+#[tonic::async_trait]
+impl inference_proto::inference_server::Inference for GrpcInference {
+    async fn predict(
+        &self,
+        request: tonic::Request<inference_proto::ImageRequest>,
+    ) -> Result<tonic::Response<inference_proto::PredictResponse>, tonic::Status> {
+        let img = decode_grpc_image(&request.get_ref().image)?;
+        let image = image_to_tensor(&img, SIMPLE_CNN_INPUT_SIZE);
+        let model_data = self.model_data.clone();
+        let prediction = self.limits.run(move || run_predict(&model_data, &image, None)).await?;
+
+        Ok(tonic::Response::new(inference_proto::PredictResponse {
+            class: prediction.class,
+            confidence: prediction.confidence,
+        }))
+    }
+
+    async fn predict_os_error(
+        &self,
+        request: tonic::Request<inference_proto::ImageRequest>,
+    ) -> Result<tonic::Response<inference_proto::OsErrorPredictResponse>, tonic::Status> {
+        let img = decode_grpc_image(&request.get_ref().image)?;
+        let size = self.os_error_model_data.read().map_err(AppError::from)?.input_size();
+        let image = image_to_tensor(&img, size);
+        let os_error_model_data = self.os_error_model_data.clone();
+        let prediction = self
+            .limits
+            .run(move || run_predict_os_error(&os_error_model_data, &image, None))
+            .await?;
+
+        Ok(tonic::Response::new(inference_proto::OsErrorPredictResponse {
+            error_type: prediction.error_type,
+            os_type: prediction.os_type,
+            confidence: prediction.confidence,
+            os_confidence: prediction.os_confidence,
+            os_corrected: prediction.os_corrected,
+            description: prediction.description,
+            possible_causes: prediction.possible_causes,
+            solutions: prediction.solutions,
+        }))
+    }
+}
+
+// Проверка API-ключа в метаданных x-api-key (аналог api_key_auth для gRPC).
+// tonic::Status появляется только здесь, на границе gRPC
+#[derive(Clone)]
+struct GrpcApiKeyCheck {
+    api_keys: Arc<ApiKeys>,
+}
+
+impl tonic::service::Interceptor for GrpcApiKeyCheck {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        let authorized = request
+            .metadata()
+            .get("x-api-key")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|key| self.api_keys.contains(key));
+
+        if self.api_keys.is_enabled() && !authorized {
+            return Err(tonic::Status::unauthenticated("Отсутствует или неверный API-ключ"));
+        }
+        Ok(request)
+    }
+}
+
+// Прогрев моделей: один холостой forward на нулевом входе, чтобы libtorch
+// выделил память и инициализировал ядра до первого пользовательского запроса
+fn warmup_models(model_data: &GenericModel, os_error_model_data: &OsErrorModels) -> Result<(), AppError> {
+    let started = std::time::Instant::now();
+
+    {
+        let models = model_data.read()?;
+        let (model, vs, _) = &**models;
+        let input = Tensor::zeros(&[1, 3, SIMPLE_CNN_INPUT_SIZE, SIMPLE_CNN_INPUT_SIZE], (Kind::Float, vs.device()));
+        let _logits = catch_inference(|| tch::no_grad(|| model.forward(&input)))?;
+    }
+    {
+        let models = os_error_model_data.read()?;
+        let size = models.input_size();
+        let input = Tensor::zeros(&[1, 3, size, size], (Kind::Float, models.device()));
+        let _logits = catch_inference(|| tch::no_grad(|| models.forward(&input)))?;
+    }
+
+    println!("Прогрев моделей завершен за {} мс", started.elapsed().as_millis());
+    Ok(())
+}
+
+// Путь, по которому обучение сохраняет модель для ошибок ОС
+const OS_ERROR_MODEL_PATH: &str = "os_error_model.pt";
+
+// Состояние фонового обучения, запускаемого через /admin/train
+struct TrainingState {
+    in_progress: AtomicBool,
+    last_result: Mutex<Option<Result<(), String>>>,
+    model_path: String, // Путь, по которому сохраняется и перезагружается модель
+}
+
+#[derive(Serialize)]
+struct TrainingStatusResponse {
+    in_progress: bool,
+    last_run_succeeded: Option<bool>,
+    last_error: Option<String>,
+}
+
+// Горячая перезагрузка обслуживаемой модели из файла.
+// Модель загружается целиком до взятия блокировки, чтобы не задерживать инференс.
+// Новая модель размещается на том же устройстве, с той же точностью, тем же словарем меток
+// и тем же переходом на CPU при нехватке памяти, что и текущая.
+fn reload_os_error_model(model_data: &OsErrorModels, path: &str) -> Result<(), String> {
+    let (device, precision, cpu_fallback, labels) = {
+        let models = model_data.read().map_err(|e| e.to_string())?;
+        (models.device(), models.precision, models.cpu_fallback, Labels::from(&models.metadata))
+    };
+    let bundle = OsErrorModelBundle::load_on(path, device, &labels)
+        .map_err(|e| e.to_string())?
+        .with_precision(precision)
+        .with_cpu_fallback(cpu_fallback);
+    let mut models = model_data.write().map_err(|e| e.to_string())?;
+    *models = InferenceModels(bundle);
+    Ok(())
+}
+
+// Запуск переобучения модели ошибок ОС в фоне
+async fn admin_train(
+    state: web::Data<TrainingState>,
+    model_data: web::Data<OsErrorModels>,
+    chat_server: web::Data<Addr<ChatServer>>,
+    caches: web::Data<PredictionCaches>,
+) -> HttpResponse {
+    if state.in_progress.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Обучение уже выполняется"
+        }));
+    }
+
+    let config = OsErrorTrainConfig {
+        progress: Some(chat_server.get_ref().clone().recipient()),
+        model_path: state.model_path.clone(),
+        ..Default::default()
+    };
+
+    actix_web::rt::spawn(async move {
+        println!("Запущено переобучение модели для ошибок ОС");
+
+        // Обучение выполняется в пуле блокирующих потоков, чтобы не занимать воркеры actix
+        let result = match web::block(move || train_os_error_model(&config).map_err(|e| e.to_string())).await {
+            Ok(result) => result,
+            Err(e) => Err(e.to_string()),
+        };
+        let result = result
+            .and_then(|_| reload_os_error_model(&model_data, &state.model_path))
+            .and_then(|_| caches.os_error.clear().map_err(|e| e.to_string()));
+
+        match &result {
+            Ok(_) => println!("Переобучение завершено, модель перезагружена"),
+            Err(e) => println!("Ошибка переобучения: {}", e),
+        }
+
+        match state.last_result.lock() {
+            Ok(mut last_result) => *last_result = Some(result),
+            Err(e) => tracing::error!("Результат переобучения не сохранен: {}", AppError::from(e)),
+        }
+        state.in_progress.store(false, Ordering::SeqCst);
+    });
+
+    HttpResponse::Accepted().json(serde_json::json!({
+        "status": "started"
+    }))
+}
+
+// Статус последнего запуска переобучения
+async fn admin_train_status(state: web::Data<TrainingState>) -> Result<HttpResponse, AppError> {
+    let last_result = state.last_result.lock().map_err(AppError::from)?;
+
+    Ok(HttpResponse::Ok().json(TrainingStatusResponse {
+        in_progress: state.in_progress.load(Ordering::SeqCst),
+        last_run_succeeded: last_result.as_ref().map(|result| result.is_ok()),
+        last_error: last_result.as_ref().and_then(|result| result.as_ref().err().cloned()),
+    }))
+}
+
+// Лимит тела /admin/upload-model: веса модели ошибок ОС не помещаются в --max-body-size
+const MAX_MODEL_UPLOAD_SIZE: usize = 512 * 1024 * 1024;
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Поля multipart/form-data по именам. Тело уже целиком в памяти под собственным
+// лимитом маршрута, поэтому достаточно разбора по разделителю без потоковой обработки
+fn parse_multipart_form(content_type: &str, body: &[u8]) -> Result<HashMap<String, Vec<u8>>, String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if params.next() != Some("multipart/form-data") {
+        return Err(format!("Ожидается multipart/form-data, получено '{}'", content_type));
+    }
+    let boundary = params
+        .find_map(|param| param.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or("В Content-Type не указан boundary")?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let start = find_subslice(body, &delimiter).ok_or("В теле нет ни одной части multipart")?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut fields = HashMap::new();
+    // После последней части разделитель заканчивается на "--"
+    while !rest.starts_with(b"--") {
+        let end = find_subslice(rest, &delimiter).ok_or("Тело multipart оборвано до закрывающего разделителя")?;
+        let part = rest[..end].strip_prefix(b"\r\n").unwrap_or(&rest[..end]);
+        rest = &rest[end + delimiter.len()..];
+
+        let header_end = find_subslice(part, b"\r\n\r\n").ok_or("Часть multipart без заголовков")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let content = &part[header_end + 4..];
+        let content = content.strip_suffix(b"\r\n").unwrap_or(content);
+
+        let name = headers
+            .lines()
+            .filter(|line| line.to_ascii_lowercase().starts_with("content-disposition:"))
+            .flat_map(|line| line.split(';'))
+            .find_map(|param| param.trim().strip_prefix("name="))
+            .map(|name| name.trim_matches('"').to_string())
+            .ok_or("Часть multipart без имени поля")?;
+        fields.insert(name, content.to_vec());
+    }
+    Ok(fields)
+}
+
+#[derive(Deserialize)]
+struct UploadModelQuery {
+    #[serde(default)]
+    reload: bool, // Сразу перезагрузить обслуживаемую модель
+}
+
+#[derive(Serialize)]
+struct UploadModelResponse {
+    model_path: String,
+    parameters: usize,
+    reloaded: bool,
+}
+
+// Проверка загруженных весов и замена файлов модели. Веса сначала пишутся рядом
+// с моделью (model.upload.pt) и загружаются в os_error_cnn: VarStore::load отклоняет
+// отсутствующие переменные и несовпадающие формы тензоров. Затем файлы
+// переименовываются поверх текущих - rename в пределах каталога атомарен
+fn store_uploaded_model(
+    model_path: &str,
+    weights: &[u8],
+    metadata: &ModelMetadata,
+    labels: &Labels,
+) -> Result<usize, AppError> {
+    let upload_path = Path::new(model_path).with_extension("upload.pt");
+    let upload_metadata_path = ModelMetadata::path_for(&upload_path);
+    let remove_upload = || {
+        let _ = std::fs::remove_file(&upload_path);
+        let _ = std::fs::remove_file(&upload_metadata_path);
+    };
+
+    let written = std::fs::write(&upload_path, weights)
+        .map_err(|e| e.to_string())
+        .and_then(|_| metadata.save(&upload_path).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        remove_upload();
+        return Err(AppError::Internal(format!("не удалось записать {}: {}", upload_path.display(), e)));
+    }
+
+    let parameters = match OsErrorModelBundle::load_on(&upload_path.to_string_lossy(), Device::Cpu, labels) {
+        Ok(bundle) => parameter_count(&bundle.vs),
+        Err(e) => {
+            remove_upload();
+            return Err(AppError::BadInput(format!("Веса не подходят к архитектуре os_error_cnn: {}", e)));
+        }
+    };
+
+    std::fs::rename(&upload_metadata_path, ModelMetadata::path_for(model_path))
+        .and_then(|_| std::fs::rename(&upload_path, model_path))
+        .map_err(|e| {
+            remove_upload();
+            AppError::Internal(format!("не удалось заменить {}: {}", model_path, e))
+        })?;
+
+    // Состояние оптимизатора относится к прежним весам, --resume с ним сломал бы обучение
+    let _ = std::fs::remove_file(ResumableAdam::path_for(model_path));
+    Ok(parameters)
+}
+
+// Загрузка новой модели ошибок ОС: части "model" (веса .pt) и "metadata" (JSON метаданных).
+// С ?reload=true модель сразу подменяет обслуживаемую
+async fn admin_upload_model(
+    req: HttpRequest,
+    body: web::Bytes,
+    query: web::Query<UploadModelQuery>,
+    state: web::Data<TrainingState>,
+    model_data: web::Data<OsErrorModels>,
+    caches: web::Data<PredictionCaches>,
+) -> Result<HttpResponse, AppError> {
+    let content_type = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mut fields = parse_multipart_form(content_type, &body).map_err(AppError::BadInput)?;
+    let weights = fields.remove("model").ok_or_else(|| AppError::BadInput("Требуется часть model с весами".to_string()))?;
+    let metadata = fields
+        .remove("metadata")
+        .ok_or_else(|| AppError::BadInput("Требуется часть metadata с JSON метаданных".to_string()))?;
+    let metadata: ModelMetadata = serde_json::from_slice(&metadata)
+        .map_err(|e| AppError::BadInput(format!("Некорректные метаданные: {}", e)))?;
+
+    // Архитектура сверяется с той, которую сервер собирает для текущего словаря меток
+    let labels = Labels::from(&model_data.read()?.metadata);
+    metadata
+        .check_compatible(&ModelMetadata::os_error_cnn_with_labels(metadata.input_size(), &labels))
+        .map_err(|e| AppError::BadInput(e.to_string()))?;
+
+    // Файл модели общий с /admin/train: пока идет обучение, загрузка запрещена
+    if state.in_progress.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Выполняется обучение, модель нельзя заменить"
+        })));
+    }
+
+    let model_path = state.model_path.clone();
+    let reload = query.reload;
+    let result = web::block(move || {
+        let parameters = store_uploaded_model(&model_path, &weights, &metadata, &labels)?;
+        if reload {
+            reload_os_error_model(&model_data, &model_path).map_err(AppError::Internal)?;
+            caches.os_error.clear()?;
+        }
+        Ok(parameters)
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))
+    .and_then(|result| result);
+    state.in_progress.store(false, Ordering::SeqCst);
+
+    let parameters = result?;
+    println!("Загружена новая модель ошибок ОС ({} параметров)", parameters);
+    Ok(HttpResponse::Ok().json(UploadModelResponse {
+        model_path: state.model_path.clone(),
+        parameters,
+        reloaded: reload,
+    }))
+}
+
+// Определение аргументов командной строки
+#[derive(Parser)]
+#[clap(name = "image-classifier")]
+#[clap(about = "Утилита для классификации изображений")]
+struct Cli {
+    /// JSON-файл со значениями опций по умолчанию
+    #[clap(long, global = true)]
+    config: Option<String>,
+    /// Число потоков libtorch для операций над тензорами (intra-op и inter-op) [по умолчанию: решает libtorch]
+    #[clap(long, global = true, value_parser = parse_torch_threads)]
+    torch_threads: Option<i32>,
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Запустить веб-сервер
+    Server {
+        /// Адрес для прослушивания [по умолчанию: 0.0.0.0]
+        #[clap(long, env = "BASHPIC_HOST")]
+        host: Option<String>,
+        /// Порт для прослушивания [по умолчанию: 5000]
+        #[clap(long, env = "BASHPIC_PORT")]
+        port: Option<u16>,
+        /// Порт gRPC-сервиса инференса [по умолчанию: 50051]
+        #[clap(long, env = "BASHPIC_GRPC_PORT")]
+        grpc_port: Option<u16>,
+        /// Путь к базовой модели [по умолчанию: model.pt]
+        #[clap(long, env = "BASHPIC_MODEL")]
+        model: Option<String>,
+        /// Путь к модели для ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(long, env = "BASHPIC_OS_ERROR_MODEL")]
+        os_error_model: Option<String>,
+        /// Файл с API-ключами (по одному на строку), дополняет переменную BASHPIC_API_KEYS
+        #[clap(long)]
+        api_keys: Option<String>,
+        /// Источник, которому разрешено вызывать REST API из браузера (можно указать несколько раз, * - любой)
+        #[clap(long = "cors-allow-origin", value_parser = parse_cors_origin)]
+        cors_allow_origins: Vec<String>,
+        /// Путь к PEM-файлу с цепочкой сертификатов для HTTPS
+        #[clap(long)]
+        tls_cert: Option<String>,
+        /// Путь к PEM-файлу с приватным ключом для HTTPS
+        #[clap(long)]
+        tls_key: Option<String>,
+        /// Не прогревать модели при запуске (быстрый старт при разработке)
+        #[clap(long)]
+        no_warmup: bool,
+        /// Сколько секунд ждать активные запросы и переобучение при завершении [по умолчанию: 30]
+        #[clap(long)]
+        shutdown_timeout: Option<u64>,
+        /// Устройство: cpu, cuda или cuda:N [по умолчанию: cpu]
+        #[clap(long, value_parser = parse_device)]
+        device: Option<Device>,
+        /// Завершаться с ошибкой, если CUDA недоступна, вместо перехода на CPU
+        #[clap(long)]
+        strict_device: bool,
+        /// Точность инференса модели ошибок ОС: fp32 или fp16 (только CUDA) [по умолчанию: fp32]
+        #[clap(long, value_parser = parse_precision)]
+        precision: Option<Precision>,
+        /// Не повторять на CPU запросы, которым не хватило памяти GPU, а возвращать ошибку
+        #[clap(long)]
+        no_cpu_fallback: bool,
+        /// JSON-файл со списками error_types и os_types для модели с другим набором классов [по умолчанию: встроенные метки]
+        #[clap(long)]
+        labels: Option<String>,
+        /// Сколько последних скриншотов помнить в сессии чата, 0 - не кэшировать [по умолчанию: 8]
+        #[clap(long)]
+        screenshot_cache_size: Option<usize>,
+        /// Расстояние Хэмминга между хэшами, при котором скриншоты считаются одинаковыми [по умолчанию: 5]
+        #[clap(long)]
+        screenshot_dedup_distance: Option<u32>,
+        /// Максимальный размер тела REST-запроса и сообщения чата в байтах [по умолчанию: 16777216]
+        #[clap(long, value_parser = parse_max_body_size)]
+        max_body_size: Option<usize>,
+        /// Сколько ответов на одинаковые запросы /predict и /predict-os-error кэшировать, 0 - не кэшировать [по умолчанию: 256]
+        #[clap(long)]
+        cache_size: Option<usize>,
+        /// Сколько последних предсказаний хранить для GET /recent, 0 - не хранить [по умолчанию: 100]
+        #[clap(long)]
+        recent_size: Option<usize>,
+        /// Как часто отправлять ping в WebSocket чата, секунд [по умолчанию: 5]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        ws_ping_interval: Option<u64>,
+        /// Через сколько секунд без ответа на ping закрывать сессию чата [по умолчанию: 15]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        ws_ping_timeout: Option<u64>,
+        /// Сколько сессий чата может быть открыто одновременно [по умолчанию: 1000]
+        #[clap(long, value_parser = parse_max_sessions)]
+        max_sessions: Option<usize>,
+        /// URL OpenAI-совместимого API completions для генеративных объяснений в чате
+        #[clap(long)]
+        llm_endpoint: Option<String>,
+        /// Имя модели в запросах к --llm-endpoint [по умолчанию: gpt-3.5-turbo-instruct]
+        #[clap(long)]
+        llm_model: Option<String>,
+        /// Сколько секунд ждать ответ языковой модели в одной попытке [по умолчанию: 30]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        llm_timeout: Option<u64>,
+        /// Сколько раз повторять неудачный запрос к языковой модели до шаблонного ответа [по умолчанию: 2]
+        #[clap(long)]
+        llm_retries: Option<u32>,
+        /// Число воркеров actix, обрабатывающих HTTP-запросы [по умолчанию: по одному на ядро]
+        #[clap(long, value_parser = parse_workers)]
+        workers: Option<usize>,
+        /// Каталог с дополнительными моделями ошибок ОС (*.pt с метаданными *.json), выбираемыми параметром ?model=<имя файла без .pt>
+        #[clap(long, alias = "model-dir")]
+        models_dir: Option<String>,
+        /// Анализы чата с уверенностью ниже порога (0..1) передаются на проверку в GET /escalations [по умолчанию: не передаются]
+        #[clap(long, value_parser = parse_escalation_threshold)]
+        escalation_threshold: Option<f32>,
+        /// Сколько секунд работает ссылка на анализ из POST /share [по умолчанию: 86400]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        share_ttl: Option<u64>,
+        /// Сколько секунд хранится разговор чата с токеном ?session= после обрыва соединения [по умолчанию: 300]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        session_ttl: Option<u64>,
+        /// Анимированные скриншоты в чате: first-frame - анализировать первый кадр с предупреждением, reject - отклонять [по умолчанию: first-frame]
+        #[clap(long, value_parser = parse_animated_images)]
+        animated_images: Option<AnimatedImages>,
+        /// Сколько секунд REST-запрос ждет инференса, прежде чем получить 503 [по умолчанию: 30]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        inference_timeout: Option<u64>,
+        /// Хост, с которого POST /predict-url может скачивать изображения (можно указать несколько раз) [по умолчанию: любой публичный]
+        #[clap(long = "fetch-allow-host")]
+        fetch_allow_hosts: Vec<String>,
+        /// Сколько секунд POST /predict-url ждет скачивания изображения [по умолчанию: 10]
+        #[clap(long, value_parser = parse_heartbeat_secs)]
+        fetch_timeout: Option<u64>,
+    },
+    /// Обучить модель
+    Train {
+        /// Куда сохранить модель [по умолчанию: model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Количество эпох [по умолчанию: 5]
+        #[clap(long)]
+        epochs: Option<i64>,
+        /// Скорость обучения [по умолчанию: 0.001]
+        #[clap(long)]
+        learning_rate: Option<f64>,
+        /// CSV-файл для записи метрик по эпохам
+        #[clap(long)]
+        metrics_csv: Option<String>,
+        /// Ограничить общую норму градиентов этим значением на каждом шаге
+        #[clap(long, value_parser = parse_grad_clip)]
+        grad_clip: Option<f64>,
+        /// Проверить данные и модель (загрузка, метки, формы выхода) и выйти без обучения
+        #[clap(long)]
+        dry_run: bool,
+        /// Зерно генератора случайных чисел для воспроизводимого обучения [по умолчанию: случайное]
+        #[clap(long)]
+        seed: Option<u64>,
+        /// Каналы первой свертки [по умолчанию: 32]
+        #[clap(long, value_parser = parse_layer_size)]
+        conv1_channels: Option<i64>,
+        /// Каналы второй свертки [по умолчанию: 64]
+        #[clap(long, value_parser = parse_layer_size)]
+        conv2_channels: Option<i64>,
+        /// Нейроны скрытого полносвязного слоя [по умолчанию: 128]
+        #[clap(long, value_parser = parse_layer_size)]
+        hidden: Option<i64>,
+    },
+    /// Предсказать класс изображения
+    Predict {
+        /// Путь к обученной модели [по умолчанию: model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Пути к нескольким моделям через запятую: их предсказания усредняются
+        #[clap(long, value_delimiter = ',', conflicts_with = "model")]
+        ensemble: Vec<String>,
+        /// Путь к изображению для предсказания
+        #[clap(short, long)]
+        image: String,
+        /// Температура softmax [по умолчанию: из метаданных модели или 1.0]
+        #[clap(long, value_parser = parse_temperature)]
+        temperature: Option<f64>,
+        /// Устройство: cpu, cuda или cuda:N [по умолчанию: cpu]
+        #[clap(long, value_parser = parse_device)]
+        device: Option<Device>,
+        /// Завершаться с ошибкой, если CUDA недоступна, вместо перехода на CPU
+        #[clap(long)]
+        strict_device: bool,
+        /// Вывести результат одним JSON-объектом в формате ответа REST API
+        #[clap(long)]
+        json: bool,
+    },
+    /// Предсказать ошибку операционной системы по скриншоту
+    PredictOsError {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Пути к нескольким моделям через запятую: их предсказания усредняются
+        #[clap(long, value_delimiter = ',', conflicts_with = "model")]
+        ensemble: Vec<String>,
+        /// Путь к скриншоту с ошибкой
+        #[clap(short, long, required_unless_present = "from_clipboard")]
+        screenshot: Option<String>,
+        /// Взять скриншот из буфера обмена вместо файла (сборка с --features clipboard)
+        #[clap(long, conflicts_with = "screenshot")]
+        from_clipboard: bool,
+        /// Температура softmax [по умолчанию: из метаданных модели или 1.0]
+        #[clap(long, value_parser = parse_temperature)]
+        temperature: Option<f64>,
+        /// Устройство: cpu, cuda или cuda:N [по умолчанию: cpu]
+        #[clap(long, value_parser = parse_device)]
+        device: Option<Device>,
+        /// Завершаться с ошибкой, если CUDA недоступна, вместо перехода на CPU
+        #[clap(long)]
+        strict_device: bool,
+        /// Точность инференса модели ошибок ОС: fp32 или fp16 (только CUDA) [по умолчанию: fp32]
+        #[clap(long, value_parser = parse_precision)]
+        precision: Option<Precision>,
+        /// JSON-файл со списками error_types и os_types для модели с другим набором классов [по умолчанию: встроенные метки]
+        #[clap(long)]
+        labels: Option<String>,
+        /// Вывести результат одним JSON-объектом в формате ответа REST API
+        #[clap(long)]
+        json: bool,
+        /// Усреднить предсказания по центральному и четырем угловым кропам (test-time augmentation)
+        #[clap(long)]
+        tta: bool,
+    },
+    /// Обучить модель для предсказания ошибок ОС
+    TrainOsError {
+        /// Куда сохранить модель [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Количество эпох [по умолчанию: 10]
+        #[clap(long)]
+        epochs: Option<i64>,
+        /// Скорость обучения [по умолчанию: 0.0001]
+        #[clap(long)]
+        learning_rate: Option<f64>,
+        /// Размер мини-батча [по умолчанию: 32]
+        #[clap(long)]
+        batch_size: Option<i64>,
+        /// Продолжить обучение сохраненной модели вместе с состоянием оптимизатора
+        #[clap(long)]
+        resume: bool,
+        /// Веса классов ошибок через запятую в порядке /labels [по умолчанию: обратная частота]
+        #[clap(long, value_delimiter = ',')]
+        class_weights: Vec<f64>,
+        /// Веса типов ОС через запятую [по умолчанию: обратная частота]
+        #[clap(long, value_delimiter = ',')]
+        os_class_weights: Vec<f64>,
+        /// Применять аугментацию (яркость, контраст, поворот, шум) к обучающим батчам
+        #[clap(long)]
+        augment: bool,
+        /// Сила аугментации [по умолчанию: 1.0]
+        #[clap(long)]
+        augment_strength: Option<f64>,
+        /// Включить mixup с параметром alpha распределения Beta(alpha, alpha), например 0.2
+        #[clap(long, value_parser = parse_mixup_alpha)]
+        mixup: Option<f64>,
+        /// CSV-файл для записи метрик по эпохам
+        #[clap(long)]
+        metrics_csv: Option<String>,
+        /// Сторона обучающих изображений в пикселях, не меньше 32 [по умолчанию: 128]
+        #[clap(long)]
+        input_size: Option<i64>,
+        /// Ограничить общую норму градиентов этим значением на каждом шаге
+        #[clap(long, value_parser = parse_grad_clip)]
+        grad_clip: Option<f64>,
+        /// Функция потерь обеих голов: ce (кросс-энтропия) или focal [по умолчанию: ce]
+        #[clap(long, value_parser = ["ce", "focal"])]
+        loss: Option<String>,
+        /// Показатель gamma для --loss focal [по умолчанию: 2.0]
+        #[clap(long, value_parser = parse_focal_gamma)]
+        focal_gamma: Option<f64>,
+        /// Сглаживание меток для --loss ce: верному классу 1 - eps, остальным eps / (K - 1) [по умолчанию: 0]
+        #[clap(long, value_parser = parse_label_smoothing)]
+        label_smoothing: Option<f64>,
+        /// Обучать на наборе, подготовленном командой preprocess, вместо случайных данных
+        #[clap(long)]
+        tensor_cache: Option<String>,
+        /// Отложить эту долю примеров для валидационной потери после каждой эпохи, например 0.2
+        #[clap(long, value_parser = parse_val_split)]
+        val_split: Option<f64>,
+        /// Остановить обучение, если валидационная потеря не улучшалась столько эпох подряд (нужен --val-split)
+        #[clap(long, value_parser = parse_early_stop_patience)]
+        early_stop_patience: Option<usize>,
+        /// Вес потери головы ОС в общей потере: ошибки + вес * ОС, 0 - не обучать голову ОС [по умолчанию: 0.5]
+        #[clap(long, value_parser = parse_os_loss_weight)]
+        os_loss_weight: Option<f64>,
+        /// Зерно генератора случайных чисел для воспроизводимого обучения [по умолчанию: случайное]
+        #[clap(long)]
+        seed: Option<u64>,
+        /// Проверить данные и модель (загрузка, метки, формы выхода) и выйти без обучения
+        #[clap(long)]
+        dry_run: bool,
+        /// Обучать, даже если у какого-то типа ошибки или ОС нет ни одного примера
+        #[clap(long)]
+        force: bool,
+        /// Нормализовать вход по среднему и отклонению каналов из файла команды stats
+        #[clap(long)]
+        normalize_from: Option<String>,
+        /// Сохранить только веса, без состояния оптимизатора: файл для развертывания втрое меньше
+        #[clap(long)]
+        weights_only: bool,
+        /// Заморозить общие сверточные слои и обучать только головы (для дообучения с --resume)
+        #[clap(long)]
+        freeze_backbone: bool,
+    },
+    /// Проанализировать все скриншоты в каталоге
+    PredictDir {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Каталог со скриншотами
+        #[clap(short, long)]
+        dir: String,
+        /// Сохранить результаты в CSV-файл
+        #[clap(long)]
+        csv: Option<String>,
+        /// Формат вывода: table (таблица и сводка), csv или json [по умолчанию: table]
+        #[clap(long, value_parser = parse_dir_output_format)]
+        format: Option<DirOutputFormat>,
+        /// JSON-файл со списками error_types и os_types для модели с другим набором классов [по умолчанию: встроенные метки]
+        #[clap(long)]
+        labels: Option<String>,
+    },
+    /// Проанализировать короткую запись экрана покадрово
+    PredictVideo {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Видеофайл (кадры извлекаются через ffmpeg) или каталог с уже извлеченными кадрами
+        #[clap(short, long)]
+        video: String,
+        /// Интервал между кадрами в секундах [по умолчанию: 1]
+        #[clap(long, value_parser = parse_frame_interval)]
+        interval: Option<f64>,
+        /// Вывести итог и покадровую шкалу в формате JSON
+        #[clap(long)]
+        json: bool,
+        /// JSON-файл со списками error_types и os_types для модели с другим набором классов [по умолчанию: встроенные метки]
+        #[clap(long)]
+        labels: Option<String>,
+    },
+    /// Оценить модель ошибок ОС на размеченном наборе скриншотов
+    Evaluate {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Каталог с набором вида <тип_ошибки>/<тип_ОС>/<скриншот>
+        #[clap(short, long)]
+        data_dir: String,
+        /// Вывести метрики в формате JSON
+        #[clap(long)]
+        json: bool,
+        /// Сохранить матрицу ошибок по типам ошибок как PNG
+        #[clap(long)]
+        confusion_out: Option<String>,
+    },
+    /// Измерить скорость инференса модели ошибок ОС на случайных данных
+    Benchmark {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Количество замеряемых прогонов [по умолчанию: 100]
+        #[clap(long)]
+        iterations: Option<usize>,
+        /// Размер батча [по умолчанию: 1]
+        #[clap(long)]
+        batch_size: Option<i64>,
+        /// Устройство: cpu, cuda или cuda:N [по умолчанию: cpu]
+        #[clap(long, value_parser = parse_device)]
+        device: Option<Device>,
+        /// Завершаться с ошибкой, если CUDA недоступна, вместо перехода на CPU
+        #[clap(long)]
+        strict_device: bool,
+        /// Точность инференса модели ошибок ОС: fp32 или fp16 (только CUDA) [по умолчанию: fp32]
+        #[clap(long, value_parser = parse_precision)]
+        precision: Option<Precision>,
+        /// JSON-файл со списками error_types и os_types для модели с другим набором классов [по умолчанию: встроенные метки]
+        #[clap(long)]
+        labels: Option<String>,
+        /// Вывести результаты в формате JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Сохранить тепловую карту Grad-CAM: какие области скриншота повлияли на тип ошибки
+    Explain {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Путь к скриншоту с ошибкой
+        #[clap(short, long)]
+        screenshot: String,
+        /// Путь для сохранения PNG с тепловой картой
+        #[clap(short, long)]
+        output: String,
+        /// JSON-файл со списками error_types и os_types для модели с другим набором классов [по умолчанию: встроенные метки]
+        #[clap(long)]
+        labels: Option<String>,
+    },
+    /// Квантовать полносвязные слои модели ошибок ОС в int8 для более быстрого инференса на CPU
+    Quantize {
+        /// Путь к исходной модели ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Путь для сохранения квантованной модели
+        #[clap(short, long)]
+        output: String,
+    },
+    /// Один раз декодировать и масштабировать размеченные скриншоты и сохранить тензоры для --tensor-cache
+    Preprocess {
+        /// Каталог с набором вида <тип_ошибки>/<тип_ОС>/<скриншот>
+        #[clap(short, long)]
+        data_dir: String,
+        /// Файл для тензоров изображений и меток
+        #[clap(short, long)]
+        out: String,
+        /// Сторона изображений в пикселях, должна совпадать с --input-size обучения [по умолчанию: 128]
+        #[clap(long)]
+        input_size: Option<i64>,
+    },
+    /// Сгенерировать синтетические скриншоты ошибок для начального обучающего набора
+    GenerateData {
+        /// Каталог для набора вида <тип_ошибки>/<тип_ОС>/<скриншот>
+        #[clap(short, long)]
+        out_dir: String,
+        /// Сколько скриншотов создать для каждого типа ошибки [по умолчанию: 50]
+        #[clap(long)]
+        per_class: Option<usize>,
+        /// Ширина скриншота в пикселях [по умолчанию: 640]
+        #[clap(long, value_parser = parse_synthetic_dimension)]
+        width: Option<u32>,
+        /// Высота скриншота в пикселях [по умолчанию: 480]
+        #[clap(long, value_parser = parse_synthetic_dimension)]
+        height: Option<u32>,
+        /// Зерно генератора случайных чисел для воспроизводимого набора
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// Подобрать температуру softmax на валидационном наборе и записать ее в метаданные модели
+    Calibrate {
+        /// Путь к модели для предсказания ошибок ОС [по умолчанию: os_error_model.pt]
+        #[clap(short, long)]
+        model: Option<String>,
+        /// Каталог с валидационным набором вида <тип_ошибки>/<тип_ОС>/<скриншот>
+        #[clap(short, long)]
+        data_dir: String,
+        /// Вывести результаты в формате JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Посчитать среднее и стандартное отклонение каналов RGB по набору изображений для нормализации
+    Stats {
+        /// Каталог с изображениями (подкаталоги обходятся рекурсивно)
+        #[clap(short, long)]
+        data_dir: String,
+        /// Сторона, к которой приводятся изображения, как при обучении [по умолчанию: 128]
+        #[clap(long)]
+        input_size: Option<i64>,
+        /// Куда записать статистику для train-os-error --normalize-from [по умолчанию: stats.json]
+        #[clap(short, long)]
+        out: Option<String>,
+    },
+    /// Найти тестовые изображения, почти совпадающие с обучающими
+    CheckLeakage {
+        /// Каталог обучающего набора (подкаталоги обходятся рекурсивно)
+        #[clap(long)]
+        train_dir: String,
+        /// Каталог тестового набора (подкаталоги обходятся рекурсивно)
+        #[clap(long)]
+        test_dir: String,
+        /// Максимальное расстояние Хэмминга между перцептивными хэшами копий [по умолчанию: 5]
+        #[clap(long)]
+        max_distance: Option<u32>,
+        /// Вывести отчет с найденными парами в формате JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Проверить окружение: версии, CUDA, потоки libtorch и пробный инференс обеих моделей
+    Doctor,
+}
+
+// Разбор --device: cpu, cuda (первая видеокарта) или cuda:N
+fn parse_device(value: &str) -> Result<Device, String> {
+    match value {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" => Ok(Device::Cuda(0)),
+        _ => value
+            .strip_prefix("cuda:")
+            .and_then(|index| index.parse::<usize>().ok())
+            .map(Device::Cuda)
+            .ok_or_else(|| format!("Неизвестное устройство {}: ожидается cpu, cuda или cuda:N", value)),
+    }
+}
+
+// Проверка запрошенного устройства до загрузки моделей: без нее libtorch паникует
+// с малопонятным сообщением при первом обращении к отсутствующей видеокарте.
+// Если CUDA недоступна, при strict возвращается ошибка, иначе - CPU с предупреждением
+fn resolve_device(requested: Option<Device>, strict: bool) -> Result<Device, String> {
+    let cuda_devices = if tch::Cuda::is_available() { Some(tch::Cuda::device_count()) } else { None };
+    resolve_device_with(requested, strict, cuda_devices)
+}
+
+// Выбор устройства по числу видеокарт (None - CUDA недоступна); отделен от опроса
+// libtorch, чтобы проверяться на машине с любым оборудованием
+fn resolve_device_with(requested: Option<Device>, strict: bool, cuda_devices: Option<i64>) -> Result<Device, String> {
+    let Some(Device::Cuda(index)) = requested else {
+        return Ok(Device::Cpu);
+    };
+
+    let problem = match cuda_devices {
+        None => "CUDA недоступна: libtorch собран без поддержки CUDA или драйвер видеокарты не найден".to_string(),
+        Some(count) if index as i64 >= count => {
+            format!("видеокарта cuda:{} не найдена, доступно устройств: {}", index, count)
+        }
+        Some(_) => return Ok(Device::Cuda(index)),
+    };
+
+    if strict {
+        Err(format!("{}. Укажите --device cpu или установите CUDA-версию libtorch", problem))
+    } else {
+        eprintln!("Внимание: {}, используется CPU", problem);
+        Ok(Device::Cpu)
+    }
+}
+
+fn parse_torch_threads(value: &str) -> Result<i32, String> {
+    match value.parse::<i32>() {
+        Ok(threads) if threads > 0 => Ok(threads),
+        _ => Err(format!("Ожидалось положительное число потоков, получено {}", value)),
+    }
+}
+
+fn parse_workers(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(workers) if workers > 0 => Ok(workers),
+        _ => Err(format!("Ожидалось положительное число воркеров, получено {}", value)),
+    }
+}
+
+fn parse_max_sessions(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(sessions) if sessions > 0 => Ok(sessions),
+        _ => Err(format!("Ожидалось положительное число сессий, получено {}", value)),
+    }
+}
+
+// Разбор --width и --height для generate-data: меньше 64 пикселей текст ошибки не помещается
+fn parse_synthetic_dimension(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(size) if (64..=4096).contains(&size) => Ok(size),
+        _ => Err(format!("Ожидался размер от 64 до 4096 пикселей, получено {}", value)),
+    }
+}
+
+// Потоки libtorch задаются до первой операции с тензорами: пул inter-op
+// создается при первом использовании, и позже libtorch его не меняет.
+// Вывод в stderr, чтобы не портить --json в stdout
+fn configure_torch_threads(threads: Option<i32>) {
+    if let Some(threads) = threads {
+        tch::set_num_threads(threads);
+        tch::set_num_interop_threads(threads);
+    }
+    eprintln!(
+        "Потоки libtorch: intra-op {}, inter-op {}",
+        tch::get_num_threads(),
+        tch::get_num_interop_threads()
+    );
+}
+
+// Точность инференса модели ошибок ОС
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Precision {
+    Fp32,
+    Fp16, // Половинная точность, только на CUDA
+}
+
+fn parse_precision(value: &str) -> Result<Precision, String> {
+    match value {
+        "fp32" => Ok(Precision::Fp32),
+        "fp16" => Ok(Precision::Fp16),
+        _ => Err(format!("Неизвестная точность {}: ожидается fp32 или fp16", value)),
+    }
+}
+
+// fp16 на CPU медленнее fp32 и поддерживается не всеми операциями, поэтому запрещено.
+// Проверяется после resolve_device, который мог перейти на CPU
+fn resolve_precision(requested: Option<Precision>, device: Device) -> Result<Precision, String> {
+    match requested.unwrap_or(Precision::Fp32) {
+        Precision::Fp16 if device == Device::Cpu => {
+            Err("--precision fp16 поддерживается только на CUDA, укажите --device cuda или --precision fp32".to_string())
+        }
+        precision => Ok(precision),
+    }
+}
+
+// Разбор --temperature: положительное конечное число
+fn parse_temperature(value: &str) -> Result<f64, String> {
+    let temperature = value.parse::<f64>().map_err(|e| e.to_string())?;
+    validate_temperature(temperature)
+}
+
+fn parse_escalation_threshold(value: &str) -> Result<f32, String> {
+    let threshold = value.parse::<f32>().map_err(|e| e.to_string())?;
+    validate_escalation_threshold(threshold)
+}
+
+fn validate_escalation_threshold(threshold: f32) -> Result<f32, String> {
+    if threshold > 0.0 && threshold <= 1.0 {
+        Ok(threshold)
+    } else {
+        Err(format!("Порог эскалации должен быть в диапазоне (0, 1], получено {}", threshold))
+    }
+}
+
+// Значения опций по умолчанию из файла --config (JSON).
+// Приоритет: флаг командной строки > переменная окружения (BASHPIC_HOST, BASHPIC_PORT,
+// BASHPIC_GRPC_PORT, BASHPIC_MODEL, BASHPIC_OS_ERROR_MODEL) > значение из файла > встроенное значение.
+// Отсутствующие в файле поля берутся из Config::default(), неизвестные поля - ошибка.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    host: String,
+    port: u16,
+    grpc_port: u16,
+    shutdown_timeout: u64,
+    model: String,
+    os_error_model: String,
+    epochs: i64,
+    learning_rate: f64,
+    os_error_epochs: i64,
+    os_error_learning_rate: f64,
+    os_error_batch_size: i64,
+    os_loss_weight: f64,
+    input_size: i64,
+    augment_strength: f64,
+    api_keys: Option<String>,
+    cors_allow_origins: Vec<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    screenshot_cache_size: usize,
+    screenshot_dedup_distance: u32,
+    labels: Option<String>,
+    max_body_size: usize,
+    cache_size: usize,
+    recent_size: usize,
+    ws_ping_interval: u64,
+    ws_ping_timeout: u64,
+    max_sessions: usize,
+    llm_endpoint: Option<String>,
+    llm_model: String,
+    llm_timeout: u64,
+    llm_retries: u32,
+    torch_threads: Option<i32>,
+    workers: Option<usize>,
+    models_dir: Option<String>,
+    escalation_threshold: Option<f32>,
+    share_ttl: u64,
+    session_ttl: u64,
+    animated_images: AnimatedImages,
+    inference_timeout: u64,
+    fetch_allow_hosts: Vec<String>,
+    fetch_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let train = TrainConfig::default();
+        let train_os_error = OsErrorTrainConfig::default();
+        let screenshot_cache = ScreenshotCacheConfig::default();
+        let heartbeat = ChatHeartbeat::default();
+
+        Config {
+            host: "0.0.0.0".to_string(),
+            port: 5000,
+            grpc_port: 50051,
+            shutdown_timeout: 30,
+            model: train.model_path,
+            os_error_model: train_os_error.model_path,
+            epochs: train.epochs,
+            learning_rate: train.learning_rate,
+            os_error_epochs: train_os_error.epochs,
+            os_error_learning_rate: train_os_error.learning_rate,
+            os_error_batch_size: train_os_error.batch_size,
+            os_loss_weight: train_os_error.os_loss_weight,
+            input_size: train_os_error.input_size,
+            augment_strength: train_os_error.augment_strength,
+            api_keys: None,
+            cors_allow_origins: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            screenshot_cache_size: screenshot_cache.capacity,
+            screenshot_dedup_distance: screenshot_cache.max_distance,
+            labels: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            cache_size: DEFAULT_PREDICTION_CACHE_SIZE,
+            recent_size: DEFAULT_RECENT_SIZE,
+            ws_ping_interval: heartbeat.interval.as_secs(),
+            ws_ping_timeout: heartbeat.timeout.as_secs(),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            llm_endpoint: None,
+            llm_model: DEFAULT_LLM_MODEL.to_string(),
+            llm_timeout: DEFAULT_LLM_TIMEOUT_SECS,
+            llm_retries: DEFAULT_LLM_RETRIES,
+            torch_threads: None,
+            workers: None,
+            models_dir: None,
+            escalation_threshold: None,
+            share_ttl: DEFAULT_SHARE_TTL_SECS,
+            session_ttl: DEFAULT_SESSION_TTL_SECS,
+            animated_images: AnimatedImages::FirstFrame,
+            inference_timeout: DEFAULT_INFERENCE_TIMEOUT_SECS,
+            fetch_allow_hosts: Vec::new(),
+            fetch_timeout: DEFAULT_FETCH_TIMEOUT_SECS,
+        }
+    }
+}
+
+impl Config {
+    // Без --config используются встроенные значения
+    fn load(path: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("Не удалось прочитать конфигурацию {}: {}", path, e))?;
+        let config = serde_json::from_str(&json)
+            .map_err(|e| format!("Некорректная конфигурация {}: {}", path, e))?;
+        Ok(config)
+    }
+}
+
+// Форматы скриншотов, которые умеет декодировать сервер
+const SUPPORTED_IMAGE_FORMATS: &str = "PNG, JPEG, WebP, BMP, TIFF, GIF";
+
+// Ошибка декодирования изображения с указанием распознанного формата
+#[derive(Debug)]
+struct ImageDecodeError {
+    format: Option<image::ImageFormat>,
+    source: image::ImageError,
+}
+
+impl std::fmt::Display for ImageDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.format {
+            Some(format) => write!(f, "Не удалось декодировать изображение формата {:?}: {}", format, self.source),
+            None => write!(f, "Не удалось распознать формат изображения: {}", self.source),
+        }
+    }
+}
+
+impl std::error::Error for ImageDecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// Что делать с анимированным скриншотом (GIF, APNG, анимированный WebP) в чате
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AnimatedImages {
+    FirstFrame, // Анализируется первый кадр, ответ предупреждает об этом
+    Reject,     // Скриншот отклоняется с просьбой загрузить статичный
+}
+
+fn parse_animated_images(value: &str) -> Result<AnimatedImages, String> {
+    match value {
+        "first-frame" => Ok(AnimatedImages::FirstFrame),
+        "reject" => Ok(AnimatedImages::Reject),
+        _ => Err(format!("Неизвестное значение {}: ожидается first-frame или reject", value)),
+    }
+}
+
+// Анимированный скриншот при --animated-images reject
+#[derive(Debug)]
+struct AnimatedImageError {
+    format: image::ImageFormat,
+}
+
+impl std::fmt::Display for AnimatedImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Изображение {:?} анимированное: по нему нельзя однозначно определить ошибку. Загрузите статичный скриншот",
+            self.format
+        )
+    }
+}
+
+impl std::error::Error for AnimatedImageError {}
+
+// Формат изображения, если в нем больше одного кадра. У GIF декодируется не больше двух кадров,
+// APNG и анимированный WebP распознаются по заголовку
+fn animated_format(bytes: &[u8]) -> Option<image::ImageFormat> {
+    use image::AnimationDecoder;
+
+    let format = image::guess_format(bytes).ok()?;
+    let cursor = std::io::Cursor::new(bytes);
+    let animated = match format {
+        image::ImageFormat::Gif => image::codecs::gif::GifDecoder::new(cursor)
+            .is_ok_and(|decoder| decoder.into_frames().take(2).count() > 1),
+        image::ImageFormat::Png => image::codecs::png::PngDecoder::new(cursor).is_ok_and(|decoder| decoder.is_apng()),
+        image::ImageFormat::WebP => image::codecs::webp::WebPDecoder::new(cursor).is_ok_and(|decoder| decoder.has_animation()),
+        _ => false,
+    };
+    animated.then_some(format)
+}
+
+// Определение формата по сигнатуре и декодирование изображения из памяти.
+// Прогрессивный JPEG декодируется целиком, а у анимации берется первый кадр
+fn detect_and_decode(bytes: &[u8]) -> Result<image::DynamicImage, ImageDecodeError> {
+    let format = image::guess_format(bytes).ok();
+    let decoded = match format {
+        Some(format) => image::load_from_memory_with_format(bytes, format),
+        None => image::load_from_memory(bytes),
+    };
+    decoded.map_err(|source| ImageDecodeError { format, source })
+}
+
+// Больше 8 бит на канал: 16-битные PNG и TIFF, изображения с каналами f32
+fn is_high_bit_depth(img: &image::DynamicImage) -> bool {
+    let color = img.color();
+    color.bits_per_pixel() > 8 * color.channel_count() as u16
+}
+
+// Приведение изображения к RGB8 независимо от исходного формата (оттенки серого, RGBA, 16 бит).
+// Пиксели с прозрачностью смешиваются с белым фоном.
+fn to_rgb8_on_white(img: &image::DynamicImage) -> image::RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+
+    let rgba = img.to_rgba8();
+    image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as f32 / 255.0;
+        let blend = |channel: u8| (channel as f32 * alpha + 255.0 * (1.0 - alpha)).round() as u8;
+        image::Rgb([blend(pixel[0]), blend(pixel[1]), blend(pixel[2])])
+    })
+}
+
+// То же для изображений больше 8 бит на канал: RGB16 без округления до 256 уровней
+fn to_rgb16_on_white(img: &image::DynamicImage) -> image::ImageBuffer<image::Rgb<u16>, Vec<u16>> {
+    if !img.color().has_alpha() {
+        return img.to_rgb16();
+    }
+
+    let rgba = img.to_rgba16();
+    image::ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as f32 / 65535.0;
+        let blend = |channel: u16| (channel as f32 * alpha + 65535.0 * (1.0 - alpha)).round() as u16;
+        image::Rgb([blend(pixel[0]), blend(pixel[1]), blend(pixel[2])])
+    })
+}
+
+// Увеличение изображения с сохранением пропорций, если меньшая сторона меньше min_side
+fn ensure_min_size(img: image::DynamicImage, min_side: u32) -> image::DynamicImage {
+    let (width, height) = (img.width(), img.height());
+    let shortest = width.min(height);
+    if shortest >= min_side {
+        return img;
+    }
+
+    let scale = min_side as f32 / shortest as f32;
+    let new_width = ((width as f32 * scale).ceil() as u32).max(min_side);
+    let new_height = ((height as f32 * scale).ceil() as u32).max(min_side);
+    img.resize_exact(new_width, new_height, image::imageops::FilterType::Lanczos3)
+}
+
+// Предобработка изображения во входной тензор модели [1, 3, H, W] на CPU.
+// Загрузчики и анализ скриншотов в чате получают ее извне, поэтому конвейер
+// можно подменить, например детерминированным
+trait Preprocessor {
+    fn to_tensor(&self, img: &image::DynamicImage) -> Tensor;
+}
+
+// RGB на белом фоне (см. to_rgb8_on_white), каналы в отдельных плоскостях.
+// 16-битные изображения нормализуются делением на 65535 и сохраняют полную точность
+struct StandardPreprocessor {
+    size: Option<i64>, // Сторона квадрата, к которому приводится изображение; None - исходное разрешение
+    normalize: bool,   // Значения 0..1 вместо 0..255
+}
+
+impl StandardPreprocessor {
+    // Квадрат size x size со значениями 0..1
+    fn square(size: i64) -> Self {
+        StandardPreprocessor { size: Some(size), normalize: true }
+    }
+
+    // Исходное разрешение; изображение меньше MIN_OS_INPUT_SIZE увеличивается с сохранением пропорций
+    fn native() -> Self {
+        StandardPreprocessor { size: None, normalize: true }
+    }
+}
+
+impl Preprocessor for StandardPreprocessor {
+    fn to_tensor(&self, img: &image::DynamicImage) -> Tensor {
+        let img = match self.size {
+            Some(size) => img.resize_exact(size as u32, size as u32, image::imageops::FilterType::Lanczos3),
+            None => ensure_min_size(img.clone(), MIN_OS_INPUT_SIZE as u32),
+        };
+        // Без нормализации значения остаются в шкале 0..255 при любой глубине цвета
+        let scale = |max: f32| if self.normalize { max } else { max / 255.0 };
+        let (width, height, flat): (u32, u32, Vec<f32>) = if is_high_bit_depth(&img) {
+            let img = to_rgb16_on_white(&img);
+            let scale = scale(65535.0);
+            (img.width(), img.height(), img.into_raw().into_iter().map(|value| value as f32 / scale).collect())
+        } else {
+            let img = to_rgb8_on_white(&img);
+            let scale = scale(255.0);
+            (img.width(), img.height(), img.into_raw().into_iter().map(|value| value as f32 / scale).collect())
+        };
+
+        // Пиксели идут подряд (H, W, C), модель ждет плоскости каналов (C, H, W)
+        Tensor::of_slice(&flat)
+            .view([1, height as i64, width as i64, 3])
+            .permute(&[0, 3, 1, 2])
+            .contiguous()
+    }
+}
+
+// Функция для загрузки и предобработки изображения
+fn load_image<P: AsRef<Path>>(path: P, preprocessor: &dyn Preprocessor) -> Result<Tensor, Box<dyn std::error::Error>> {
+    let img = image::open(path)?;
+    Ok(preprocessor.to_tensor(&img))
+}
+
+// Приведение изображения к квадрату size x size и тензору [1, 3, size, size] со значениями 0..1
+fn image_to_tensor(img: &image::DynamicImage, size: i64) -> Tensor {
+    StandardPreprocessor::square(size).to_tensor(img)
+}
+
+// Файл модели, указанный в командной строке, не существует. Вместо невнятной ошибки
+// libtorch пользователь видит модели .pt из того же каталога и ближайшую по написанию
+#[derive(Debug)]
+struct ModelNotFoundError {
+    path: String,
+    available: Vec<String>,  // Файлы .pt в каталоге модели, по имени
+    suggestion: Option<String>, // Ближайший по расстоянию Левенштейна, если он достаточно похож
+}
+
+impl std::fmt::Display for ModelNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Модель {} не найдена", self.path)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "; возможно, имелась в виду {}", suggestion)?;
+        }
+        if self.available.is_empty() {
+            write!(f, "; в каталоге нет файлов .pt")
+        } else {
+            write!(f, "; доступные модели: [{}]", self.available.join(", "))
+        }
+    }
+}
+
+impl std::error::Error for ModelNotFoundError {}
+
+// Расстояние Левенштейна между строками (по символам)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Проверка существования файла модели до vs.load. Подсказка предлагается, только
+// если отличается не больше трети символов имени, иначе это скорее другой файл
+fn check_model_path(model_path: &str) -> Result<(), ModelNotFoundError> {
+    let path = Path::new(model_path);
+    if path.exists() {
+        return Ok(());
+    }
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let available: Vec<String> = sorted_dir_entries(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|candidate| candidate.is_file() && candidate.extension().is_some_and(|ext| ext == "pt"))
+        .map(|candidate| candidate.display().to_string())
+        .collect();
+
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let suggestion = available
+        .iter()
+        .map(|candidate| {
+            let candidate_name = Path::new(candidate).file_name().unwrap_or_default().to_string_lossy();
+            (edit_distance(&file_name, &candidate_name), candidate)
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .filter(|(distance, _)| *distance <= file_name.chars().count().div_ceil(3))
+        .map(|(_, candidate)| candidate.clone());
+
+    Err(ModelNotFoundError {
+        path: model_path.to_string(),
+        available,
+        suggestion,
+    })
+}
+
+// Функция для предсказания с использованием утилиты командной строки
+// Несколько путей к моделям - ансамбль: softmax-выходы моделей усредняются
+// При json результат печатается одним объектом в формате ответа REST API
+fn predict_from_cli(
+    model_paths: &[String],
+    image_path: &str,
+    temperature: Option<f64>,
+    device: Device,
+    json: bool,
+    out: &mut impl std::io::Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let models = model_paths
+        .iter()
+        .map(|path| {
+            check_model_path(path)?;
+            load_generic_model(path, device)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if models.len() > 1 && !json {
+        writeln!(out, "Ансамбль из {} моделей", models.len())?;
+    }
+
+    let image_tensor = load_image(image_path, &StandardPreprocessor::square(SIMPLE_CNN_INPUT_SIZE))?.to_device(device);
+    let (confidence, class) = tch::no_grad(|| {
+        let probs = models.iter().map(|(model, _, metadata)| {
+            softmax_with_temperature(&model.forward(&image_tensor), temperature.unwrap_or(metadata.temperature))
+        });
+        average_probs(probs).max_dim(-1, false)
+    });
+
+    let prediction = PredictResponse {
+        class: i64::from(&class.get(0)),
+        confidence: f32::from(&confidence.get(0)),
+    };
+
+    if json {
+        writeln!(out, "{}", serde_json::to_string(&prediction)?)?;
+    } else {
+        writeln!(out, "Предсказанный класс: {}", prediction.class)?;
+        writeln!(out, "Уверенность: {:.2}%", prediction.confidence * 100.0)?;
+    }
+
+    Ok(())
+}
+
+// Загрузка базовой модели для обслуживания запросов. Размеры слоев берутся
+// из метаданных, без них - CnnConfig::default()
+fn load_generic_model(model_path: &str, device: Device) -> Result<LoadedGenericModel, Box<dyn std::error::Error>> {
+    let cnn = ModelMetadata::load(model_path)?.map(|metadata| metadata.cnn_config()).unwrap_or_default();
+    let metadata = verify_model_metadata(model_path, ModelMetadata::simple_cnn(10, cnn))?;
+
+    let mut vs = nn::VarStore::new(device);
+    let model = simple_cnn(&vs.root(), 10, &cnn);
+    vs.load(model_path)?;
+
+    Ok((Box::new(model), vs, metadata))
+}
+
+// Загрузка модели с обучением при неудаче: если файл не загружается, модель обучается
+// (train сохраняет ее по тому же пути) и загружается повторно. Ошибка повторной
+// загрузки возвращается вызывающему, чтобы не работать со случайными весами.
+// Если обучение не создало файл, это ошибка ввода-вывода, а не libtorch.
+fn ensure_model<T>(
+    model_path: &str,
+    load: impl Fn(&str) -> Result<T, Box<dyn std::error::Error>>,
+    train: impl FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<T, CliError> {
+    println!("Загрузка модели {}...", model_path);
+    match load(model_path) {
+        Ok(model) => {
+            println!("Модель {} загружена", model_path);
+            return Ok(model);
+        }
+        Err(e) => println!("Не удалось загрузить модель {}: {}", model_path, e),
+    }
+
+    println!("Обучение новой модели {}...", model_path);
+    train().map_err(|e| CliError::command(&format!("Не удалось обучить модель {}", model_path), e, CliError::Train))?;
+    if !Path::new(model_path).exists() {
+        return Err(CliError::Io(format!("Модель {} не найдена: обучение не создало файл", model_path)));
+    }
+
+    println!("Обучение завершено, повторная загрузка модели {}...", model_path);
+    let model = load(model_path).map_err(|e| {
+        CliError::command(&format!("Модель {} не загружается после обучения", model_path), e, CliError::Torch)
+    })?;
+    println!("Модель {} загружена", model_path);
+    Ok(model)
+}
+
+// Функция для загрузки изображения большего размера для анализа ошибок ОС
+fn load_screenshot<P: AsRef<Path>>(path: P, preprocessor: &dyn Preprocessor) -> Result<Tensor, Box<dyn std::error::Error>> {
+    let img = image::open(path)?;
+    Ok(preprocessor.to_tensor(&img))
+}
+
+// Содержимое каталога, отсортированное по имени для воспроизводимого порядка
+fn sorted_dir_entries<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<std::path::PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+// Загрузка размеченного набора скриншотов из каталога вида
+// data_dir/<тип_ошибки>/<тип_ОС>/<скриншот>. Неизвестные каталоги и файлы,
+// которые не удалось декодировать, пропускаются с предупреждением.
+fn load_os_error_dataset<P: AsRef<Path>>(
+    data_dir: P,
+    input_size: i64,
+) -> Result<(Tensor, Tensor, Tensor), Box<dyn std::error::Error>> {
+    let mut images = Vec::new();
+    let mut error_labels = Vec::new();
+    let mut os_labels = Vec::new();
+    let preprocessor = StandardPreprocessor::square(input_size);
+
+    for error_dir in sorted_dir_entries(&data_dir)?.into_iter().filter(|path| path.is_dir()) {
+        let error_name = error_dir.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let Some(error_idx) = OS_ERROR_TYPES.iter().position(|error_type| *error_type == error_name) else {
+            println!("Пропуск каталога {}: неизвестный тип ошибки", error_dir.display());
+            continue;
+        };
+
+        for os_dir in sorted_dir_entries(&error_dir)?.into_iter().filter(|path| path.is_dir()) {
+            let os_name = os_dir.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            let Some(os_idx) = OS_TYPES.iter().position(|os_type| *os_type == os_name) else {
+                println!("Пропуск каталога {}: неизвестный тип ОС", os_dir.display());
+                continue;
+            };
+
+            for path in sorted_dir_entries(&os_dir)?.into_iter().filter(|path| path.is_file()) {
+                match load_screenshot(&path, &preprocessor) {
+                    Ok(tensor) => {
+                        images.push(tensor);
+                        error_labels.push(error_idx as i64);
+                        os_labels.push(os_idx as i64);
+                    }
+                    Err(e) => println!("Пропуск файла {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+
+    if images.is_empty() {
+        return Err(format!("В каталоге {} не найдено размеченных скриншотов", data_dir.as_ref().display()).into());
+    }
+
+    Ok((Tensor::cat(&images, 0), Tensor::of_slice(&error_labels), Tensor::of_slice(&os_labels)))
+}
+
+// Цвет со случайным сдвигом каналов, чтобы скриншоты одного класса различались
+fn jitter_color(rng: &mut StdRng, [r, g, b]: [u8; 3]) -> image::Rgb<u8> {
+    let mut channel = |value: u8| (value as i32 + rng.gen_range(-12..=12)).clamp(0, 255) as u8;
+    image::Rgb([channel(r), channel(g), channel(b)])
+}
+
+// Строки сообщения об ошибке в стиле указанной ОС со случайными кодами и путями
+fn synthetic_error_lines(error_type: &str, os_type: &str, rng: &mut StdRng) -> Vec<String> {
+    let code = format!("0X{:08X}", rng.r#gen::<u32>());
+    let address = format!("0X{:012X}", rng.r#gen::<u64>() & 0xFFFF_FFFF_FFFF);
+    let pid = rng.gen_range(100..32768);
+    let file = ["CONFIG.INI", "DATA.DB", "SETTINGS.JSON", "REPORT.PDF"][rng.gen_range(0..4)];
+    let app = ["EXPLORER", "CHROME", "EDITOR", "GAME", "PYTHON"][rng.gen_range(0..5)];
+    let stop = ["CRITICAL_PROCESS_DIED", "IRQL_NOT_LESS_OR_EQUAL", "PAGE_FAULT_IN_NONPAGED_AREA", "SYSTEM_SERVICE_EXCEPTION"][rng.gen_range(0..4)];
+    match (error_type, os_type) {
+        ("blue_screen_of_death", _) => vec![
+            ":(".to_string(),
+            "YOUR PC RAN INTO A PROBLEM AND NEEDS TO RESTART.".to_string(),
+            format!("{}% COMPLETE", rng.gen_range(0..=100)),
+            format!("STOP CODE: {}", stop),
+            code,
+        ],
+        ("kernel_panic", "macos") => vec![
+            "YOUR COMPUTER RESTARTED BECAUSE OF A PROBLEM.".to_string(),
+            format!("PANIC(CPU {} CALLER {})", rng.gen_range(0..8), address),
+            "KERNEL TRAP AT".to_string(),
+            code,
+        ],
+        ("kernel_panic", _) => vec![
+            "KERNEL PANIC - NOT SYNCING: FATAL EXCEPTION".to_string(),
+            format!("CPU: {} PID: {} COMM: SWAPPER", rng.gen_range(0..8), pid),
+            format!("RIP: 0010:{}", address),
+            "---[ END KERNEL PANIC ]---".to_string(),
+        ],
+        ("application_crash", "linux") => vec![
+            format!("$ ./{}", app.to_lowercase()),
+            "SEGMENTATION FAULT (CORE DUMPED)".to_string(),
+        ],
+        ("application_crash", _) => vec![
+            format!("{} HAS STOPPED WORKING", app),
+            "A PROBLEM CAUSED THE PROGRAM TO STOP".to_string(),
+            "WORKING CORRECTLY.".to_string(),
+            format!("EXCEPTION CODE: {}", code),
+        ],
+        ("memory_error", "linux") => vec![
+            format!("OUT OF MEMORY: KILLED PROCESS {} ({})", pid, app.to_lowercase()),
+            format!("TOTAL-VM:{}KB", rng.gen_range(100000..9000000)),
+        ],
+        ("memory_error", _) => vec![
+            "OUT OF MEMORY".to_string(),
+            format!("THE INSTRUCTION AT {}", address),
+            "REFERENCED MEMORY. THE MEMORY COULD NOT BE READ.".to_string(),
+        ],
+        ("disk_error", "linux") => vec![
+            format!("I/O ERROR, DEV SDA, SECTOR {}", rng.gen_range(1000..99999999)),
+            "EXT4-FS ERROR: UNABLE TO READ INODE BLOCK".to_string(),
+            "BUFFER I/O ERROR ON DEVICE SDA1".to_string(),
+        ],
+        ("disk_error", _) => vec![
+            "DISK ERROR".to_string(),
+            "THE DISK IS NOT READABLE.".to_string(),
+            "A DISK READ ERROR OCCURRED".to_string(),
+            code,
+        ],
+        ("network_error", "linux") => vec![
+            format!("$ ping 10.0.{}.1", rng.gen_range(0..255)),
+            "CONNECT: NETWORK IS UNREACHABLE".to_string(),
+        ],
+        ("network_error", _) => vec![
+            "NO INTERNET CONNECTION".to_string(),
+            "THE SERVER CANNOT BE REACHED.".to_string(),
+            "ERR_CONNECTION_TIMED_OUT".to_string(),
+        ],
+        ("permission_denied", "linux") => vec![
+            format!("$ cat /etc/{}", file.to_lowercase()),
+            format!("CAT: /ETC/{}: PERMISSION DENIED", file),
+        ],
+        ("permission_denied", _) => vec![
+            "ACCESS DENIED".to_string(),
+            "YOU DON'T HAVE PERMISSION TO ACCESS".to_string(),
+            file.to_string(),
+        ],
+        ("file_not_found", "linux") => vec![
+            format!("$ cat {}", file.to_lowercase()),
+            format!("CAT: {}: NO SUCH FILE OR DIRECTORY", file),
+        ],
+        ("file_not_found", _) => vec![
+            "FILE NOT FOUND".to_string(),
+            format!("CANNOT FIND {}.", file),
+            "CHECK THE FILE NAME AND TRY AGAIN.".to_string(),
+        ],
+        ("system_overload", "linux") => vec![
+            format!("LOAD AVERAGE: {}.{:02}", rng.gen_range(20..99), rng.gen_range(0..100)),
+            format!("CPU: 100% MEM: {}%", rng.gen_range(90..=100)),
+            "TASK BLOCKED FOR MORE THAN 120 SECONDS.".to_string(),
+        ],
+        ("system_overload", _) => vec![
+            format!("{} IS NOT RESPONDING", app),
+            "THE SYSTEM IS LOW ON RESOURCES.".to_string(),
+            format!("CPU USAGE: 100% MEMORY: {}%", rng.gen_range(90..=100)),
+        ],
+        ("driver_error", "linux") => vec![
+            format!("NVIDIA: MODULE VERIFICATION FAILED: {}", code),
+            "MODPROBE: ERROR: COULD NOT INSERT DRIVER".to_string(),
+        ],
+        ("driver_error", _) => vec![
+            "DRIVER ERROR".to_string(),
+            "THE DEVICE DRIVER FAILED TO LOAD.".to_string(),
+            format!("ERROR CODE: {}", code),
+        ],
+        _ => vec![error_type.to_uppercase()],
+    }
+}
+
+// Синтетический скриншот ошибки: полноэкранные ошибки (BSOD, паника ядра) и терминал
+// Linux рисуются текстом на фоне, остальные - окном-диалогом на рабочем столе
+fn render_synthetic_screenshot(error_type: &str, os_type: &str, width: u32, height: u32, rng: &mut StdRng) -> image::RgbImage {
+    let lines = synthetic_error_lines(error_type, os_type, rng);
+    let scale = (width / 240).max(1) + rng.gen_range(0..=1);
+    let line_height = 8 * scale;
+
+    let (background, text_color, dialog) = match (error_type, os_type) {
+        ("blue_screen_of_death", _) => ([0, 120, 215], [255, 255, 255], false),
+        ("kernel_panic", _) => ([0, 0, 0], [200, 200, 200], false),
+        (_, "linux") => ([30, 30, 30], [170, 220, 170], false),
+        (_, "macos") => ([110, 140, 190], [40, 40, 40], true),
+        _ => ([0, 110, 130], [0, 0, 0], true),
+    };
+    let mut image = image::RgbImage::from_pixel(width, height, jitter_color(rng, background));
+    let text_color = jitter_color(rng, text_color);
+
+    let (mut x, mut y) = (rng.gen_range(width / 20..=width / 8), rng.gen_range(height / 20..=height / 6));
+    if dialog {
+        let dialog_width = width * rng.gen_range(60..=80) / 100;
+        let dialog_height = (line_height * (lines.len() as u32 + 3)).min(height);
+        let (dialog_x, dialog_y) = (rng.gen_range(0..=width - dialog_width), rng.gen_range(0..=height - dialog_height));
+        let title_bar = if os_type == "macos" { [220, 220, 220] } else { [0, 90, 200] };
+        fill_rect(&mut image, dialog_x, dialog_y, dialog_width, dialog_height, jitter_color(rng, [245, 245, 245]));
+        fill_rect(&mut image, dialog_x, dialog_y, dialog_width, line_height + scale * 2, jitter_color(rng, title_bar));
+        x = dialog_x + line_height;
+        y = dialog_y + line_height * 2;
+    }
+    for line in &lines {
+        draw_text(&mut image, x, y, line, scale, text_color);
+        y += line_height;
+    }
+
+    // Немного шума, чтобы модель не запоминала точные пиксели шрифта
+    for _ in 0..(width * height / 200) {
+        let (px, py) = (rng.gen_range(0..width), rng.gen_range(0..height));
+        let image::Rgb([r, g, b]) = *image.get_pixel(px, py);
+        image.put_pixel(px, py, jitter_color(rng, [r, g, b]));
+    }
+    image
+}
+
+// Генерация синтетического набора в формате load_os_error_dataset:
+// out_dir/<тип_ошибки>/<тип_ОС>/<номер>.png. ОС чередуются среди допустимых для
+// типа ошибки. Возвращает число созданных файлов
+fn generate_synthetic_dataset(
+    out_dir: &str,
+    per_class: usize,
+    width: u32,
+    height: u32,
+    seed: Option<u64>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut written = 0;
+    for error_type in OS_ERROR_TYPES {
+        let os_types = plausible_os_types(error_type).unwrap_or(&["windows", "linux", "macos"]);
+        for i in 0..per_class {
+            let os_type = os_types[i % os_types.len()];
+            let dir = Path::new(out_dir).join(error_type).join(os_type);
+            std::fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("{:05}.png", i));
+            render_synthetic_screenshot(error_type, os_type, width, height, &mut rng)
+                .save(&path)
+                .map_err(|e| format!("Не удалось сохранить {}: {}", path.display(), e))?;
+            written += 1;
+        }
+        println!("{}: {} скриншотов", error_type, per_class);
+    }
+    Ok(written)
+}
+
+// Имена тензоров в файле preprocess
+const TENSOR_CACHE_IMAGES: &str = "images";
+const TENSOR_CACHE_ERROR_LABELS: &str = "error_labels";
+const TENSOR_CACHE_OS_LABELS: &str = "os_labels";
+
+// Поканальная статистика набора, записываемая командой stats для --normalize-from
+#[derive(Serialize, Deserialize, Debug)]
+struct DatasetStats {
+    images: usize,   // Сколько изображений учтено
+    input_size: i64, // Сторона, к которой приводились изображения
+    #[serde(flatten)]
+    normalization: Normalization,
+}
+
+impl DatasetStats {
+    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("Не удалось прочитать {}: {}", path, e))?;
+        let stats: DatasetStats = serde_json::from_str(&json).map_err(|e| format!("Некорректная статистика {}: {}", path, e))?;
+        stats.normalization.validate()?;
+        Ok(stats)
+    }
+}
+
+// Накопление сумм и сумм квадратов по каналам: изображения не держатся в памяти все сразу.
+// Суммы в f64, так что точности хватает и на миллиарды пикселей со значениями 0..1
+#[derive(Default)]
+struct ChannelAccumulator {
+    pixels: u64, // Пикселей на канал
+    sum: [f64; 3],
+    sum_sq: [f64; 3],
+}
+
+impl ChannelAccumulator {
+    // Изображение [1, 3, H, W]
+    fn add(&mut self, image: &Tensor) {
+        let values = Vec::<f32>::from(&image.to_kind(Kind::Float).contiguous().view([-1]));
+        let plane = values.len() / 3;
+        for (channel, plane_values) in values.chunks(plane.max(1)).take(3).enumerate() {
+            for &value in plane_values {
+                self.sum[channel] += value as f64;
+                self.sum_sq[channel] += (value as f64).powi(2);
+            }
+        }
+        self.pixels += plane as u64;
+    }
+
+    fn finish(&self) -> Normalization {
+        let count = self.pixels.max(1) as f64;
+        let mean = self.sum.map(|sum| sum / count);
+        let mut std = [0.0; 3];
+        for channel in 0..3 {
+            // Дисперсия совокупности; отрицательный остаток округления обнуляется
+            std[channel] = (self.sum_sq[channel] / count - mean[channel].powi(2)).max(0.0).sqrt();
+        }
+        Normalization { mean, std }
+    }
+}
+
+// Изображения каталога и всех его подкаталогов по порядку имен
+fn collect_files_recursive(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+    for path in sorted_dir_entries(dir)? {
+        if path.is_dir() {
+            collect_files_recursive(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Среднее и стандартное отклонение каналов RGB по всем изображениям data_dir после
+// той же предобработки, что и при обучении. Файлы, которые не удалось декодировать, пропускаются
+fn compute_dataset_stats(data_dir: &str, input_size: i64) -> Result<DatasetStats, Box<dyn std::error::Error>> {
+    if input_size < MIN_OS_INPUT_SIZE {
+        return Err(format!("Размер входа {} меньше минимального {}", input_size, MIN_OS_INPUT_SIZE).into());
+    }
+    let mut files = Vec::new();
+    collect_files_recursive(Path::new(data_dir), &mut files)?;
+
+    let preprocessor = StandardPreprocessor::square(input_size);
+    let mut accumulator = ChannelAccumulator::default();
+    let mut images = 0;
+    for path in files {
+        match load_screenshot(&path, &preprocessor) {
+            Ok(tensor) => {
+                accumulator.add(&tensor);
+                images += 1;
+            }
+            Err(e) => println!("Пропуск файла {}: {}", path.display(), e),
+        }
+    }
+    if images == 0 {
+        return Err(format!("В {} нет изображений", data_dir).into());
+    }
+    Ok(DatasetStats { images, input_size, normalization: accumulator.finish() })
+}
+
+fn dataset_stats(data_dir: &str, input_size: i64, out: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let stats = compute_dataset_stats(data_dir, input_size)?;
+    let Normalization { mean, std } = stats.normalization;
+    println!("Изображений: {}, сторона {}", stats.images, stats.input_size);
+    for (channel, name) in ["R", "G", "B"].iter().enumerate() {
+        println!("{}: mean {:.4}, std {:.4}", name, mean[channel], std[channel]);
+    }
+    if std.contains(&0.0) {
+        eprintln!("Внимание: у одного из каналов нулевой разброс, нормализовать по такой статистике нельзя");
+    }
+    std::fs::write(out, serde_json::to_string_pretty(&stats)?)?;
+    println!("Статистика сохранена в {}", out);
+    println!("Для обучения с этой нормализацией: train-os-error --normalize-from {} --input-size {}", out, stats.input_size);
+    Ok(())
+}
+
+// Расстояние Хэмминга между average hash, до которого тестовое изображение считается
+// копией обучающего; то же значение, что у кэша скриншотов чата
+const DEFAULT_LEAKAGE_DISTANCE: u32 = 5;
+
+// Тестовое изображение, почти совпадающее с обучающим
+#[derive(Serialize)]
+struct LeakageCollision {
+    test: String,
+    train: String,
+    distance: u32,
+}
+
+// Итог проверки утечки
+#[derive(Serialize)]
+struct LeakageReport {
+    train_images: usize,
+    test_images: usize,
+    leaked_test_images: usize, // Тестовые изображения хотя бы с одной коллизией
+    collisions: Vec<LeakageCollision>,
+}
+
+// Перцептивные хэши всех изображений каталога (рекурсивно); нечитаемые файлы пропускаются
+fn hash_images_recursive(dir: &str) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    collect_files_recursive(Path::new(dir), &mut files)?;
+    let mut hashes = Vec::with_capacity(files.len());
+    for path in files {
+        match image::open(&path) {
+            Ok(img) => hashes.push((path.display().to_string(), average_hash(&img))),
+            Err(e) => eprintln!("Пропуск файла {}: {}", path.display(), e),
+        }
+    }
+    if hashes.is_empty() {
+        return Err(format!("В каталоге {} не найдено изображений", dir).into());
+    }
+    Ok(hashes)
+}
+
+// Поиск тестовых изображений, почти совпадающих с обучающими: каждая пара с расстоянием
+// Хэмминга между average hash не больше max_distance считается коллизией
+fn find_leakage(train_dir: &str, test_dir: &str, max_distance: u32) -> Result<LeakageReport, Box<dyn std::error::Error>> {
+    let train = hash_images_recursive(train_dir)?;
+    let test = hash_images_recursive(test_dir)?;
+
+    let mut collisions = Vec::new();
+    let mut leaked_test_images = 0;
+    for (test_path, test_hash) in &test {
+        let mut matches: Vec<LeakageCollision> = train
+            .iter()
+            .map(|(train_path, train_hash)| (train_path, (test_hash ^ train_hash).count_ones()))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .map(|(train_path, distance)| LeakageCollision { test: test_path.clone(), train: train_path.clone(), distance })
+            .collect();
+        if !matches.is_empty() {
+            leaked_test_images += 1;
+            matches.sort_by_key(|collision| collision.distance);
+            collisions.append(&mut matches);
+        }
+    }
+
+    Ok(LeakageReport { train_images: train.len(), test_images: test.len(), leaked_test_images, collisions })
+}
+
+fn check_leakage(train_dir: &str, test_dir: &str, max_distance: u32, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let report = find_leakage(train_dir, test_dir, max_distance)?;
+    // С --json stdout содержит только отчет
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    for collision in &report.collisions {
+        println!("{} ~ {} (расстояние {})", collision.test, collision.train, collision.distance);
+    }
+    println!(
+        "Обучающих изображений: {}, тестовых: {}, совпадают с обучающими: {} (пар: {}, порог расстояния {})",
+        report.train_images, report.test_images, report.leaked_test_images, report.collisions.len(), max_distance
+    );
+    if report.leaked_test_images > 0 {
+        eprintln!("Внимание: тестовые изображения попали в обучающий набор, метрики оценки будут завышены");
+    }
+    Ok(())
+}
+
+// Однократная подготовка набора: скриншоты декодируются и масштабируются до input_size,
+// тензоры изображений и меток сохраняются одним файлом для --tensor-cache
+fn preprocess_os_error_dataset(data_dir: &str, out: &str, input_size: i64) -> Result<(), Box<dyn std::error::Error>> {
+    if input_size < MIN_OS_INPUT_SIZE {
+        return Err(format!("Размер входа {} меньше минимального {}", input_size, MIN_OS_INPUT_SIZE).into());
+    }
+    let (images, error_labels, os_labels) = load_os_error_dataset(data_dir, input_size)?;
+    println!("Скриншотов: {}, форма тензора: {:?}", images.size()[0], images.size());
+    print_class_distribution("Распределение типов ошибок", OS_ERROR_TYPES, &error_labels);
+    print_class_distribution("Распределение типов ОС", OS_TYPES, &os_labels);
+    Tensor::save_multi(
+        &[
+            (TENSOR_CACHE_IMAGES, &images),
+            (TENSOR_CACHE_ERROR_LABELS, &error_labels),
+            (TENSOR_CACHE_OS_LABELS, &os_labels),
+        ],
+        out,
+    )?;
+    println!("Подготовленный набор сохранен в {}", out);
+    Ok(())
+}
+
+// Загрузка файла preprocess с проверкой, что он подготовлен для того же размера входа
+fn load_tensor_cache(path: &str, input_size: i64) -> Result<(Tensor, Tensor, Tensor), Box<dyn std::error::Error>> {
+    let mut tensors: HashMap<String, Tensor> = Tensor::load_multi(path)?.into_iter().collect();
+    let mut take = |name: &str| {
+        tensors.remove(name).ok_or_else(|| format!("В {} нет тензора {}: файл создан не командой preprocess", path, name))
+    };
+    let (images, error_labels, os_labels) = (take(TENSOR_CACHE_IMAGES)?, take(TENSOR_CACHE_ERROR_LABELS)?, take(TENSOR_CACHE_OS_LABELS)?);
+
+    let size = images.size();
+    if size.len() != 4 || size[1] != 3 || size[2] != input_size || size[3] != input_size {
+        return Err(format!(
+            "Форма изображений в {} {:?} не совпадает с входом модели [N, 3, {}, {}]: подготовьте набор с --input-size {}",
+            path, size, input_size, input_size, input_size
+        ).into());
+    }
+    let samples = size[0];
+    if error_labels.size() != [samples] || os_labels.size() != [samples] {
+        return Err(format!(
+            "Число меток в {} ({:?}, {:?}) не совпадает с числом изображений {}",
+            path, error_labels.size(), os_labels.size(), samples
+        ).into());
+    }
+    println!("Загружен подготовленный набор {}: {} примеров", path, samples);
+    Ok((images, error_labels, os_labels))
+}
+
+// Создание тестовых данных для ошибок ОС
+fn create_os_error_dummy_data(input_size: i64) -> (Tensor, Tensor, Tensor) {
+    let device = Device::Cpu;
+    let train_images = Tensor::randn(&[200, 3, input_size, input_size], (Kind::Float, device));
+    let error_labels = Tensor::randint(OS_ERROR_TYPES.len() as i64, &[200], (Kind::Int64, device));
+    let os_labels = Tensor::randint(OS_TYPES.len() as i64, &[200], (Kind::Int64, device));
+    (train_images, error_labels, os_labels)
+}
+
+// Adam с явным состоянием (номер шага и моменты по каждому параметру), которое можно
+// сохранить вместе с моделью: у оптимизаторов tch это состояние скрыто внутри libtorch,
+// и после загрузки весов моменты начинались бы с нуля, давая скачок потерь
+struct ResumableAdam {
+    learning_rate: f64,
+    beta1: f64,
+    beta2: f64,
+    eps: f64,
+    step: i64,
+    params: Vec<(String, Tensor)>, // Параметры, отсортированные по имени
+    exp_avg: Vec<Tensor>,          // Первый момент
+    exp_avg_sq: Vec<Tensor>,       // Второй момент
+}
+
+impl ResumableAdam {
+    // Гиперпараметры совпадают с nn::Adam::default()
+    fn new(vs: &nn::VarStore, learning_rate: f64) -> Self {
+        let mut params: Vec<(String, Tensor)> = vs.variables().into_iter().collect();
+        params.sort_by(|a, b| a.0.cmp(&b.0));
+        let exp_avg = params.iter().map(|(_, param)| param.zeros_like()).collect();
+        let exp_avg_sq = params.iter().map(|(_, param)| param.zeros_like()).collect();
+
+        ResumableAdam {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            step: 0,
+            params,
+            exp_avg,
+            exp_avg_sq,
+        }
+    }
+
+    fn zero_grad(&mut self) {
+        for (_, param) in self.params.iter_mut() {
+            param.zero_grad();
+        }
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.params.iter().map(|(_, param)| param.shallow_clone()).collect()
+    }
+
+    // Шаг по уже посчитанным градиентам
+    fn step(&mut self) {
+        self.step += 1;
+        let (beta1, beta2, eps, learning_rate) = (self.beta1, self.beta2, self.eps, self.learning_rate);
+        let bias_correction1 = 1.0 - beta1.powi(self.step as i32);
+        let bias_correction2 = 1.0 - beta2.powi(self.step as i32);
+
+        tch::no_grad(|| {
+            let state = self.params.iter_mut().zip(self.exp_avg.iter_mut()).zip(self.exp_avg_sq.iter_mut());
+            for (((_, param), exp_avg), exp_avg_sq) in state {
+                let grad = param.grad();
+                // Замороженный параметр не обновляется, даже если моменты остались от прошлого обучения
+                if !grad.defined() || !param.requires_grad() {
+                    continue;
+                }
+
+                exp_avg.copy_(&(&*exp_avg * beta1 + &grad * (1.0 - beta1)));
+                exp_avg_sq.copy_(&(&*exp_avg_sq * beta2 + &grad * &grad * (1.0 - beta2)));
+
+                let denominator = (&*exp_avg_sq / bias_correction2).sqrt() + eps;
+                let update = &*exp_avg / bias_correction1 / denominator * learning_rate;
+                let updated = &*param - update;
+                param.copy_(&updated);
+            }
+        });
+    }
+
+    // Файл состояния оптимизатора рядом с моделью (model.pt -> model.optim)
+    fn path_for<P: AsRef<Path>>(model_path: P) -> std::path::PathBuf {
+        model_path.as_ref().with_extension("optim")
+    }
+
+    fn save<P: AsRef<Path>>(&self, model_path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let step = Tensor::of_slice(&[self.step]);
+        let mut named: Vec<(String, &Tensor)> = vec![("step".to_string(), &step)];
+        for (((name, _), exp_avg), exp_avg_sq) in self.params.iter().zip(&self.exp_avg).zip(&self.exp_avg_sq) {
+            named.push((format!("exp_avg.{}", name), exp_avg));
+            named.push((format!("exp_avg_sq.{}", name), exp_avg_sq));
+        }
+
+        Tensor::save_multi(&named, Self::path_for(model_path))?;
+        Ok(())
+    }
+
+    fn load<P: AsRef<Path>>(&mut self, model_path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Self::path_for(model_path);
+        let mut saved: std::collections::HashMap<String, Tensor> = Tensor::load_multi(&path)?.into_iter().collect();
+        let mut take = |name: String| {
+            saved.remove(&name)
+                .ok_or_else(|| format!("В состоянии оптимизатора {} нет тензора {}", path.display(), name))
+        };
+
+        let step = take("step".to_string())?;
+        for (((name, param), exp_avg), exp_avg_sq) in self.params.iter().zip(self.exp_avg.iter_mut()).zip(self.exp_avg_sq.iter_mut()) {
+            for (prefix, target) in [("exp_avg", exp_avg), ("exp_avg_sq", exp_avg_sq)] {
+                let value = take(format!("{}.{}", prefix, name))?;
+                if value.size() != param.size() {
+                    return Err(format!(
+                        "Размер {}.{} в состоянии оптимизатора {:?} не совпадает с параметром {:?}",
+                        prefix, name, value.size(), param.size()
+                    ).into());
+                }
+                target.copy_(&value);
+            }
+        }
+        self.step = i64::from(&step.get(0));
+
+        Ok(())
+    }
+}
+
+// Веса классов, обратно пропорциональные их частоте: w_c = N / (C * n_c).
+// Классы, которых нет в данных, получают вес 0 - на потерю они все равно не влияют
+fn inverse_frequency_weights(labels: &Tensor, num_classes: usize) -> Vec<f64> {
+    let counts = class_counts(labels, num_classes);
+    let total = counts.iter().sum::<usize>() as f64;
+    counts
+        .iter()
+        .map(|&count| if count == 0 { 0.0 } else { total / (num_classes as f64 * count as f64) })
+        .collect()
+}
+
+// Веса, заданные пользователем, или обратная частота по меткам
+fn resolve_class_weights(
+    supplied: Option<&[f64]>,
+    labels: &Tensor,
+    names: &[&str],
+) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
+    match supplied {
+        Some(weights) if weights.len() != names.len() => Err(format!(
+            "Ожидается {} весов классов, получено {}", names.len(), weights.len()
+        ).into()),
+        Some(weights) if weights.iter().any(|weight| !weight.is_finite() || *weight < 0.0) => {
+            Err("Веса классов должны быть неотрицательными числами".into())
+        }
+        Some(weights) => Ok(weights.to_vec()),
+        None => Ok(inverse_frequency_weights(labels, names.len())),
+    }
+}
+
+fn print_class_weights(title: &str, names: &[&str], weights: &[f64]) {
+    let formatted: Vec<String> = names
+        .iter()
+        .zip(weights)
+        .map(|(name, weight)| format!("{}={:.3}", name, weight))
+        .collect();
+    println!("{}: {}", title, formatted.join(", "));
+}
+
+// Кросс-энтропия с весами классов
+fn weighted_cross_entropy(logits: &Tensor, targets: &Tensor, weights: &Tensor) -> Tensor {
+    logits
+        .log_softmax(-1, Kind::Float)
+        .g_nll_loss(targets, Some(weights), tch::Reduction::Mean, -100)
+}
+
+// Focal loss: -alpha_t * (1 - p_t)^gamma * log(p_t), где p_t - вероятность верного класса.
+// Множитель (1 - p_t)^gamma уменьшает вклад уверенно распознанных примеров, и обучение
+// сосредоточено на трудных. alpha - веса классов; нормировка на сумму весов та же, что
+// у weighted_cross_entropy, поэтому при gamma = 0 потери совпадают
+fn focal_loss(logits: &Tensor, targets: &Tensor, gamma: f64, alpha: &Tensor) -> Tensor {
+    let log_pt = logits
+        .log_softmax(-1, Kind::Float)
+        .gather(1, &targets.unsqueeze(1), false)
+        .squeeze_dim(1);
+    let modulating = (-log_pt.exp() + 1.0).pow(gamma);
+    let alpha_t = alpha.index_select(0, targets);
+    -(modulating * &log_pt * &alpha_t).sum(Kind::Float) / alpha_t.sum(Kind::Float)
+}
+
+// Кросс-энтропия со сглаживанием меток: вместо one-hot целевое распределение отдает
+// верному классу 1 - eps, а остальным по eps / (K - 1). Модель перестает выжимать
+// вероятность верного класса до единицы. Взвешивание и нормировка как у
+// weighted_cross_entropy, поэтому при eps = 0 потери совпадают
+fn smoothed_cross_entropy(logits: &Tensor, targets: &Tensor, eps: f64, weights: &Tensor) -> Tensor {
+    let log_probs = logits.log_softmax(-1, Kind::Float);
+    let num_classes = log_probs.size()[1];
+    let off_target = eps / (num_classes - 1).max(1) as f64;
+    let soft_targets = targets.onehot(num_classes).to_device(logits.device()) * (1.0 - eps - off_target) + off_target;
+
+    let per_sample = -(soft_targets * &log_probs).sum_dim_intlist(&[-1], false, Kind::Float);
+    let weight_t = weights.index_select(0, targets);
+    (per_sample * &weight_t).sum(Kind::Float) / weight_t.sum(Kind::Float)
+}
+
+// Показатель gamma focal loss по умолчанию
+const DEFAULT_FOCAL_GAMMA: f64 = 2.0;
+
+// Функция потерь обеих голов модели ошибок ОС
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OsErrorLoss {
+    CrossEntropy,
+    SmoothedCrossEntropy { eps: f64 },
+    Focal { gamma: f64 },
+}
+
+impl OsErrorLoss {
+    fn compute(&self, logits: &Tensor, targets: &Tensor, weights: &Tensor) -> Tensor {
+        match self {
+            OsErrorLoss::CrossEntropy => weighted_cross_entropy(logits, targets, weights),
+            OsErrorLoss::SmoothedCrossEntropy { eps } => smoothed_cross_entropy(logits, targets, *eps, weights),
+            OsErrorLoss::Focal { gamma } => focal_loss(logits, targets, *gamma, weights),
+        }
+    }
+
+    // Потери на батче после mixup: lam * loss(targets) + (1 - lam) * loss(targets[permutation])
+    fn compute_mixed(&self, logits: &Tensor, targets: &Tensor, mixing: &Mixup, weights: &Tensor) -> Tensor {
+        let mixed_targets = targets.index_select(0, &mixing.permutation);
+        self.compute(logits, targets, weights) * mixing.lam
+            + self.compute(logits, &mixed_targets, weights) * (1.0 - mixing.lam)
+    }
+}
+
+impl std::fmt::Display for OsErrorLoss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OsErrorLoss::CrossEntropy => write!(f, "кросс-энтропия"),
+            OsErrorLoss::SmoothedCrossEntropy { eps } => write!(f, "кросс-энтропия со сглаживанием меток (eps = {})", eps),
+            OsErrorLoss::Focal { gamma } => write!(f, "focal loss (gamma = {})", gamma),
+        }
+    }
+}
+
+fn parse_focal_gamma(value: &str) -> Result<f64, String> {
+    let gamma = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if gamma.is_finite() && gamma >= 0.0 {
+        Ok(gamma)
+    } else {
+        Err(format!("gamma должна быть неотрицательным числом, получено {}", gamma))
+    }
+}
+
+fn parse_os_loss_weight(value: &str) -> Result<f64, String> {
+    let weight = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if weight.is_finite() && weight >= 0.0 {
+        Ok(weight)
+    } else {
+        Err(format!("Вес потери ОС должен быть неотрицательным числом, получено {}", weight))
+    }
+}
+
+fn parse_label_smoothing(value: &str) -> Result<f64, String> {
+    let eps = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if (0.0..1.0).contains(&eps) {
+        Ok(eps)
+    } else {
+        Err(format!("Сглаживание меток должно быть в диапазоне [0, 1), получено {}", eps))
+    }
+}
+
+// Вес потери головы ОС по умолчанию: тип ошибки важнее типа ОС
+const DEFAULT_OS_LOSS_WEIGHT: f64 = 0.5;
+
+// Параметры обучения модели для ошибок ОС
+struct OsErrorTrainConfig {
+    augment: bool,          // Включить аугментацию обучающих батчей
+    augment_strength: f64,  // Множитель интенсивности аугментации (0 - без изменений)
+    metrics_csv: Option<String>, // CSV-файл для поэпоховых метрик
+    progress: Option<actix::Recipient<TrainingProgress>>, // Получатель прогресса (сервер чата)
+    input_size: i64,        // Сторона обучающих изображений
+    epochs: i64,
+    learning_rate: f64,
+    batch_size: i64,        // Размер мини-батча
+    model_path: String,     // Куда сохранить обученную модель
+    resume: bool,           // Продолжить обучение модели из model_path с сохраненным состоянием оптимизатора
+    class_weights: Option<Vec<f64>>,    // Веса классов ошибок (по умолчанию - обратная частота)
+    os_class_weights: Option<Vec<f64>>, // Веса типов ОС (по умолчанию - обратная частота)
+    grad_clip: Option<f64>,             // Максимальная общая норма градиентов
+    loss: OsErrorLoss,                  // Функция потерь обеих голов
+    os_loss_weight: f64,                // Вес потери головы ОС относительно головы ошибок
+    mixup: Option<f64>,                 // Параметр alpha распределения Beta для mixup; нет - без mixup
+    tensor_cache: Option<String>,       // Файл preprocess с набором; нет - случайные данные
+    val_split: Option<f64>,             // Доля примеров, отложенная для валидационной потери
+    early_stop_patience: Option<usize>, // Сколько эпох без улучшения валидационной потери терпеть
+    seed: Option<u64>,                  // Зерно для воспроизводимого обучения; нет - случайное
+    dry_run: bool,                      // Только проверить данные и модель, не обучая и не сохраняя
+    force: bool,                        // Обучать, даже если у какого-то класса нет примеров
+    normalization: Option<Normalization>, // Поканальная нормализация входа из stats.json
+    weights_only: bool,                 // Сохранить только веса, без состояния оптимизатора
+    freeze_backbone: bool,              // Обучать только головы, сверточные слои не менять
+}
+
+impl Default for OsErrorTrainConfig {
+    fn default() -> Self {
+        OsErrorTrainConfig {
+            augment: false,
+            augment_strength: 1.0,
+            metrics_csv: None,
+            progress: None,
+            input_size: DEFAULT_OS_INPUT_SIZE,
+            epochs: 10,
+            learning_rate: 1e-4,
+            batch_size: 32,
+            model_path: OS_ERROR_MODEL_PATH.to_string(),
+            resume: false,
+            class_weights: None,
+            os_class_weights: None,
+            grad_clip: None,
+            loss: OsErrorLoss::CrossEntropy,
+            os_loss_weight: DEFAULT_OS_LOSS_WEIGHT,
+            mixup: None,
+            tensor_cache: None,
+            val_split: None,
+            early_stop_patience: None,
+            seed: None,
+            dry_run: false,
+            force: false,
+            normalization: None,
+            weights_only: false,
+            freeze_backbone: false,
+        }
+    }
+}
+
+// Аугментация батча скриншотов: яркость, контраст, небольшой поворот и гауссов шум.
+// Горизонтальные отражения намеренно не используются - они портят текст ошибки.
+fn augment<R: Rng>(images: &Tensor, rng: &mut R, strength: f64) -> Tensor {
+    let size = images.size();
+    let batch = size[0];
+    let device = images.device();
+
+    let mut brightness = Vec::with_capacity(batch as usize);
+    let mut contrast = Vec::with_capacity(batch as usize);
+    let mut theta = Vec::with_capacity(batch as usize * 6);
+    for _ in 0..batch {
+        brightness.push(1.0 + (strength * rng.gen_range(-0.2..0.2)) as f32);
+        contrast.push(1.0 + (strength * rng.gen_range(-0.2..0.2)) as f32);
+
+        // Поворот не более чем на 5 градусов при единичной силе
+        let angle = (strength * rng.gen_range(-5.0f64..5.0)).to_radians() as f32;
+        let (sin, cos) = angle.sin_cos();
+        theta.extend_from_slice(&[cos, -sin, 0.0, sin, cos, 0.0]);
+    }
+
+    let brightness = Tensor::of_slice(&brightness).view([batch, 1, 1, 1]).to_device(device);
+    let contrast = Tensor::of_slice(&contrast).view([batch, 1, 1, 1]).to_device(device);
+    let theta = Tensor::of_slice(&theta).view([batch, 2, 3]).to_device(device);
+
+    let mean = images.mean_dim(&[1, 2, 3], true, Kind::Float);
+    let adjusted = ((images - &mean) * contrast + &mean) * brightness;
+
+    // Билинейная интерполяция (0), края заполняются граничными пикселями (1)
+    let grid = Tensor::affine_grid_generator(&theta, &size, false);
+    let rotated = adjusted.grid_sampler(&grid, 0, 1, false);
+
+    let noise = rotated.randn_like() * (0.03 * strength);
+    (rotated + noise).clamp(0.0, 1.0)
+}
+
+// Перестановка батча и доля исходных примеров после mixup
+struct Mixup {
+    permutation: Tensor,
+    lam: f64,
+}
+
+// Mixup: батч смешивается со своей случайной перестановкой,
+// x = lam * x + (1 - lam) * x[permutation], где lam ~ Beta(alpha, alpha).
+// Перестановка возвращается, чтобы взять по ней вторые метки каждой головы
+fn mixup<R: Rng>(images: &Tensor, alpha: f64, rng: &mut R) -> Result<(Tensor, Mixup), Box<dyn std::error::Error>> {
+    let lam = rng.sample(rand_distr::Beta::new(alpha, alpha)?);
+    let permutation = Tensor::randperm(images.size()[0], (Kind::Int64, images.device()));
+    let mixed = images * lam + images.index_select(0, &permutation) * (1.0 - lam);
+    Ok((mixed, Mixup { permutation, lam }))
+}
+
+fn parse_val_split(value: &str) -> Result<f64, String> {
+    let fraction = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if fraction > 0.0 && fraction < 1.0 {
+        Ok(fraction)
+    } else {
+        Err(format!("Доля валидационных примеров должна быть в (0, 1), получено {}", fraction))
+    }
+}
+
+fn parse_early_stop_patience(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(patience) if patience > 0 => Ok(patience),
+        _ => Err(format!("Ожидалось положительное число эпох, получено {}", value)),
+    }
+}
+
+// Средняя потеря (как в обучении: ошибки + os_loss_weight * ОС) на отложенных примерах,
+// без аугментации, mixup и градиентов
+fn os_error_validation_loss(
+    config: &OsErrorTrainConfig,
+    error_model: &impl nn::ModuleT,
+    os_model: &impl nn::ModuleT,
+    (images, error_labels, os_labels): &(Tensor, Tensor, Tensor),
+    error_weights: &Tensor,
+    os_weights: &Tensor,
+) -> f64 {
+    let num_samples = images.size()[0];
+    let mut loss_sum = 0.0;
+    let mut start = 0;
+    tch::no_grad(|| {
+        while start < num_samples {
+            let batch_len = config.batch_size.min(num_samples - start);
+            let batch_images = images.narrow(0, start, batch_len);
+            let error_loss = config.loss.compute(
+                &error_model.forward_t(&batch_images, false),
+                &error_labels.narrow(0, start, batch_len),
+                error_weights,
+            );
+            let os_loss = config.loss.compute(
+                &os_model.forward_t(&batch_images, false),
+                &os_labels.narrow(0, start, batch_len),
+                os_weights,
+            );
+            loss_sum += f64::from(&(error_loss + os_loss * config.os_loss_weight)) * batch_len as f64;
+            start += batch_len;
+        }
+    });
+    loss_sum / num_samples as f64
+}
+
+fn parse_mixup_alpha(value: &str) -> Result<f64, String> {
+    let alpha = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if alpha.is_finite() && alpha > 0.0 {
+        Ok(alpha)
+    } else {
+        Err(format!("alpha для mixup должна быть положительной, получено {}", alpha))
+    }
+}
+
+// Копии весов слоев признаков (backbone = true) или голов модели ошибок ОС
+fn snapshot_variables(vs: &nn::VarStore, backbone: bool) -> Vec<(String, Tensor)> {
+    vs.variables()
+        .into_iter()
+        .filter(|(name, _)| is_backbone_variable(name) == backbone)
+        .map(|(name, variable)| (name, variable.detach().copy()))
+        .collect()
+}
+
+// Наибольшее абсолютное изменение весов относительно снимка
+fn max_weight_delta(vs: &nn::VarStore, snapshot: &[(String, Tensor)]) -> f64 {
+    let variables = vs.variables();
+    tch::no_grad(|| {
+        snapshot
+            .iter()
+            .filter_map(|(name, before)| variables.get(name).map(|after| f64::from(&(after - before).abs().max())))
+            .fold(0.0, f64::max)
+    })
+}
+
+// Обучение модели для ошибок ОС
+fn train_os_error_model(config: &OsErrorTrainConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if config.input_size < MIN_OS_INPUT_SIZE {
+        return Err(format!("Размер входа {} меньше минимального {}", config.input_size, MIN_OS_INPUT_SIZE).into());
+    }
+    if config.batch_size <= 0 {
+        return Err(format!("Размер батча должен быть положительным, получено {}", config.batch_size).into());
+    }
+
+    let seed = training_seed(config.seed);
+    let device = Device::Cpu;
+    let mut vs = nn::VarStore::new(device);
+    let (error_model, os_model) = os_error_cnn(&vs.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
+
+    let mut resumed_weights_only = false;
+    if config.resume {
+        let existing = resolve_os_error_metadata(&config.model_path, &Labels::builtin())?;
+        if existing.quantization.is_some() {
+            return Err(format!("Модель {} квантована, продолжить обучение можно только с исходной модели", config.model_path).into());
+        }
+        load_named_weights(&vs, &config.model_path)?;
+        resumed_weights_only = existing.weights_only;
+    }
+
+    let mut optimizer = ResumableAdam::new(&vs, config.learning_rate);
+    if resumed_weights_only {
+        println!("Обучение продолжено с {}: модель сохранена без состояния оптимизатора, Adam начинает заново", config.model_path);
+    } else if config.resume {
+        optimizer.load(&config.model_path)?;
+        println!("Обучение продолжено с {} (шаг оптимизатора {})", config.model_path, optimizer.step);
+    }
+
+    // Дообучение на малом наборе: общие сверточные слои не получают градиентов, обучаются
+    // только головы. Снимки весов нужны, чтобы после обучения убедиться, что слои не изменились
+    let frozen = config.freeze_backbone.then(|| {
+        let mut frozen_params = 0;
+        for (name, variable) in vs.variables() {
+            if is_backbone_variable(&name) {
+                let _ = variable.set_requires_grad(false);
+                frozen_params += variable.size().iter().product::<i64>();
+            }
+        }
+        println!("Слои признаков заморожены ({}): {} параметров не обучаются", OS_BACKBONE_LAYERS.join(", "), frozen_params);
+        if !config.resume {
+            println!("Внимание: --freeze-backbone без --resume замораживает случайно инициализированные слои");
+        }
+        (snapshot_variables(&vs, true), snapshot_variables(&vs, false))
+    });
+
+    // Нормализация сохраняется в метаданных, чтобы инференс приводил вход к тому же виду.
+    // При продолжении обучения без --normalize-from берется нормализация модели
+    let normalization = match config.normalization {
+        Some(normalization) => Some(normalization),
+        None if config.resume => ModelMetadata::load(&config.model_path)?.and_then(|metadata| metadata.normalization),
+        None => None,
+    };
+    let metadata = ModelMetadata {
+        normalization,
+        weights_only: config.weights_only,
+        ..ModelMetadata::os_error_cnn(config.input_size)
+    };
+    if let Some(normalization) = &metadata.normalization {
+        println!("Нормализация входа: mean {:?}, std {:?}", normalization.mean, normalization.std);
+    }
+
+    if config.early_stop_patience.is_some() && config.val_split.is_none() {
+        return Err("Для --early-stop-patience нужна валидационная выборка: укажите --val-split".into());
+    }
+
+    let (train_images, error_labels, os_labels) = match &config.tensor_cache {
+        Some(path) => load_tensor_cache(path, config.input_size)?,
+        None => create_os_error_dummy_data(config.input_size),
+    };
+    check_label_range(&error_labels, OS_ERROR_TYPES.len(), "Метки типов ошибок")?;
+    check_label_range(&os_labels, OS_TYPES.len(), "Метки типов ОС")?;
+
+    // Распределение считается по всему набору до разбиения на обучение и валидацию
+    let empty_errors = report_class_distribution("Распределение типов ошибок", OS_ERROR_TYPES, &error_labels);
+    let empty_os = report_class_distribution("Распределение типов ОС", OS_TYPES, &os_labels);
+    check_empty_classes("Типы ошибок", &empty_errors, config.force)?;
+    check_empty_classes("Типы ОС", &empty_os, config.force)?;
+
+    // Случайная часть примеров откладывается для валидации и в обучении не участвует
+    let (train_images, error_labels, os_labels, validation) = match config.val_split {
+        Some(fraction) => {
+            let total = train_images.size()[0];
+            let val_len = (total as f64 * fraction).round() as i64;
+            if val_len == 0 || val_len == total {
+                return Err(format!("--val-split {} оставляет пустую выборку из {} примеров", fraction, total).into());
+            }
+            let permutation = Tensor::randperm(total, (Kind::Int64, device));
+            let val_indices = permutation.narrow(0, 0, val_len);
+            let train_indices = permutation.narrow(0, val_len, total - val_len);
+            let validation = (
+                train_images.index_select(0, &val_indices),
+                error_labels.index_select(0, &val_indices),
+                os_labels.index_select(0, &val_indices),
+            );
+            println!("Валидационная выборка: {} из {} примеров", val_len, total);
+            (
+                train_images.index_select(0, &train_indices),
+                error_labels.index_select(0, &train_indices),
+                os_labels.index_select(0, &train_indices),
+                Some(validation),
+            )
+        }
+        None => (train_images, error_labels, os_labels, None),
+    };
+    // У валидации нет аугментации, поэтому ее можно нормализовать сразу
+    let validation = validation.map(|(images, error_labels, os_labels)| (metadata.normalize(&images), error_labels, os_labels));
+
+    // Веса классов считаются по всему набору и применяются к каждой голове отдельно
+    let error_weights = resolve_class_weights(config.class_weights.as_deref(), &error_labels, OS_ERROR_TYPES)?;
+    let os_weights = resolve_class_weights(config.os_class_weights.as_deref(), &os_labels, OS_TYPES)?;
+    print_class_weights("Веса классов ошибок", OS_ERROR_TYPES, &error_weights);
+    print_class_weights("Веса типов ОС", OS_TYPES, &os_weights);
+    let error_weights = Tensor::of_slice(&error_weights).to_kind(Kind::Float).to_device(device);
+    let os_weights = Tensor::of_slice(&os_weights).to_kind(Kind::Float).to_device(device);
+
+    // Пробный запуск: данные, веса и модель проверены, до оптимизатора и записи файлов не доходит
+    if config.dry_run {
+        println!("Обучающих примеров: {}, форма входа: {:?}", train_images.size()[0], train_images.size());
+        let batch = DRY_RUN_BATCH.min(train_images.size()[0]);
+        let sample = metadata.normalize(&train_images.narrow(0, 0, batch));
+        let (error_output, os_output) = tch::no_grad(|| {
+            (error_model.forward_t(&sample, false), os_model.forward_t(&sample, false))
+        });
+        check_output_shape(&error_output, batch, OS_ERROR_TYPES.len(), "Голова типов ошибок")?;
+        check_output_shape(&os_output, batch, OS_TYPES.len(), "Голова типов ОС")?;
+        println!("Пробный запуск: обучение пропущено, {} не изменен", config.model_path);
+        return Ok(());
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut metrics = match &config.metrics_csv {
+        Some(path) if validation.is_some() => {
+            Some(MetricsWriter::create(path, &["epoch", "error_loss", "os_loss", "total_loss", "val_loss"])?)
+        }
+        Some(path) => Some(MetricsWriter::create(path, &["epoch", "error_loss", "os_loss", "total_loss"])?),
+        None => None,
+    };
+
+    // Веса эпохи с наименьшей валидационной потерей; в конце восстанавливаются перед сохранением
+    let mut best = match validation {
+        Some(_) => {
+            let best_vs = nn::VarStore::new(device);
+            let _ = os_error_cnn(&best_vs.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
+            Some((best_vs, 0, f64::INFINITY))
+        }
+        None => None,
+    };
+
+    println!("Функция потерь: {}", config.loss);
+    println!("Вес потери головы ОС: {}", config.os_loss_weight);
+    if let Some(alpha) = config.mixup {
+        println!("Mixup включен (alpha: {})", alpha);
+    }
+    if config.augment {
+        println!("Аугментация включена (сила: {})", config.augment_strength);
+    }
+
+    let num_samples = train_images.size()[0];
+    let epochs = config.epochs;
+    let params = optimizer.parameters();
+    for epoch in 1..=epochs {
+        // Новый порядок примеров в каждой эпохе
+        let permutation = Tensor::randperm(num_samples, (Kind::Int64, device));
+        let (mut error_loss_sum, mut os_loss_sum, mut total_loss_sum) = (0.0, 0.0, 0.0);
+        let (mut steps, mut clipped_steps, mut max_grad_norm) = (0, 0, 0.0f64);
+
+        let mut start = 0;
+        while start < num_samples {
+            let batch_len = config.batch_size.min(num_samples - start);
+            let indices = permutation.narrow(0, start, batch_len);
+            start += batch_len;
+
+            let batch_images = train_images.index_select(0, &indices);
+            let batch_images = if config.augment {
+                augment(&batch_images, &mut rng, config.augment_strength)
+            } else {
+                batch_images
+            };
+
+            let (batch_images, mixing) = match config.mixup {
+                Some(alpha) => {
+                    let (mixed, mixing) = mixup(&batch_images, alpha, &mut rng)?;
+                    (mixed, Some(mixing))
+                }
+                None => (batch_images, None),
+            };
+            // Аугментация и mixup работают в шкале 0..1, нормализация - последним шагом
+            let batch_images = metadata.normalize(&batch_images);
+
+            let error_output = error_model.forward_t(&batch_images, true);
+            let os_output = os_model.forward_t(&batch_images, true);
+
+            let error_targets = error_labels.index_select(0, &indices);
+            let os_targets = os_labels.index_select(0, &indices);
+            let (error_loss, os_loss) = match &mixing {
+                Some(mixing) => (
+                    config.loss.compute_mixed(&error_output, &error_targets, mixing, &error_weights),
+                    config.loss.compute_mixed(&os_output, &os_targets, mixing, &os_weights),
+                ),
+                None => (
+                    config.loss.compute(&error_output, &error_targets, &error_weights),
+                    config.loss.compute(&os_output, &os_targets, &os_weights),
+                ),
+            };
+            let total_loss = &error_loss + &os_loss * config.os_loss_weight; // Взвешенная потеря
+            let total_loss_value = check_loss(&total_loss, epoch)?;
+
+            optimizer.zero_grad();
+            total_loss.backward();
+            if let Some(max_norm) = config.grad_clip {
+                let norm = clip_grad_norm(&params, max_norm);
+                if norm > max_norm {
+                    clipped_steps += 1;
+                    max_grad_norm = max_grad_norm.max(norm);
+                }
+            }
+            optimizer.step();
+            steps += 1;
+
+            // Потери батча взвешиваются его размером, чтобы неполный последний батч не искажал среднее
+            let weight = batch_len as f64;
+            error_loss_sum += f64::from(&error_loss) * weight;
+            os_loss_sum += f64::from(&os_loss) * weight;
+            total_loss_sum += total_loss_value * weight;
+        }
+
+        // Одно предупреждение на эпоху вместо сообщения на каждый шаг
+        if clipped_steps > 0 {
+            println!("Внимание: эпоха {}: градиенты ограничены в {} из {} шагов (наибольшая норма {:.4}, порог {})",
+                epoch, clipped_steps, steps, max_grad_norm, config.grad_clip.unwrap_or_default());
+        }
+
+        // Средние потери по эпохе
+        let error_loss = error_loss_sum / num_samples as f64;
+        let os_loss = os_loss_sum / num_samples as f64;
+        let total_loss = total_loss_sum / num_samples as f64;
+
+        let val_loss = validation.as_ref().map(|validation| {
+            os_error_validation_loss(config, &error_model, &os_model, validation, &error_weights, &os_weights)
+        });
+
+        if let Some(metrics) = metrics.as_mut() {
+            match val_loss {
+                Some(val_loss) => metrics.write_row(epoch, &[error_loss, os_loss, total_loss, val_loss])?,
+                None => metrics.write_row(epoch, &[error_loss, os_loss, total_loss])?,
+            }
+        }
+
+        // do_send только кладет сообщение в очередь актора и не задерживает обучение
+        if let Some(progress) = &config.progress {
+            progress.do_send(TrainingProgress {
+                epoch,
+                total_epochs: epochs,
+                loss: total_loss,
+            });
+        }
+
+        match val_loss {
+            Some(val_loss) => println!("Epoch: {}, Error Loss: {:.4}, OS Loss: {:.4}, Total Loss: {:.4}, Val Loss: {:.4}",
+                epoch, error_loss, os_loss, total_loss, val_loss),
+            None => println!("Epoch: {}, Error Loss: {:.4}, OS Loss: {:.4}, Total Loss: {:.4}",
+                epoch, error_loss, os_loss, total_loss),
+        }
+
+        if let (Some((best_vs, best_epoch, best_loss)), Some(val_loss)) = (best.as_mut(), val_loss) {
+            if val_loss < *best_loss {
+                best_vs.copy(&vs)?;
+                *best_epoch = epoch;
+                *best_loss = val_loss;
+            } else if let Some(patience) = config.early_stop_patience
+                && epoch - *best_epoch >= patience as i64
+            {
+                println!("Ранняя остановка на эпохе {}: валидационная потеря не улучшалась {} эпох", epoch, patience);
+                break;
+            }
+        }
+    }
+
+    // Эпоха 0 - потеря ни разу не была конечной, восстанавливать нечего
+    if let Some((best_vs, best_epoch, best_loss)) = &best
+        && *best_epoch > 0
+    {
+        vs.copy(best_vs)?;
+        println!("Лучшая модель: эпоха {} (валидационная потеря {:.4})", best_epoch, best_loss);
+    }
+
+    if let Some((backbone, heads)) = &frozen {
+        let backbone_delta = max_weight_delta(&vs, backbone);
+        let heads_delta = max_weight_delta(&vs, heads);
+        println!("Наибольшее изменение весов: слои признаков {:e}, головы {:e}", backbone_delta, heads_delta);
+        if backbone_delta != 0.0 {
+            return Err(format!("Веса замороженных слоев признаков изменились на {:e}, модель не сохранена", backbone_delta).into());
+        }
+    }
+
+    if config.weights_only {
+        save_weights_only(&vs, &config.model_path)?;
+        metadata.save(&config.model_path)?;
+        println!("Веса модели для предсказания ошибок ОС сохранены в {} (без состояния оптимизатора)", config.model_path);
+        return Ok(());
+    }
+    vs.save(&config.model_path)?;
+    metadata.save(&config.model_path)?;
+    optimizer.save(&config.model_path)?;
+    println!("Модель для предсказания ошибок ОС сохранена в {}", config.model_path);
+    Ok(())
+}
+
+// Загрузка чекпоинтов для ансамбля. Все они должны иметь одинаковые архитектуру,
+// размер входа и словарь меток, иначе усреднение вероятностей бессмысленно
+fn load_os_error_ensemble(
+    model_paths: &[String],
+    device: Device,
+    labels: &Labels,
+) -> Result<Vec<OsErrorModelBundle>, Box<dyn std::error::Error>> {
+    let models = model_paths
+        .iter()
+        .map(|path| {
+            check_model_path(path)?;
+            OsErrorModelBundle::load_on(path, device, labels)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let Some(first) = models.first() else {
+        return Err("Не указано ни одной модели".into());
+    };
+    for (path, model) in model_paths.iter().zip(&models).skip(1) {
+        model.metadata.check_compatible(&first.metadata)
+            .map_err(|e| format!("Модель {} несовместима с {}: {}", path, model_paths[0], e))?;
+    }
+
+    Ok(models)
+}
+
+// Среднее softmax-распределений нескольких моделей
+fn average_probs(probs: impl Iterator<Item = Tensor>) -> Tensor {
+    let probs: Vec<Tensor> = probs.collect();
+    Tensor::stack(&probs, 0).mean_dim(&[0], false, Kind::Float)
+}
+
+// Доля стороны скриншота, которую занимает каждый кроп test-time augmentation
+const TTA_CROP_FRACTION: f64 = 0.875;
+
+// Кропы для test-time augmentation: центральный и четыре угловых размером
+// TTA_CROP_FRACTION от исходного, каждый масштабируется до входа модели. Результат [5, 3, size, size]
+fn tta_crops(img: &image::DynamicImage, size: i64) -> Tensor {
+    let (width, height) = (img.width(), img.height());
+    let crop_width = ((width as f64 * TTA_CROP_FRACTION).round() as u32).max(1);
+    let crop_height = ((height as f64 * TTA_CROP_FRACTION).round() as u32).max(1);
+    let (right, bottom) = (width - crop_width, height - crop_height);
+
+    let crops: Vec<Tensor> = [(right / 2, bottom / 2), (0, 0), (right, 0), (0, bottom), (right, bottom)]
+        .iter()
+        .map(|&(x, y)| image_to_tensor(&img.crop_imm(x, y, crop_width, crop_height), size))
+        .collect();
+    Tensor::cat(&crops, 0)
+}
+
+// Изображение из буфера обмена (RGBA) для predict-os-error --from-clipboard
+#[cfg(feature = "clipboard")]
+fn read_clipboard_image() -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|e| format!("Буфер обмена недоступен: {}", e))?;
+    let data = clipboard.get_image().map_err(|e| match e {
+        arboard::Error::ContentNotAvailable => "В буфере обмена нет изображения, скопируйте скриншот или укажите --screenshot".to_string(),
+        e => format!("Не удалось прочитать изображение из буфера обмена: {}", e),
+    })?;
+    let rgba = image::RgbaImage::from_raw(data.width as u32, data.height as u32, data.bytes.into_owned())
+        .ok_or("Буфер обмена вернул изображение с некорректным размером данных")?;
+    Ok(image::DynamicImage::ImageRgba8(rgba))
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn read_clipboard_image() -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+    Err("Чтение из буфера обмена не поддерживается этой сборкой, пересоберите с --features clipboard".into())
+}
+
+// Функция для предсказания ошибок ОС
+// Несколько моделей (см. load_os_error_ensemble) - ансамбль: softmax-выходы моделей усредняются.
+// С tta каждая модель получает пять кропов скриншота, и ее распределение усредняется по кропам
+fn predict_os_error_from_cli(
+    models: &[OsErrorModelBundle],
+    img: &image::DynamicImage,
+    temperature: Option<f64>,
+    json: bool,
+    tta: bool,
+    out: &mut impl std::io::Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let device = models[0].device();
+    if models.len() > 1 && !json {
+        writeln!(out, "Ансамбль из {} моделей", models.len())?;
+    }
+    let size = models[0].input_size();
+    let image_tensor = if tta { tta_crops(img, size) } else { image_to_tensor(img, size) }.to_device(device);
+    if tta && !json {
+        writeln!(out, "Test-time augmentation: усреднение по {} кропам", image_tensor.size()[0])?;
+    }
+
+    let ((error_confidence, error_class), os_probs) = tch::no_grad(|| {
+        let temperature_of = |models: &OsErrorModelBundle| temperature.unwrap_or(models.metadata.temperature);
+        // Усреднение по батчу кропов; без TTA батч из одного изображения
+        let mean_over_crops = |probs: Tensor| probs.mean_dim(&[0], true, Kind::Float);
+        let (error_probs, os_probs): (Vec<Tensor>, Vec<Tensor>) = models
+            .iter()
+            .map(|models| {
+                let (error_logits, os_logits) = models.forward(&image_tensor);
+                (
+                    mean_over_crops(softmax_with_temperature(&error_logits, temperature_of(models))),
+                    mean_over_crops(softmax_with_temperature(&os_logits, temperature_of(models))),
+                )
+            })
+            .unzip();
+        // Предсказание типа ошибки
+        let error_probs = average_probs(error_probs.into_iter());
+        // Предсказание типа ОС
+        let os_probs = average_probs(os_probs.into_iter());
+        (error_probs.max_dim(-1, false), Vec::<f32>::from(&os_probs.get(0)))
+    });
+
+    let metadata = &models[0].metadata;
+    let error_idx = i64::from(&error_class.get(0)) as usize;
+    let error_type = metadata.error_label(error_idx);
+
+    let (os_idx, os_confidence, os_corrected) = reconcile_os_type(error_type, &os_probs, &metadata.os_types);
+    let os_type = metadata.os_label(os_idx);
+
+    let (_, possible_causes, solutions) = get_detailed_error_info(error_type, os_type);
+    let solutions = solution_texts(solutions, None);
+    let prediction = OsErrorPredictResponse {
+        error_type: error_type.to_string(),
+        os_type: os_type.to_string(),
+        confidence: f32::from(&error_confidence.get(0)),
+        os_confidence,
+        os_corrected,
+        description: error_description(error_type).to_string(),
+        possible_causes,
+        solutions,
+    };
+
+    if json {
+        writeln!(out, "{}", serde_json::to_string(&prediction)?)?;
+        return Ok(());
+    }
+
+    writeln!(out, "=== Анализ ошибки операционной системы ===")?;
+    writeln!(out, "Тип ошибки: {}", prediction.error_type)?;
+    writeln!(out, "Операционная система: {}", prediction.os_type)?;
+    writeln!(out, "Уверенность (ошибка): {:.2}%", prediction.confidence * 100.0)?;
+    writeln!(out, "Уверенность (ОС): {:.2}%", prediction.os_confidence * 100.0)?;
+    if prediction.os_corrected {
+        writeln!(out, "Тип ОС исправлен: модель предсказала ОС, несовместимую с типом ошибки")?;
+    }
+    writeln!(out, "Описание: {}", prediction.description)?;
+
+    Ok(())
+}
+
+// Размер батча при оценке модели
+const EVAL_BATCH_SIZE: i64 = 32;
+
+// Предсказание для одного файла каталога
+#[derive(Serialize)]
+struct DirPrediction {
+    filename: String,
+    error_type: String,
+    error_confidence: f32,
+    os_type: String,
+    os_confidence: f32,
+}
+
+// Формат вывода predict-dir
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DirOutputFormat {
+    Table, // Таблица для чтения и сводка по типам ошибок
+    Csv,   // CSV для электронных таблиц, см. write_predictions_csv
+    Json,  // Массив объектов с полями DirPrediction
+}
+
+fn parse_dir_output_format(value: &str) -> Result<DirOutputFormat, String> {
+    match value {
+        "table" => Ok(DirOutputFormat::Table),
+        "csv" => Ok(DirOutputFormat::Csv),
+        "json" => Ok(DirOutputFormat::Json),
+        _ => Err(format!("Неизвестный формат {}: ожидается table, csv или json", value)),
+    }
+}
+
+// Число знаков после запятой для уверенностей в CSV
+const CSV_CONFIDENCE_PRECISION: usize = 6;
+
+// Строка заголовка и по строке на изображение; уверенности с фиксированной точностью
+fn write_predictions_csv(writer: &mut impl std::io::Write, predictions: &[DirPrediction]) -> std::io::Result<()> {
+    writeln!(writer, "filename,error_type,error_confidence,os_type,os_confidence")?;
+    for prediction in predictions {
+        writeln!(writer, "{},{},{:.precision$},{},{:.precision$}",
+            csv_field(&prediction.filename), csv_field(&prediction.error_type), prediction.error_confidence,
+            csv_field(&prediction.os_type), prediction.os_confidence,
+            precision = CSV_CONFIDENCE_PRECISION)?;
+    }
+    writer.flush()
+}
+
+// Экранирование поля CSV: поля с запятыми, кавычками и переводами строк берутся в кавычки
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Предсказания для списка файлов батчами по EVAL_BATCH_SIZE. Файлы, которые не
+// декодируются как изображения, пропускаются с предупреждением
+fn predict_files(models: &OsErrorModelBundle, files: &[std::path::PathBuf]) -> Vec<DirPrediction> {
+    let preprocessor = StandardPreprocessor::square(models.input_size());
+    let temperature = models.metadata.temperature;
+    let mut predictions = Vec::with_capacity(files.len());
+
+    for chunk in files.chunks(EVAL_BATCH_SIZE as usize) {
+        let mut names = Vec::with_capacity(chunk.len());
+        let mut images = Vec::with_capacity(chunk.len());
+        for path in chunk {
+            match load_screenshot(path, &preprocessor) {
+                Ok(tensor) => {
+                    names.push(path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+                    images.push(tensor);
+                }
+                Err(e) => eprintln!("Пропуск файла {}: {}", path.display(), e),
+            }
+        }
+        if images.is_empty() {
+            continue;
+        }
+
+        let batch = Tensor::cat(&images, 0);
+        let ((error_confidence, error_class), os_probs) = tch::no_grad(|| {
+            let (error_logits, os_logits) = models.forward(&batch);
+            let error_probs = softmax_with_temperature(&error_logits, temperature);
+            let os_probs = softmax_with_temperature(&os_logits, temperature);
+            (error_probs.max_dim(-1, false), Vec::<Vec<f32>>::from(&os_probs))
+        });
+        let error_confidence = Vec::<f32>::from(&error_confidence);
+        let error_class = Vec::<i64>::from(&error_class);
+
+        for (i, filename) in names.into_iter().enumerate() {
+            let error_type = models.metadata.error_label(error_class[i] as usize).to_string();
+            let (os_idx, os_confidence, _) = reconcile_os_type(&error_type, &os_probs[i], &models.metadata.os_types);
+            predictions.push(DirPrediction {
+                filename,
+                os_type: models.metadata.os_label(os_idx).to_string(),
+                error_type,
+                error_confidence: error_confidence[i],
+                os_confidence,
+            });
+        }
+    }
+    predictions
+}
+
+// Анализ всех скриншотов каталога (без вложенных каталогов)
+fn predict_dir(
+    model_path: &str,
+    dir: &str,
+    csv: Option<&str>,
+    format: DirOutputFormat,
+    labels: &Labels,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let models = OsErrorModelBundle::load_on(model_path, Device::Cpu, labels)?;
+    let files: Vec<std::path::PathBuf> = sorted_dir_entries(dir)?.into_iter().filter(|path| path.is_file()).collect();
+    let predictions = predict_files(&models, &files);
+    if predictions.is_empty() {
+        return Err(format!("В каталоге {} не найдено изображений", dir).into());
+    }
+
+    // В форматах csv и json stdout содержит только данные, чтобы его можно было перенаправить в файл
+    match format {
+        DirOutputFormat::Table => {
+            println!("{:<40} {:<24} {:<10} {:>11}", "Файл", "Тип ошибки", "ОС", "Уверенность");
+            for prediction in &predictions {
+                println!("{:<40} {:<24} {:<10} {:>10.2}%",
+                    prediction.filename, prediction.error_type, prediction.os_type, prediction.error_confidence * 100.0);
+            }
+
+            let mut counts: std::collections::BTreeMap<&str, usize> = std::collections::BTreeMap::new();
+            for prediction in &predictions {
+                *counts.entry(prediction.error_type.as_str()).or_default() += 1;
+            }
+            println!("\nВсего изображений: {}", predictions.len());
+            for (error_type, count) in &counts {
+                println!("  {}: {}", error_type, count);
+            }
+        }
+        DirOutputFormat::Csv => write_predictions_csv(&mut std::io::stdout().lock(), &predictions)?,
+        DirOutputFormat::Json => println!("{}", serde_json::to_string_pretty(&predictions)?),
+    }
+
+    if let Some(path) = csv {
+        write_predictions_csv(&mut std::io::BufWriter::new(std::fs::File::create(path)?), &predictions)?;
+        eprintln!("Результаты сохранены в {}", path);
+    }
+
+    Ok(())
+}
+
+// Интервал между кадрами видео по умолчанию, в секундах
+const DEFAULT_FRAME_INTERVAL_SECS: f64 = 1.0;
+
+// Разбор --interval: положительное конечное число секунд
+fn parse_frame_interval(value: &str) -> Result<f64, String> {
+    let interval = value.parse::<f64>().map_err(|e| e.to_string())?;
+    if interval.is_finite() && interval > 0.0 {
+        Ok(interval)
+    } else {
+        Err(format!("Интервал между кадрами должен быть положительным, получено {}", interval))
+    }
+}
+
+// Кадр видео на временной шкале
+#[derive(Serialize)]
+struct VideoFrame {
+    time: f64, // секунды от начала записи
+    #[serde(flatten)]
+    prediction: DirPrediction,
+}
+
+// Итог по видео: тип ошибки и ОС, набравшие большинство кадров, самый уверенный кадр и шкала
+#[derive(Serialize)]
+struct VideoPrediction {
+    error_type: String,
+    error_votes: usize,
+    os_type: String,
+    os_votes: usize,
+    description: String,
+    best_frame: usize, // индекс в timeline
+    timeline: Vec<VideoFrame>,
+}
+
+// Метка, набравшая больше всего голосов; при равенстве побеждает большая суммарная уверенность
+fn majority_vote<'a>(votes: impl Iterator<Item = (&'a str, f32)>) -> (String, usize) {
+    let mut tally: HashMap<&str, (usize, f32)> = HashMap::new();
+    for (label, confidence) in votes {
+        let entry = tally.entry(label).or_default();
+        entry.0 += 1;
+        entry.1 += confidence;
+    }
+    tally
+        .into_iter()
+        .max_by(|(a_label, a), (b_label, b)| {
+            a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(b_label.cmp(a_label))
+        })
+        .map(|(label, (count, _))| (label.to_string(), count))
+        .unwrap_or_default()
+}
+
+// Временный каталог для извлеченных кадров; удаляется вместе с содержимым при выходе
+struct FrameDir(std::path::PathBuf);
+
+impl Drop for FrameDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Извлечение кадров через ffmpeg: по кадру каждые interval секунд
+fn extract_video_frames(video: &str, interval: f64) -> Result<FrameDir, Box<dyn std::error::Error>> {
+    let frames = FrameDir(std::env::temp_dir().join(format!("bashpic-frames-{}", Uuid::new_v4())));
+    std::fs::create_dir_all(&frames.0)?;
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-v", "error", "-nostdin", "-i", video, "-vf", &format!("fps=1/{}", interval)])
+        .arg(frames.0.join("frame_%06d.png"))
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                "ffmpeg не найден: установите его или извлеките кадры заранее и передайте каталог с ними".to_string()
+            }
+            _ => format!("Не удалось запустить ffmpeg: {}", e),
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffmpeg не смог извлечь кадры из {}: {}",
+            video,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ).into());
+    }
+    Ok(frames)
+}
+
+// Анализ короткой записи экрана: кадры каждые interval секунд (из ffmpeg или готового
+// каталога, где файлы по порядку имен идут с тем же интервалом) прогоняются через модель
+// ошибок ОС, итоговая метка выбирается большинством голосов по кадрам
+fn predict_video(
+    model_path: &str,
+    video: &str,
+    interval: f64,
+    json: bool,
+    labels: &Labels,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let models = OsErrorModelBundle::load_on(model_path, Device::Cpu, labels)?;
+
+    let extracted;
+    let frames_dir = if Path::new(video).is_dir() {
+        Path::new(video)
+    } else {
+        extracted = extract_video_frames(video, interval)?;
+        extracted.0.as_path()
+    };
+    let files: Vec<std::path::PathBuf> = sorted_dir_entries(frames_dir)?.into_iter().filter(|path| path.is_file()).collect();
+    // Время кадра считается по его месту среди всех файлов, чтобы пропуск битого кадра не сдвигал шкалу
+    let positions: HashMap<String, usize> = files
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (path.file_name().unwrap_or_default().to_string_lossy().into_owned(), i))
+        .collect();
+
+    let timeline: Vec<VideoFrame> = predict_files(&models, &files)
+        .into_iter()
+        .map(|prediction| VideoFrame {
+            time: positions.get(&prediction.filename).copied().unwrap_or_default() as f64 * interval,
+            prediction,
+        })
+        .collect();
+    if timeline.is_empty() {
+        return Err(format!("В {} не найдено кадров", video).into());
+    }
+
+    let (error_type, error_votes) = majority_vote(
+        timeline.iter().map(|frame| (frame.prediction.error_type.as_str(), frame.prediction.error_confidence)),
+    );
+    let (os_type, os_votes) = majority_vote(
+        timeline.iter().map(|frame| (frame.prediction.os_type.as_str(), frame.prediction.os_confidence)),
+    );
+    let best_frame = timeline
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.prediction.error_confidence.total_cmp(&b.prediction.error_confidence))
+        .map(|(i, _)| i)
+        .unwrap_or_default();
+    let result = VideoPrediction {
+        description: error_description(&error_type).to_string(),
+        error_type,
+        error_votes,
+        os_type,
+        os_votes,
+        best_frame,
+        timeline,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+
+    println!("{:>9} {:<40} {:<24} {:<10} {:>11}", "Время, с", "Кадр", "Тип ошибки", "ОС", "Уверенность");
+    for frame in &result.timeline {
+        println!("{:>9.2} {:<40} {:<24} {:<10} {:>10.2}%",
+            frame.time, frame.prediction.filename, frame.prediction.error_type,
+            frame.prediction.os_type, frame.prediction.error_confidence * 100.0);
+    }
+    let frames = result.timeline.len();
+    let best = &result.timeline[result.best_frame];
+    println!("\nТип ошибки: {} ({} из {} кадров)", result.error_type, result.error_votes, frames);
+    println!("Тип ОС: {} ({} из {} кадров)", result.os_type, result.os_votes, frames);
+    println!("Самый уверенный кадр: {} на {:.2} с ({}, {:.2}%)",
+        best.prediction.filename, best.time, best.prediction.error_type, best.prediction.error_confidence * 100.0);
+    println!("Описание: {}", result.description);
+    Ok(())
+}
+
+// Метрики одного класса
+#[derive(Serialize)]
+struct ClassMetrics {
+    label: String,
+    precision: f64,
+    recall: f64,
+    support: i64,
+}
+
+// Метрики одной головы модели (тип ошибки или тип ОС)
+#[derive(Serialize)]
+struct HeadMetrics {
+    accuracy: f64,
+    classes: Vec<ClassMetrics>,
+    confusion_matrix: Vec<Vec<i64>>, // строки - истинный класс, столбцы - предсказанный
+}
+
+impl HeadMetrics {
+    fn compute(labels: &[&str], targets: &[i64], predictions: &[i64]) -> Self {
+        let num_classes = labels.len();
+        let mut confusion_matrix = vec![vec![0i64; num_classes]; num_classes];
+        for (&target, &prediction) in targets.iter().zip(predictions) {
+            if let Some(cell) = confusion_matrix
+                .get_mut(target as usize)
+                .and_then(|row| row.get_mut(prediction as usize))
+            {
+                *cell += 1;
+            }
+        }
+
+        let correct: i64 = (0..num_classes).map(|i| confusion_matrix[i][i]).sum();
+        let ratio = |numerator: i64, denominator: i64| {
+            if denominator > 0 { numerator as f64 / denominator as f64 } else { 0.0 }
+        };
+
+        let classes = labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                let true_positive = confusion_matrix[i][i];
+                let predicted: i64 = confusion_matrix.iter().map(|row| row[i]).sum();
+                let support: i64 = confusion_matrix[i].iter().sum();
+                ClassMetrics {
+                    label: label.to_string(),
+                    precision: ratio(true_positive, predicted),
+                    recall: ratio(true_positive, support),
+                    support,
+                }
+            })
+            .collect();
+
+        HeadMetrics {
+            accuracy: ratio(correct, targets.len() as i64),
+            classes,
+            confusion_matrix,
+        }
+    }
+
+    fn print(&self, title: &str) {
+        println!("=== {} ===", title);
+        println!("Точность (accuracy): {:.2}%", self.accuracy * 100.0);
+        println!();
+        println!("{:<24} {:>10} {:>10} {:>8}", "Класс", "Precision", "Recall", "Примеры");
+        for class in &self.classes {
+            println!(
+                "{:<24} {:>9.2}% {:>9.2}% {:>8}",
+                class.label, class.precision * 100.0, class.recall * 100.0, class.support
+            );
+        }
+
+        println!();
+        println!("Матрица ошибок (строки - истинный класс, столбцы - предсказанный):");
+        let header: String = (0..self.classes.len()).map(|i| format!("{:>6}", i)).collect();
+        println!("{:<28}{}", "", header);
+        for (i, (class, row)) in self.classes.iter().zip(&self.confusion_matrix).enumerate() {
+            let cells: String = row.iter().map(|count| format!("{:>6}", count)).collect();
+            println!("{:>3} {:<24}{}", i, class.label, cells);
+        }
+        println!();
+    }
+}
+
+// Битовый шрифт 3x5 для подписей на изображениях и текста синтетических скриншотов
+// (строчные буквы выводятся как заглавные, неизвестные символы - пробелом)
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        _ => [0b000; 5],
+    }
+}
+
+// Ширина символа с учетом межсимвольного интервала
+const GLYPH_ADVANCE: u32 = 4;
+
+fn fill_rect(img: &mut image::RgbImage, x: u32, y: u32, width: u32, height: u32, color: image::Rgb<u8>) {
+    for py in y..(y + height).min(img.height()) {
+        for px in x..(x + width).min(img.width()) {
+            img.put_pixel(px, py, color);
+        }
+    }
+}
+
+fn draw_text(img: &mut image::RgbImage, x: u32, y: u32, text: &str, scale: u32, color: image::Rgb<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let origin_x = x + i as u32 * GLYPH_ADVANCE * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill_rect(img, origin_x + col * scale, y + row as u32 * scale, scale, scale, color);
+                }
+            }
+        }
+    }
+}
+
+// Тепловая карта матрицы ошибок: интенсивность ячейки - доля примеров истинного класса.
+// Строки подписаны именами классов, столбцы - их индексами.
+fn render_confusion_matrix(matrix: &[Vec<i64>], labels: &[String]) -> image::RgbImage {
+    const CELL: u32 = 40;
+    const SCALE: u32 = 2;
+    const PADDING: u32 = 10;
+
+    let num_classes = labels.len() as u32;
+    let longest_label = labels.iter().map(|label| label.len()).max().unwrap_or(0) as u32 + 3;
+    let left = PADDING + longest_label * GLYPH_ADVANCE * SCALE;
+    let top = PADDING + 5 * SCALE + PADDING;
+
+    let width = left + num_classes * CELL + PADDING;
+    let height = top + num_classes * CELL + PADDING;
+    let mut img = image::RgbImage::from_pixel(width, height, image::Rgb([255, 255, 255]));
+
+    let black = image::Rgb([0, 0, 0]);
+    let white = image::Rgb([255, 255, 255]);
+    let low = [247.0, 251.0, 255.0];
+    let high = [8.0, 48.0, 107.0];
+
+    for (i, label) in labels.iter().enumerate() {
+        let i = i as u32;
+        draw_text(&mut img, PADDING, top + i * CELL + (CELL - 5 * SCALE) / 2, &format!("{} {}", i, label), SCALE, black);
+        draw_text(&mut img, left + i * CELL + CELL / 2 - GLYPH_ADVANCE * SCALE / 2, PADDING, &i.to_string(), SCALE, black);
+    }
+
+    for (row, counts) in matrix.iter().enumerate() {
+        let total: i64 = counts.iter().sum();
+        for (col, &count) in counts.iter().enumerate() {
+            let value = if total > 0 { count as f32 / total as f32 } else { 0.0 };
+            let color = image::Rgb([
+                (low[0] + (high[0] - low[0]) * value) as u8,
+                (low[1] + (high[1] - low[1]) * value) as u8,
+                (low[2] + (high[2] - low[2]) * value) as u8,
+            ]);
+
+            let x = left + col as u32 * CELL;
+            let y = top + row as u32 * CELL;
+            fill_rect(&mut img, x, y, CELL - 1, CELL - 1, color);
+
+            let text = count.to_string();
+            let text_width = text.len() as u32 * GLYPH_ADVANCE * SCALE;
+            let text_color = if value > 0.5 { white } else { black };
+            draw_text(&mut img, x + (CELL - text_width) / 2, y + (CELL - 5 * SCALE) / 2, &text, SCALE, text_color);
+        }
+    }
+
+    img
+}
+
+// Итоговый отчет об оценке модели
+#[derive(Serialize)]
+struct EvaluationReport {
+    samples: i64,
+    error_type: HeadMetrics,
+    os_type: HeadMetrics,
+}
+
+// Оценка модели для ошибок ОС на размеченном наборе
+fn evaluate_os_error_model(
+    model_path: &str,
+    data_dir: &str,
+    json: bool,
+    confusion_out: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let models = OsErrorModelBundle::load(model_path)?;
+    let (images, error_labels, os_labels) = load_os_error_dataset(data_dir, models.input_size())?;
+    let num_samples = images.size()[0];
+
+    let mut error_predictions: Vec<i64> = Vec::with_capacity(num_samples as usize);
+    let mut os_predictions: Vec<i64> = Vec::with_capacity(num_samples as usize);
+    tch::no_grad(|| {
+        let mut start = 0;
+        while start < num_samples {
+            let length = EVAL_BATCH_SIZE.min(num_samples - start);
+            let (error_logits, os_logits) = models.forward(&images.narrow(0, start, length));
+            error_predictions.extend(Vec::<i64>::from(&error_logits.argmax(-1, false)));
+            os_predictions.extend(Vec::<i64>::from(&os_logits.argmax(-1, false)));
+            start += length;
+        }
+    });
+
+    let report = EvaluationReport {
+        samples: num_samples,
+        error_type: HeadMetrics::compute(OS_ERROR_TYPES, &Vec::<i64>::from(&error_labels), &error_predictions),
+        os_type: HeadMetrics::compute(OS_TYPES, &Vec::<i64>::from(&os_labels), &os_predictions),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("Оценено примеров: {}\n", report.samples);
+        report.error_type.print("Тип ошибки");
+        report.os_type.print("Тип ОС");
+    }
+
+    if let Some(path) = confusion_out {
+        let labels: Vec<String> = report.error_type.classes.iter().map(|class| class.label.clone()).collect();
+        render_confusion_matrix(&report.error_type.confusion_matrix, &labels)
+            .save_with_format(path, image::ImageFormat::Png)?;
+        if !json {
+            println!("Матрица ошибок сохранена в {}", path);
+        }
+    }
+
+    Ok(())
+}
+
+// Число корзин по уверенности для ECE
+const CALIBRATION_BINS: usize = 15;
+
+// Шаги и скорость градиентного спуска по логарифму температуры
+const CALIBRATION_STEPS: usize = 300;
+const CALIBRATION_LEARNING_RATE: f64 = 0.01;
+
+// Ожидаемая ошибка калибровки (ECE): примеры делятся на корзины по уверенности,
+// в каждой сравниваются доля верных ответов и средняя уверенность, разности
+// усредняются с весом по числу примеров в корзине
+fn expected_calibration_error(logits: &Tensor, labels: &Tensor, temperature: f64) -> f64 {
+    let (confidence, predicted) = softmax_with_temperature(logits, temperature).max_dim(-1, false);
+    let confidence = Vec::<f32>::from(&confidence);
+    let predicted = Vec::<i64>::from(&predicted);
+    let labels = Vec::<i64>::from(labels);
+
+    // (примеров, сумма уверенности, верных ответов) по корзинам
+    let mut bins = vec![(0usize, 0.0f64, 0usize); CALIBRATION_BINS];
+    for ((confidence, predicted), label) in confidence.iter().zip(&predicted).zip(&labels) {
+        let bin = ((*confidence as f64 * CALIBRATION_BINS as f64) as usize).min(CALIBRATION_BINS - 1);
+        bins[bin].0 += 1;
+        bins[bin].1 += *confidence as f64;
+        bins[bin].2 += usize::from(predicted == label);
+    }
+
+    let total = labels.len().max(1) as f64;
+    bins.iter()
+        .filter(|(count, _, _)| *count > 0)
+        .map(|(count, confidence_sum, correct)| {
+            let count = *count as f64;
+            (*correct as f64 / count - confidence_sum / count).abs() * count / total
+        })
+        .sum()
+}
+
+// Температура, минимизирующая суммарный NLL обеих голов на отложенных логитах.
+// Оптимизируется логарифм температуры, чтобы она оставалась положительной
+fn fit_temperature(
+    error_logits: &Tensor,
+    error_labels: &Tensor,
+    os_logits: &Tensor,
+    os_labels: &Tensor,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let log_temperature = vs.root().zeros("log_temperature", &[1]);
+    let mut optimizer = nn::Adam::default().build(&vs, CALIBRATION_LEARNING_RATE)?;
+
+    for _ in 0..CALIBRATION_STEPS {
+        let temperature = log_temperature.exp();
+        let loss = (error_logits / &temperature).cross_entropy_for_logits(error_labels)
+            + (os_logits / &temperature).cross_entropy_for_logits(os_labels);
+        optimizer.backward_step(&loss);
+    }
+
+    let temperature = log_temperature.exp().double_value(&[0]);
+    validate_temperature(temperature).map_err(|e| e.into())
+}
+
+// Результат калибровки: температура и ECE обеих голов до и после
+#[derive(Serialize)]
+struct CalibrationReport {
+    samples: i64,
+    temperature_before: f64,
+    temperature: f64,
+    error_type_ece_before: f64,
+    error_type_ece: f64,
+    os_type_ece_before: f64,
+    os_type_ece: f64,
+}
+
+// Подбор температуры softmax на валидационном наборе и запись ее в метаданные модели.
+// Все пути инференса берут температуру из метаданных, поэтому перезапуска с флагами не нужно
+fn calibrate_os_error_model(model_path: &str, data_dir: &str, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let models = OsErrorModelBundle::load(model_path)?;
+    let (images, error_labels, os_labels) = load_os_error_dataset(data_dir, models.input_size())?;
+    let num_samples = images.size()[0];
+    if num_samples == 0 {
+        return Err(format!("В {} нет размеченных скриншотов", data_dir).into());
+    }
+
+    // Логиты считаются один раз, дальше оптимизируется только температура
+    let (error_logits, os_logits) = tch::no_grad(|| {
+        let mut error_logits = Vec::new();
+        let mut os_logits = Vec::new();
+        let mut start = 0;
+        while start < num_samples {
+            let length = EVAL_BATCH_SIZE.min(num_samples - start);
+            let (error_batch, os_batch) = models.forward(&images.narrow(0, start, length));
+            error_logits.push(error_batch.to_kind(Kind::Float).to_device(Device::Cpu));
+            os_logits.push(os_batch.to_kind(Kind::Float).to_device(Device::Cpu));
+            start += length;
+        }
+        (Tensor::cat(&error_logits, 0), Tensor::cat(&os_logits, 0))
+    });
+
+    let temperature_before = models.metadata.temperature;
+    let temperature = fit_temperature(&error_logits, &error_labels, &os_logits, &os_labels)?;
+    let report = CalibrationReport {
+        samples: num_samples,
+        temperature_before,
+        temperature,
+        error_type_ece_before: expected_calibration_error(&error_logits, &error_labels, temperature_before),
+        error_type_ece: expected_calibration_error(&error_logits, &error_labels, temperature),
+        os_type_ece_before: expected_calibration_error(&os_logits, &os_labels, temperature_before),
+        os_type_ece: expected_calibration_error(&os_logits, &os_labels, temperature),
+    };
+
+    // Метаданные перечитываются с диска, чтобы не подменить сохраненный словарь меток
+    let mut metadata = ModelMetadata::load(model_path)?.unwrap_or(models.metadata);
+    metadata.temperature = temperature;
+    metadata.save(model_path)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("Откалибровано на {} примерах", report.samples);
+        println!("Температура: {:.3} -> {:.3}", report.temperature_before, report.temperature);
+        println!("ECE типа ошибки: {:.4} -> {:.4}", report.error_type_ece_before, report.error_type_ece);
+        println!("ECE типа ОС: {:.4} -> {:.4}", report.os_type_ece_before, report.os_type_ece);
+        println!("Температура записана в {}", ModelMetadata::path_for(model_path).display());
+    }
+
+    Ok(())
+}
+
+// Прогревочные прогоны перед замером: первые вызовы включают выделение памяти
+// и выбор алгоритмов cuDNN и не отражают установившуюся скорость
+const BENCHMARK_WARMUP_ITERATIONS: usize = 10;
+
+// Результаты замера скорости инференса
+#[derive(Serialize)]
+struct BenchmarkReport {
+    device: String,
+    precision: String,
+    torch_threads: i32,
+    batch_size: i64,
+    input_size: i64,
+    iterations: usize,
+    mean_ms: f64,
+    p50_ms: f64,
+    p95_ms: f64,
+    images_per_second: f64,
+}
+
+// Перцентиль по отсортированным значениям (ближайший ранг)
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// Замер задержки и пропускной способности модели ошибок ОС.
+// Вход - случайные батчи, набор данных не нужен
+fn benchmark_os_error_model(
+    model_path: &str,
+    iterations: usize,
+    batch_size: i64,
+    device: Device,
+    precision: Precision,
+    labels: &Labels,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if iterations == 0 {
+        return Err("--iterations должно быть больше нуля".into());
+    }
+    if batch_size < 1 {
+        return Err("--batch-size должен быть больше нуля".into());
+    }
+
+    let models = OsErrorModelBundle::load_on(model_path, device, labels)?.with_precision(precision);
+    let device = models.device();
+    let size = models.input_size();
+    let input = Tensor::rand(&[batch_size, 3, size, size], (Kind::Float, device));
+
+    // Чтение результата на CPU дожидается завершения асинхронных ядер CUDA
+    let forward = || {
+        let (error_logits, os_logits) = models.forward(&input);
+        f64::from(&(error_logits.sum(Kind::Float) + os_logits.sum(Kind::Float)))
+    };
+
+    let mut latencies_ms = Vec::with_capacity(iterations);
+    tch::no_grad(|| {
+        for _ in 0..BENCHMARK_WARMUP_ITERATIONS {
+            forward();
+        }
+        for _ in 0..iterations {
+            let started = std::time::Instant::now();
+            forward();
+            latencies_ms.push(started.elapsed().as_secs_f64() * 1000.0);
+        }
+    });
+
+    let mean_ms = latencies_ms.iter().sum::<f64>() / iterations as f64;
+    latencies_ms.sort_by(|a, b| a.total_cmp(b));
+    let report = BenchmarkReport {
+        device: format!("{:?}", device),
+        precision: format!("{:?}", precision).to_lowercase(),
+        torch_threads: tch::get_num_threads(),
+        batch_size,
+        input_size: size,
+        iterations,
+        mean_ms,
+        p50_ms: percentile(&latencies_ms, 50.0),
+        p95_ms: percentile(&latencies_ms, 95.0),
+        images_per_second: batch_size as f64 * 1000.0 / mean_ms,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("Устройство: {} ({}), батч: {}, вход: {}x{}", report.device, report.precision, report.batch_size, size, size);
+        println!("Потоки libtorch: {}", report.torch_threads);
+        println!("Прогонов: {} (после {} прогревочных)", report.iterations, BENCHMARK_WARMUP_ITERATIONS);
+        println!("Задержка: среднее {:.2} мс, p50 {:.2} мс, p95 {:.2} мс", report.mean_ms, report.p50_ms, report.p95_ms);
+        println!("Пропускная способность: {:.1} изображений/с", report.images_per_second);
+    }
+
+    Ok(())
+}
+
+// Результат одной проверки doctor
+struct DoctorCheck {
+    name: String,
+    passed: bool,
+    critical: bool, // Провал критической проверки - ненулевой код выхода
+    details: String,
+}
+
+// Пробный прямой проход обеих архитектур на случайных весах: проверяет, что libtorch
+// загружается и выполняет свертки на устройстве. Паника tch превращается в FAIL
+fn doctor_forward_checks(device: Device, critical: bool) -> Vec<DoctorCheck> {
+    let check = |name: &str, run: &dyn Fn() -> Result<String, String>| {
+        let result = catch_inference(run).map_err(|e| e.to_string()).and_then(|result| result);
+        DoctorCheck {
+            name: format!("{} ({:?})", name, device),
+            passed: result.is_ok(),
+            critical,
+            details: result.unwrap_or_else(|e| e),
+        }
+    };
+
+    let simple = check("Прямой проход simple_cnn", &|| {
+        let vs = nn::VarStore::new(device);
+        let model = simple_cnn(&vs.root(), 10, &CnnConfig::default());
+        let input = Tensor::rand(&[1, 3, SIMPLE_CNN_INPUT_SIZE, SIMPLE_CNN_INPUT_SIZE], (Kind::Float, device));
+        let output = tch::no_grad(|| model.forward(&input));
+        match output.size() {
+            shape if shape == vec![1, 10] => Ok(format!("выход {:?}", shape)),
+            shape => Err(format!("выход формы {:?}, ожидалась [1, 10]", shape)),
+        }
+    });
+    let os_error = check("Прямой проход os_error_cnn", &|| {
+        let vs = nn::VarStore::new(device);
+        let (error_model, os_model) = os_error_cnn(&vs.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
+        let input = Tensor::rand(&[1, 3, DEFAULT_OS_INPUT_SIZE, DEFAULT_OS_INPUT_SIZE], (Kind::Float, device));
+        let (error_output, os_output) = tch::no_grad(|| (error_model.forward_t(&input, false), os_model.forward_t(&input, false)));
+        let expected = (vec![1, OS_ERROR_TYPES.len() as i64], vec![1, OS_TYPES.len() as i64]);
+        let shapes = (error_output.size(), os_output.size());
+        if shapes == expected {
+            Ok(format!("выходы {:?} и {:?}", shapes.0, shapes.1))
+        } else {
+            Err(format!("выходы {:?} и {:?}, ожидались {:?} и {:?}", shapes.0, shapes.1, expected.0, expected.1))
+        }
+    });
+    vec![simple, os_error]
+}
+
+// Самопроверка окружения: версии, CUDA, потоки и пробный инференс обеих архитектур.
+// Возвращает false, если провалилась хотя бы одна критическая проверка
+fn run_doctor() -> bool {
+    println!("bashpic {}, tch {}", env!("CARGO_PKG_VERSION"), env!("TCH_VERSION"));
+    match std::env::var("LIBTORCH") {
+        Ok(path) => println!("libtorch: {} (LIBTORCH)", path),
+        Err(_) => println!("libtorch: загружена torch-sys при сборке (LIBTORCH не задан)"),
+    }
+    println!("Потоки libtorch: intra-op {}, inter-op {}", tch::get_num_threads(), tch::get_num_interop_threads());
+
+    let cuda_devices = tch::Cuda::device_count();
+    let mut checks = vec![DoctorCheck {
+        name: "CUDA".to_string(),
+        passed: tch::Cuda::is_available(),
+        critical: false,
+        details: if tch::Cuda::is_available() {
+            format!("устройств: {}, cuDNN: {}", cuda_devices, if tch::Cuda::cudnn_is_available() { "да" } else { "нет" })
+        } else {
+            "недоступна, инференс только на CPU".to_string()
+        },
+    }];
+    checks.extend(doctor_forward_checks(Device::Cpu, true));
+    // Если CUDA видна, но свертки на ней не выполняются, сервер с --device cuda не заработает
+    if tch::Cuda::is_available() {
+        checks.extend(doctor_forward_checks(Device::Cuda(0), true));
+    }
+
+    for check in &checks {
+        let status = match (check.passed, check.critical) {
+            (true, _) => "PASS",
+            (false, true) => "FAIL",
+            (false, false) => "WARN",
+        };
+        println!("{:<4}  {}: {}", status, check.name, check.details);
+    }
+    let failed = checks.iter().filter(|check| !check.passed && check.critical).count();
+    if failed == 0 {
+        println!("Все критические проверки пройдены");
+    } else {
+        println!("Провалено критических проверок: {}", failed);
+    }
+    failed == 0
+}
+
+// Число случайных изображений для сравнения исходной и квантованной модели
+const QUANTIZE_CHECK_SAMPLES: i64 = 64;
+
+// Доля совпадающих предсказаний, ниже которой quantize предупреждает о потере точности
+const QUANTIZE_MIN_AGREEMENT: f64 = 0.95;
+
+// Среднее время прогона обеих голов на батче, мс
+fn mean_forward_ms(models: &OsErrorModelBundle, input: &Tensor, iterations: usize) -> f64 {
+    tch::no_grad(|| {
+        for _ in 0..BENCHMARK_WARMUP_ITERATIONS {
+            let _ = models.error_model.forward_t(input, false);
+            let _ = models.os_model.forward_t(input, false);
+        }
+        let started = std::time::Instant::now();
+        for _ in 0..iterations {
+            let _ = models.error_model.forward_t(input, false);
+            let _ = models.os_model.forward_t(input, false);
+        }
+        started.elapsed().as_secs_f64() * 1000.0 / iterations as f64
+    })
+}
+
+// Доля совпадающих argmax и максимальное расхождение вероятностей двух моделей
+fn prediction_agreement(reference: &Tensor, candidate: &Tensor) -> (f64, f64) {
+    let agreement = f64::from(
+        &reference.argmax(-1, false).eq_tensor(&candidate.argmax(-1, false)).to_kind(Kind::Float).mean(Kind::Float),
+    );
+    let max_diff = f64::from(
+        &(reference.softmax(-1, Kind::Float) - candidate.softmax(-1, Kind::Float)).abs().max(),
+    );
+    (agreement, max_diff)
+}
+
+// Динамическое int8-квантование полносвязных слоев модели ошибок ОС.
+// Сверточные слои остаются float32; метаданные копируются с пометкой quantization
+fn quantize_os_error_model(model_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let labels = ModelMetadata::load(model_path)?
+        .map(|metadata| Labels::from(&metadata))
+        .unwrap_or_else(Labels::builtin);
+    let source = OsErrorModelBundle::load_on(model_path, Device::Cpu, &labels)?;
+    if source.metadata.quantization.is_some() {
+        return Err(format!("Модель {} уже квантована", model_path).into());
+    }
+
+    let mut named_tensors: Vec<(String, Tensor)> = Vec::new();
+    tch::no_grad(|| {
+        for (name, tensor) in source.vs.variables() {
+            let layer = name.strip_suffix(".weight").filter(|layer| OS_LINEAR_LAYERS.contains(layer));
+            match layer {
+                Some(layer) => {
+                    let (qweight, scale, zero_point) = quantize_weight(&tensor);
+                    named_tensors.push((format!("{}.qweight", layer), qweight));
+                    named_tensors.push((format!("{}.scale", layer), Tensor::of_slice(&[scale])));
+                    named_tensors.push((format!("{}.zero_point", layer), Tensor::of_slice(&[zero_point])));
+                }
+                None => named_tensors.push((name, tensor)),
+            }
+        }
+    });
+
+    Tensor::save_multi(&named_tensors, output_path)?;
+    let mut metadata = source.metadata.clone();
+    metadata.quantization = Some(QUANTIZATION_DYNAMIC_INT8.to_string());
+    metadata.save(output_path)?;
+
+    // Проверка на случайных данных: совпадение предсказаний и скорость до и после квантования
+    let quantized = OsErrorModelBundle::load_on(output_path, Device::Cpu, &labels)?;
+    let size = source.input_size();
+    let input = Tensor::rand(&[QUANTIZE_CHECK_SAMPLES, 3, size, size], (Kind::Float, Device::Cpu));
+    let (error_agreement, error_diff, os_agreement, os_diff) = tch::no_grad(|| {
+        let (error_agreement, error_diff) = prediction_agreement(
+            &source.error_model.forward_t(&input, false),
+            &quantized.error_model.forward_t(&input, false),
+        );
+        let (os_agreement, os_diff) = prediction_agreement(
+            &source.os_model.forward_t(&input, false),
+            &quantized.os_model.forward_t(&input, false),
+        );
+        (error_agreement, error_diff, os_agreement, os_diff)
+    });
+    let batch = input.narrow(0, 0, 1);
+    let float_ms = mean_forward_ms(&source, &batch, 20);
+    let quantized_ms = mean_forward_ms(&quantized, &batch, 20);
+
+    let megabytes = |path: &str| std::fs::metadata(path).map(|m| m.len() as f64 / 1_048_576.0).unwrap_or(0.0);
+    println!("Квантованная модель сохранена в {}", output_path);
+    println!("Размер: {:.1} МБ -> {:.1} МБ", megabytes(model_path), megabytes(output_path));
+    println!("Совпадение предсказаний на {} случайных изображениях: ошибки {:.1}% (макс. расхождение вероятностей {:.3}), ОС {:.1}% ({:.3})",
+        QUANTIZE_CHECK_SAMPLES, error_agreement * 100.0, error_diff, os_agreement * 100.0, os_diff);
+    println!("Время прогона одного изображения: float32 {:.2} мс, int8 {:.2} мс (ускорение x{:.2})",
+        float_ms, quantized_ms, float_ms / quantized_ms);
+    if error_agreement.min(os_agreement) < QUANTIZE_MIN_AGREEMENT {
+        eprintln!("Внимание: квантованная модель расходится с исходной более чем на {:.0}% предсказаний, проверьте ее командой evaluate",
+            (1.0 - QUANTIZE_MIN_AGREEMENT) * 100.0);
+    }
+
+    Ok(())
+}
+
+// Непрозрачность тепловой карты при наложении на скриншот
+const HEATMAP_ALPHA: f32 = 0.45;
+
+// Grad-CAM для предсказанного типа ошибки: веса каналов - средний градиент логита класса
+// по карте признаков conv3, карта - ReLU взвешенной суммы каналов, нормированная в [0, 1].
+// Возвращает карту [h, w] (нулевую, если значимых областей нет) и логиты головы ошибок
+fn grad_cam(layers: &OsErrorLayers, input: &Tensor) -> (Tensor, Tensor) {
+    // Карта признаков отделяется от графа, чтобы градиент накапливался прямо в ней
+    let activations = tch::no_grad(|| input.apply_t(&layers.conv_features(), false)).set_requires_grad(true);
+    let logits = pool_conv_features(&activations).apply_t(&layers.error_classifier, false);
+    let class = i64::from(&logits.argmax(-1, false));
+    logits.get(0).get(class).backward();
+
+    let weights = activations.grad().mean_dim(&[2, 3], true, Kind::Float);
+    let cam = (weights * &activations).sum_dim_intlist(&[1], false, Kind::Float).relu().get(0).detach();
+    let (min, max) = (f64::from(&cam.min()), f64::from(&cam.max()));
+    let cam = if max - min > 1e-8 { (cam - min) / (max - min) } else { cam.zeros_like() };
+    (cam, logits.detach())
+}
+
+// Цветовая шкала jet: 0 - синий, 0.5 - зеленый, 1 - красный
+fn jet_color(value: f32) -> [f32; 3] {
+    let channel = |center: f32| (1.5 - (4.0 * value - center).abs()).clamp(0.0, 1.0) * 255.0;
+    [channel(3.0), channel(2.0), channel(1.0)]
+}
+
+// Наложение карты Grad-CAM [h, w] на исходное изображение в его полном размере
+fn overlay_heatmap(img: &image::DynamicImage, cam: &Tensor) -> image::RgbImage {
+    let (width, height) = (img.width(), img.height());
+    let cam_size = cam.size();
+    let values = Vec::<f32>::from(
+        &cam.view([1, 1, cam_size[0], cam_size[1]])
+            .upsample_bilinear2d(&[height as i64, width as i64], false, None, None)
+            .clamp(0.0, 1.0)
+            .flatten(0, -1),
+    );
+    let base = to_rgb8_on_white(img);
+
+    image::RgbImage::from_fn(width, height, |x, y| {
+        let heat = jet_color(values[(y * width + x) as usize]);
+        let pixel = base.get_pixel(x, y);
+        image::Rgb(std::array::from_fn(|c| {
+            (pixel[c] as f32 * (1.0 - HEATMAP_ALPHA) + heat[c] * HEATMAP_ALPHA).round() as u8
+        }))
+    })
+}
+
+// Тепловая карта Grad-CAM по последнему сверточному слою для предсказанного типа ошибки,
+// наложенная на скриншот и сохраненная в PNG
+fn explain_os_error(
+    model_path: &str,
+    screenshot_path: &str,
+    output_path: &str,
+    labels: &Labels,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = resolve_os_error_metadata(model_path, labels)?;
+    if metadata.quantization.is_some() {
+        return Err(format!("Модель {} квантована, для Grad-CAM нужна исходная модель float32", model_path).into());
+    }
+
+    let mut vs = nn::VarStore::new(Device::Cpu);
+    let layers = os_error_cnn_layers(
+        &vs.root(),
+        metadata.num_classes,
+        metadata.os_types.len() as i64,
+        &mut |p, name, in_dim, out_dim| Ok(nn::linear(p / name, in_dim, out_dim, Default::default())),
+    )?;
+    vs.load(model_path)?;
+    vs.freeze();
+
+    let bytes = std::fs::read(screenshot_path)
+        .map_err(|e| format!("Не удалось прочитать {}: {}", screenshot_path, e))?;
+    let img = detect_and_decode(&bytes)?;
+    let input = metadata.normalize(&image_to_tensor(&img, metadata.input_size()));
+
+    let (cam, logits) = grad_cam(&layers, &input);
+    let probs = softmax_with_temperature(&logits, metadata.temperature);
+    let (confidence, class) = probs.max_dim(-1, false);
+    let class = i64::from(&class) as usize;
+
+    overlay_heatmap(&img, &cam).save_with_format(output_path, image::ImageFormat::Png)?;
+
+    println!("Тип ошибки: {} (уверенность: {:.2}%)", metadata.error_label(class), f64::from(&confidence) * 100.0);
+    if f64::from(&cam.max()) == 0.0 {
+        eprintln!("Внимание: для этого предсказания Grad-CAM не выделил значимых областей");
+    }
+    println!("Тепловая карта сохранена в {}", output_path);
+    Ok(())
+}
+
+// Параметры подключения к чату
+#[derive(Deserialize)]
+struct ChatQuery {
+    // Токен, по которому после обрыва соединения продолжается тот же разговор
+    session: Option<String>,
+}
+
+// Токен выбирает клиент, поэтому он проверяется: 16-128 символов из латинских букв, цифр, '-' и '_'
+fn validate_session_token(token: &str) -> Result<(), AppError> {
+    if SESSION_TOKEN_LEN.contains(&token.len())
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(AppError::BadInput(format!(
+            "Токен сессии должен содержать от {} до {} латинских букв, цифр, '-' или '_'",
+            SESSION_TOKEN_LEN.start(),
+            SESSION_TOKEN_LEN.end()
+        )))
+    }
+}
+
+// WebSocket обработчик
+async fn websocket_handler(
+    req: HttpRequest,
+    query: web::Query<ChatQuery>,
+    stream: web::Payload,
+    srv: web::Data<Addr<ChatServer>>,
+    max_body_size: web::Data<MaxBodySize>,
+    heartbeat: web::Data<ChatHeartbeat>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let MaxBodySize(max_message_size) = **max_body_size;
+    let token = query.into_inner().session;
+    if let Some(token) = &token {
+        validate_session_token(token)?;
+    }
+    let chat_session = ChatSession {
+        id: Uuid::new_v4(),
+        token,
+        addr: srv.get_ref().clone(),
+        max_message_size,
+        heartbeat: **heartbeat,
+        last_heartbeat: std::time::Instant::now(),
+        binary_frames: 0,
+    };
+
+    // Скриншоты в base64 не помещаются в стандартный кадр actix (64 КБ)
+    ws::WsResponseBuilder::new(chat_session, &req, stream)
+        .frame_size(max_message_size)
+        .start()
+}
+
+// Стили страницы чата, общие со страницей /shared/{token}
+const CHAT_STYLE: &str = r#"
+        body { font-family: Arial, sans-serif; margin: 0; padding: 20px; background-color: #f5f5f5; }
+        .container { max-width: 800px; margin: 0 auto; background: white; border-radius: 10px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); }
+        .header { background: #007bff; color: white; padding: 20px; border-radius: 10px 10px 0 0; text-align: center; }
+        .chat-area { height: 400px; overflow-y: auto; padding: 20px; border-bottom: 1px solid #eee; }
+        .message { margin: 10px 0; padding: 10px; border-radius: 5px; }
+        .user-message { background: #e3f2fd; margin-left: 20%; }
+        .bot-message { background: #f1f8e9; margin-right: 20%; }
+        .error-analysis { background: #fff3e0; border-left: 4px solid #ff9800; padding: 15px; margin: 10px 0; }
+        .suggestions { background: #f3e5f5; border-left: 4px solid #9c27b0; padding: 15px; margin: 10px 0; }
+        .input-area { padding: 20px; display: flex; gap: 10px; }
+        .input-area input { flex: 1; padding: 10px; border: 1px solid #ddd; border-radius: 5px; }
+        .input-area button { padding: 10px 20px; background: #007bff; color: white; border: none; border-radius: 5px; cursor: pointer; }
+        .file-input { margin: 10px 0; }
+        .status { padding: 5px 20px; background: #e8f5e8; border-bottom: 1px solid #ddd; font-size: 14px; }
+        .error-type { font-weight: bold; color: #d32f2f; }
+        .os-type { font-weight: bold; color: #1976d2; }
+        .confidence { font-weight: bold; color: #388e3c; }
+"#;
+
+// Страница чата
+async fn chat_page() -> Result<HttpResponse, actix_web::Error> {
+    let html = r#"
+<!DOCTYPE html>
+<html>
+<head>
+    <title>AI Error Analysis Chat</title>
+    <meta charset="utf-8">
+    <style>
+{style}    </style>
+</head>
+<body>
+    <div class="container">
+        <div class="header">
+            <h1>🤖 AI Assistant для анализа ошибок ОС</h1>
+            <p>Загрузите скриншот ошибки или задайте вопрос</p>
+        </div>
+        <div class="status" id="status">Подключение к серверу...</div>
+        <div class="chat-area" id="chatArea"></div>
+        <div class="input-area">
+            <input type="text" id="messageInput" placeholder="Введите ваш вопрос или опишите проблему..." onkeypress="handleKeyPress(event)">
+            <input type="file" id="imageInput" accept="image/*" class="file-input" multiple onchange="handleImageUpload(event)">
+            <button onclick="sendMessage()">Отправить</button>
+        </div>
+    </div>
+
+    <script>
+        let socket;
+        let connected = false;
+        let streamDiv = null;
+        let pendingDiv = null;
+        // Токен разговора: после переподключения сервер продолжает тот же разговор
+        const sessionToken = window.crypto && crypto.randomUUID ? crypto.randomUUID() : null;
+        // API-ключ из адреса страницы (/chat?api_key=...), если на сервере заданы ключи
+        const apiKey = new URLSearchParams(window.location.search).get('api_key');
+
+        function connectWebSocket() {
+            const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+            const params = new URLSearchParams();
+            if (sessionToken) params.set('session', sessionToken);
+            if (apiKey) params.set('api_key', apiKey);
+            const query = params.toString() ? '?' + params.toString() : '';
+            const wsUrl = protocol + '//' + window.location.host + '/ws/' + query;
+
+            socket = new WebSocket(wsUrl);
+
+            socket.onopen = function(event) {
+                connected = true;
+                document.getElementById('status').textContent = '✅ Подключено к AI помощнику';
+                document.getElementById('status').style.background = '#e8f5e8';
+
+                addMessage('bot', 'Привет! Я AI помощник для анализа ошибок операционных систем. Загрузите скриншот ошибки или задайте вопрос о проблемах с компьютером.');
+            };
+
+            socket.onmessage = function(event) {
+                const response = JSON.parse(event.data);
+                handleBotResponse(response);
+            };
+
+            socket.onclose = function(event) {
+                connected = false;
+                document.getElementById('status').textContent = '❌ Соединение потеряно. Переподключение...';
+                document.getElementById('status').style.background = '#ffebee';
+                setTimeout(connectWebSocket, 3000);
+            };
+
+            socket.onerror = function(error) {
+                console.error('WebSocket error:', error);
+                document.getElementById('status').textContent = '❌ Ошибка подключения';
+                document.getElementById('status').style.background = '#ffebee';
+            };
+        }
+
+        function renderAnalysis(analysis, title) {
+            return `
+                    <div class="error-analysis">
+                        <h4>📊 ${title}:</h4>
+                        <p><span class="error-type">Тип ошибки:</span> ${analysis.error_type}</p>
+                        <p><span class="os-type">Операционная система:</span> ${analysis.os_type}</p>
+                        <p><span class="confidence">Уверенность:</span> ${(analysis.confidence * 100).toFixed(1)}%</p>
+                        <p><strong>Описание:</strong> ${analysis.detailed_description}</p>
+
+                        <h5>🔍 Возможные причины:</h5>
+                        <ul>
+                            ${analysis.possible_causes.map(cause => `<li>${cause}</li>`).join('')}
+                        </ul>
+
+                        <h5>🛠️ Рекомендуемые решения:</h5>
+                        <ul>
+                            ${analysis.solutions.map(solution => `<li>${solution}</li>`).join('')}
+                        </ul>
+                    </div>
+                `;
+        }
+
+        function addMessage(type, content, analysis = null, suggestions = null, analyses = null) {
+            const chatArea = document.getElementById('chatArea');
+            const messageDiv = document.createElement('div');
+            messageDiv.className = 'message ' + type + '-message';
+
+            let html = '<div>' + content.replace(/\n/g, '<br>') + '</div>';
+
+            if (analysis) {
+                html += renderAnalysis(analysis, 'Анализ ошибки');
+            }
+
+            if (analyses) {
+                html += analyses.map((item, i) => renderAnalysis(item, `Анализ ошибки (${i + 1})`)).join('');
+            }
+
+            if (suggestions && suggestions.length > 0) {
+                html += `
+                    <div class="suggestions">
+                        <h5>💡 Дополнительные рекомендации:</h5>
+                        <ul>
+                            ${suggestions.map(suggestion => `<li>${suggestion}</li>`).join('')}
+                        </ul>
+                    </div>
+                `;
+            }
+
+            messageDiv.innerHTML = html;
+            chatArea.appendChild(messageDiv);
+            chatArea.scrollTop = chatArea.scrollHeight;
+        }
+
+        // Фрагменты объяснения языковой модели, пока не придет итоговый ответ
+        function appendStreamChunk(chunk) {
+            const chatArea = document.getElementById('chatArea');
+            if (!streamDiv) {
+                streamDiv = document.createElement('div');
+                streamDiv.className = 'message bot-message';
+                chatArea.appendChild(streamDiv);
+            }
+            streamDiv.textContent += chunk;
+            chatArea.scrollTop = chatArea.scrollHeight;
+        }
+
+        function handleBotResponse(response) {
+            if (response.training_progress) {
+                document.getElementById('status').textContent = '🧠 ' + response.response;
+                return;
+            }
+            // Подтверждение приема скриншота показывается, пока не придет ответ
+            if (pendingDiv) {
+                pendingDiv.remove();
+                pendingDiv = null;
+            }
+            if (response.pending !== undefined) {
+                const chatArea = document.getElementById('chatArea');
+                pendingDiv = document.createElement('div');
+                pendingDiv.className = 'message bot-message';
+                pendingDiv.textContent = '⏳ ' + response.response;
+                chatArea.appendChild(pendingDiv);
+                chatArea.scrollTop = chatArea.scrollHeight;
+                return;
+            }
+            if (response.chunk !== undefined) {
+                appendStreamChunk(response.chunk);
+                return;
+            }
+            if (streamDiv) {
+                streamDiv.remove();
+                streamDiv = null;
+            }
+            addMessage('bot', response.response, response.analysis, response.suggestions, response.analyses);
+        }
+
+        function sendMessage() {
+            const input = document.getElementById('messageInput');
+            const message = input.value.trim();
+
+            if (message && connected) {
+                addMessage('user', message);
+
+                socket.send(JSON.stringify({
+                    message: message,
+                    image_data: null
+                }));
+
+                input.value = '';
+            }
+        }
+
+        function readAsBase64(file) {
+            return new Promise((resolve, reject) => {
+                const reader = new FileReader();
+                reader.onload = e => resolve(e.target.result.split(',')[1]);
+                reader.onerror = reject;
+                reader.readAsDataURL(file);
+            });
+        }
+
+        function handleImageUpload(event) {
+            const files = Array.from(event.target.files);
+            if (files.length > 0 && connected) {
+                Promise.all(files.map(readAsBase64)).then(images => {
+                    addMessage('user', images.length > 1
+                        ? `📷 Скриншоты загружены для анализа: ${images.length}`
+                        : '📷 Скриншот загружен для анализа...');
+
+                    socket.send(JSON.stringify({
+                        message: "Проанализируйте этот скриншот с ошибкой",
+                        image_data: images.length > 1 ? images : images[0]
+                    }));
+                });
+
+                // Очистить input
+                event.target.value = '';
+            }
+        }
+
+        function handleKeyPress(event) {
+            if (event.key === 'Enter') {
+                sendMessage();
+            }
+        }
+
+        // Подключение при загрузке страницы
+        connectWebSocket();
+    </script>
+</body>
+</html>
+    "#;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(html.replace("{style}", CHAT_STYLE)))
+}
+
+// Список эндпоинтов на главной странице
+async fn index() -> HttpResponse {
+    HttpResponse::Ok().body("Сервер классификации изображений запущен!\n\
+                          Используйте:\n\
+                          POST /predict - для общей классификации\n\
+                          POST /predict-batch - общая классификация нескольких изображений (NDJSON)\n\
+                          POST /predict-os-error - для анализа ошибок ОС\n\
+                          POST /predict-url - анализ изображения, скачанного по URL\n\
+                          POST /analyze-text - анализ ошибки по текстовому описанию\n\
+                          POST /share - ссылка на анализ для передачи коллегам\n\
+                          GET /shared/{token} - страница анализа по ссылке\n\
+                          GET /labels - список типов ошибок и ОС\n\
+                          GET /model-info - архитектура загруженной модели ошибок ОС\n\
+                          GET /recent - последние предсказания модели ошибок ОС\n\
+                          GET /escalations - неуверенные анализы чата для проверки специалистом\n\
+                          GET /chat - для чата с AI помощником\n\
+                          WS /ws/ - WebSocket подключение для чата\n\
+                          POST /admin/train - переобучение модели ошибок ОС\n\
+                          GET /admin/train/status - статус переобучения\n\
+                          POST /admin/upload-model - загрузка новой модели ошибок ОС (multipart)")
+}
+
+// Маршруты сервера. Защита задается тем, в какую группу зарегистрирован маршрут, а не
+// сравнением пути: роутер сопоставляет путь после percent-декодирования, поэтому
+// /predict%2Dos-error и /%61dmin/train попадают в те же защищенные группы.
+// Открыты без ключа: главная, /chat, /shared/{token}, /labels и /model-info.
+// CORS применяется только к REST-маршрутам: WebSocket чата открывается со своей страницы
+// и не должен отклоняться по заголовку Origin. CORS снаружи проверки ключа, чтобы
+// preflight-запросы не требовали API-ключ
+fn configure_routes(cfg: &mut web::ServiceConfig, cors_allow_origins: &[String]) {
+    // Администрирование: ключ проверен api_key_auth, а без ключей закрыто полностью
+    let admin = web::scope("/admin")
+        .wrap(from_fn(admin_enabled))
+        .route("/train", web::post().to(admin_train))
+        .route("/train/status", web::get().to(admin_train_status))
+        .service(
+            web::resource("/upload-model")
+                .app_data(web::PayloadConfig::new(MAX_MODEL_UPLOAD_SIZE))
+                .route(web::post().to(admin_upload_model)),
+        );
+
+    // Все остальное под ключом, в том числе несуществующие пути
+    let protected = web::scope("")
+        .wrap(from_fn(api_key_auth))
+        .route("/predict", web::post().to(predict))
+        .route("/predict-batch", web::post().to(predict_batch))
+        .route("/predict-os-error", web::post().to(predict_os_error))
+        .route("/predict-url", web::post().to(predict_url))
+        .route("/analyze-text", web::post().to(analyze_text))
+        .route("/share", web::post().to(share_analysis))
+        .route("/recent", web::get().to(recent))
+        .route("/escalations", web::get().to(escalations))
+        .service(admin);
+
+    let rest = web::scope("")
+        .wrap(Condition::new(!cors_allow_origins.is_empty(), build_cors(cors_allow_origins)))
+        .route("/", web::get().to(index))
+        .route("/labels", web::get().to(labels))
+        .route("/model-info", web::get().to(model_info))
+        .service(protected);
+
+    // Чат анализирует скриншоты, поэтому WebSocket закрыт тем же ключом
+    cfg.service(
+        web::resource("/ws/")
+            .wrap(from_fn(websocket_api_key_auth))
+            .route(web::get().to(websocket_handler)),
+    )
+    .route("/chat", web::get().to(chat_page))
+    .route("/shared/{token}", web::get().to(shared_analysis_page))
+    .service(rest);
+}
+
+#[actix_web::main]
+async fn main() -> std::process::ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), CliError> {
+    let config = Config::load(cli.config.as_deref()).map_err(|e| CliError::Config(e.to_string()))?;
+    if config.torch_threads.is_some_and(|threads| threads < 1) {
+        return Err(CliError::Config("torch_threads в файле конфигурации должен быть больше нуля".to_string()));
+    }
+    configure_torch_threads(cli.torch_threads.or(config.torch_threads));
+
+    match cli.command {
+        Commands::Server {
+            host, port, grpc_port, model, os_error_model, api_keys, cors_allow_origins, tls_cert, tls_key, no_warmup,
+            shutdown_timeout,
+            device, strict_device, precision, no_cpu_fallback, labels: labels_path, screenshot_cache_size,
+            screenshot_dedup_distance, max_body_size, cache_size, recent_size, ws_ping_interval, ws_ping_timeout, max_sessions,
+            llm_endpoint, llm_model, llm_timeout, llm_retries, workers, models_dir, escalation_threshold, share_ttl,
+            session_ttl, animated_images, inference_timeout, fetch_allow_hosts, fetch_timeout,
+        } => {
+            tracing_subscriber::fmt::init();
+
+            let device = resolve_device(device, strict_device)
+                .map_err(CliError::Config)?;
+            let precision = resolve_precision(precision, device)
+                .map_err(CliError::Config)?;
+            let model_labels = Labels::load(labels_path.or(config.labels).as_deref())
+                .map_err(|e| CliError::Config(e.to_string()))?;
+
+            let host = host.unwrap_or(config.host);
+            let address = format!("{}:{}", host, port.unwrap_or(config.port));
+            let shutdown_timeout = std::time::Duration::from_secs(shutdown_timeout.unwrap_or(config.shutdown_timeout));
+            let grpc_address: std::net::SocketAddr = format!("{}:{}", host, grpc_port.unwrap_or(config.grpc_port))
+                .parse()
+                .map_err(|e| CliError::Config(format!("Некорректный адрес gRPC: {}", e)))?;
+            let model_path = model.unwrap_or(config.model);
+            let os_error_model_path = os_error_model.unwrap_or(config.os_error_model);
+            let api_keys = api_keys.or(config.api_keys);
+            let cors_allow_origins = if cors_allow_origins.is_empty() {
+                config.cors_allow_origins
+                    .iter()
+                    .map(|origin| parse_cors_origin(origin))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(CliError::Config)?
+            } else {
+                cors_allow_origins
+            };
+            if !cors_allow_origins.is_empty() {
+                println!("CORS разрешен для источников: {}", cors_allow_origins.join(", "));
+            }
+
+            // TLS проверяется до загрузки моделей, чтобы ошибка конфигурации была видна сразу
+            let tls_config = match (tls_cert.or(config.tls_cert), tls_key.or(config.tls_key)) {
+                (Some(cert), Some(key)) => Some(load_rustls_config(&cert, &key)?),
+                (None, None) => None,
+                _ => {
+                    return Err(CliError::Config("Для HTTPS необходимо указать и --tls-cert, и --tls-key".to_string()));
+                }
+            };
+
+            let scheme = if tls_config.is_some() { "https" } else { "http" };
+            println!("Запуск веб-сервера на {}://{}", scheme, address);
+
+            let api_keys = web::Data::new(ApiKeys::load(api_keys.as_deref())?);
+            if api_keys.is_enabled() {
+                println!("Аутентификация по API-ключу включена ({} ключей)", api_keys.keys.len());
+            } else {
+                println!("Внимание: API-ключи не заданы, эндпоинты предсказания открыты для всех, /admin/* отключены");
+            }
+
+            // Загрузка моделей; отсутствующая модель обучается заново, а если ее
+            // все равно не удается загрузить, сервер не запускается
+            let generic_model = ensure_model(&model_path, |path| load_generic_model(path, device), || {
+                let vs = nn::VarStore::new(Device::Cpu);
+                let model = simple_cnn(&vs.root(), 10, &CnnConfig::default());
+                let (train_images, train_labels) = create_dummy_data();
+                let train_config = TrainConfig {
+                    epochs: config.epochs,
+                    learning_rate: config.learning_rate,
+                    model_path: model_path.clone(),
+                    ..Default::default()
+                };
+                train_model(&model, &train_images, &train_labels, &vs, &train_config)
+            })?;
+
+            let model_data: web::Data<GenericModel> = web::Data::new(RwLock::new(InferenceModels(generic_model)));
+
+            // Архитектура модели для ошибок ОС восстанавливается по метаданным
+            let os_error_models = ensure_model(&os_error_model_path, |path| {
+                Ok(OsErrorModelBundle::load_on(path, device, &model_labels)?
+                    .with_precision(precision)
+                    .with_cpu_fallback(!no_cpu_fallback))
+            }, || {
+                train_os_error_model(&OsErrorTrainConfig {
+                    input_size: config.input_size,
+                    epochs: config.os_error_epochs,
+                    learning_rate: config.os_error_learning_rate,
+                    batch_size: config.os_error_batch_size,
+                    os_loss_weight: config.os_loss_weight,
+                    model_path: os_error_model_path.clone(),
+                    ..Default::default()
+                })
+            })?;
+
+            let os_error_model_data: web::Data<OsErrorModels> =
+                web::Data::new(RwLock::new(InferenceModels(os_error_models)));
+
+            // Сбой прогрева не мешает запуску: те же ошибки получат запросы в виде JSON
+            if !no_warmup
+                && let Err(e) = warmup_models(&model_data, &os_error_model_data)
+            {
+                eprintln!("Внимание: прогрев моделей не удался: {}", e);
+            }
+
+            // Основная модель доступна под именем своего файла и выбирается по умолчанию
+            let primary_name = Path::new(&os_error_model_path)
+                .file_stem()
+                .map_or_else(|| "default".to_string(), |stem| stem.to_string_lossy().into_owned());
+            let mut model_registry = OsErrorModelRegistry::new(primary_name, os_error_model_data.clone().into_inner());
+            if let Some(models_dir) = models_dir.or(config.models_dir) {
+                model_registry.load_dir(&models_dir, device, precision, !no_cpu_fallback, &model_labels)?;
+            }
+            println!("Модели ошибок ОС: {} (основная '{}')", model_registry.names().join(", "), model_registry.primary);
+            let model_registry = web::Data::new(model_registry);
+
+            // Создание сервера чата
+            let screenshot_cache = ScreenshotCacheConfig {
+                capacity: screenshot_cache_size.unwrap_or(config.screenshot_cache_size),
+                max_distance: screenshot_dedup_distance.unwrap_or(config.screenshot_dedup_distance),
+            };
+            let recent_predictions = web::Data::new(RecentPredictions::new(recent_size.unwrap_or(config.recent_size)));
+            let llm = match llm_endpoint.or(config.llm_endpoint) {
+                Some(endpoint) => {
+                    let llm_model = llm_model.unwrap_or(config.llm_model);
+                    let llm_timeout = std::time::Duration::from_secs(llm_timeout.unwrap_or(config.llm_timeout));
+                    if llm_timeout.is_zero() {
+                        return Err(CliError::Config("llm_timeout в файле конфигурации должен быть больше нуля".to_string()));
+                    }
+                    let llm_retries = llm_retries.unwrap_or(config.llm_retries);
+                    println!(
+                        "Генеративные объяснения в чате: {} (модель {}, таймаут {} с, повторов {})",
+                        endpoint,
+                        llm_model,
+                        llm_timeout.as_secs(),
+                        llm_retries
+                    );
+                    Some(LlmClient::new(endpoint, llm_model, llm_timeout, llm_retries).map_err(std::io::Error::other)?)
+                }
+                None => None,
+            };
+            let escalation_threshold = escalation_threshold
+                .or(config.escalation_threshold)
+                .map(validate_escalation_threshold)
+                .transpose()
+                .map_err(CliError::Config)?;
+            if let Some(threshold) = escalation_threshold {
+                println!("Анализы чата с уверенностью ниже {:.0}% передаются на проверку (GET /escalations)", threshold * 100.0);
+            }
+            let escalation_queue = web::Data::new(EscalationQueue::new(escalation_threshold, ESCALATION_QUEUE_SIZE));
+            let share_ttl = share_ttl.unwrap_or(config.share_ttl);
+            if share_ttl == 0 {
+                return Err(CliError::Config("share_ttl в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            let shared_analyses = web::Data::new(SharedAnalyses::new(
+                std::time::Duration::from_secs(share_ttl),
+                SHARED_ANALYSES_CAPACITY,
+            ));
+            SharedAnalyses::start_sweeper(shared_analyses.clone().into_inner());
+            let max_sessions = max_sessions.unwrap_or(config.max_sessions);
+            if max_sessions == 0 {
+                return Err(CliError::Config("max_sessions в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            let session_ttl = session_ttl.unwrap_or(config.session_ttl);
+            if session_ttl == 0 {
+                return Err(CliError::Config("session_ttl в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            let inference_queue = InferenceQueue::start(os_error_model_data.clone().into_inner(), CHAT_INFERENCE_BATCH)?;
+            let chat_server = ChatServer::new(
+                inference_queue,
+                recent_predictions.clone().into_inner(),
+                llm,
+                escalation_queue.clone().into_inner(),
+                ChatServerConfig {
+                    screenshot_cache,
+                    max_sessions,
+                    session_ttl: std::time::Duration::from_secs(session_ttl),
+                    animated_images: animated_images.unwrap_or(config.animated_images),
+                },
+            ).start();
+            let max_body_size = max_body_size.unwrap_or(config.max_body_size);
+            if max_body_size == 0 {
+                return Err(CliError::Config("max_body_size в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            let prediction_caches = web::Data::new(PredictionCaches::new(cache_size.unwrap_or(config.cache_size)));
+            let heartbeat = ChatHeartbeat {
+                interval: std::time::Duration::from_secs(ws_ping_interval.unwrap_or(config.ws_ping_interval)),
+                timeout: std::time::Duration::from_secs(ws_ping_timeout.unwrap_or(config.ws_ping_timeout)),
+            };
+            if heartbeat.interval.is_zero() || heartbeat.timeout.is_zero() {
+                return Err(CliError::Config("ws_ping_interval и ws_ping_timeout в файле конфигурации должны быть больше нуля".to_string()));
+            }
+            if heartbeat.timeout <= heartbeat.interval {
+                eprintln!(
+                    "Внимание: таймаут ping ({} с) не больше интервала ({} с), живые сессии чата могут закрываться",
+                    heartbeat.timeout.as_secs(),
+                    heartbeat.interval.as_secs()
+                );
+            }
+
+            let training_state = web::Data::new(TrainingState {
+                in_progress: AtomicBool::new(false),
+                last_result: Mutex::new(None),
+                model_path: os_error_model_path,
+            });
+
+            let in_flight = web::Data::new(InFlightRequests::default());
+            let shutdown_training_state = training_state.clone();
+            let shutdown_in_flight = in_flight.clone();
+
+            // Каждый воркер может выполнять инференс одновременно с другими, и каждый
+            // инференс занимает до torch_threads потоков libtorch
+            let cores = std::thread::available_parallelism().map_or(1, usize::from);
+            let workers = workers.or(config.workers).unwrap_or(cores);
+            if workers == 0 {
+                return Err(CliError::Config("workers в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            println!("Воркеров actix: {}", workers);
+            let torch_threads = tch::get_num_threads().max(1) as usize;
+            if workers * torch_threads > cores {
+                eprintln!(
+                    "Внимание: воркеры ({}) x потоки libtorch ({}) = {} больше числа ядер ({}), \
+                     одновременные запросы будут конкурировать за процессор. Уменьшите --workers или --torch-threads",
+                    workers,
+                    torch_threads,
+                    workers * torch_threads,
+                    cores
+                );
+            }
+            let inference_timeout = inference_timeout.unwrap_or(config.inference_timeout);
+            if inference_timeout == 0 {
+                return Err(CliError::Config("inference_timeout в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            let inference_limits = web::Data::new(InferenceLimits::new(std::time::Duration::from_secs(inference_timeout), workers));
+
+            // gRPC-сервис работает на отдельном порту с теми же моделями, ключами и слотами инференса
+            let grpc_service = inference_proto::inference_server::InferenceServer::with_interceptor(
+                GrpcInference {
+                    model_data: model_data.clone().into_inner(),
+                    os_error_model_data: os_error_model_data.clone().into_inner(),
+                    limits: inference_limits.get_ref().clone(),
+                },
+                GrpcApiKeyCheck { api_keys: api_keys.clone().into_inner() },
+            );
+            println!("Запуск gRPC-сервиса на {}", grpc_address);
+            let (grpc_stop, grpc_stopped) = tokio::sync::oneshot::channel::<()>();
+            let grpc_server = tonic::transport::Server::builder()
+                .add_service(grpc_service)
+                .serve_with_shutdown(grpc_address, async {
+                    let _ = grpc_stopped.await;
+                });
+
+            let fetch_timeout = fetch_timeout.unwrap_or(config.fetch_timeout);
+            if fetch_timeout == 0 {
+                return Err(CliError::Config("fetch_timeout в файле конфигурации должен быть больше нуля".to_string()));
+            }
+            let fetch_allow_hosts = if fetch_allow_hosts.is_empty() { config.fetch_allow_hosts } else { fetch_allow_hosts };
+            if !fetch_allow_hosts.is_empty() {
+                println!("POST /predict-url скачивает изображения только с: {}", fetch_allow_hosts.join(", "));
+            }
+            let image_fetcher = web::Data::new(ImageFetcher {
+                allow_hosts: fetch_allow_hosts.iter().map(|host| host.to_lowercase()).collect(),
+                timeout: std::time::Duration::from_secs(fetch_timeout),
+                max_bytes: max_body_size,
+            });
+
+            let server = HttpServer::new(move || {
+                App::new()
+                    .wrap(from_fn(request_id_and_timing))
+                    .app_data(api_keys.clone())
+                    .app_data(model_data.clone())
+                    .app_data(os_error_model_data.clone())
+                    .app_data(model_registry.clone())
+                    .app_data(web::Data::new(chat_server.clone()))
+                    .app_data(training_state.clone())
+                    .app_data(in_flight.clone())
+                    .app_data(web::Data::new(MaxBodySize(max_body_size)))
+                    .app_data(web::Data::new(heartbeat))
+                    .app_data(prediction_caches.clone())
+                    .app_data(recent_predictions.clone())
+                    .app_data(escalation_queue.clone())
+                    .app_data(shared_analyses.clone())
+                    .app_data(inference_limits.clone())
+                    .app_data(image_fetcher.clone())
+                    .app_data(json_config(max_body_size))
+                    .app_data(query_config())
+                    .configure(|cfg| configure_routes(cfg, &cors_allow_origins))
+            });
+
+            // Сигналы обрабатываются в graceful_shutdown, чтобы остановить оба сервера
+            // и дождаться переобучения
+            let server = server
+                .workers(workers)
+                .disable_signals()
+                .shutdown_timeout(shutdown_timeout.as_secs());
+            let server = match tls_config {
+                Some(tls_config) => server.bind_rustls_0_23(&address, tls_config)?,
+                None => server.bind(&address)?,
+            }
+            .run();
+
+            let shutdown = graceful_shutdown(
+                shutdown_signal(),
+                server.handle(),
+                grpc_stop,
+                shutdown_in_flight,
+                shutdown_training_state,
+                shutdown_timeout,
+            );
+
+            tokio::try_join!(
+                server,
+                async { grpc_server.await.map_err(|e| std::io::Error::other(e.to_string())) },
+                shutdown,
+            )?;
+            Ok(())
+        },
+        Commands::Train {
+            model, epochs, learning_rate, metrics_csv, grad_clip, dry_run, seed, conv1_channels, conv2_channels, hidden,
+        } => {
+            println!("Обучение модели...");
+
+            let default_cnn = CnnConfig::default();
+            let cnn = CnnConfig {
+                conv1_channels: conv1_channels.unwrap_or(default_cnn.conv1_channels),
+                conv2_channels: conv2_channels.unwrap_or(default_cnn.conv2_channels),
+                hidden: hidden.unwrap_or(default_cnn.hidden),
+            };
+            if cnn != default_cnn {
+                println!("Размеры слоев: {:?}", cnn);
+            }
+
+            let train_config = TrainConfig {
+                epochs: epochs.unwrap_or(config.epochs),
+                learning_rate: learning_rate.unwrap_or(config.learning_rate),
+                model_path: model.unwrap_or(config.model),
+                metrics_csv,
+                grad_clip,
+                dry_run,
+                cnn,
+            };
+
+            // До создания слоев: от зерна зависит инициализация весов
+            training_seed(seed);
+            let device = Device::Cpu;
+            let vs = nn::VarStore::new(device);
+            let model = simple_cnn(&vs.root(), 10, &cnn);
+
+            let (train_images, train_labels) = create_dummy_data();
+            match train_model(&model, &train_images, &train_labels, &vs, &train_config) {
+                Ok(_) if dry_run => println!("Проверка перед обучением пройдена"),
+                Ok(_) => println!("Обучение завершено успешно"),
+                Err(e) => return Err(CliError::command("Ошибка при обучении", e, CliError::Train)),
+            }
+
+            Ok(())
+        },
+        Commands::Predict { model, ensemble, image, temperature, device, strict_device, json } => {
+            let models = if ensemble.is_empty() { vec![model.unwrap_or(config.model)] } else { ensemble };
+            let result = resolve_device(device, strict_device)
+                .map_err(|e| e.into())
+                .and_then(|device| predict_from_cli(&models, &image, temperature, device, json, &mut std::io::stdout()));
+            match result {
+                Ok(_) if json => {}
+                Ok(_) => println!("Предсказание выполнено успешно"),
+                Err(e) => return Err(CliError::command("Ошибка при предсказании", e, CliError::Torch)),
+            }
+            Ok(())
+        },
+        Commands::TrainOsError {
+            model, epochs, learning_rate, batch_size, resume, class_weights, os_class_weights,
+            augment, augment_strength, mixup, metrics_csv, input_size, grad_clip, loss, focal_gamma, label_smoothing,
+            tensor_cache, val_split, early_stop_patience, os_loss_weight, seed, dry_run, force, normalize_from,
+            weights_only, freeze_backbone,
+        } => {
+            println!("Обучение модели для предсказания ошибок ОС...");
+            let input_size = input_size.unwrap_or(config.input_size);
+            let normalization = match normalize_from.as_deref().map(DatasetStats::load).transpose() {
+                Ok(stats) => stats.map(|stats| {
+                    if stats.input_size != input_size {
+                        eprintln!(
+                            "Внимание: статистика посчитана для стороны {}, обучение идет на {}",
+                            stats.input_size, input_size
+                        );
+                    }
+                    stats.normalization
+                }),
+                Err(e) => return Err(CliError::command("Ошибка при обучении", e, CliError::Config)),
+            };
+            let loss = match loss.as_deref() {
+                Some("focal") => {
+                    if label_smoothing.is_some() {
+                        eprintln!("Внимание: --label-smoothing учитывается только вместе с --loss ce");
+                    }
+                    OsErrorLoss::Focal { gamma: focal_gamma.unwrap_or(DEFAULT_FOCAL_GAMMA) }
+                }
+                _ => {
+                    if focal_gamma.is_some() {
+                        eprintln!("Внимание: --focal-gamma учитывается только вместе с --loss focal");
+                    }
+                    match label_smoothing {
+                        Some(eps) if eps > 0.0 => OsErrorLoss::SmoothedCrossEntropy { eps },
+                        _ => OsErrorLoss::CrossEntropy,
+                    }
+                }
+            };
+            let train_config = OsErrorTrainConfig {
+                augment,
+                augment_strength: augment_strength.unwrap_or(config.augment_strength),
+                metrics_csv,
+                input_size,
+                epochs: epochs.unwrap_or(config.os_error_epochs),
+                learning_rate: learning_rate.unwrap_or(config.os_error_learning_rate),
+                batch_size: batch_size.unwrap_or(config.os_error_batch_size),
+                model_path: model.unwrap_or(config.os_error_model),
+                resume,
+                class_weights: (!class_weights.is_empty()).then_some(class_weights),
+                os_class_weights: (!os_class_weights.is_empty()).then_some(os_class_weights),
+                grad_clip,
+                loss,
+                mixup,
+                tensor_cache,
+                val_split,
+                early_stop_patience,
+                os_loss_weight: os_loss_weight.unwrap_or(config.os_loss_weight),
+                seed,
+                dry_run,
+                force,
+                normalization,
+                weights_only,
+                freeze_backbone,
+                ..Default::default()
+            };
+            match train_os_error_model(&train_config) {
+                Ok(_) if dry_run => println!("Проверка перед обучением пройдена"),
+                Ok(_) => println!("Обучение завершено успешно"),
+                Err(e) => return Err(CliError::command("Ошибка при обучении", e, CliError::Train)),
+            }
+            Ok(())
+        },
+        Commands::PredictOsError {
+            model, ensemble, screenshot, from_clipboard: _, temperature, device, strict_device, precision, labels, json, tta,
+        } => {
+            let models = if ensemble.is_empty() { vec![model.unwrap_or(config.os_error_model)] } else { ensemble };
+            let result = resolve_device(device, strict_device)
+                .and_then(|device| Ok((device, resolve_precision(precision, device)?)))
+                .map_err(|e| e.into())
+                .and_then(|(device, precision)| Ok((device, precision, Labels::load(labels.or(config.labels).as_deref())?)))
+                .and_then(|(device, precision, labels)| {
+                    // Без --screenshot clap требует --from-clipboard
+                    let img = match &screenshot {
+                        Some(path) => image::open(path)?,
+                        None => read_clipboard_image()?,
+                    };
+                    let models: Vec<OsErrorModelBundle> = load_os_error_ensemble(&models, device, &labels)?
+                        .into_iter()
+                        .map(|models| models.with_precision(precision))
+                        .collect();
+                    predict_os_error_from_cli(&models, &img, temperature, json, tta, &mut std::io::stdout())
+                });
+            match result {
+                Ok(_) if json => {}
+                Ok(_) => println!("\nАнализ скриншота завершен успешно"),
+                Err(e) => return Err(CliError::command("Ошибка при анализе скриншота", e, CliError::Torch)),
+            }
+            Ok(())
+        },
+        Commands::PredictDir { model, dir, csv, format, labels } => {
+            let model = model.unwrap_or(config.os_error_model);
+            let format = format.unwrap_or(DirOutputFormat::Table);
+            let result = Labels::load(labels.or(config.labels).as_deref())
+                .and_then(|labels| predict_dir(&model, &dir, csv.as_deref(), format, &labels));
+            result.map_err(|e| CliError::command("Ошибка при анализе каталога", e, CliError::Torch))
+        }
+        Commands::PredictVideo { model, video, interval, json, labels } => {
+            let model = model.unwrap_or(config.os_error_model);
+            let interval = interval.unwrap_or(DEFAULT_FRAME_INTERVAL_SECS);
+            let result = Labels::load(labels.or(config.labels).as_deref())
+                .and_then(|labels| predict_video(&model, &video, interval, json, &labels));
+            result.map_err(|e| CliError::command("Ошибка при анализе видео", e, CliError::Image))
+        }
+        Commands::Evaluate { model, data_dir, json, confusion_out } => {
+            let model = model.unwrap_or(config.os_error_model);
+            evaluate_os_error_model(&model, &data_dir, json, confusion_out.as_deref())
+                .map_err(|e| CliError::command("Ошибка при оценке модели", e, CliError::Torch))
+        }
+        Commands::Benchmark { model, iterations, batch_size, device, strict_device, precision, labels, json } => {
+            let model = model.unwrap_or(config.os_error_model);
+            let iterations = iterations.unwrap_or(100);
+            let batch_size = batch_size.unwrap_or(1);
+            let result = resolve_device(device, strict_device)
+                .and_then(|device| Ok((device, resolve_precision(precision, device)?)))
+                .map_err(|e| e.into())
+                .and_then(|(device, precision)| Ok((device, precision, Labels::load(labels.or(config.labels).as_deref())?)))
+                .and_then(|(device, precision, labels)| {
+                    benchmark_os_error_model(&model, iterations, batch_size, device, precision, &labels, json)
+                });
+            result.map_err(|e| CliError::command("Ошибка при замере скорости", e, CliError::Torch))
+        }
+        Commands::Explain { model, screenshot, output, labels } => {
+            let model = model.unwrap_or(config.os_error_model);
+            let result = Labels::load(labels.or(config.labels).as_deref())
+                .and_then(|labels| explain_os_error(&model, &screenshot, &output, &labels));
+            result.map_err(|e| CliError::command("Ошибка при построении тепловой карты", e, CliError::Torch))
+        }
+        Commands::Quantize { model, output } => {
+            let model = model.unwrap_or(config.os_error_model);
+            quantize_os_error_model(&model, &output)
+                .map_err(|e| CliError::command("Ошибка при квантовании модели", e, CliError::Torch))
+        }
+        Commands::Preprocess { data_dir, out, input_size } => {
+            preprocess_os_error_dataset(&data_dir, &out, input_size.unwrap_or(config.input_size))
+                .map_err(|e| CliError::command("Ошибка при подготовке набора", e, CliError::Image))
+        }
+        Commands::GenerateData { out_dir, per_class, width, height, seed } => {
+            let per_class = per_class.unwrap_or(50);
+            let (width, height) = (width.unwrap_or(640), height.unwrap_or(480));
+            match generate_synthetic_dataset(&out_dir, per_class, width, height, seed) {
+                Ok(written) => println!("Создано {} синтетических скриншотов в {}", written, out_dir),
+                Err(e) => return Err(CliError::command("Ошибка при генерации данных", e, CliError::Image)),
+            }
+            Ok(())
+        }
+        Commands::Calibrate { model, data_dir, json } => {
+            let model = model.unwrap_or(config.os_error_model);
+            calibrate_os_error_model(&model, &data_dir, json)
+                .map_err(|e| CliError::command("Ошибка при калибровке модели", e, CliError::Torch))
+        }
+        Commands::Stats { data_dir, input_size, out } => {
+            let out = out.unwrap_or_else(|| "stats.json".to_string());
+            dataset_stats(&data_dir, input_size.unwrap_or(config.input_size), &out)
+                .map_err(|e| CliError::command("Ошибка при подсчете статистики", e, CliError::Image))
+        }
+        Commands::CheckLeakage { train_dir, test_dir, max_distance, json } => {
+            check_leakage(&train_dir, &test_dir, max_distance.unwrap_or(DEFAULT_LEAKAGE_DISTANCE), json)
+                .map_err(|e| CliError::command("Ошибка при проверке утечки", e, CliError::Image))
+        }
+        Commands::Doctor => {
+            if run_doctor() {
+                Ok(())
+            } else {
+                Err(CliError::Torch("Самопроверка не пройдена".to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Логиты двух примеров трех классов и их метки, общие для тестов функций потерь
+    fn sample_logits() -> (Tensor, Tensor) {
+        let logits = Tensor::of_slice(&[2.0f32, 0.0, 0.0, 0.0, 1.0, 0.0]).view([2, 3]);
+        let targets = Tensor::of_slice(&[0i64, 2]);
+        (logits, targets)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    // Путь во временном каталоге, уникальный для теста и процесса
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bashpic-test-{}-{}", std::process::id(), name))
+    }
+
+    // Случайно инициализированная модель ошибок ОС, сохраненная вместе с метаданными
+    fn save_os_error_model(name: &str, input_size: i64) -> String {
+        let bundle = OsErrorModelBundle::new(ModelMetadata::os_error_cnn(input_size), Device::Cpu);
+        let path = temp_path(name).to_string_lossy().into_owned();
+        bundle.vs.save(&path).unwrap();
+        bundle.metadata.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn focal_loss_matches_reference_value() {
+        let (logits, targets) = sample_logits();
+        let alpha = Tensor::of_slice(&[1.0f32, 2.0, 0.5]);
+        // sum(-alpha_t * (1 - p_t)^2 * ln p_t) / sum(alpha_t), посчитано вручную
+        let loss = focal_loss(&logits, &targets, 2.0, &alpha);
+        assert_close(loss.double_value(&[]), 0.328_413_95);
+    }
+
+    #[test]
+    fn focal_loss_with_zero_gamma_is_weighted_cross_entropy() {
+        let (logits, targets) = sample_logits();
+        let alpha = Tensor::of_slice(&[1.0f32, 2.0, 0.5]);
+        let focal = focal_loss(&logits, &targets, 0.0, &alpha);
+        let cross_entropy = weighted_cross_entropy(&logits, &targets, &alpha);
+        assert_close(focal.double_value(&[]), cross_entropy.double_value(&[]));
+    }
+
+    #[test]
+    fn smoothed_cross_entropy_matches_reference_value() {
+        let (logits, targets) = sample_logits();
+        let weights = Tensor::ones(&[3], (Kind::Float, Device::Cpu));
+        // Целевое распределение (0.9, 0.05, 0.05) с верным классом на месте метки
+        let loss = smoothed_cross_entropy(&logits, &targets, 0.1, &weights);
+        assert_close(loss.double_value(&[]), 0.970_494_74);
+    }
 
-        function connectWebSocket() {
-            const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
-            const wsUrl = protocol + '//' + window.location.host + '/ws/';
+    #[test]
+    fn smoothed_cross_entropy_with_zero_eps_is_weighted_cross_entropy() {
+        let (logits, targets) = sample_logits();
+        let weights = Tensor::of_slice(&[1.0f32, 2.0, 0.5]);
+        let smoothed = smoothed_cross_entropy(&logits, &targets, 0.0, &weights);
+        let cross_entropy = weighted_cross_entropy(&logits, &targets, &weights);
+        assert_close(smoothed.double_value(&[]), cross_entropy.double_value(&[]));
+    }
 
-            socket = new WebSocket(wsUrl);
+    #[test]
+    fn mixed_loss_with_unit_lam_is_plain_loss() {
+        let (logits, targets) = sample_logits();
+        let weights = Tensor::ones(&[3], (Kind::Float, Device::Cpu));
+        let mixing = Mixup { permutation: Tensor::of_slice(&[1i64, 0]), lam: 1.0 };
+        for loss in [OsErrorLoss::CrossEntropy, OsErrorLoss::SmoothedCrossEntropy { eps: 0.1 }, OsErrorLoss::Focal { gamma: 2.0 }] {
+            let mixed = loss.compute_mixed(&logits, &targets, &mixing, &weights);
+            let plain = loss.compute(&logits, &targets, &weights);
+            assert_close(mixed.double_value(&[]), plain.double_value(&[]));
+        }
+    }
 
-            socket.onopen = function(event) {
-                connected = true;
-                document.getElementById('status').textContent = '✅ Подключено к AI помощнику';
-                document.getElementById('status').style.background = '#e8f5e8';
+    #[test]
+    fn mixup_blends_batch_with_its_permutation() {
+        let images = Tensor::rand(&[4, 3, 8, 8], (Kind::Float, Device::Cpu));
+        let (mixed, mixing) = mixup(&images, 0.4, &mut StdRng::seed_from_u64(0)).unwrap();
+        assert!((0.0..=1.0).contains(&mixing.lam));
+        let expected = &images * mixing.lam + images.index_select(0, &mixing.permutation) * (1.0 - mixing.lam);
+        assert!(mixed.allclose(&expected, 1e-5, 1e-6, false));
+    }
 
-                addMessage('bot', 'Привет! Я AI помощник для анализа ошибок операционных систем. Загрузите скриншот ошибки или задайте вопрос о проблемах с компьютером.');
-            };
+    #[test]
+    fn ensemble_of_identical_models_matches_single_model() {
+        let path = save_os_error_model("ensemble.pt", 32);
+        let models = load_os_error_ensemble(&[path.clone(), path], Device::Cpu, &Labels::builtin()).unwrap();
+        let images = Tensor::rand(&[2, 3, 32, 32], (Kind::Float, Device::Cpu));
 
-            socket.onmessage = function(event) {
-                const response = JSON.parse(event.data);
-                handleBotResponse(response);
-            };
+        let probs: Vec<Tensor> = tch::no_grad(|| {
+            models.iter().map(|model| model.forward(&images).0.softmax(-1, Kind::Float)).collect()
+        });
+        let single = probs[0].shallow_clone();
+        let averaged = average_probs(probs.into_iter());
+        assert!(averaged.allclose(&single, 1e-5, 1e-6, false));
+    }
 
-            socket.onclose = function(event) {
-                connected = false;
-                document.getElementById('status').textContent = '❌ Соединение потеряно. Переподключение...';
-                document.getElementById('status').style.background = '#ffebee';
-                setTimeout(connectWebSocket, 3000);
-            };
+    #[test]
+    fn fitted_temperature_reduces_ece_of_overconfident_logits() {
+        // Модель почти уверена в каждом ответе, но права только в 7 случаях из 10
+        let labels: Vec<i64> = (0..200).map(|i| i % 3).collect();
+        let predicted: Vec<i64> = labels.iter().enumerate().map(|(i, label)| if i % 10 < 7 { *label } else { (label + 1) % 3 }).collect();
+        let labels = Tensor::of_slice(&labels);
+        let logits = Tensor::of_slice(&predicted).onehot(3).to_kind(Kind::Float) * 10.0;
+
+        let ece_before = expected_calibration_error(&logits, &labels, 1.0);
+        let temperature = fit_temperature(&logits, &labels, &logits, &labels).unwrap();
+        let ece_after = expected_calibration_error(&logits, &labels, temperature);
+        assert!(temperature > 1.0, "{}", temperature);
+        assert!(ece_after < ece_before, "{} >= {}", ece_after, ece_before);
+    }
 
-            socket.onerror = function(error) {
-                console.error('WebSocket error:', error);
-                document.getElementById('status').textContent = '❌ Ошибка подключения';
-                document.getElementById('status').style.background = '#ffebee';
-            };
+    #[test]
+    fn temperature_above_one_lowers_top1_confidence() {
+        let (logits, _) = sample_logits();
+        let top1 = |temperature: f64| Vec::<f32>::from(&softmax_with_temperature(&logits, temperature).max_dim(-1, false).0);
+        let (sharp, soft) = (top1(1.0), top1(2.0));
+        for (sharp, soft) in sharp.iter().zip(&soft) {
+            assert!(soft < sharp, "{} >= {}", soft, sharp);
         }
+        // Температура не меняет предсказанный класс
+        assert_eq!(
+            Vec::<i64>::from(&softmax_with_temperature(&logits, 2.0).argmax(-1, false)),
+            Vec::<i64>::from(&logits.argmax(-1, false)),
+        );
+    }
 
-        function addMessage(type, content, analysis = null, suggestions = null) {
-            const chatArea = document.getElementById('chatArea');
-            const messageDiv = document.createElement('div');
-            messageDiv.className = 'message ' + type + '-message';
+    #[test]
+    fn quantized_model_agrees_with_source_on_dummy_data() {
+        let source_path = save_os_error_model("quantize-source.pt", 32);
+        let quantized_path = temp_path("quantize-int8.pt").to_string_lossy().into_owned();
+        quantize_os_error_model(&source_path, &quantized_path).unwrap();
+
+        let source = OsErrorModelBundle::load(&source_path).unwrap();
+        let quantized = OsErrorModelBundle::load(&quantized_path).unwrap();
+        assert_eq!(quantized.metadata.quantization.as_deref(), Some(QUANTIZATION_DYNAMIC_INT8));
+
+        let (images, _, _) = create_os_error_dummy_data(32);
+        let ((source_errors, source_os), (quantized_errors, quantized_os)) =
+            tch::no_grad(|| (source.forward(&images), quantized.forward(&images)));
+        let (error_agreement, _) = prediction_agreement(&source_errors, &quantized_errors);
+        let (os_agreement, _) = prediction_agreement(&source_os, &quantized_os);
+        assert!(error_agreement >= QUANTIZE_MIN_AGREEMENT, "{}", error_agreement);
+        assert!(os_agreement >= QUANTIZE_MIN_AGREEMENT, "{}", os_agreement);
+    }
 
-            let html = '<div>' + content + '</div>';
+    // Белый скриншот с темной строкой текста в верхней левой четверти
+    fn structured_screenshot(width: u32, height: u32) -> image::DynamicImage {
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            if x < width / 2 && (height / 8..height / 4).contains(&y) && x % 4 != 3 {
+                image::Rgb([20, 20, 20])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        }))
+    }
 
-            if (analysis) {
-                html += `
-                    <div class="error-analysis">
-                        <h4>📊 Анализ ошибки:</h4>
-                        <p><span class="error-type">Тип ошибки:</span> ${analysis.error_type}</p>
-                        <p><span class="os-type">Операционная система:</span> ${analysis.os_type}</p>
-                        <p><span class="confidence">Уверенность:</span> ${(analysis.confidence * 100).toFixed(1)}%</p>
-                        <p><strong>Описание:</strong> ${analysis.detailed_description}</p>
+    #[test]
+    fn grad_cam_highlights_part_of_structured_input() {
+        tch::manual_seed(0);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let layers = os_error_cnn_layers(
+            &vs.root(),
+            OS_ERROR_TYPES.len() as i64,
+            OS_TYPES.len() as i64,
+            &mut |p, name, in_dim, out_dim| Ok(nn::linear(p / name, in_dim, out_dim, Default::default())),
+        )
+        .unwrap();
+        let input = image_to_tensor(&structured_screenshot(64, 64), 64);
+
+        let (cam, _) = grad_cam(&layers, &input);
+        let (min, max) = (f64::from(&cam.min()), f64::from(&cam.max()));
+        assert!(min >= 0.0 && max <= 1.0, "{}..{}", min, max);
+        assert!(max - min > 0.1, "карта почти однородна: {}..{}", min, max);
+    }
 
-                        <h5>🔍 Возможные причины:</h5>
-                        <ul>
-                            ${analysis.possible_causes.map(cause => `<li>${cause}</li>`).join('')}
-                        </ul>
+    #[test]
+    fn explain_writes_heatmap_of_screenshot_size() {
+        let model = save_os_error_model("explain.pt", 32);
+        let screenshot = temp_path("explain-input.png");
+        let heatmap = temp_path("explain-heatmap.png");
+        structured_screenshot(120, 80).save(&screenshot).unwrap();
 
-                        <h5>🛠️ Рекомендуемые решения:</h5>
-                        <ul>
-                            ${analysis.solutions.map(solution => `<li>${solution}</li>`).join('')}
-                        </ul>
-                    </div>
-                `;
-            }
+        explain_os_error(&model, &screenshot.to_string_lossy(), &heatmap.to_string_lossy(), &Labels::builtin()).unwrap();
+        let written = image::open(&heatmap).unwrap();
+        assert_eq!((written.width(), written.height()), (120, 80));
+    }
 
-            if (suggestions && suggestions.length > 0) {
-                html += `
-                    <div class="suggestions">
-                        <h5>💡 Дополнительные рекомендации:</h5>
-                        <ul>
-                            ${suggestions.map(suggestion => `<li>${suggestion}</li>`).join('')}
-                        </ul>
-                    </div>
-                `;
+    #[test]
+    fn tta_averages_valid_distribution_over_five_crops() {
+        let crops = tta_crops(&structured_screenshot(120, 80), 32);
+        assert_eq!(crops.size(), vec![5, 3, 32, 32]);
+
+        let path = save_os_error_model("tta.pt", 32);
+        let model = OsErrorModelBundle::load(&path).unwrap();
+        let (error_logits, _) = tch::no_grad(|| model.forward(&crops));
+        assert_eq!(error_logits.size(), vec![5, OS_ERROR_TYPES.len() as i64]);
+
+        let averaged = softmax_with_temperature(&error_logits, 1.0).mean_dim(&[0], false, Kind::Float);
+        let probs = Vec::<f32>::from(&averaged);
+        assert!(probs.iter().all(|p| (0.0..=1.0).contains(p)), "{:?}", probs);
+        assert_close(probs.iter().map(|p| *p as f64).sum(), 1.0);
+
+        predict_os_error_from_cli(&[model], &structured_screenshot(120, 80), None, true, true, &mut std::io::sink()).unwrap();
+    }
+
+    // Потеря после SGD со слишком большой скоростью обучения на задаче (10w - 1)^2
+    fn loss_after_high_learning_rate(grad_clip: Option<f64>) -> f64 {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let w = vs.root().zeros("w", &[1]);
+        let mut optimizer = nn::Sgd::default().build(&vs, 1.0).unwrap();
+        let params = vs.trainable_variables();
+        let mut loss = 0.0;
+        for step in 1..=100 {
+            let total_loss = (&w * 10.0 - 1.0).pow(2.0).sum(Kind::Float);
+            loss = match check_loss(&total_loss, step) {
+                Ok(loss) => loss,
+                Err(TrainingError::NonFiniteLoss { loss, .. }) => return loss,
+            };
+            optimizer.zero_grad();
+            total_loss.backward();
+            if let Some(max_norm) = grad_clip {
+                clip_grad_norm(&params, max_norm);
             }
+            optimizer.step();
+        }
+        loss
+    }
 
-            messageDiv.innerHTML = html;
-            chatArea.appendChild(messageDiv);
-            chatArea.scrollTop = chatArea.scrollHeight;
+    #[test]
+    fn grad_clip_keeps_loss_finite_at_high_learning_rate() {
+        assert!(!loss_after_high_learning_rate(None).is_finite());
+        let clipped = loss_after_high_learning_rate(Some(1.0));
+        assert!(clipped.is_finite(), "{}", clipped);
+    }
+
+    #[test]
+    fn augment_keeps_pixels_in_unit_range() {
+        let images = Tensor::rand(&[8, 3, 32, 32], (Kind::Float, Device::Cpu));
+        let mut rng = StdRng::seed_from_u64(0);
+        for strength in [0.0, 1.0, 3.0] {
+            let augmented = augment(&images, &mut rng, strength);
+            assert_eq!(augmented.size(), images.size());
+            let (min, max) = (f64::from(&augmented.min()), f64::from(&augmented.max()));
+            assert!(min >= 0.0 && max <= 1.0, "сила {}: {}..{}", strength, min, max);
         }
+    }
 
-        function handleBotResponse(response) {
-            addMessage('bot', response.response, response.analysis, response.suggestions);
+    #[test]
+    fn grayscale_and_rgba_images_become_three_channel_tensors() {
+        let fixtures = [
+            image::DynamicImage::ImageLuma8(image::GrayImage::from_pixel(40, 30, image::Luma([128]))),
+            image::DynamicImage::ImageLumaA8(image::GrayAlphaImage::from_pixel(40, 30, image::LumaA([0, 0]))),
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(40, 30, image::Rgba([255, 0, 0, 128]))),
+            image::DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(40, 30, image::Luma([40000u16]))),
+        ];
+        for img in &fixtures {
+            assert_eq!(image_to_tensor(img, 32).size(), vec![1, 3, 32, 32], "{:?}", img.color());
         }
+    }
 
-        function sendMessage() {
-            const input = document.getElementById('messageInput');
-            const message = input.value.trim();
+    #[test]
+    fn transparent_pixels_are_blended_with_white() {
+        let rgba = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([255, 0, 0, 128]) }
+        }));
+        let rgb = to_rgb8_on_white(&rgba);
+        assert_eq!(rgb.get_pixel(0, 0), &image::Rgb([255, 255, 255]));
+        assert_eq!(rgb.get_pixel(1, 0), &image::Rgb([255, 127, 127]));
+    }
 
-            if (message && connected) {
-                addMessage('user', message);
+    #[test]
+    fn sixteen_bit_images_normalize_by_full_range() {
+        let rgb16 = |value: u16| image::DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(8, 8, image::Rgb([value; 3])));
+        let value_of = |img: &image::DynamicImage| {
+            let tensor = image_to_tensor(img, 8);
+            (f64::from(&tensor.min()), f64::from(&tensor.max()))
+        };
+
+        assert_eq!(value_of(&rgb16(65535)), (1.0, 1.0));
+        assert_eq!(value_of(&rgb16(0)), (0.0, 0.0));
+        // Уровень ниже шага 8-битной шкалы не округляется до нуля
+        let (min, max) = value_of(&rgb16(1));
+        assert!((min - 1.0 / 65535.0).abs() < 1e-9 && min == max, "{}..{}", min, max);
+    }
 
-                socket.send(JSON.stringify({
-                    message: message,
-                    image_data: null
-                }));
+    #[test]
+    fn model_metadata_round_trips_through_json() {
+        let path = temp_path("metadata.pt");
+        let metadata = ModelMetadata {
+            temperature: 1.7,
+            quantization: Some(QUANTIZATION_DYNAMIC_INT8.to_string()),
+            normalization: Some(Normalization { mean: [0.5, 0.4, 0.3], std: [0.2, 0.25, 0.3] }),
+            weights_only: true,
+            ..ModelMetadata::os_error_cnn(64)
+        };
+        metadata.save(&path).unwrap();
+        assert_eq!(ModelMetadata::load(&path).unwrap(), Some(metadata));
+
+        let simple = ModelMetadata::simple_cnn(5, CnnConfig::default());
+        simple.save(&path).unwrap();
+        assert_eq!(ModelMetadata::load(&path).unwrap(), Some(simple));
+    }
 
-                input.value = '';
-            }
+    #[test]
+    fn missing_metadata_loads_as_none() {
+        assert_eq!(ModelMetadata::load(temp_path("no-metadata.pt")).unwrap(), None);
+    }
+
+    #[test]
+    fn screenshot_analysis_is_deterministic_with_dropout_disabled() {
+        let path = save_os_error_model("eval-mode.pt", 32);
+        let models: OsErrorModels = RwLock::new(InferenceModels(OsErrorModelBundle::load(&path).unwrap()));
+        let image = image_to_tensor(&structured_screenshot(64, 64), 32);
+
+        let first = predict_screenshots(&models, &image).unwrap();
+        let second = predict_screenshots(&models, &image).unwrap();
+        assert_eq!(first[0].error_type, second[0].error_type);
+        assert_eq!(first[0].confidence, second[0].confidence);
+
+        // В режиме обучения dropout включен, и выходы двух прогонов расходятся
+        let models = models.read().unwrap();
+        let (train_first, train_second) = tch::no_grad(|| {
+            (models.error_model.forward_t(&image, true), models.error_model.forward_t(&image, true))
+        });
+        assert!(!train_first.allclose(&train_second, 1e-6, 1e-8, false));
+    }
+
+    #[test]
+    fn confusion_matrix_image_size_follows_labels() {
+        let labels: Vec<String> = ["a", "bb", "ccc"].iter().map(|label| label.to_string()).collect();
+        let img = render_confusion_matrix(&[vec![2, 0, 0], vec![1, 1, 0], vec![0, 0, 0]], &labels);
+        // Отступы 10, подписи строк "<индекс> <имя>" шрифтом x2, ячейки по 40
+        assert_eq!(img.dimensions(), (10 + 6 * GLYPH_ADVANCE * 2 + 3 * 40 + 10, 10 + 10 + 10 + 3 * 40 + 10));
+    }
+
+    #[test]
+    fn evaluate_writes_confusion_matrix_png() {
+        let model = save_os_error_model("confusion.pt", 32);
+        let data_dir = temp_path("confusion-data").to_string_lossy().into_owned();
+        generate_synthetic_dataset(&data_dir, 1, 64, 64, Some(0)).unwrap();
+        let out = temp_path("confusion.png");
+
+        evaluate_os_error_model(&model, &data_dir, true, Some(&out.to_string_lossy())).unwrap();
+        let bytes = std::fs::read(&out).unwrap();
+        assert!(bytes.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        let labels: Vec<String> = OS_ERROR_TYPES.iter().map(|label| label.to_string()).collect();
+        let expected = render_confusion_matrix(&vec![vec![0; labels.len()]; labels.len()], &labels);
+        let written = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((written.width(), written.height()), expected.dimensions());
+    }
+
+    // Статус ответа приложения; ошибки middleware превращаются в свой HTTP-статус
+    async fn response_status<S, R, B>(app: &S, req: R) -> actix_web::http::StatusCode
+    where
+        S: actix_web::dev::Service<R, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        match actix_web::test::try_call_service(app, req).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
         }
+    }
 
-        function handleImageUpload(event) {
-            const file = event.target.files[0];
-            if (file && connected) {
-                const reader = new FileReader();
-                reader.onload = function(e) {
-                    const base64Data = e.target.result.split(',')[1];
+    #[actix_web::test]
+    async fn percent_encoded_paths_still_require_api_key() {
+        use actix_web::test::TestRequest;
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(ApiKeys { keys: vec!["secret".to_string()] }))
+                .configure(|cfg| configure_routes(cfg, &[])),
+        )
+        .await;
+
+        let protected = [
+            TestRequest::post().uri("/predict-os-error"),
+            TestRequest::post().uri("/predict%2Dos-error"),
+            TestRequest::post().uri("/%61dmin/train"),
+            TestRequest::get().uri("/admin/train/status"),
+            TestRequest::get().uri("/w%73/"),
+            TestRequest::get().uri("/ws/?api_key=wrong"),
+        ];
+        for req in protected {
+            assert_eq!(response_status(&app, req.to_request()).await, 401);
+        }
 
-                    addMessage('user', '📷 Скриншот загружен для анализа...');
+        // С верным ключом запрос проходит проверку и доходит до обработчика
+        let with_key = TestRequest::post().uri("/predict%2Dos-error").insert_header(("X-API-Key", "secret"));
+        assert_ne!(response_status(&app, with_key.to_request()).await, 401);
+        assert_eq!(response_status(&app, TestRequest::get().uri("/").to_request()).await, 200);
+    }
 
-                    socket.send(JSON.stringify({
-                        message: "Проанализируйте этот скриншот с ошибкой",
-                        image_data: base64Data
-                    }));
-                };
-                reader.readAsDataURL(file);
+    #[actix_web::test]
+    async fn admin_routes_are_closed_without_keys() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(ApiKeys { keys: Vec::new() }))
+                .configure(|cfg| configure_routes(cfg, &[])),
+        )
+        .await;
+        for uri in ["/admin/train", "/%61dmin/train"] {
+            assert_eq!(response_status(&app, actix_web::test::TestRequest::post().uri(uri).to_request()).await, 403);
+        }
+    }
 
-                // Очистить input
-                event.target.value = '';
-            }
+    #[test]
+    fn fp16_is_rejected_on_cpu() {
+        assert!(resolve_precision(Some(Precision::Fp16), Device::Cpu).is_err());
+        assert_eq!(resolve_precision(None, Device::Cpu), Ok(Precision::Fp32));
+    }
+
+    #[test]
+    fn fp16_agrees_with_fp32_on_cuda() {
+        if !tch::Cuda::is_available() {
+            eprintln!("CUDA недоступна, сравнение fp16 и fp32 пропущено");
+            return;
         }
+        let path = save_os_error_model("fp16.pt", 32);
+        let device = Device::Cuda(0);
+        let fp32 = OsErrorModelBundle::load_on(&path, device, &Labels::builtin()).unwrap();
+        let fp16 = OsErrorModelBundle::load_on(&path, device, &Labels::builtin()).unwrap().with_precision(Precision::Fp16);
+
+        let (images, _, _) = create_os_error_dummy_data(32);
+        let images = images.to_device(device);
+        let ((fp32_errors, fp32_os), (fp16_errors, fp16_os)) = tch::no_grad(|| (fp32.forward(&images), fp16.forward(&images)));
+        let (error_agreement, _) = prediction_agreement(&fp32_errors, &fp16_errors.to_kind(Kind::Float));
+        let (os_agreement, _) = prediction_agreement(&fp32_os, &fp16_os.to_kind(Kind::Float));
+        assert!(error_agreement >= 0.95, "{}", error_agreement);
+        assert!(os_agreement >= 0.95, "{}", os_agreement);
+    }
 
-        function handleKeyPress(event) {
-            if (event.key === 'Enter') {
-                sendMessage();
+    #[test]
+    fn os_type_is_reconciled_for_every_error_and_os_pair() {
+        let os_types: Vec<String> = OS_TYPES.iter().map(|os| os.to_string()).collect();
+        // Допустимые ОС для ошибок, у которых они ограничены; остальные возможны в любой ОС
+        let table: &[(&str, &[&str])] = &[("blue_screen_of_death", &["windows"]), ("kernel_panic", &["linux", "macos"])];
+
+        for &error_type in OS_ERROR_TYPES {
+            let plausible = table.iter().find(|(error, _)| *error == error_type).map(|(_, os)| *os);
+            for (predicted, predicted_os) in OS_TYPES.iter().enumerate() {
+                // Предсказанная ОС самая вероятная, вторая по вероятности - следующая за ней
+                let mut os_probs = vec![0.1f32; OS_TYPES.len()];
+                os_probs[predicted] = 0.6;
+                os_probs[(predicted + 1) % OS_TYPES.len()] = 0.2;
+
+                let (idx, confidence, corrected) = reconcile_os_type(error_type, &os_probs, &os_types);
+                match plausible {
+                    Some(plausible) if !plausible.contains(predicted_os) => {
+                        let expected = (0..OS_TYPES.len())
+                            .filter(|&i| plausible.contains(&OS_TYPES[i]))
+                            .max_by(|a, b| os_probs[*a].total_cmp(&os_probs[*b]))
+                            .unwrap();
+                        assert_eq!((idx, corrected), (expected, true), "{} / {}", error_type, predicted_os);
+                        assert_close(confidence as f64, os_probs[expected] as f64);
+                        assert!(confidence < 0.6);
+                    }
+                    _ => {
+                        assert_eq!((idx, corrected), (predicted, false), "{} / {}", error_type, predicted_os);
+                        assert_close(confidence as f64, 0.6);
+                    }
+                }
             }
         }
+    }
 
-        // Подключение при загрузке страницы
-        connectWebSocket();
-    </script>
-</body>
-</html>
-    "#;
+    #[actix_web::test]
+    async fn responses_carry_request_id_and_timing() {
+        use actix_web::test::{call_service, init_service, TestRequest};
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(request_id_and_timing))
+                .app_data(web::Data::new(ApiKeys { keys: Vec::new() }))
+                .configure(|cfg| configure_routes(cfg, &[])),
+        )
+        .await;
+
+        // Модель не загружена, но заголовки есть и у ответа с ошибкой
+        let res = call_service(&app, TestRequest::post().uri("/predict").to_request()).await;
+        let request_id = res.headers().get("x-request-id").unwrap().to_str().unwrap();
+        assert!(Uuid::parse_str(request_id).is_ok(), "{}", request_id);
+        let elapsed: f64 = res.headers().get("x-response-time-ms").unwrap().to_str().unwrap().parse().unwrap();
+        assert!(elapsed >= 0.0);
+
+        let client_id = Uuid::new_v4().to_string();
+        let res = call_service(&app, TestRequest::get().uri("/").insert_header(("X-Request-Id", client_id.as_str())).to_request()).await;
+        assert_eq!(res.headers().get("x-request-id").unwrap(), client_id.as_str());
+
+        // Произвольная строка не принимается за идентификатор
+        let res = call_service(&app, TestRequest::get().uri("/").insert_header(("X-Request-Id", "not-a-uuid")).to_request()).await;
+        assert_ne!(res.headers().get("x-request-id").unwrap(), "not-a-uuid");
+    }
 
-    Ok(HttpResponse::Ok().content_type("text/html").body(html))
-}
+    #[test]
+    fn ensure_model_reports_missing_file_as_io_error() {
+        let path = temp_path("missing-model.pt").to_string_lossy().into_owned();
+        let load = |path: &str| -> Result<Vec<u8>, Box<dyn std::error::Error>> { Ok(std::fs::read(path)?) };
+
+        // Обучение "прошло", но файл не появился: ошибка понятна и дает код выхода Io
+        let error = ensure_model(&path, load, || Ok(())).unwrap_err();
+        assert!(matches!(error, CliError::Io(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 3);
+        assert!(error.to_string().contains(&path), "{}", error);
+
+        // Обучение сохраняет модель, и она загружается повторно
+        let model = ensure_model(&path, load, || Ok(std::fs::write(&path, b"weights")?)).unwrap();
+        assert_eq!(model, b"weights");
+        std::fs::remove_file(&path).unwrap();
+    }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let cli = Cli::parse();
+    #[test]
+    fn config_precedence_is_cli_then_env_then_file_then_default() {
+        let path = temp_path("precedence.json");
+        std::fs::write(&path, r#"{"host": "10.0.0.1", "port": 7000, "model": "file-model.pt"}"#).unwrap();
+        let config = Config::load(path.to_str()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // SAFETY: переменные BASHPIC_PORT и BASHPIC_MODEL читает только этот тест
+        unsafe {
+            std::env::set_var("BASHPIC_PORT", "7100");
+            std::env::set_var("BASHPIC_MODEL", "env-model.pt");
+        }
+        let cli = Cli::try_parse_from(["bashpic", "server", "--model", "cli-model.pt"]);
+        unsafe {
+            std::env::remove_var("BASHPIC_PORT");
+            std::env::remove_var("BASHPIC_MODEL");
+        }
 
-    match cli.command {
-        Commands::Server => {
-            println!("Запуск веб-сервера на http://0.0.0.0:5000");
+        let Commands::Server { host, port, grpc_port, model, .. } = cli.unwrap().command else {
+            panic!("ожидалась команда server");
+        };
+        assert_eq!(model.unwrap_or(config.model), "cli-model.pt"); // флаг важнее переменной
+        assert_eq!(port.unwrap_or(config.port), 7100); // переменная важнее файла
+        assert_eq!(host.unwrap_or(config.host), "10.0.0.1"); // файл важнее встроенного значения
+        assert_eq!(grpc_port.unwrap_or(config.grpc_port), 50051); // встроенное значение
+    }
 
-            let device = Device::Cpu;
-            let vs = nn::VarStore::new(device);
-            let model = simple_cnn(&vs.root(), 10);
+    // Набор из n скриншотов, где тип ошибки задается яркостью первого канала, а тип ОС -
+    // второго: классы легко разделимы. Сохраняется в формате preprocess для --tensor-cache
+    fn save_separable_dataset(name: &str, n: i64, input_size: i64) -> String {
+        let error_labels = Tensor::arange(n, (Kind::Int64, Device::Cpu)).remainder(OS_ERROR_TYPES.len() as i64);
+        let os_labels = Tensor::arange(n, (Kind::Int64, Device::Cpu)).remainder(OS_TYPES.len() as i64);
+        let error_level = (error_labels.to_kind(Kind::Float) / OS_ERROR_TYPES.len() as f64).view([n, 1, 1, 1]);
+        let os_level = (os_labels.to_kind(Kind::Float) / OS_TYPES.len() as f64).view([n, 1, 1, 1]);
+        let plane = Tensor::ones(&[n, 1, input_size, input_size], (Kind::Float, Device::Cpu));
+        let images = Tensor::cat(&[&plane * &error_level, &plane * &os_level, plane.zeros_like()], 1);
+
+        let path = temp_path(name).to_string_lossy().into_owned();
+        Tensor::save_multi(
+            &[(TENSOR_CACHE_IMAGES, &images), (TENSOR_CACHE_ERROR_LABELS, &error_labels), (TENSOR_CACHE_OS_LABELS, &os_labels)],
+            &path,
+        )
+        .unwrap();
+        path
+    }
 
-            // Попытка загрузить существующую модель или создать новую
-            if vs.load("model.pt").is_err() {
-                println!("Модель не найдена, создание новой...");
-                let (train_images, train_labels) = create_dummy_data();
-                train_model(&model, &train_images, &train_labels, &vs);
-            }
+    // Обучение модели ошибок ОС на наборе из save_separable_dataset; возвращает
+    // общую потерю каждой эпохи из --metrics-csv
+    fn train_os_error_losses(config: OsErrorTrainConfig) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
+        let metrics = config.metrics_csv.clone().expect("для потерь нужен metrics_csv");
+        train_os_error_model(&config)?;
+        let losses = std::fs::read_to_string(&metrics)?
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').nth(3).unwrap().parse().unwrap())
+            .collect();
+        std::fs::remove_file(&metrics)?;
+        Ok(losses)
+    }
 
-            let model_data = web::Data::new(Mutex::new((Box::new(model) as Box<dyn nn::Module + Send>, vs)));
+    #[test]
+    fn mini_batch_training_over_50_images_decreases_loss() {
+        let dataset = save_separable_dataset("mini-batch.cache", 50, 32);
+        let model_path = temp_path("mini-batch.pt").to_string_lossy().into_owned();
+        let losses = train_os_error_losses(OsErrorTrainConfig {
+            input_size: 32,
+            epochs: 5,
+            learning_rate: 1e-3,
+            batch_size: 8, // 6 полных батчей и неполный последний из 2 примеров
+            model_path: model_path.clone(),
+            tensor_cache: Some(dataset.clone()),
+            metrics_csv: Some(temp_path("mini-batch.csv").to_string_lossy().into_owned()),
+            seed: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
 
-            // Инициализация модели для ошибок ОС
-            let device = Device::Cpu;
-            let vs_os = nn::VarStore::new(device);
-            let (error_model, os_model) = os_error_cnn(&vs_os.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
+        assert_eq!(losses.len(), 5);
+        assert!(losses.iter().all(|loss| loss.is_finite()), "{:?}", losses);
+        assert!(losses[4] < losses[0], "{:?}", losses);
+        std::fs::remove_file(&dataset).unwrap();
+        std::fs::remove_file(&model_path).unwrap();
+    }
 
-            // Попытка загрузить модель для ошибок ОС
-            if vs_os.load("os_error_model.pt").is_err() {
-                println!("Модель для ошибок ОС не найдена, создание новой...");
-                match train_os_error_model() {
-                    Ok(_) => println!("Модель для ошибок ОС создана успешно"),
-                    Err(e) => println!("Ошибка создания модели для ошибок ОС: {}", e),
-                }
-                let _ = vs_os.load("os_error_model.pt");
+    // Шаг ResumableAdam на маленькой линейной модели без dropout; возвращает потерю до шага
+    fn adam_step(model: &nn::Linear, optimizer: &mut ResumableAdam) -> f64 {
+        let xs = Tensor::of_slice(&[1.0f32, 0.0, 0.0, 1.0, 1.0, 1.0, -1.0, 0.5]).view([4, 2]);
+        let targets = Tensor::of_slice(&[0i64, 1, 2, 0]);
+        let loss = model.forward(&xs).cross_entropy_for_logits(&targets);
+        optimizer.zero_grad();
+        loss.backward();
+        optimizer.step();
+        f64::from(&loss)
+    }
+
+    #[test]
+    fn resumed_adam_matches_uninterrupted_training() {
+        let linear = |vs: &nn::VarStore| nn::linear(&vs.root() / "fc", 2, 3, Default::default());
+
+        // Начальные веса копируются, а не берутся из зерна: тесты выполняются параллельно
+        // и делят генератор случайных чисел libtorch
+        let initial = nn::VarStore::new(Device::Cpu);
+        let _ = linear(&initial);
+        let start = || {
+            let mut vs = nn::VarStore::new(Device::Cpu);
+            let model = linear(&vs);
+            vs.copy(&initial).unwrap();
+            (vs, model)
+        };
+
+        let (vs, model) = start();
+        let mut optimizer = ResumableAdam::new(&vs, 0.1);
+        let uninterrupted: Vec<f64> = (0..4).map(|_| adam_step(&model, &mut optimizer)).collect();
+
+        // Те же два шага, затем сохранение модели и состояния оптимизатора
+        let (vs, model) = start();
+        let mut optimizer = ResumableAdam::new(&vs, 0.1);
+        adam_step(&model, &mut optimizer);
+        adam_step(&model, &mut optimizer);
+        let path = temp_path("resume-adam.pt");
+        vs.save(&path).unwrap();
+        optimizer.save(&path).unwrap();
+
+        let resume = |with_state: bool| {
+            let mut vs = nn::VarStore::new(Device::Cpu);
+            let model = linear(&vs);
+            vs.load(&path).unwrap();
+            let mut optimizer = ResumableAdam::new(&vs, 0.1);
+            if with_state {
+                optimizer.load(&path).unwrap();
             }
+            adam_step(&model, &mut optimizer);
+            adam_step(&model, &mut optimizer)
+        };
+
+        // Потеря на шаге после возобновления совпадает с непрерывным обучением,
+        // а Adam с нулевыми моментами дает другой шаг
+        assert_close(resume(true), uninterrupted[3]);
+        assert!((resume(false) - uninterrupted[3]).abs() > 1e-4);
+    }
 
-            let os_error_model_data = web::Data::new(Mutex::new(
-                ((Box::new(error_model) as Box<dyn nn::Module + Send>,
-                  Box::new(os_model) as Box<dyn nn::Module + Send>), vs_os)
-            ));
+    // Полнота редкого класса после обучения логистической регрессии на несбалансированном
+    // наборе: 90 примеров частого класса на [-1, 1.5] и 10 редкого на [0.5, 2], классы перекрываются
+    fn rare_class_recall(weighted: bool) -> f64 {
+        let majority = (0..90).map(|i| -1.0 + 2.5 * i as f32 / 89.0);
+        let minority = (0..10).map(|i| 0.5 + 1.5 * i as f32 / 9.0);
+        let xs: Vec<f32> = majority.chain(minority).collect();
+        let labels: Vec<i64> = (0..100).map(|i| i64::from(i >= 90)).collect();
+        let xs = Tensor::of_slice(&xs).view([100, 1]);
+        let labels = Tensor::of_slice(&labels);
+        let weights = if weighted { inverse_frequency_weights(&labels, 2) } else { vec![1.0; 2] };
+        let weights = Tensor::of_slice(&weights).to_kind(Kind::Float);
+
+        tch::manual_seed(0);
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = nn::linear(&vs.root() / "fc", 1, 2, Default::default());
+        let mut optimizer = ResumableAdam::new(&vs, 0.05);
+        for _ in 0..500 {
+            let loss = weighted_cross_entropy(&model.forward(&xs), &labels, &weights);
+            optimizer.zero_grad();
+            loss.backward();
+            optimizer.step();
+        }
 
-            // Создание сервера чата
-            let chat_server = ChatServer::new(os_error_model_data.clone().into_inner()).start();
+        let predicted = Vec::<i64>::from(&tch::no_grad(|| model.forward(&xs.narrow(0, 90, 10))).argmax(-1, false));
+        predicted.iter().filter(|&&class| class == 1).count() as f64 / 10.0
+    }
 
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(model_data.clone())
-                    .app_data(os_error_model_data.clone())
-                    .app_data(web::Data::new(chat_server.clone()))
-                    .route("/predict", web::post().to(predict))
-                    .route("/predict-os-error", web::post().to(predict_os_error))
-                    .route("/ws/", web::get().to(websocket_handler))
-                    .route("/chat", web::get().to(chat_page))
-                    .route("/", web::get().to(|| async {
-                        HttpResponse::Ok().body("Сервер классификации изображений запущен!\n\
-                                              Используйте:\n\
-                                              POST /predict - для общей классификации\n\
-                                              POST /predict-os-error - для анализа ошибок ОС\n\
-                                              GET /chat - для чата с AI помощником\n\
-                                              WS /ws/ - WebSocket подключение для чата")
-                    }))
-            })
-            .bind("0.0.0.0:5000")?
-            .run()
+    #[test]
+    fn class_weights_improve_rare_class_recall() {
+        let (unweighted, weighted) = (rare_class_recall(false), rare_class_recall(true));
+        assert!(weighted > unweighted, "{} <= {}", weighted, unweighted);
+    }
+
+    #[test]
+    fn cuda_request_without_cuda_falls_back_or_fails() {
+        assert_eq!(resolve_device_with(None, true, None), Ok(Device::Cpu));
+        assert_eq!(resolve_device_with(Some(Device::Cpu), true, None), Ok(Device::Cpu));
+        // Без --strict-device - CPU с предупреждением, со --strict-device - понятная ошибка
+        assert_eq!(resolve_device_with(Some(Device::Cuda(0)), false, None), Ok(Device::Cpu));
+        let error = resolve_device_with(Some(Device::Cuda(0)), true, None).unwrap_err();
+        assert!(error.contains("CUDA недоступна") && error.contains("--device cpu"), "{}", error);
+
+        // Видеокарта с несуществующим номером
+        assert_eq!(resolve_device_with(Some(Device::Cuda(1)), false, Some(1)), Ok(Device::Cpu));
+        assert!(resolve_device_with(Some(Device::Cuda(1)), true, Some(1)).unwrap_err().contains("cuda:1"));
+        assert_eq!(resolve_device_with(Some(Device::Cuda(0)), true, Some(1)), Ok(Device::Cuda(0)));
+    }
+
+    // Сессия чата, которая молча принимает ответы сервера
+    struct SilentSession;
+
+    impl Actor for SilentSession {
+        type Context = actix::Context<Self>;
+    }
+
+    impl Handler<SessionText> for SilentSession {
+        type Result = ();
+
+        fn handle(&mut self, _: SessionText, _: &mut Self::Context) {}
+    }
+
+    // Сервер чата на одну сессию; задания инференса уходят в jobs, а не в поток с моделью
+    fn single_session_chat_server(jobs: std::sync::mpsc::Sender<InferenceJob>) -> ChatServer {
+        ChatServer::new(
+            InferenceQueue { jobs },
+            Arc::new(RecentPredictions::new(DEFAULT_RECENT_SIZE)),
+            None,
+            Arc::new(EscalationQueue::new(None, 16)),
+            ChatServerConfig {
+                screenshot_cache: ScreenshotCacheConfig::default(),
+                max_sessions: 1,
+                session_ttl: std::time::Duration::from_secs(DEFAULT_SESSION_TTL_SECS),
+                animated_images: AnimatedImages::FirstFrame,
+            },
+        )
+    }
+
+    #[actix_web::test]
+    async fn identical_screenshots_run_one_forward() {
+        // Вместо потока с моделью задания очереди инференса получает тест и считает их
+        let (jobs, queue) = std::sync::mpsc::channel();
+        let mut server = single_session_chat_server(jobs);
+        let session = Uuid::new_v4();
+        server.sessions.insert(session, SilentSession.start().recipient());
+
+        let mut png = Vec::new();
+        structured_screenshot(64, 48).write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png).unwrap();
+        let image_data = base64::engine::general_purpose::STANDARD.encode(&png);
+
+        let (pending, _) = server.prepare_screenshot(session, &image_data);
+        let job = queue.try_recv().expect("первый скриншот должен попасть в очередь инференса");
+        let prediction = ScreenshotPrediction { error_type: "kernel_panic".to_string(), os_type: "linux".to_string(), confidence: 0.9 };
+        assert!(job.reply.send(Ok(prediction)).is_ok());
+        let first = server.finish_analysis(session, pending.resolve().await).unwrap();
+
+        let (pending, _) = server.prepare_screenshot(session, &image_data);
+        assert!(queue.try_recv().is_err(), "повторный скриншот не должен запускать модель");
+        let second = server.finish_analysis(session, pending.resolve().await).unwrap();
+        assert_eq!((second.error_type, second.image_hash), (first.error_type, first.image_hash));
+    }
+
+    // Файл меток во временном каталоге
+    fn write_labels(name: &str, json: &str) -> String {
+        let path = temp_path(name).to_string_lossy().into_owned();
+        std::fs::write(&path, json).unwrap();
+        path
+    }
+
+    #[test]
+    fn three_class_labels_name_outputs_of_three_class_model() {
+        let path = write_labels("labels-3.json", r#"{"error_types": ["crash", "hang", "oom"], "os_types": ["windows", "linux"]}"#);
+        let labels = Labels::load(Some(&path)).unwrap();
+        assert_eq!(labels.error_types, ["crash", "hang", "oom"]);
+
+        let bundle = OsErrorModelBundle::new(ModelMetadata::os_error_cnn_with_labels(32, &labels), Device::Cpu);
+        let model_path = temp_path("labels-3.pt").to_string_lossy().into_owned();
+        bundle.vs.save(&model_path).unwrap();
+        bundle.metadata.save(&model_path).unwrap();
+
+        let loaded = OsErrorModelBundle::load_on(&model_path, Device::Cpu, &labels).unwrap();
+        let (error_logits, os_logits) = tch::no_grad(|| loaded.forward(&Tensor::rand(&[2, 3, 32, 32], (Kind::Float, Device::Cpu))));
+        assert_eq!((error_logits.size(), os_logits.size()), (vec![2, 3], vec![2, 2]));
+        assert_eq!((loaded.metadata.error_label(2), loaded.metadata.os_label(1)), ("oom", "linux"));
+
+        // Встроенный словарь из 10 классов к модели с тремя выходами не подходит
+        let error = OsErrorModelBundle::load_on(&model_path, Device::Cpu, &Labels::builtin()).err().unwrap();
+        assert!(error.to_string().contains("--labels"), "{}", error);
+    }
+
+    #[test]
+    fn empty_or_duplicate_labels_are_rejected() {
+        let empty = write_labels("labels-empty.json", r#"{"error_types": [], "os_types": ["linux"]}"#);
+        let error = Labels::load(Some(&empty)).unwrap_err();
+        assert!(error.to_string().contains("error_types пуст"), "{}", error);
+
+        let duplicate = write_labels("labels-duplicate.json", r#"{"error_types": ["crash"], "os_types": ["linux", "linux"]}"#);
+        let error = Labels::load(Some(&duplicate)).unwrap_err();
+        assert!(error.to_string().contains("os_types есть повторы"), "{}", error);
+    }
+
+    #[test]
+    fn nan_input_aborts_training_without_overwriting_checkpoint() {
+        let model_path = temp_path("nan-model.pt");
+        std::fs::write(&model_path, b"good checkpoint").unwrap();
+
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = simple_cnn(&vs.root(), 10, &CnnConfig::default());
+        let (images, labels) = create_dummy_data();
+        let images = images * f64::NAN;
+        let config = TrainConfig {
+            epochs: 3,
+            model_path: model_path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+
+        let error = train_model(&model, &images, &labels, &vs, &config).unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<TrainingError>(), Some(TrainingError::NonFiniteLoss { epoch: 1, .. })),
+            "{}", error
+        );
+        assert!(error.to_string().contains("--learning-rate"), "{}", error);
+        assert_eq!(std::fs::read(&model_path).unwrap(), b"good checkpoint");
+    }
+
+    #[test]
+    fn json_mode_prints_rest_response_schema() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = simple_cnn(&vs.root(), 10, &CnnConfig::default());
+        let model_path = temp_path("json-model.pt").to_string_lossy().into_owned();
+        vs.save(&model_path).unwrap();
+        ModelMetadata::simple_cnn(10, CnnConfig::default()).save(&model_path).unwrap();
+        let image_path = temp_path("json-image.png");
+        structured_screenshot(64, 48).save(&image_path).unwrap();
+
+        let mut out = Vec::new();
+        predict_from_cli(&[model_path], image_path.to_str().unwrap(), None, Device::Cpu, true, &mut out).unwrap();
+        let prediction: PredictResponse = serde_json::from_slice(&out).unwrap();
+        assert!((0..10).contains(&prediction.class), "{:?}", prediction);
+
+        let model = OsErrorModelBundle::load(&save_os_error_model("json-os.pt", 32)).unwrap();
+        let mut out = Vec::new();
+        predict_os_error_from_cli(&[model], &structured_screenshot(64, 48), None, true, false, &mut out).unwrap();
+        // Одна строка JSON без текстового отчета
+        assert_eq!(out.iter().filter(|&&byte| byte == b'\n').count(), 1);
+        let prediction: OsErrorPredictResponse = serde_json::from_slice(&out).unwrap();
+        assert!(OS_ERROR_TYPES.contains(&prediction.error_type.as_str()), "{:?}", prediction);
+        assert!(OS_TYPES.contains(&prediction.os_type.as_str()), "{:?}", prediction);
+        assert!((0.0..=1.0).contains(&prediction.confidence));
+    }
+
+    #[actix_web::test]
+    async fn in_process_server_round_trips_predictions() {
+        use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+
+        let generic_path = temp_path("in-process-generic.pt").to_string_lossy().into_owned();
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = simple_cnn(&vs.root(), 10, &CnnConfig::default());
+        vs.save(&generic_path).unwrap();
+        ModelMetadata::simple_cnn(10, CnnConfig::default()).save(&generic_path).unwrap();
+        let model_data: web::Data<GenericModel> =
+            web::Data::new(RwLock::new(InferenceModels(load_generic_model(&generic_path, Device::Cpu).unwrap())));
+
+        let os_error_model = OsErrorModelBundle::load(&save_os_error_model("in-process-os.pt", 32)).unwrap();
+        let os_error_model_data: web::Data<OsErrorModels> = web::Data::new(RwLock::new(InferenceModels(os_error_model)));
+        let registry = OsErrorModelRegistry::new("in-process-os".to_string(), os_error_model_data.clone().into_inner());
+
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(request_id_and_timing))
+                .app_data(web::Data::new(ApiKeys { keys: Vec::new() }))
+                .app_data(model_data)
+                .app_data(os_error_model_data)
+                .app_data(web::Data::new(registry))
+                .app_data(web::Data::new(PredictionCaches::new(DEFAULT_PREDICTION_CACHE_SIZE)))
+                .app_data(web::Data::new(RecentPredictions::new(DEFAULT_RECENT_SIZE)))
+                .app_data(web::Data::new(InferenceLimits::new(std::time::Duration::from_secs(30), 1)))
+                .app_data(json_config(DEFAULT_MAX_BODY_SIZE))
+                .app_data(query_config())
+                .configure(|cfg| configure_routes(cfg, &[])),
+        )
+        .await;
+
+        // Тело запроса такое же, как у клиента из модуля client
+        let mut png = Vec::new();
+        structured_screenshot(64, 48).write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png).unwrap();
+        let body = PredictRequest {
+            image: None,
+            image_base64: Some(base64::engine::general_purpose::STANDARD.encode(&png)),
+            input_format: None,
+        };
+
+        let res = call_service(&app, TestRequest::post().uri("/predict").set_json(&body).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let prediction: PredictResponse = read_body_json(res).await;
+        assert!((0..10).contains(&prediction.class), "{:?}", prediction);
+
+        let res = call_service(&app, TestRequest::post().uri("/predict-os-error").set_json(&body).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let prediction: OsErrorPredictResponse = read_body_json(res).await;
+        assert!(OS_ERROR_TYPES.contains(&prediction.error_type.as_str()), "{:?}", prediction);
+        assert!(!prediction.solutions.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn silent_chat_client_is_dropped_by_heartbeat() {
+        use actix_web::http::header;
+
+        let (jobs, _queue) = std::sync::mpsc::channel();
+        let server = single_session_chat_server(jobs).start();
+        let session = ChatSession {
+            id: Uuid::new_v4(),
+            token: None,
+            addr: server.clone(),
+            max_message_size: DEFAULT_MAX_BODY_SIZE,
+            heartbeat: ChatHeartbeat {
+                interval: std::time::Duration::from_millis(20),
+                timeout: std::time::Duration::from_millis(100),
+            },
+            last_heartbeat: std::time::Instant::now(),
+            binary_frames: 0,
+        };
+        let req = actix_web::test::TestRequest::get()
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_request();
+        // Клиент держит соединение открытым, но ничего не присылает и не отвечает на ping
+        let silent = futures_util::stream::pending::<Result<web::Bytes, actix_web::error::PayloadError>>();
+        let res = ws::WsResponseBuilder::new(session, &req, silent).start().unwrap();
+        assert_eq!(res.status(), 101);
+
+        // Поток кадров заканчивается, когда сессия закрывается по таймауту
+        let frames = tokio::time::timeout(std::time::Duration::from_secs(5), actix_web::body::to_bytes(res.into_body()))
             .await
-        },
-        Commands::Train => {
-            println!("Обучение модели...");
+            .expect("молчащая сессия не закрыта")
+            .unwrap();
+        assert!(frames.windows(2).any(|frame| frame == [0x89, 0x00]), "сессия не отправляла ping");
 
-            let device = Device::Cpu;
-            let vs = nn::VarStore::new(device);
-            let model = simple_cnn(&vs.root(), 10);
+        // Сервер освободил место единственной сессии
+        let connect = Connect { id: Uuid::new_v4(), token: None, addr: SilentSession.start().recipient() };
+        assert!(server.send(connect).await.unwrap());
+    }
 
-            let (train_images, train_labels) = create_dummy_data();
-            train_model(&model, &train_images, &train_labels, &vs);
+    #[test]
+    fn dry_run_writes_no_checkpoint() {
+        let model_path = temp_path("dry-run.pt");
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = simple_cnn(&vs.root(), 10, &CnnConfig::default());
+        let (images, labels) = create_dummy_data();
+        let config = TrainConfig {
+            model_path: model_path.to_string_lossy().into_owned(),
+            dry_run: true,
+            ..Default::default()
+        };
+        train_model(&model, &images, &labels, &vs, &config).unwrap();
+        assert!(!model_path.exists());
+
+        let dataset = save_separable_dataset("dry-run.cache", 20, 32);
+        let os_model_path = temp_path("dry-run-os.pt");
+        train_os_error_model(&OsErrorTrainConfig {
+            input_size: 32,
+            model_path: os_model_path.to_string_lossy().into_owned(),
+            tensor_cache: Some(dataset),
+            dry_run: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!os_model_path.exists());
+        assert!(!ModelMetadata::path_for(&os_model_path).exists());
+        assert!(!ResumableAdam::path_for(&os_model_path).exists());
+    }
 
-            Ok(())
-        },
-        Commands::Predict { model, image } => {
-            match predict_from_cli(&model, &image) {
-                Ok(_) => println!("Предсказание выполнено успешно"),
-                Err(e) => eprintln!("Ошибка при предсказании: {}", e),
-            }
-            Ok(())
-        },
-        Commands::TrainOsError => {
-            println!("Обучение модели для предсказания ошибок ОС...");
-            match train_os_error_model() {
-                Ok(_) => println!("Обучение завершено успешно"),
-                Err(e) => eprintln!("Ошибка при обучении: {}", e),
+    #[test]
+    fn smaller_cnn_config_runs_forward_and_round_trips() {
+        let cnn = CnnConfig { conv1_channels: 8, conv2_channels: 16, hidden: 32 };
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = simple_cnn(&vs.root(), 10, &cnn);
+        let output = tch::no_grad(|| model.forward(&Tensor::rand(&[4, 3, SIMPLE_CNN_INPUT_SIZE, SIMPLE_CNN_INPUT_SIZE], (Kind::Float, Device::Cpu))));
+        assert_eq!(output.size(), vec![4, 10]);
+        let params: i64 = vs.variables().values().map(|variable| variable.numel() as i64).sum();
+        assert!(params < 100_000, "{}", params);
+
+        // Размеры слоев восстанавливаются из метаданных при загрузке
+        let path = temp_path("small-cnn.pt").to_string_lossy().into_owned();
+        vs.save(&path).unwrap();
+        ModelMetadata::simple_cnn(10, cnn).save(&path).unwrap();
+        let (loaded, _, metadata) = load_generic_model(&path, Device::Cpu).unwrap();
+        assert_eq!(metadata.cnn_config(), cnn);
+        let output = tch::no_grad(|| loaded.forward(&Tensor::rand(&[1, 3, SIMPLE_CNN_INPUT_SIZE, SIMPLE_CNN_INPUT_SIZE], (Kind::Float, Device::Cpu))));
+        assert_eq!(output.size(), vec![1, 10]);
+    }
+
+    #[test]
+    fn square_preprocessor_output_shapes_at_32_and_128() {
+        let img = structured_screenshot(200, 90);
+        for size in [32, 128] {
+            let tensor = StandardPreprocessor::square(size).to_tensor(&img);
+            assert_eq!(tensor.size(), vec![1, 3, size, size]);
+            assert_eq!(tensor.kind(), Kind::Float);
+            let (min, max) = (f64::from(&tensor.min()), f64::from(&tensor.max()));
+            assert!(0.0 <= min && max <= 1.0, "{}..{}", min, max);
+        }
+        // Без размера сохраняется исходное разрешение
+        assert_eq!(StandardPreprocessor::native().to_tensor(&img).size(), vec![1, 3, 90, 200]);
+    }
+
+    #[test]
+    fn mistyped_model_path_suggests_closest_model() {
+        let dir = temp_path("models-dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["os_error_model.pt", "model.pt", "notes.txt"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let path = |name: &str| dir.join(name).display().to_string();
+
+        let error = check_model_path(&path("os_eror_model.pt")).unwrap_err();
+        assert_eq!(error.suggestion, Some(path("os_error_model.pt")));
+        assert_eq!(error.available, [path("model.pt"), path("os_error_model.pt")]);
+        assert!(error.to_string().contains("возможно, имелась в виду"), "{}", error);
+
+        // Совсем другое имя - без подсказки, но со списком моделей
+        let error = check_model_path(&path("classifier.pt")).unwrap_err();
+        assert_eq!(error.suggestion, None);
+        assert!(check_model_path(&path("model.pt")).is_ok());
+    }
+
+    // Снимок весов из snapshot_variables
+    type Snapshot = Vec<(String, Tensor)>;
+
+    // Один шаг Adam по общей потере модели ошибок ОС; возвращает VarStore после шага
+    // и снимки весов слоев признаков и голов до него
+    fn os_error_step(os_loss_weight: f64, freeze_backbone: bool) -> (nn::VarStore, Snapshot, Snapshot) {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let (error_model, os_model) = os_error_cnn(&vs.root(), OS_ERROR_TYPES.len() as i64, OS_TYPES.len() as i64);
+        if freeze_backbone {
+            for (name, variable) in vs.variables() {
+                if is_backbone_variable(&name) {
+                    let _ = variable.set_requires_grad(false);
+                }
             }
-            Ok(())
-        },
-        Commands::PredictOsError { model, screenshot } => {
-            match predict_os_error_from_cli(&model, &screenshot) {
-                Ok(_) => println!("\nАнализ скриншота завершен успешно"),
-                Err(e) => eprintln!("Ошибка при анализе скриншота: {}", e),
+        }
+        let (backbone, heads) = (snapshot_variables(&vs, true), snapshot_variables(&vs, false));
+
+        let images = Tensor::rand(&[4, 3, 32, 32], (Kind::Float, Device::Cpu));
+        let error_labels = Tensor::of_slice(&[0i64, 1, 2, 3]);
+        let os_labels = Tensor::of_slice(&[0i64, 1, 2, 0]);
+        let mut optimizer = ResumableAdam::new(&vs, 0.01);
+        let error_loss = error_model.forward_t(&images, true).cross_entropy_for_logits(&error_labels);
+        let os_loss = os_model.forward_t(&images, true).cross_entropy_for_logits(&os_labels);
+        optimizer.zero_grad();
+        (&error_loss + &os_loss * os_loss_weight).backward();
+        optimizer.step();
+
+        (vs, backbone, heads)
+    }
+
+    #[test]
+    fn zero_os_loss_weight_leaves_os_head_unchanged() {
+        let os_head = |heads: &[(String, Tensor)]| -> Snapshot {
+            heads.iter().filter(|(name, _)| name.starts_with("os_")).map(|(name, t)| (name.clone(), t.shallow_clone())).collect()
+        };
+
+        let (vs, _, heads) = os_error_step(0.0, false);
+        assert!(!os_head(&heads).is_empty());
+        assert_eq!(max_weight_delta(&vs, &os_head(&heads)), 0.0);
+        // Голова ошибок при этом обучается
+        assert!(max_weight_delta(&vs, &heads) > 0.0);
+
+        let (vs, _, heads) = os_error_step(DEFAULT_OS_LOSS_WEIGHT, false);
+        assert!(max_weight_delta(&vs, &os_head(&heads)) > 0.0);
+    }
+
+    #[test]
+    fn frozen_backbone_is_unchanged_after_a_step() {
+        let (vs, backbone, heads) = os_error_step(DEFAULT_OS_LOSS_WEIGHT, true);
+        assert!(!backbone.is_empty());
+        assert_eq!(max_weight_delta(&vs, &backbone), 0.0);
+        assert!(max_weight_delta(&vs, &heads) > 0.0);
+
+        let (vs, backbone, _) = os_error_step(DEFAULT_OS_LOSS_WEIGHT, false);
+        assert!(max_weight_delta(&vs, &backbone) > 0.0);
+    }
+
+    #[test]
+    fn cuda_oom_falls_back_to_cpu_forward() {
+        let bundle = OsErrorModelBundle::new(ModelMetadata::os_error_cnn(32), Device::Cpu);
+        let input = Tensor::rand(&[1, 3, 32, 32], (Kind::Float, Device::Cpu));
+        let oom = || Err(AppError::InferenceFailed("CUDA out of memory. Tried to allocate 20.00 MiB".to_string()));
+        let cpu_forward = || bundle.infer(&input, |model, input| model.forward(input).0.size());
+
+        // Нехватка памяти на GPU: результат дает повтор на CPU
+        let result = retry_on_cpu_after_oom(Device::Cuda(0), true, oom, cpu_forward);
+        assert_eq!(result.unwrap(), vec![1, OS_ERROR_TYPES.len() as i64]);
+
+        // --no-cpu-fallback, другие ошибки и инференс на CPU не повторяются
+        let retried = std::cell::Cell::new(false);
+        let mark = || {
+            retried.set(true);
+            cpu_forward()
+        };
+        assert!(is_cuda_out_of_memory(&retry_on_cpu_after_oom(Device::Cuda(0), false, oom, mark).unwrap_err()));
+        let other = || Err(AppError::InferenceFailed("shape mismatch".to_string()));
+        assert!(retry_on_cpu_after_oom(Device::Cuda(0), true, other, mark).is_err());
+        assert!(retry_on_cpu_after_oom(Device::Cpu, true, oom, mark).is_err());
+        assert!(!retried.get());
+    }
+
+    #[test]
+    fn inference_builds_no_autograd_graph() {
+        let bundle = OsErrorModelBundle::new(ModelMetadata::os_error_cnn(64), Device::Cpu);
+        let input = Tensor::rand(&[4, 3, 64, 64], (Kind::Float, Device::Cpu));
+
+        // Без no_grad выход тянет за собой граф обратного прохода по всем весам
+        let (error_logits, os_logits) = bundle.forward(&input);
+        assert!(error_logits.requires_grad() && os_logits.requires_grad());
+        let (error_logits, os_logits) = bundle.infer(&input, |model, input| model.forward(input)).unwrap();
+        assert!(!error_logits.requires_grad() && !os_logits.requires_grad());
+
+        // Микробенчмарк: время выводится для сравнения (cargo test -- --nocapture), но не
+        // проверяется, чтобы тест не зависел от загрузки машины
+        let time = |with_graph: bool| {
+            let start = std::time::Instant::now();
+            for _ in 0..5 {
+                let _logits = if with_graph { bundle.forward(&input) } else { bundle.infer(&input, |m, x| m.forward(x)).unwrap() };
             }
-            Ok(())
+            start.elapsed()
+        };
+        println!("5 прогонов 4x3x64x64: с графом {:?}, no_grad {:?}", time(true), time(false));
+    }
+
+    // WebP без потерь 1x1 (прозрачный пиксель): в image 0.24 нет кодировщика WebP без libwebp
+    const WEBP_1X1: &[u8] = &[
+        0x52, 0x49, 0x46, 0x46, 0x1a, 0x00, 0x00, 0x00, 0x57, 0x45, 0x42, 0x50, 0x56, 0x50, 0x38, 0x4c, 0x0d, 0x00,
+        0x00, 0x00, 0x2f, 0x00, 0x00, 0x00, 0x10, 0x07, 0x10, 0x11, 0x11, 0x88, 0x88, 0xfe, 0x07, 0x00,
+    ];
+
+    #[test]
+    fn webp_bmp_and_tiff_screenshots_decode() {
+        let webp = detect_and_decode(WEBP_1X1).unwrap();
+        assert_eq!((webp.width(), webp.height()), (1, 1));
+
+        for format in [image::ImageOutputFormat::Bmp, image::ImageOutputFormat::Tiff] {
+            let mut bytes = Vec::new();
+            structured_screenshot(64, 48).write_to(&mut std::io::Cursor::new(&mut bytes), format.clone()).unwrap();
+            let decoded = detect_and_decode(&bytes).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (64, 48), "{:?}", format);
+        }
+
+        // Испорченный файл: в ошибке назван распознанный формат
+        let error = detect_and_decode(&WEBP_1X1[..24]).unwrap_err();
+        assert_eq!(error.format, Some(image::ImageFormat::WebP));
+        assert!(error.to_string().contains("WebP"), "{}", error);
+    }
+
+    #[test]
+    fn os_error_model_at_256_runs_forward() {
+        let path = save_os_error_model("os-error-256.pt", 256);
+        let bundle = OsErrorModelBundle::load(&path).unwrap();
+        assert_eq!(bundle.input_size(), 256);
+
+        let input = Tensor::rand(&[2, 3, 256, 256], (Kind::Float, Device::Cpu));
+        let (error_logits, os_logits) = bundle.infer(&input, |model, input| model.forward(input)).unwrap();
+        assert_eq!(error_logits.size(), vec![2, OS_ERROR_TYPES.len() as i64]);
+        assert_eq!(os_logits.size(), vec![2, OS_TYPES.len() as i64]);
+    }
+
+    #[test]
+    fn one_loaded_model_accepts_96_and_160_images() {
+        let path = save_os_error_model("os-error-variable.pt", DEFAULT_OS_INPUT_SIZE);
+        let bundle = OsErrorModelBundle::load(&path).unwrap();
+
+        for side in [96, 160] {
+            // Исходное разрешение без приведения к размеру обучения
+            let input = StandardPreprocessor::native().to_tensor(&structured_screenshot(side, side));
+            assert_eq!(input.size(), vec![1, 3, side as i64, side as i64]);
+            let (error_logits, os_logits) = bundle.infer(&input, |model, input| model.forward(input)).unwrap();
+            assert_eq!(error_logits.size(), vec![1, OS_ERROR_TYPES.len() as i64], "{}", side);
+            assert_eq!(os_logits.size(), vec![1, OS_TYPES.len() as i64], "{}", side);
         }
     }
+
+    #[actix_web::test]
+    async fn shutdown_signal_drains_requests_and_waits_for_training() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let in_flight = web::Data::new(InFlightRequests::default());
+        let app_in_flight = in_flight.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_in_flight.clone())
+                .wrap(from_fn(request_id_and_timing))
+                .route("/slow", web::get().to(|| async {
+                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                    HttpResponse::Ok().body("done")
+                }))
+        })
+        .workers(1)
+        .disable_signals()
+        .shutdown_timeout(5)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        let server = tokio::spawn(server);
+
+        // Запрос начат до сигнала
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        while in_flight.0.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // Переобучение заканчивается после остановки HTTP-сервера
+        let training_state = web::Data::new(TrainingState {
+            in_progress: AtomicBool::new(true),
+            last_result: Mutex::new(None),
+            model_path: String::new(),
+        });
+        let training = training_state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+            training.in_progress.store(false, Ordering::SeqCst);
+        });
+
+        let (signal, received) = tokio::sync::oneshot::channel::<()>();
+        let (grpc_stop, grpc_stopped) = tokio::sync::oneshot::channel();
+        let shutdown = tokio::spawn(graceful_shutdown(
+            async {
+                let _ = received.await;
+            },
+            handle,
+            grpc_stop,
+            in_flight.clone(),
+            training_state.clone(),
+            std::time::Duration::from_secs(5),
+        ));
+        signal.send(()).unwrap();
+        shutdown.await.unwrap().unwrap();
+
+        // Начатый запрос дообработан, gRPC остановлен, переобучение дождались
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("done"), "{}", response);
+        assert_eq!(in_flight.0.load(Ordering::SeqCst), 0);
+        assert!(grpc_stopped.await.is_ok());
+        assert!(!training_state.in_progress.load(Ordering::SeqCst));
+        server.await.unwrap().unwrap();
+
+        // Новые соединения после остановки не принимаются
+        assert!(tokio::net::TcpStream::connect(address).await.is_err());
+    }
 }
@@ -0,0 +1,59 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+import time
+
+import requests
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+PORT = 5095
+SERVER_URL = f"http://localhost:{PORT}"
+API_KEY = "admin-test-key"
+
+def start_server(api_keys=None):
+    env = dict(os.environ)
+    env.pop("BASHPIC_API_KEYS", None)
+    if api_keys:
+        env["BASHPIC_API_KEYS"] = api_keys
+    server = subprocess.Popen(
+        BASHPIC + ["server", "--port", str(PORT), "--grpc-port", "50095", "--no-warmup"],
+        stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True, env=env,
+    )
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"{SERVER_URL}/", timeout=1)
+            return server
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    server.terminate()
+    raise AssertionError("Сервер не запустился")
+
+def stop_server(server):
+    server.terminate()
+    server.wait()
+
+def test_admin_closed_without_keys():
+    """Без API-ключей /admin/* отвечает 403, а предсказания остаются открытыми"""
+    server = start_server()
+    try:
+        for method, path in [("get", "/admin/train/status"), ("post", "/admin/train"), ("post", "/admin/upload-model")]:
+            response = requests.request(method, f"{SERVER_URL}{path}", timeout=30)
+            assert response.status_code == 403, (path, response.status_code, response.text)
+        response = requests.get(f"{SERVER_URL}/labels", timeout=30)
+        assert response.status_code == 200, response.text
+        print("✅ Без ключей /admin/* закрыт (403)")
+    finally:
+        stop_server(server)
+
+if __name__ == "__main__":
+    try:
+        test_admin_closed_without_keys()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании /admin: {e}")
+        sys.exit(1)
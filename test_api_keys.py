@@ -0,0 +1,82 @@
+#!/usr/bin/env python3
+
+import base64
+import io
+import os
+import subprocess
+import sys
+import time
+
+import requests
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+PORT = 5094
+SERVER_URL = f"http://localhost:{PORT}"
+API_KEY = "api-test-key"
+
+def start_server(api_keys):
+    env = dict(os.environ)
+    env["BASHPIC_API_KEYS"] = api_keys
+    server = subprocess.Popen(
+        BASHPIC + ["server", "--port", str(PORT), "--grpc-port", "50094", "--no-warmup"],
+        stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True, env=env,
+    )
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"{SERVER_URL}/", timeout=1)
+            return server
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    server.terminate()
+    raise AssertionError("Сервер не запустился")
+
+def stop_server(server):
+    server.terminate()
+    server.wait()
+
+def screenshot_base64():
+    buffer = io.BytesIO()
+    Image.new('RGB', (64, 64), color='blue').save(buffer, format='PNG')
+    return base64.b64encode(buffer.getvalue()).decode('utf-8')
+
+def test_api_keys():
+    """Без ключа и с неверным ключом /predict-os-error и /ws/ отвечают 401, с верным ключом запрос проходит"""
+    server = start_server(API_KEY)
+    try:
+        payload = {"image_base64": screenshot_base64()}
+        for headers, expected in [({}, 401), ({"X-API-Key": "wrong-key"}, 401), ({"X-API-Key": API_KEY}, 200)]:
+            response = requests.post(f"{SERVER_URL}/predict-os-error", json=payload, headers=headers, timeout=60)
+            assert response.status_code == expected, (headers, response.status_code, response.text)
+        print("✅ /predict-os-error: без ключа 401, неверный ключ 401, верный ключ 200")
+
+        # Роутер декодирует путь, поэтому закодированные варианты ведут к тем же обработчикам
+        for method, path in [("post", "/predict%2Dos-error"), ("post", "/%61dmin/train"), ("get", "/w%73/")]:
+            response = requests.request(method, f"{SERVER_URL}{path}", json=payload, timeout=60)
+            assert response.status_code == 401, (path, response.status_code, response.text)
+        print("✅ Пути с percent-кодированием без ключа тоже получают 401")
+
+        # Чат анализирует скриншоты, поэтому WebSocket закрыт тем же ключом. Запрос без
+        # заголовков Upgrade с верным ключом доходит до обработчика и отклоняется им (400)
+        for query, expected in [("", 401), ("?api_key=wrong-key", 401), (f"?api_key={API_KEY}", 400)]:
+            response = requests.get(f"{SERVER_URL}/ws/{query}", timeout=30)
+            assert response.status_code == expected, (query, response.status_code, response.text)
+        print("✅ /ws/: без ключа 401, неверный ключ 401, верный ключ пропущен к обработчику")
+
+        for path in ["/labels", "/chat"]:
+            response = requests.get(f"{SERVER_URL}{path}", timeout=30)
+            assert response.status_code == 200, (path, response.status_code)
+        print("✅ /labels и /chat открыты без ключа")
+    finally:
+        stop_server(server)
+
+if __name__ == "__main__":
+    try:
+        test_api_keys()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании API-ключей: {e}")
+        sys.exit(1)
@@ -45,6 +45,12 @@ async def test_chat():
         print(f"❌ Ошибка подключения: {e}")
         print("Убедитесь, что сервер запущен: cargo run server")
 
+async def receive_analysis(websocket, timeout=60):
+    """Ответ на сообщение со скриншотом: сначала подтверждение приема, затем анализ"""
+    acknowledgement = json.loads(await asyncio.wait_for(websocket.recv(), timeout=timeout))
+    assert "pending" in acknowledgement, acknowledgement
+    return json.loads(await asyncio.wait_for(websocket.recv(), timeout=timeout))
+
 def test_image_upload():
     """Тест загрузки изображения через base64"""
     try:
@@ -88,8 +94,7 @@ async def test_image_analysis():
             print("📤 Отправка тестового изображения...")
             await websocket.send(json.dumps(message))
 
-            response = await websocket.recv()
-            data = json.loads(response)
+            data = await receive_analysis(websocket)
 
             print(f"🤖 Ответ: {data['response']}")
 
@@ -104,6 +109,410 @@ async def test_image_analysis():
     except Exception as e:
         print(f"❌ Ошибка при тестировании изображения: {e}")
 
+async def test_invalid_images():
+    """Тест ответов на поврежденный base64 и на base64, который не является изображением"""
+    img_data = test_image_upload()
+    uri = "ws://localhost:5000/ws/"
+
+    cases = [
+        ("поврежденный base64", "iVBORw0KGgo!!!AAAANSUhEUg", "повреждены"),
+        ("base64 не изображения", base64.b64encode(b"definitely not an image").decode(), "формат"),
+    ]
+    if img_data:
+        cases.append(("data URL", "data:image/png;base64," + img_data, None))
+
+    try:
+        async with websockets.connect(uri) as websocket:
+            for name, image_data, expected in cases:
+                await websocket.send(json.dumps({"message": "Проанализируй", "image_data": image_data}))
+                data = await receive_analysis(websocket)
+                if expected is None:
+                    assert data.get("analysis"), data["response"]
+                else:
+                    assert data.get("analysis") is None, data
+                    assert expected in data["response"], data["response"]
+                print(f"✅ {name}: {data['response']}")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании некорректных изображений: {e}")
+
+MOCK_LLM_PORT = 8099
+MOCK_LLM_TOKENS = ["Синий ", "экран ", "означает ", "критическую ", "ошибку."]
+
+MOCK_LLM_STALL_SECS = 5
+
+def start_mock_llm(stalled_requests=0):
+    """Mock OpenAI-совместимого API completions, отдающий ответ потоком SSE.
+    Первые stalled_requests запросов зависают на MOCK_LLM_STALL_SECS секунд"""
+    import threading
+    import time
+    from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+    class Handler(BaseHTTPRequestHandler):
+        def do_POST(self):
+            body = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+            assert body["stream"] is True
+            assert "blue_screen_of_death" in body["prompt"]
+
+            server.requests += 1
+            if server.requests <= stalled_requests:
+                time.sleep(MOCK_LLM_STALL_SECS)
+                return
+
+            self.send_response(200)
+            self.send_header("Content-Type", "text/event-stream")
+            self.end_headers()
+            for token in MOCK_LLM_TOKENS:
+                event = {"choices": [{"text": token, "index": 0}]}
+                self.wfile.write(f"data: {json.dumps(event)}\n\n".encode())
+                self.wfile.flush()
+            self.wfile.write(b"data: [DONE]\n\n")
+
+        def log_message(self, *args):
+            pass
+
+    server = ThreadingHTTPServer(("localhost", MOCK_LLM_PORT), Handler)
+    server.requests = 0
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    return server
+
+async def test_llm_streaming():
+    """Тест потокового ответа языковой модели (сервер запущен с --llm-endpoint на mock)"""
+    mock = start_mock_llm()
+    uri = "ws://localhost:5000/ws/"
+
+    try:
+        async with websockets.connect(uri) as websocket:
+            await websocket.send(json.dumps({"message": "У меня синий экран BSOD", "image_data": None}))
+
+            chunks = []
+            while True:
+                data = json.loads(await websocket.recv())
+                if "chunk" not in data:
+                    break
+                assert data["index"] == len(chunks)
+                chunks.append(data["chunk"])
+
+            assert chunks == MOCK_LLM_TOKENS, chunks
+            assert data["response"] == "".join(MOCK_LLM_TOKENS).strip(), data["response"]
+            assert data["analysis"]["error_type"] == "blue_screen_of_death"
+            print(f"✅ Получено фрагментов: {len(chunks)}, итоговый ответ: {data['response']}")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании потокового ответа: {e}")
+        print(f"Запустите сервер: cargo run server --llm-endpoint http://localhost:{MOCK_LLM_PORT}/v1/completions")
+    finally:
+        mock.shutdown()
+
+async def test_llm_retry():
+    """Тест повторов и шаблонного ответа (сервер запущен с --llm-timeout 2 --llm-retries 1)"""
+    for stalled, expect_generated in [(1, True), (2, False)]:
+        mock = start_mock_llm(stalled_requests=stalled)
+        try:
+            async with websockets.connect("ws://localhost:5000/ws/") as websocket:
+                await websocket.send(json.dumps({"message": "У меня синий экран BSOD", "image_data": None}))
+
+                chunks = []
+                while True:
+                    data = json.loads(await asyncio.wait_for(websocket.recv(), timeout=30))
+                    if "chunk" not in data:
+                        break
+                    chunks.append(data["chunk"])
+
+                generated = "".join(MOCK_LLM_TOKENS).strip()
+                assert mock.requests == 2, mock.requests
+                if expect_generated:
+                    assert chunks == MOCK_LLM_TOKENS, chunks
+                    assert data["response"] == generated, data["response"]
+                else:
+                    assert chunks == [], chunks
+                    assert data["response"] != generated, data["response"]
+                assert data["analysis"]["error_type"] == "blue_screen_of_death"
+                print(f"✅ Зависших попыток: {stalled}, ответ: {data['response'][:60]}")
+
+        except Exception as e:
+            print(f"❌ Ошибка при тестировании повторов: {e}")
+            print(f"Запустите сервер: cargo run server --llm-endpoint http://localhost:{MOCK_LLM_PORT}/v1/completions --llm-timeout 2 --llm-retries 1")
+        finally:
+            mock.shutdown()
+
+async def test_frame_limits():
+    """Тест отказа на слишком большие и двоичные кадры (сервер с --max-body-size по умолчанию)"""
+    uri = "ws://localhost:5000/ws/"
+    try:
+        async with websockets.connect(uri) as websocket:
+            oversized = json.dumps({"message": "x" * (17 * 1024 * 1024), "image_data": None})
+            await websocket.send(oversized)
+            data = json.loads(await websocket.recv())
+            assert "больше допустимых" in data["response"], data["response"]
+            assert data["analysis"] is None
+            try:
+                await websocket.recv()
+                raise AssertionError("соединение не закрыто после слишком большого кадра")
+            except websockets.exceptions.ConnectionClosed as e:
+                assert e.rcvd.code == 1009, e.rcvd
+            print(f"✅ Слишком большой кадр: {data['response']}")
+
+        async with websockets.connect(uri) as websocket:
+            for _ in range(3):
+                await websocket.send(b"\x00\x01\x02")
+                data = json.loads(await websocket.recv())
+                assert "Двоичные кадры" in data["response"], data["response"]
+            try:
+                await websocket.recv()
+                raise AssertionError("соединение не закрыто после двоичных кадров")
+            except websockets.exceptions.ConnectionClosed as e:
+                assert e.rcvd.code == 1003, e.rcvd
+            print(f"✅ Двоичные кадры: {data['response']}")
+
+        # Сервер после отказов продолжает обслуживать новые сессии
+        async with websockets.connect(uri) as websocket:
+            await websocket.send(json.dumps({"message": "Привет", "image_data": None}))
+            data = json.loads(await websocket.recv())
+            assert data["response"], data
+            print("✅ Новая сессия после отказов работает")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании ограничений кадров: {e}")
+
+CONCURRENT_IMAGES = 8
+
+def noise_image_base64(seed, size=(160, 120)):
+    """PNG со случайным шумом: у разных seed разные перцептивные хэши, кэш сессии не срабатывает"""
+    import io
+    import random
+    from PIL import Image
+
+    rng = random.Random(seed)
+    img = Image.new('RGB', size)
+    img.putdata([(rng.randrange(256), rng.randrange(256), rng.randrange(256)) for _ in range(size[0] * size[1])])
+    buffer = io.BytesIO()
+    img.save(buffer, format='PNG')
+    return base64.b64encode(buffer.getvalue()).decode('utf-8')
+
+async def analyze_in_new_session(image_data):
+    async with websockets.connect("ws://localhost:5000/ws/") as websocket:
+        await websocket.send(json.dumps({"message": "Проанализируй", "image_data": image_data}))
+        return await receive_analysis(websocket)
+
+async def test_concurrent_images():
+    """Тест очереди инференса: одновременные скриншоты из разных сессий выполняются батчами,
+    и каждая сессия получает тот же анализ, что и при отправке в одиночку"""
+    try:
+        images = [noise_image_base64(seed) for seed in range(CONCURRENT_IMAGES)]
+
+        expected = []
+        for image_data in images:
+            expected.append((await analyze_in_new_session(image_data))["analysis"])
+
+        results = await asyncio.gather(*(analyze_in_new_session(image_data) for image_data in images))
+        for i, (data, reference) in enumerate(zip(results, expected)):
+            analysis = data["analysis"]
+            assert analysis, data["response"]
+            assert (analysis["error_type"], analysis["os_type"]) == (reference["error_type"], reference["os_type"]), (i, analysis, reference)
+            assert abs(analysis["confidence"] - reference["confidence"]) < 1e-4, (i, analysis, reference)
+        print(f"✅ Одновременных скриншотов: {len(results)}, все ответы совпали с одиночными")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании одновременного анализа: {e}")
+
+MAX_SESSIONS = 3
+
+async def test_session_limit():
+    """Тест лимита сессий чата (сервер запущен с --max-sessions 3)"""
+    uri = "ws://localhost:5000/ws/"
+    sessions = []
+    try:
+        for _ in range(MAX_SESSIONS):
+            websocket = await websockets.connect(uri)
+            sessions.append(websocket)
+            await websocket.send(json.dumps({"message": "Привет", "image_data": None}))
+            data = json.loads(await websocket.recv())
+            assert "Сервер занят" not in data["response"], data["response"]
+        print(f"✅ Открыто сессий: {len(sessions)}")
+
+        async with websockets.connect(uri) as overflow:
+            data = json.loads(await asyncio.wait_for(overflow.recv(), timeout=10))
+            assert "Сервер занят" in data["response"], data["response"]
+            assert data["analysis"] is None
+            try:
+                await overflow.recv()
+                raise AssertionError("лишняя сессия не закрыта")
+            except websockets.exceptions.ConnectionClosed as e:
+                assert e.rcvd.code == 1013, e.rcvd
+            print(f"✅ Сессия сверх лимита: {data['response']}")
+
+        # Закрытая сессия освобождает место
+        await sessions.pop().close()
+        await asyncio.sleep(0.5)
+        async with websockets.connect(uri) as websocket:
+            await websocket.send(json.dumps({"message": "Привет", "image_data": None}))
+            data = json.loads(await websocket.recv())
+            assert "Сервер занят" not in data["response"], data["response"]
+            print("✅ После отключения новая сессия принята")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании лимита сессий: {e}")
+        print(f"Запустите сервер: cargo run server --max-sessions {MAX_SESSIONS}")
+    finally:
+        for websocket in sessions:
+            await websocket.close()
+
+async def test_escalation():
+    """Тест передачи неуверенных анализов на проверку (сервер запущен с --escalation-threshold 0.6).
+    Одно ключевое слово дает уверенность 0.5, три - 0.7"""
+    import os
+    import urllib.request
+
+    cases = [
+        ("BSOD", True),
+        ("BSOD, синий экран, stop code", False),
+    ]
+    try:
+        async with websockets.connect("ws://localhost:5000/ws/") as websocket:
+            for message, expect_escalated in cases:
+                await websocket.send(json.dumps({"message": message, "image_data": None}))
+                data = json.loads(await websocket.recv())
+                assert data["analysis"]["error_type"] == "blue_screen_of_death", data
+                assert data.get("escalated", False) == expect_escalated, data
+                assert ("передан на проверку" in data["response"]) == expect_escalated, data["response"]
+
+        request = urllib.request.Request("http://localhost:5000/escalations?limit=100")
+        api_key = os.environ.get("BASHPIC_API_KEY")
+        if api_key:
+            request.add_header("X-API-Key", api_key)
+        with urllib.request.urlopen(request, timeout=30) as response:
+            escalations = json.loads(response.read())
+
+        messages = [escalation["message"] for escalation in escalations]
+        assert escalations[0]["message"] == "BSOD", escalations[0]
+        assert escalations[0]["analysis"]["confidence"] < 0.6, escalations[0]
+        assert escalations[0]["image_hash"] is None, escalations[0]
+        assert "BSOD, синий экран, stop code" not in messages, messages
+        print(f"✅ В очереди на проверку: {len(escalations)}, последний случай: {escalations[0]['message']}")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании эскалации: {e}")
+        print("Запустите сервер: cargo run server --escalation-threshold 0.6")
+
+def animated_gif_base64():
+    """GIF из двух кадров разного цвета"""
+    import io
+    from PIL import Image
+
+    frames = [Image.new('RGB', (120, 90), color=color) for color in ('blue', 'black')]
+    buffer = io.BytesIO()
+    frames[0].save(buffer, format='GIF', save_all=True, append_images=frames[1:], duration=200, loop=0)
+    return base64.b64encode(buffer.getvalue()).decode('utf-8')
+
+def progressive_jpeg_base64():
+    import io
+    from PIL import Image
+
+    buffer = io.BytesIO()
+    Image.new('RGB', (120, 90), color='blue').save(buffer, format='JPEG', progressive=True)
+    return base64.b64encode(buffer.getvalue()).decode('utf-8')
+
+async def test_animated(expect_reject=False):
+    """Тест анимированных скриншотов: по умолчанию анализируется первый кадр с предупреждением,
+    с --animated-images reject скриншот отклоняется. Прогрессивный JPEG анализируется как обычно"""
+    try:
+        async with websockets.connect("ws://localhost:5000/ws/") as websocket:
+            await websocket.send(json.dumps({"message": "Анимация", "image_data": animated_gif_base64()}))
+            data = await receive_analysis(websocket)
+            if expect_reject:
+                assert data["analysis"] is None, data
+                assert "анимированное" in data["response"], data["response"]
+                assert "статичный скриншот" in data["response"], data["response"]
+            else:
+                assert data["analysis"], data["response"]
+                assert "только его первый кадр" in data["response"], data["response"]
+            print(f"✅ Анимированный GIF: {data['response']}")
+
+            await websocket.send(json.dumps({"message": "Прогрессивный JPEG", "image_data": progressive_jpeg_base64()}))
+            data = await receive_analysis(websocket)
+            assert data["analysis"], data["response"]
+            assert "первый кадр" not in data["response"], data["response"]
+            print("✅ Прогрессивный JPEG проанализирован")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании анимированных изображений: {e}")
+        if expect_reject:
+            print("Запустите сервер: cargo run server --animated-images reject")
+
+async def test_acknowledgement():
+    """Тест быстрого ответа: на скриншот сначала приходит подтверждение, затем полный анализ.
+    Текстовое сообщение получает один ответ"""
+    try:
+        async with websockets.connect("ws://localhost:5000/ws/") as websocket:
+            for images in ([noise_image_base64(100)], [noise_image_base64(101), noise_image_base64(102)]):
+                image_data = images[0] if len(images) == 1 else images
+                await websocket.send(json.dumps({"message": "Проанализируй", "image_data": image_data}))
+
+                first = json.loads(await asyncio.wait_for(websocket.recv(), timeout=10))
+                assert first["pending"] == len(images), first
+                assert "Анализирую" in first["response"], first
+                assert "analysis" not in first, first
+
+                second = json.loads(await asyncio.wait_for(websocket.recv(), timeout=60))
+                assert "pending" not in second, second
+                if len(images) == 1:
+                    assert second["analysis"], second["response"]
+                else:
+                    assert len(second["analyses"]) == len(images), second
+                print(f"✅ Скриншотов: {len(images)}, порядок ответов: «{first['response']}», затем анализ")
+
+            await websocket.send(json.dumps({"message": "помощь", "image_data": None}))
+            data = json.loads(await asyncio.wait_for(websocket.recv(), timeout=10))
+            assert "pending" not in data, data
+            try:
+                extra = json.loads(await asyncio.wait_for(websocket.recv(), timeout=1))
+                raise AssertionError(f"на текстовое сообщение пришел второй ответ: {extra}")
+            except asyncio.TimeoutError:
+                print("✅ Текстовое сообщение получает один ответ")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании подтверждения приема: {e}")
+
+async def test_resume():
+    """Тест возобновления разговора: после отключения сессия с тем же токеном
+    получает последний анализ, а сессия с новым токеном начинает с чистого листа"""
+    import uuid
+
+    token = uuid.uuid4().hex
+    uri = f"ws://localhost:5000/ws/?session={token}"
+    try:
+        async with websockets.connect(uri) as websocket:
+            await websocket.send(json.dumps({"message": "BSOD, синий экран, stop code", "image_data": None}))
+            data = json.loads(await websocket.recv())
+            last_analysis = data["analysis"]
+            assert last_analysis["error_type"] == "blue_screen_of_death", data
+        print("✅ Анализ получен, соединение закрыто")
+
+        await asyncio.sleep(0.5)
+        async with websockets.connect(uri) as websocket:
+            data = json.loads(await asyncio.wait_for(websocket.recv(), timeout=10))
+            assert data["response"].startswith("Соединение восстановлено"), data["response"]
+            assert data["analysis"] == last_analysis, (data["analysis"], last_analysis)
+            print(f"✅ После переподключения: {data['response']}")
+
+        async with websockets.connect(f"ws://localhost:5000/ws/?session={uuid.uuid4().hex}") as websocket:
+            try:
+                data = json.loads(await asyncio.wait_for(websocket.recv(), timeout=2))
+                raise AssertionError(f"новая сессия получила чужой разговор: {data}")
+            except asyncio.TimeoutError:
+                print("✅ Сессия с новым токеном начинается без восстановления")
+
+        try:
+            async with websockets.connect("ws://localhost:5000/ws/?session=short") as websocket:
+                raise AssertionError("короткий токен принят")
+        except websockets.exceptions.InvalidStatusCode as e:
+            assert e.status_code == 400, e
+            print("✅ Короткий токен отклонен")
+
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании возобновления сессии: {e}")
+
 if __name__ == "__main__":
     print("🧪 Тестирование чата с AI помощником")
     print("="*50)
@@ -111,9 +520,49 @@ if __name__ == "__main__":
     if len(sys.argv) > 1 and sys.argv[1] == "image":
         print("Тестирование анализа изображений...")
         asyncio.run(test_image_analysis())
+        asyncio.run(test_invalid_images())
+    elif len(sys.argv) > 1 and sys.argv[1] == "llm":
+        print("Тестирование потокового ответа языковой модели...")
+        asyncio.run(test_llm_streaming())
+    elif len(sys.argv) > 1 and sys.argv[1] == "limits":
+        print("Тестирование ограничений кадров WebSocket...")
+        asyncio.run(test_frame_limits())
+    elif len(sys.argv) > 1 and sys.argv[1] == "llm-retry":
+        print("Тестирование повторов запросов к языковой модели...")
+        asyncio.run(test_llm_retry())
+    elif len(sys.argv) > 1 and sys.argv[1] == "concurrent":
+        print("Тестирование одновременного анализа скриншотов...")
+        asyncio.run(test_concurrent_images())
+    elif len(sys.argv) > 1 and sys.argv[1] == "sessions":
+        print("Тестирование лимита сессий чата...")
+        asyncio.run(test_session_limit())
+    elif len(sys.argv) > 1 and sys.argv[1] == "escalation":
+        print("Тестирование передачи неуверенных анализов на проверку...")
+        asyncio.run(test_escalation())
+    elif len(sys.argv) > 1 and sys.argv[1] == "animated":
+        print("Тестирование анимированных скриншотов...")
+        asyncio.run(test_animated())
+    elif len(sys.argv) > 1 and sys.argv[1] == "animated-reject":
+        print("Тестирование отклонения анимированных скриншотов...")
+        asyncio.run(test_animated(expect_reject=True))
+    elif len(sys.argv) > 1 and sys.argv[1] == "ack":
+        print("Тестирование быстрого ответа на скриншот...")
+        asyncio.run(test_acknowledgement())
+    elif len(sys.argv) > 1 and sys.argv[1] == "resume":
+        print("Тестирование возобновления сессии после обрыва...")
+        asyncio.run(test_resume())
     else:
         print("Тестирование текстового чата...")
         asyncio.run(test_chat())
 
     print("\n✅ Тестирование завершено")
     print("Для тестирования изображений: python3 test_chat_api.py image")
+    print("Для тестирования языковой модели: python3 test_chat_api.py llm")
+    print("Для тестирования повторов: python3 test_chat_api.py llm-retry")
+    print("Для тестирования ограничений кадров: python3 test_chat_api.py limits")
+    print("Для тестирования одновременного анализа: python3 test_chat_api.py concurrent")
+    print("Для тестирования лимита сессий: python3 test_chat_api.py sessions")
+    print("Для тестирования эскалации: python3 test_chat_api.py escalation")
+    print("Для тестирования возобновления сессии: python3 test_chat_api.py resume")
+    print("Для тестирования быстрого ответа на скриншот: python3 test_chat_api.py ack")
+    print("Для тестирования анимированных скриншотов: python3 test_chat_api.py animated (или animated-reject)")
@@ -0,0 +1,65 @@
+#!/usr/bin/env python3
+
+import json
+import os
+import random
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image, ImageDraw
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+def make_image(path, seed, size=(200, 160)):
+    """Случайная сетка 8x8 черных и белых блоков: хэши разных картинок различаются примерно в половине бит"""
+    rng = random.Random(seed)
+    image = Image.new('RGB', size, color='white')
+    draw = ImageDraw.Draw(image)
+    block_w, block_h = size[0] // 8, size[1] // 8
+    for row in range(8):
+        for col in range(8):
+            if rng.random() < 0.5:
+                draw.rectangle([col * block_w, row * block_h, (col + 1) * block_w - 1, (row + 1) * block_h - 1], fill='black')
+    image.save(path)
+
+def test_planted_duplicate():
+    """Копия обучающего изображения в тестовом наборе (пересохраненная в JPEG меньшего размера) находится"""
+    with tempfile.TemporaryDirectory() as directory:
+        train_dir = os.path.join(directory, "train")
+        test_dir = os.path.join(directory, "test")
+        os.makedirs(os.path.join(train_dir, "nested"))
+        os.makedirs(test_dir)
+        for seed in range(3):
+            make_image(os.path.join(train_dir, "nested", f"train_{seed}.png"), seed)
+        make_image(os.path.join(test_dir, "fresh.png"), 7)
+        Image.open(os.path.join(train_dir, "nested", "train_1.png")).resize((160, 128)).save(os.path.join(test_dir, "leaked.jpg"))
+
+        result = subprocess.run(
+            BASHPIC + ["check-leakage", "--train-dir", train_dir, "--test-dir", test_dir, "--json"],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        report = json.loads(result.stdout)
+        assert report["train_images"] == 3, report
+        assert report["test_images"] == 2, report
+        assert report["leaked_test_images"] == 1, report
+        pairs = {(os.path.basename(c["test"]), os.path.basename(c["train"])) for c in report["collisions"]}
+        assert pairs == {("leaked.jpg", "train_1.png")}, report
+        print(f"✅ Найдена подброшенная копия: {report['collisions'][0]}")
+
+        result = subprocess.run(
+            BASHPIC + ["check-leakage", "--train-dir", train_dir, "--test-dir", test_dir],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        assert "совпадают с обучающими: 1" in result.stdout, result.stdout
+        print("✅ Сводка выводится в текстовом режиме")
+
+if __name__ == "__main__":
+    try:
+        test_planted_duplicate()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании check-leakage: {e}")
+        sys.exit(1)
@@ -0,0 +1,25 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+def test_cpu_checks():
+    """doctor завершается успешно, а прямой проход обеих архитектур на CPU помечен PASS"""
+    result = subprocess.run(BASHPIC + ["doctor"], capture_output=True, text=True)
+    assert result.returncode == 0, (result.stdout, result.stderr)
+    for architecture in ("simple_cnn", "os_error_cnn"):
+        line = next((line for line in result.stdout.splitlines() if f"{architecture} (Cpu)" in line), None)
+        assert line and line.startswith("PASS"), result.stdout
+    assert "FAIL" not in result.stdout, result.stdout
+    print("✅ Проверки на CPU пройдены")
+
+if __name__ == "__main__":
+    try:
+        test_cpu_checks()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании doctor: {e}")
+        sys.exit(1)
@@ -0,0 +1,55 @@
+#!/usr/bin/env python3
+
+import csv
+import os
+import subprocess
+import sys
+import tempfile
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+MAX_EPOCHS = 40
+
+def train(model, *extra):
+    return subprocess.run(
+        BASHPIC + ["train-os-error", "--model", model, "--input-size", "32", "--seed", "1", *extra],
+        capture_output=True, text=True,
+    )
+
+def test_early_stop_before_max_epochs():
+    """На случайных метках валидационная потеря перестает снижаться после первых эпох,
+    и обучение останавливается задолго до --epochs"""
+    with tempfile.TemporaryDirectory() as directory:
+        model = os.path.join(directory, "model.pt")
+        metrics = os.path.join(directory, "metrics.csv")
+        result = train(model, "--epochs", str(MAX_EPOCHS), "--learning-rate", "0.01",
+                       "--val-split", "0.2", "--early-stop-patience", "2", "--metrics-csv", metrics)
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        assert "Ранняя остановка" in result.stdout, result.stdout
+        assert "Лучшая модель" in result.stdout, result.stdout
+        assert os.path.exists(model)
+
+        with open(metrics) as f:
+            rows = list(csv.DictReader(f))
+        assert 2 < len(rows) < MAX_EPOCHS, len(rows)
+        assert all(row["val_loss"] for row in rows)
+        print(f"✅ Ранняя остановка после {len(rows)} из {MAX_EPOCHS} эпох")
+
+def test_patience_requires_val_split():
+    """--early-stop-patience без --val-split отклоняется до обучения"""
+    with tempfile.TemporaryDirectory() as directory:
+        model = os.path.join(directory, "model.pt")
+        result = train(model, "--epochs", "1", "--early-stop-patience", "2")
+        assert result.returncode != 0
+        assert "--val-split" in result.stderr, result.stderr
+        assert not os.path.exists(model)
+        print("✅ --early-stop-patience требует --val-split")
+
+if __name__ == "__main__":
+    try:
+        test_early_stop_before_max_epochs()
+        test_patience_requires_val_split()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании ранней остановки: {e}")
+        sys.exit(1)
@@ -0,0 +1,44 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+import tempfile
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+# Коды выхода по категориям ошибок
+EXIT_CONFIG = 2
+EXIT_IMAGE = 4
+
+def test_missing_screenshot():
+    """predict-os-error с несуществующим скриншотом завершается кодом ошибки изображения"""
+    with tempfile.TemporaryDirectory() as directory:
+        screenshot = os.path.join(directory, "missing.png")
+        result = subprocess.run(BASHPIC + ["predict-os-error", "--screenshot", screenshot], capture_output=True, text=True)
+        assert result.returncode == EXIT_IMAGE, (result.returncode, result.stderr)
+        assert "Ошибка при анализе скриншота" in result.stderr, result.stderr
+        print(f"✅ Несуществующий скриншот: код {result.returncode}")
+
+def test_invalid_config():
+    """Некорректный файл конфигурации завершается кодом ошибки конфигурации до запуска команды"""
+    with tempfile.TemporaryDirectory() as directory:
+        config = os.path.join(directory, "config.json")
+        with open(config, "w") as f:
+            f.write('{"no_such_option": 1}')
+        result = subprocess.run(
+            BASHPIC + ["--config", config, "predict-os-error", "--screenshot", config],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == EXIT_CONFIG, (result.returncode, result.stderr)
+        assert "no_such_option" in result.stderr, result.stderr
+        print(f"✅ Некорректная конфигурация: код {result.returncode}")
+
+if __name__ == "__main__":
+    try:
+        test_missing_screenshot()
+        test_invalid_config()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании кодов выхода: {e}")
+        sys.exit(1)
@@ -0,0 +1,47 @@
+#!/usr/bin/env python3
+
+import os
+import re
+import subprocess
+import sys
+import tempfile
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+DELTA = re.compile(r"Наибольшее изменение весов: слои признаков (\S+), головы (\S+)")
+
+def train(model, *extra):
+    result = subprocess.run(
+        BASHPIC + ["train-os-error", "--model", model, "--epochs", "1", "--input-size", "32", *extra],
+        capture_output=True, text=True,
+    )
+    assert result.returncode == 0, (result.stdout, result.stderr)
+    return result.stdout
+
+def test_frozen_backbone_unchanged():
+    """При --freeze-backbone веса сверточных слоев после обучения те же, а веса голов меняются"""
+    with tempfile.TemporaryDirectory() as directory:
+        model = os.path.join(directory, "model.pt")
+        train(model, "--seed", "1")
+
+        output = train(model, "--resume", "--freeze-backbone", "--seed", "2")
+        assert "Слои признаков заморожены (conv1, conv2, conv3)" in output, output
+        match = DELTA.search(output)
+        assert match, output
+        backbone_delta, heads_delta = float(match.group(1)), float(match.group(2))
+        assert backbone_delta == 0.0, output
+        assert heads_delta > 0.0, output
+        print(f"✅ Слои признаков не изменились, веса голов изменились на {heads_delta:e}")
+
+        # Без заморозки меняются и сверточные слои: проверка не выводится
+        output = train(model, "--resume", "--seed", "3")
+        assert not DELTA.search(output), output
+        print("✅ Без --freeze-backbone проверка заморозки не выполняется")
+
+if __name__ == "__main__":
+    try:
+        test_frozen_backbone_unchanged()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании --freeze-backbone: {e}")
+        sys.exit(1)
@@ -0,0 +1,48 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+# Типы ошибок модели (OS_ERROR_TYPES), для каждого generate-data создает --per-class скриншотов
+ERROR_TYPES = [
+    "blue_screen_of_death", "kernel_panic", "application_crash", "memory_error", "disk_error",
+    "network_error", "permission_denied", "file_not_found", "system_overload", "driver_error",
+]
+
+PER_CLASS = 3
+
+def test_file_count():
+    """generate-data создает по --per-class PNG нужного размера на каждый тип ошибки"""
+    with tempfile.TemporaryDirectory() as directory:
+        out_dir = os.path.join(directory, "synthetic")
+        result = subprocess.run(
+            BASHPIC + ["generate-data", "--out-dir", out_dir, "--per-class", str(PER_CLASS),
+                       "--width", "160", "--height", "120", "--seed", "7"],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+
+        assert sorted(os.listdir(out_dir)) == sorted(ERROR_TYPES), os.listdir(out_dir)
+        for error_type in ERROR_TYPES:
+            files = [os.path.join(root, name)
+                     for root, _, names in os.walk(os.path.join(out_dir, error_type)) for name in names]
+            assert len(files) == PER_CLASS, (error_type, files)
+            for path in files:
+                with Image.open(path) as img:
+                    assert img.size == (160, 120), (path, img.size)
+        assert f"Создано {PER_CLASS * len(ERROR_TYPES)} синтетических скриншотов" in result.stdout, result.stdout
+        print(f"✅ Создано {PER_CLASS * len(ERROR_TYPES)} скриншотов, по {PER_CLASS} на тип ошибки")
+
+if __name__ == "__main__":
+    try:
+        test_file_count()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании generate-data: {e}")
+        sys.exit(1)
@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+import tempfile
+import time
+
+import requests
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+# gRPC-клиент из examples/grpc_client.rs
+GRPC_CLIENT = os.environ.get("BASHPIC_GRPC_CLIENT", "cargo run -q --example grpc_client --").split()
+
+PORT = 5099
+GRPC_PORT = 50099
+
+def wait_for_server(server):
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"http://localhost:{PORT}/", timeout=1)
+            return
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    raise AssertionError("Сервер не запустился")
+
+def test_predict_os_error_rpc():
+    """RPC PredictOsError через сгенерированный клиент возвращает анализ скриншота"""
+    with tempfile.TemporaryDirectory() as directory:
+        screenshot = os.path.join(directory, "screenshot.png")
+        Image.new('RGB', (200, 150), color='blue').save(screenshot)
+
+        server = subprocess.Popen(
+            BASHPIC + ["server", "--port", str(PORT), "--grpc-port", str(GRPC_PORT), "--no-warmup"],
+            stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True,
+        )
+        try:
+            wait_for_server(server)
+            result = subprocess.run(GRPC_CLIENT + [screenshot, f"http://localhost:{GRPC_PORT}"],
+                                    capture_output=True, text=True, timeout=600)
+            assert result.returncode == 0, result.stderr
+            assert "Тип ошибки:" in result.stdout, result.stdout
+            assert "ОС:" in result.stdout, result.stdout
+            print(f"✅ PredictOsError по gRPC: {result.stdout.splitlines()[0]}")
+        finally:
+            server.terminate()
+            server.wait(timeout=60)
+
+if __name__ == "__main__":
+    try:
+        test_predict_os_error_rpc()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании gRPC: {e}")
+        sys.exit(1)
@@ -0,0 +1,88 @@
+#!/usr/bin/env python3
+
+import base64
+import io
+import json
+import os
+import shutil
+import subprocess
+import sys
+import tempfile
+import time
+
+import requests
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+PORT = 5097
+
+# Модель ошибок ОС не зависит от разрешения, поэтому медленную модель дает та же
+# обученная модель, в метаданных которой указан огромный размер входа
+SLOW_INPUT_SIZE = 1536
+
+def make_models_dir(directory):
+    """Каталог с моделью slow, forward которой на CPU занимает несколько секунд"""
+    trained = os.path.join(directory, "trained.pt")
+    subprocess.run(BASHPIC + ["train-os-error", "--model", trained, "--epochs", "1", "--input-size", "32"],
+                   capture_output=True, text=True, check=True)
+    models_dir = os.path.join(directory, "models")
+    os.makedirs(models_dir)
+    shutil.copy(trained, os.path.join(models_dir, "slow.pt"))
+    with open(os.path.join(directory, "trained.json")) as f:
+        metadata = json.load(f)
+    metadata["input_shape"] = [3, SLOW_INPUT_SIZE, SLOW_INPUT_SIZE]
+    with open(os.path.join(models_dir, "slow.json"), "w") as f:
+        json.dump(metadata, f)
+    return models_dir
+
+def screenshot_base64():
+    buffer = io.BytesIO()
+    Image.new('RGB', (64, 64), color='blue').save(buffer, format='PNG')
+    return base64.b64encode(buffer.getvalue()).decode('utf-8')
+
+def wait_for_server(server):
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"http://localhost:{PORT}/", timeout=1)
+            return
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    raise AssertionError("Сервер не запустился")
+
+def test_timeout():
+    """Медленный forward при --inference-timeout 1 дает 503 overloaded, а не зависание"""
+    with tempfile.TemporaryDirectory() as directory:
+        models_dir = make_models_dir(directory)
+        server = subprocess.Popen(
+            BASHPIC + ["server", "--models-dir", models_dir, "--port", str(PORT), "--grpc-port", "50097",
+                       "--no-warmup", "--workers", "1", "--inference-timeout", "1"],
+            stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True,
+        )
+        try:
+            wait_for_server(server)
+            started = time.monotonic()
+            response = requests.post(
+                f"http://localhost:{PORT}/predict-os-error?model=slow",
+                json={"image_base64": screenshot_base64()},
+                timeout=30,
+            )
+            elapsed = time.monotonic() - started
+            assert response.status_code == 503, (response.status_code, response.text)
+            assert response.json()["code"] == "overloaded", response.text
+            assert response.headers.get("Retry-After") == "1", response.headers
+            assert elapsed < 10, elapsed
+            print(f"✅ Медленный инференс: 503 через {elapsed:.1f} с: {response.json()['error']}")
+        finally:
+            server.terminate()
+            server.wait(timeout=60)
+
+if __name__ == "__main__":
+    try:
+        test_timeout()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании таймаута инференса: {e}")
+        sys.exit(1)
@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+
+import csv
+import os
+import subprocess
+import sys
+import tempfile
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+EPOCHS = 2
+
+def read_metrics(command, header, *extra):
+    """Обучение на EPOCHS эпох с --metrics-csv; возвращает строки CSV без заголовка"""
+    with tempfile.TemporaryDirectory() as directory:
+        metrics = os.path.join(directory, "metrics.csv")
+        result = subprocess.run(
+            BASHPIC + [command, "--model", os.path.join(directory, "model.pt"), "--epochs", str(EPOCHS),
+                       "--metrics-csv", metrics, *extra],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        with open(metrics, newline="") as f:
+            rows = list(csv.reader(f))
+    assert rows[0] == header, rows[0]
+    return rows[1:]
+
+def check_rows(rows):
+    assert len(rows) == EPOCHS, rows
+    for epoch, row in enumerate(rows, start=1):
+        assert int(row[0]) == epoch, row
+        assert all(float(value) >= 0.0 for value in row[1:]), row
+
+def test_train_metrics():
+    """train пишет заголовок и по строке на эпоху"""
+    check_rows(read_metrics("train", ["epoch", "loss"]))
+    print("✅ train --metrics-csv: заголовок и строка на каждую эпоху")
+
+def test_train_os_error_metrics():
+    """train-os-error пишет потери обеих голов и общую"""
+    rows = read_metrics("train-os-error", ["epoch", "error_loss", "os_loss", "total_loss"], "--input-size", "32")
+    check_rows(rows)
+    print("✅ train-os-error --metrics-csv: заголовок и строка на каждую эпоху")
+
+if __name__ == "__main__":
+    try:
+        test_train_metrics()
+        test_train_os_error_metrics()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании --metrics-csv: {e}")
+        sys.exit(1)
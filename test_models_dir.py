@@ -0,0 +1,59 @@
+#!/usr/bin/env python3
+
+import os
+import re
+import shutil
+import subprocess
+import sys
+import tempfile
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+def make_models_dir(directory):
+    """Две корректные модели (first, second) и одна с испорченными метаданными (broken)"""
+    trained = os.path.join(directory, "trained.pt")
+    subprocess.run(BASHPIC + ["train-os-error", "--model", trained, "--epochs", "1", "--input-size", "32"],
+                   capture_output=True, text=True, check=True)
+    models_dir = os.path.join(directory, "models")
+    os.makedirs(models_dir)
+    for name in ("first", "second", "broken"):
+        shutil.copy(trained, os.path.join(models_dir, f"{name}.pt"))
+        shutil.copy(os.path.join(directory, "trained.json"), os.path.join(models_dir, f"{name}.json"))
+    with open(os.path.join(models_dir, "broken.json"), "w") as f:
+        f.write("{ not json")
+    return models_dir
+
+def test_models_dir():
+    """Сервер с --model-dir загружает обе корректные модели и пропускает модель с испорченными метаданными"""
+    with tempfile.TemporaryDirectory() as directory:
+        models_dir = make_models_dir(directory)
+        server = subprocess.Popen(
+            BASHPIC + ["server", "--model-dir", models_dir, "--port", "5098", "--grpc-port", "50098", "--no-warmup"],
+            stdout=subprocess.PIPE, stderr=subprocess.PIPE, text=True,
+        )
+        try:
+            summary = None
+            for line in server.stdout:
+                if line.startswith("Каталог моделей"):
+                    summary = line
+                if line.startswith("Модели ошибок ОС:"):
+                    break
+            else:
+                raise AssertionError(f"Сервер завершился до загрузки моделей: {server.stderr.read()}")
+
+            names = re.match(r"Модели ошибок ОС: (.*) \(основная", line).group(1).split(", ")
+            assert "first" in names and "second" in names, names
+            assert "broken" not in names, names
+            assert summary and "загружено 2, пропущено 1" in summary, summary
+            print(f"✅ Загружены модели: {', '.join(names)}; broken пропущена")
+        finally:
+            server.terminate()
+            server.wait(timeout=60)
+
+if __name__ == "__main__":
+    try:
+        test_models_dir()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании каталога моделей: {e}")
+        sys.exit(1)
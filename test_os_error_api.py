@@ -3,9 +3,11 @@
 
 import requests
 import json
+import base64
 import numpy as np
 from PIL import Image
 import sys
+import os
 
 def image_to_array(image_path, size=(128, 128)):
     """Convert image to normalized array for API"""
@@ -24,34 +26,67 @@ def image_to_array(image_path, size=(128, 128)):
         print(f"Ошибка при обработке изображения: {e}")
         return None
 
-def test_os_error_prediction(image_path, server_url="http://localhost:5000"):
+def image_to_nhwc_array(image_path, size=(128, 128)):
+    """Same image as image_to_array, but with interleaved channels [H, W, C] as TensorFlow exports it"""
+    img = Image.open(image_path).convert('RGB').resize(size)
+    return (np.array(img) / 255.0).flatten().tolist()
+
+def image_to_base64(image_path):
+    """Read image file as base64 string for the image_base64 field"""
+    with open(image_path, 'rb') as f:
+        return base64.b64encode(f.read()).decode('ascii')
+
+def request_headers():
+    headers = {'Content-Type': 'application/json'}
+    api_key = os.environ.get("BASHPIC_API_KEY")
+    if api_key:
+        headers['X-API-Key'] = api_key
+    return headers
+
+def test_os_error_prediction(image_path, server_url="http://localhost:5000", use_base64=False):
     """Test OS error prediction API"""
 
     print(f"Загрузка и обработка изображения: {image_path}")
-    image_data = image_to_array(image_path)
-
-    if image_data is None:
-        return
+    if use_base64:
+        # Сервер сам декодирует и масштабирует изображение
+        payload = {"image_base64": image_to_base64(image_path)}
+    else:
+        image_data = image_to_array(image_path)
+        if image_data is None:
+            return
+        payload = {"image": image_data}
 
-    # Prepare request
-    payload = {
-        "image": image_data
-    }
+    headers = request_headers()
 
     try:
         print(f"Отправка запроса на {server_url}/predict-os-error...")
         response = requests.post(f"{server_url}/predict-os-error",
                                json=payload,
-                               headers={'Content-Type': 'application/json'},
+                               headers=headers,
                                timeout=30)
 
-        if response.status_code == 200:
+        if response.status_code == 401:
+            print("Ошибка авторизации: задайте ключ в переменной BASHPIC_API_KEY")
+        elif response.status_code == 200:
             result = response.json()
             print("\n=== Результат анализа ошибки ОС ===")
             print(f"Тип ошибки: {result['error_type']}")
             print(f"Операционная система: {result['os_type']}")
             print(f"Уверенность: {result['confidence']:.2%}")
             print(f"Описание: {result['description']}")
+            print("Возможные причины:")
+            for cause in result['possible_causes']:
+                print(f"  - {cause}")
+            print("Решения:")
+            for solution in result['solutions']:
+                print(f"  - {solution}")
+            assert result['possible_causes'], "Список причин не должен быть пустым"
+            # Тип ОС согласуется с типом ошибки
+            if result['error_type'] == 'blue_screen_of_death':
+                assert result['os_type'] == 'windows', result
+            if result['error_type'] == 'kernel_panic':
+                assert result['os_type'] in ('linux', 'macos'), result
+            assert result['os_confidence'] <= 1.0
         else:
             print(f"Ошибка API: {response.status_code}")
             print(f"Ответ: {response.text}")
@@ -60,6 +95,330 @@ def test_os_error_prediction(image_path, server_url="http://localhost:5000"):
         print(f"Ошибка подключения: {e}")
         print("Убедитесь, что сервер запущен: cargo run server")
 
+def test_missing_image(server_url="http://localhost:5000"):
+    """Request without image fields must be rejected with 400"""
+    try:
+        response = requests.post(f"{server_url}/predict-os-error",
+                               json={},
+                               headers=request_headers(),
+                               timeout=30)
+        assert response.status_code == 400, f"Ожидался 400, получен {response.status_code}"
+        assert response.json()['code'] == 'bad_input', response.text
+        print("Запрос без изображения отклонен: 400")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_malformed_body(server_url="http://localhost:5000"):
+    """Malformed JSON must come back as a structured error, not a plain-text body"""
+    try:
+        response = requests.post(f"{server_url}/predict-os-error",
+                               data="{not json",
+                               headers=request_headers(),
+                               timeout=30)
+        assert response.status_code == 400, f"Ожидался 400, получен {response.status_code}"
+        body = response.json()
+        assert body['code'] == 'bad_input' and body['error'], body
+        print(f"Некорректный JSON отклонен: {body['error']}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_body_limit(server_url="http://localhost:5000"):
+    """A body just over --max-body-size gets 413, one just under reaches the handler (400 without an image)"""
+    limit = int(os.environ.get("BASHPIC_MAX_BODY_SIZE", 16 * 1024 * 1024))
+    template = '{"padding": "%s"}'
+    filler = len(template % "")
+    try:
+        for size, expected_status, expected_code in [(limit - 1, 400, 'bad_input'), (limit + 1, 413, 'payload_too_large')]:
+            body = template % ("a" * (size - filler))
+            response = requests.post(f"{server_url}/predict-os-error",
+                                   data=body,
+                                   headers=request_headers(),
+                                   timeout=60)
+            assert response.status_code == expected_status, f"Тело {size} байт: ожидался {expected_status}, получен {response.status_code}"
+            assert response.json()['code'] == expected_code, response.text
+            print(f"Тело {size} байт: {response.status_code} {expected_code}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_prediction_cache(image_path, server_url="http://localhost:5000"):
+    """A repeated identical request is served from the cache (X-Cache: hit), a different input is not"""
+    image_data = image_to_array(image_path)
+    if image_data is None:
+        return
+    # Случайный сдвиг делает вход уникальным, чтобы не попасть в ответы предыдущих тестов
+    image_data[0] = float(np.random.rand())
+    changed = list(image_data)
+    changed[1] = float(np.random.rand())
+    try:
+        statuses = []
+        for payload in [image_data, image_data, changed]:
+            response = requests.post(f"{server_url}/predict-os-error",
+                                   json={"image": payload},
+                                   headers=request_headers(),
+                                   timeout=30)
+            assert response.status_code == 200, response.text
+            statuses.append(response.headers.get('X-Cache'))
+        assert statuses == ['miss', 'hit', 'miss'], statuses
+        print(f"Кэш предсказаний: {statuses}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_recent(image_path, server_url="http://localhost:5000"):
+    """Three predictions then /recent returns them newest first"""
+    image_data = image_to_array(image_path)
+    if image_data is None:
+        return
+    try:
+        # Разные входы, чтобы каждый запрос прошел через модель, а не только через кэш
+        predictions = []
+        for _ in range(3):
+            image_data[0] = float(np.random.rand())
+            response = requests.post(f"{server_url}/predict-os-error",
+                                   json={"image": image_data},
+                                   headers=request_headers(),
+                                   timeout=30)
+            assert response.status_code == 200, response.text
+            predictions.append(response.json())
+
+        response = requests.get(f"{server_url}/recent?limit=3", headers=request_headers(), timeout=30)
+        assert response.status_code == 200, response.text
+        recent = response.json()
+        assert len(recent) == 3, recent
+        expected = [(p['error_type'], p['os_type']) for p in reversed(predictions)]
+        assert [(r['error_type'], r['os_type']) for r in recent] == expected, recent
+        assert recent[0]['timestamp'] >= recent[1]['timestamp'] >= recent[2]['timestamp'], recent
+        print(f"Последние предсказания: {[r['error_type'] for r in recent]}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_predict_batch(image_path, server_url="http://localhost:5000"):
+    """/predict-batch streams one NDJSON line per image; Accept: application/json returns the same as an array"""
+    payload = {"images": [{"image_base64": image_to_base64(image_path)}] * 3}
+    try:
+        response = requests.post(f"{server_url}/predict-batch",
+                               json=payload,
+                               headers=request_headers(),
+                               stream=True,
+                               timeout=30)
+        assert response.status_code == 200, response.text
+        assert response.headers.get('Content-Type', '').startswith('application/x-ndjson'), response.headers
+        lines = [json.loads(line) for line in response.iter_lines() if line]
+        assert len(lines) == 3, lines
+        for line in lines:
+            assert set(line) == {'class', 'confidence'}, line
+        assert len({(line['class'], line['confidence']) for line in lines}) == 1, lines
+
+        headers = dict(request_headers(), Accept='application/json')
+        response = requests.post(f"{server_url}/predict-batch", json=payload, headers=headers, timeout=30)
+        assert response.status_code == 200, response.text
+        assert response.json() == lines, response.json()
+
+        response = requests.post(f"{server_url}/predict-batch",
+                               json={"images": [{"image_base64": image_to_base64(image_path)}, {}]},
+                               headers=request_headers(),
+                               timeout=30)
+        assert response.status_code == 400, response.text
+        assert response.json()['error'].startswith('images[1]'), response.json()
+        print(f"Пакетное предсказание: {lines[0]}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_labels(server_url="http://localhost:5000"):
+    """Label vocabulary must match OS_ERROR_TYPES and OS_TYPES"""
+    try:
+        response = requests.get(f"{server_url}/labels", timeout=30)
+        labels = response.json()
+        assert len(labels['error_types']) == 10, labels['error_types']
+        assert len(labels['os_types']) == 4, labels['os_types']
+        assert set(labels['error_descriptions']) == set(labels['error_types'])
+        print(f"Метки: {len(labels['error_types'])} типов ошибок, {len(labels['os_types'])} типов ОС")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_model_info(server_url="http://localhost:5000"):
+    """/model-info head sizes must match /labels and the parameter count must be positive"""
+    try:
+        info = requests.get(f"{server_url}/model-info", timeout=30).json()
+        labels = requests.get(f"{server_url}/labels", timeout=30).json()
+        assert info['heads'] == {'error_type': len(labels['error_types']), 'os_type': len(labels['os_types'])}, info
+        assert info['error_types'] == labels['error_types'], info
+        assert info['input_shape'][0] == 3 and info['input_shape'][1] == info['input_shape'][2], info
+        assert info['parameters'] > 0, info
+        print(f"Модель: {info['architecture']}, {info['parameters']} параметров, вход {info['input_shape']}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_model_selection(image_path, server_url="http://localhost:5000"):
+    """Route requests to BASHPIC_EXTRA_MODEL (a model from --models-dir) and to the primary model; unknown names get 404"""
+    extra = os.environ.get("BASHPIC_EXTRA_MODEL")
+    if not extra:
+        return
+    request_data = {"image_base64": image_to_base64(image_path)}
+    try:
+        for model in (None, extra):
+            params = {'model': model} if model else {}
+            info = requests.get(f"{server_url}/model-info", params=params, timeout=30).json()
+            labels = requests.get(f"{server_url}/labels", params=params, timeout=30).json()
+            assert info['error_types'] == labels['error_types'], info
+            response = requests.post(f"{server_url}/predict-os-error", params=params, json=request_data,
+                                     headers=request_headers(), timeout=30)
+            assert response.status_code == 200, f"Ожидался 200, получен {response.status_code}: {response.text}"
+            assert response.json()['error_type'] in labels['error_types'], response.json()
+            print(f"Модель {model or 'основная'}: вход {info['input_shape']}, ответ {response.json()['error_type']}")
+
+        response = requests.get(f"{server_url}/model-info", params={'model': 'no_such_model'}, timeout=30)
+        assert response.status_code == 404, f"Ожидался 404, получен {response.status_code}"
+        assert response.json()['code'] == 'not_found', response.json()
+        print(f"Неизвестная модель: {response.json()['error']}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_cors(server_url="http://localhost:5000"):
+    """Preflight from the origin in BASHPIC_CORS_ORIGIN must be allowed (server started with --cors-allow-origin)"""
+    origin = os.environ.get("BASHPIC_CORS_ORIGIN")
+    if not origin:
+        return
+    try:
+        response = requests.options(f"{server_url}/predict-os-error",
+                                    headers={'Origin': origin,
+                                             'Access-Control-Request-Method': 'POST',
+                                             'Access-Control-Request-Headers': 'content-type, x-api-key'},
+                                    timeout=30)
+        assert response.status_code == 200, f"Ожидался 200, получен {response.status_code}"
+        allowed = response.headers.get('Access-Control-Allow-Origin')
+        assert allowed in (origin, '*'), allowed
+        print(f"CORS preflight разрешен для {origin}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_upload_model(server_url="http://localhost:5000"):
+    """Upload BASHPIC_UPLOAD_MODEL (os_error .pt with .json metadata next to it), then the generic model.pt under the same metadata"""
+    model_path = os.environ.get("BASHPIC_UPLOAD_MODEL")
+    if not model_path:
+        return
+    metadata_path = os.path.splitext(model_path)[0] + ".json"
+    headers = {k: v for k, v in request_headers().items() if k != 'Content-Type'}
+    try:
+        with open(model_path, 'rb') as model, open(metadata_path, 'rb') as metadata:
+            response = requests.post(f"{server_url}/admin/upload-model", headers=headers,
+                                     files={'model': model, 'metadata': metadata}, timeout=120)
+        assert response.status_code == 200, f"Ожидался 200, получен {response.status_code}: {response.text}"
+        assert response.json()['parameters'] > 0, response.json()
+        print(f"Модель загружена: {response.json()['parameters']} параметров")
+
+        # Веса simple_cnn не подходят к os_error_cnn
+        with open("model.pt", 'rb') as model, open(metadata_path, 'rb') as metadata:
+            response = requests.post(f"{server_url}/admin/upload-model", headers=headers,
+                                     files={'model': model, 'metadata': metadata}, timeout=120)
+        assert response.status_code == 400, f"Ожидался 400, получен {response.status_code}"
+        print(f"Несовместимая модель отклонена: {response.json()['error']}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_severity_filter(image_path, server_url="http://localhost:5000"):
+    """?severity_max= hides riskier solutions without changing the prediction"""
+    image_data = image_to_array(image_path)
+    if image_data is None:
+        return
+    try:
+        solutions = {}
+        for severity in [None, "advanced", "safe"]:
+            query = f"?severity_max={severity}" if severity else ""
+            response = requests.post(f"{server_url}/predict-os-error{query}",
+                                   json={"image": image_data},
+                                   headers=request_headers(),
+                                   timeout=30)
+            assert response.status_code == 200, response.text
+            solutions[severity] = response.json()["solutions"]
+        assert set(solutions["safe"]) <= set(solutions["advanced"]) <= set(solutions[None]), solutions
+        assert "Переустановите или замените модули RAM" not in solutions["advanced"], solutions
+
+        response = requests.post(f"{server_url}/predict-os-error?severity_max=risky",
+                               json={"image": image_data},
+                               headers=request_headers(),
+                               timeout=30)
+        assert response.status_code == 400, response.text
+        print(f"Решений: все {len(solutions[None])}, advanced {len(solutions['advanced'])}, safe {len(solutions['safe'])}")
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_input_format(image_path, server_url="http://localhost:5000"):
+    """The same image sent as NCHW and as NHWC gets identical predictions"""
+    payloads = {
+        "nchw": {"image": image_to_array(image_path)},
+        "nhwc": {"image": image_to_nhwc_array(image_path), "input_format": "nhwc"},
+    }
+    try:
+        results = {}
+        for layout, payload in payloads.items():
+            response = requests.post(f"{server_url}/predict-os-error", json=payload, headers=request_headers(), timeout=30)
+            assert response.status_code == 200, response.text
+            results[layout] = response.json()
+
+        nchw, nhwc = results["nchw"], results["nhwc"]
+        assert (nchw['error_type'], nchw['os_type']) == (nhwc['error_type'], nhwc['os_type']), results
+        assert abs(nchw['confidence'] - nhwc['confidence']) < 1e-6, results
+        assert abs(nchw['os_confidence'] - nhwc['os_confidence']) < 1e-6, results
+        print(f"NCHW и NHWC: {nchw['error_type']} ({nchw['confidence']:.2%}) в обоих случаях")
+
+        response = requests.post(f"{server_url}/predict-os-error",
+                               json={"image": payloads["nhwc"]["image"], "input_format": "hwc"},
+                               headers=request_headers(),
+                               timeout=30)
+        assert response.status_code == 400, response.text
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_analyze_text(server_url="http://localhost:5000"):
+    """POST /analyze-text returns the chat's text triage for a "kernel panic" description"""
+    try:
+        response = requests.post(f"{server_url}/analyze-text",
+                               json={"message": "kernel panic", "lang": "ru"},
+                               headers=request_headers(),
+                               timeout=30)
+        assert response.status_code == 200, response.text
+        result = response.json()
+        assert result['analysis']['error_type'] == 'kernel_panic', result
+        assert 'kernel_panic' in result['response'], result
+        assert result['suggestions'], result
+        print(f"Текстовый анализ: {result['analysis']['error_type']} ({result['analysis']['confidence']:.2%})")
+
+        response = requests.post(f"{server_url}/analyze-text", json={"message": "  "}, headers=request_headers(), timeout=30)
+        assert response.status_code == 400, response.text
+
+        # Ответы только на русском: английский и неизвестные языки отклоняются
+        for lang in ["en", "de"]:
+            response = requests.post(f"{server_url}/analyze-text", json={"message": "kernel panic", "lang": lang},
+                                     headers=request_headers(), timeout=30)
+            assert response.status_code == 400, (lang, response.text)
+            assert response.json()['code'] == 'bad_input', response.text
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
+def test_share(server_url="http://localhost:5000"):
+    """POST /share creates a link, GET /shared/{token} renders it (expiry is checked by test_share_links.py)"""
+    try:
+        analysis = requests.post(f"{server_url}/analyze-text", json={"message": "kernel panic"},
+                                 headers=request_headers(), timeout=30).json()['analysis']
+        analysis['possible_causes'].append('<script>alert(1)</script>')
+
+        response = requests.post(f"{server_url}/share", json=analysis, headers=request_headers(), timeout=30)
+        assert response.status_code == 201, response.text
+        shared = response.json()
+        assert shared['url'].endswith(f"/shared/{shared['token']}"), shared
+        assert shared['expires_in'] > 0, shared
+
+        page = requests.get(f"{server_url}/shared/{shared['token']}", timeout=30)
+        assert page.status_code == 200, page.status_code
+        assert 'kernel_panic' in page.text, page.text
+        assert '<script>alert(1)</script>' not in page.text, "HTML в анализе должен экранироваться"
+        print(f"Ссылка на анализ: {shared['url']}")
+
+        missing = requests.get(f"{server_url}/shared/no-such-token", timeout=30)
+        assert missing.status_code == 404, missing.status_code
+    except requests.exceptions.RequestException as e:
+        print(f"Ошибка подключения: {e}")
+
 if __name__ == "__main__":
     if len(sys.argv) != 2:
         print("Использование: python3 test_os_error_api.py <путь_к_скриншоту>")
@@ -68,3 +427,19 @@ if __name__ == "__main__":
 
     image_path = sys.argv[1]
     test_os_error_prediction(image_path)
+    test_os_error_prediction(image_path, use_base64=True)
+    test_missing_image()
+    test_malformed_body()
+    test_body_limit()
+    test_prediction_cache(image_path)
+    test_recent(image_path)
+    test_predict_batch(image_path)
+    test_labels()
+    test_model_info()
+    test_model_selection(image_path)
+    test_severity_filter(image_path)
+    test_input_format(image_path)
+    test_analyze_text()
+    test_share()
+    test_cors()
+    test_upload_model()
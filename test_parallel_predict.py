@@ -0,0 +1,106 @@
+#!/usr/bin/env python3
+
+import base64
+import io
+import json
+import os
+import shutil
+import subprocess
+import sys
+import tempfile
+import time
+from concurrent.futures import ThreadPoolExecutor
+
+import requests
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+PORT = 5098
+
+# Одновременных запросов; столько же воркеров у сервера
+PARALLEL = 4
+
+# Размер входа медленной модели: forward на CPU в один поток занимает заметное время,
+# и разница между параллельным и последовательным выполнением видна на фоне HTTP
+SLOW_INPUT_SIZE = 768
+
+def make_models_dir(directory):
+    """Каталог с моделью slow — обученной моделью с большим размером входа в метаданных"""
+    trained = os.path.join(directory, "trained.pt")
+    subprocess.run(BASHPIC + ["train-os-error", "--model", trained, "--epochs", "1", "--input-size", "32"],
+                   capture_output=True, text=True, check=True)
+    models_dir = os.path.join(directory, "models")
+    os.makedirs(models_dir)
+    shutil.copy(trained, os.path.join(models_dir, "slow.pt"))
+    with open(os.path.join(directory, "trained.json")) as f:
+        metadata = json.load(f)
+    metadata["input_shape"] = [3, SLOW_INPUT_SIZE, SLOW_INPUT_SIZE]
+    with open(os.path.join(models_dir, "slow.json"), "w") as f:
+        json.dump(metadata, f)
+    return models_dir
+
+def screenshot_base64(seed):
+    """Разные изображения, чтобы ответы не брались из кэша предсказаний"""
+    buffer = io.BytesIO()
+    Image.new('RGB', (64, 64), color=(seed * 40 % 256, 80, 160)).save(buffer, format='PNG')
+    return base64.b64encode(buffer.getvalue()).decode('utf-8')
+
+def wait_for_server(server):
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"http://localhost:{PORT}/", timeout=1)
+            return
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    raise AssertionError("Сервер не запустился")
+
+def timed_prediction(seed):
+    started = time.monotonic()
+    response = requests.post(
+        f"http://localhost:{PORT}/predict-os-error?model=slow",
+        json={"image_base64": screenshot_base64(seed)},
+        timeout=120,
+    )
+    assert response.status_code == 200, (response.status_code, response.text)
+    return time.monotonic() - started
+
+def test_parallel_predictions():
+    """N одновременных /predict-os-error выполняются параллельно под блокировкой на чтение,
+    а не по одному: общее время заметно меньше N последовательных запросов"""
+    if (os.cpu_count() or 1) < 2:
+        print("⚠️  Меньше двух ядер, параллельный инференс не проверить")
+        return
+    with tempfile.TemporaryDirectory() as directory:
+        models_dir = make_models_dir(directory)
+        # Один поток libtorch на forward, чтобы параллелизм давали только воркеры
+        server = subprocess.Popen(
+            BASHPIC + ["server", "--models-dir", models_dir, "--port", str(PORT), "--grpc-port", "50098",
+                       "--no-warmup", "--workers", str(PARALLEL), "--torch-threads", "1"],
+            stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True,
+        )
+        try:
+            wait_for_server(server)
+            timed_prediction(0)
+            sequential = sum(timed_prediction(seed) for seed in range(1, PARALLEL + 1))
+
+            started = time.monotonic()
+            with ThreadPoolExecutor(max_workers=PARALLEL) as pool:
+                list(pool.map(timed_prediction, range(PARALLEL + 1, 2 * PARALLEL + 1)))
+            parallel = time.monotonic() - started
+
+            assert parallel < 0.75 * sequential, (parallel, sequential)
+            print(f"✅ {PARALLEL} запроса: параллельно {parallel:.2f} с, последовательно {sequential:.2f} с")
+        finally:
+            server.terminate()
+            server.wait(timeout=60)
+
+if __name__ == "__main__":
+    try:
+        test_parallel_predictions()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании параллельного инференса: {e}")
+        sys.exit(1)
@@ -0,0 +1,48 @@
+#!/usr/bin/env python3
+
+import csv
+import io
+import os
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+IMAGE_NAMES = ["bsod.png", "kernel panic.png", "crash, second try.png", 'quoted "name".png']
+
+def make_screenshots(directory):
+    """Несколько скриншотов, в том числе с запятой и кавычками в имени, и файл, который не является изображением"""
+    for i, name in enumerate(IMAGE_NAMES):
+        Image.new('RGB', (200, 150), color=(40 * i, 80, 200 - 40 * i)).save(os.path.join(directory, name))
+    with open(os.path.join(directory, "notes.txt"), "w") as f:
+        f.write("не изображение")
+
+def test_csv_format(model=None):
+    """predict-dir --format csv: заголовок, по строке на обработанное изображение, фиксированная точность"""
+    with tempfile.TemporaryDirectory() as directory:
+        make_screenshots(directory)
+        command = BASHPIC + ["predict-dir", "--dir", directory, "--format", "csv"]
+        if model:
+            command += ["--model", model]
+        result = subprocess.run(command, capture_output=True, text=True, check=True)
+
+        rows = list(csv.DictReader(io.StringIO(result.stdout)))
+        assert len(rows) == len(IMAGE_NAMES), (rows, result.stderr)
+        assert sorted(row["filename"] for row in rows) == sorted(IMAGE_NAMES), rows
+        for row in rows:
+            for field in ("error_confidence", "os_confidence"):
+                assert len(row[field].split(".")[1]) == 6, row
+                assert 0.0 <= float(row[field]) <= 1.0, row
+        assert "notes.txt" in result.stderr, result.stderr
+        print(f"✅ CSV: {len(rows)} строк для {len(IMAGE_NAMES)} изображений, нечитаемый файл пропущен")
+
+if __name__ == "__main__":
+    try:
+        test_csv_format(sys.argv[1] if len(sys.argv) > 1 else None)
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании predict-dir: {e}")
+        sys.exit(1)
@@ -0,0 +1,140 @@
+#!/usr/bin/env python3
+
+import io
+import os
+import subprocess
+import sys
+import threading
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+import requests
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+PORT = 5096
+MOCK_PORT = 8096
+
+def screenshot_png():
+    buffer = io.BytesIO()
+    Image.new('RGB', (64, 64), color='blue').save(buffer, format='PNG')
+    return buffer.getvalue()
+
+def start_mock_images():
+    """Mock хостинга изображений: маленький PNG, слишком большой ответ, редирект и 404"""
+    png = screenshot_png()
+
+    class Handler(BaseHTTPRequestHandler):
+        def do_GET(self):
+            if self.path == "/screenshot.png":
+                self.send_response(200)
+                self.send_header("Content-Type", "image/png")
+                self.send_header("Content-Length", str(len(png)))
+                self.end_headers()
+                self.wfile.write(png)
+            elif self.path == "/huge.png":
+                # Заявленный размер больше --max-body-size: сервер должен отказаться, не читая тело
+                self.send_response(200)
+                self.send_header("Content-Length", str(64 * 1024 * 1024))
+                self.end_headers()
+            elif self.path == "/redirect":
+                self.send_response(302)
+                self.send_header("Location", f"http://127.0.0.1:{MOCK_PORT}/screenshot.png")
+                self.end_headers()
+            else:
+                self.send_response(404)
+                self.end_headers()
+
+        def log_message(self, *args):
+            pass
+
+    server = ThreadingHTTPServer(("127.0.0.1", MOCK_PORT), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    return server
+
+def start_server(*extra):
+    server = subprocess.Popen(
+        BASHPIC + ["server", "--port", str(PORT), "--grpc-port", "50096", "--no-warmup", *extra],
+        stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True,
+    )
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"http://localhost:{PORT}/", timeout=1)
+            return server
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    server.terminate()
+    raise AssertionError("Сервер не запустился")
+
+def predict_url(url, kind=None):
+    body = {"url": url}
+    if kind:
+        body["kind"] = kind
+    headers = {}
+    api_key = os.environ.get("BASHPIC_API_KEY")
+    if api_key:
+        headers["X-API-Key"] = api_key
+    return requests.post(f"http://localhost:{PORT}/predict-url", json=body, headers=headers, timeout=60)
+
+def test_allowed_host():
+    """С --fetch-allow-host 127.0.0.1 изображение скачивается с mock и анализируется обеими моделями"""
+    image_url = f"http://127.0.0.1:{MOCK_PORT}/screenshot.png"
+    server = start_server("--fetch-allow-host", "127.0.0.1", "--max-body-size", str(1024 * 1024))
+    try:
+        response = predict_url(image_url)
+        assert response.status_code == 200, response.text
+        prediction = response.json()
+        assert prediction["error_type"] and prediction["os_type"], prediction
+        assert 0.0 <= prediction["confidence"] <= 1.0, prediction
+        print(f"✅ os_error: {prediction['error_type']} ({prediction['os_type']})")
+
+        response = predict_url(image_url, kind="generic")
+        assert response.status_code == 200, response.text
+        assert "class" in response.json(), response.text
+        print(f"✅ generic: класс {response.json()['class']}")
+
+        cases = [
+            ("ftp://127.0.0.1/screenshot.png", 400, "bad_input"),
+            (f"http://localhost:{MOCK_PORT}/screenshot.png", 400, "bad_input"),
+            ("http://169.254.169.254/latest/meta-data/", 400, "bad_input"),
+            (f"http://127.0.0.1:{MOCK_PORT}/huge.png", 413, "payload_too_large"),
+            (f"http://127.0.0.1:{MOCK_PORT}/redirect", 502, "fetch_failed"),
+            (f"http://127.0.0.1:{MOCK_PORT}/missing.png", 502, "fetch_failed"),
+        ]
+        for url, status, code in cases:
+            response = predict_url(url)
+            assert response.status_code == status, (url, response.status_code, response.text)
+            assert response.json()["code"] == code, (url, response.text)
+            print(f"✅ {url}: {status} {response.json()['error']}")
+    finally:
+        server.terminate()
+        server.wait()
+
+def test_private_address_rejected():
+    """Без списка разрешенных хостов адрес из частной сети отклоняется до запроса"""
+    server = start_server()
+    try:
+        for url in (f"http://127.0.0.1:{MOCK_PORT}/screenshot.png", f"http://localhost:{MOCK_PORT}/screenshot.png",
+                    f"http://[::1]:{MOCK_PORT}/screenshot.png"):
+            response = predict_url(url)
+            assert response.status_code == 400, (url, response.status_code, response.text)
+            assert "частной сети" in response.json()["error"], response.text
+        print("✅ Адреса частной сети отклонены")
+    finally:
+        server.terminate()
+        server.wait()
+
+if __name__ == "__main__":
+    mock = start_mock_images()
+    try:
+        test_allowed_host()
+        test_private_address_rejected()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании /predict-url: {e}")
+        sys.exit(1)
+    finally:
+        mock.shutdown()
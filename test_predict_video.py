@@ -0,0 +1,66 @@
+#!/usr/bin/env python3
+
+import json
+import os
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+FRAMES = 6
+INTERVAL = 0.5
+
+def make_frames(directory):
+    """Каталог кадров вместо видео, чтобы тест не зависел от ffmpeg; один файл не является изображением"""
+    for i in range(FRAMES):
+        Image.new('RGB', (200, 150), color=(30 * i, 90, 200 - 30 * i)).save(os.path.join(directory, f"frame_{i:06d}.png"))
+    with open(os.path.join(directory, f"frame_{FRAMES:06d}.png"), "w") as f:
+        f.write("битый кадр")
+
+def test_frame_directory(model=None):
+    """predict-video по каталогу кадров: шкала времени, голосование большинством и самый уверенный кадр"""
+    with tempfile.TemporaryDirectory() as directory:
+        make_frames(directory)
+        command = BASHPIC + ["predict-video", "--video", directory, "--interval", str(INTERVAL), "--json"]
+        if model:
+            command += ["--model", model]
+        result = subprocess.run(command, capture_output=True, text=True)
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        report = json.loads(result.stdout)
+
+        timeline = report["timeline"]
+        assert len(timeline) == FRAMES, timeline
+        assert [frame["time"] for frame in timeline] == [i * INTERVAL for i in range(FRAMES)], timeline
+        assert f"frame_{FRAMES:06d}.png" in result.stderr, result.stderr
+        print(f"✅ Шкала из {FRAMES} кадров с шагом {INTERVAL} с, битый кадр пропущен")
+
+        votes = sum(frame["error_type"] == report["error_type"] for frame in timeline)
+        assert votes == report["error_votes"], report
+        assert all(votes >= sum(frame["error_type"] == other for frame in timeline)
+                   for other in {frame["error_type"] for frame in timeline}), report
+        best = timeline[report["best_frame"]]
+        assert best["error_confidence"] == max(frame["error_confidence"] for frame in timeline), report
+        print(f"✅ Итог: {report['error_type']} ({votes} из {FRAMES} кадров), самый уверенный кадр {best['filename']}")
+
+def test_missing_video(model=None):
+    """Несуществующий видеофайл отклоняется с понятной ошибкой"""
+    command = BASHPIC + ["predict-video", "--video", "no_such_clip.mp4"]
+    if model:
+        command += ["--model", model]
+    result = subprocess.run(command, capture_output=True, text=True)
+    assert result.returncode != 0, result.stdout
+    assert "ffmpeg" in result.stderr, result.stderr
+    print("✅ Ошибка извлечения кадров сообщается пользователю")
+
+if __name__ == "__main__":
+    try:
+        model = sys.argv[1] if len(sys.argv) > 1 else None
+        test_frame_directory(model)
+        test_missing_video(model)
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании predict-video: {e}")
+        sys.exit(1)
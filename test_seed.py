@@ -0,0 +1,45 @@
+#!/usr/bin/env python3
+
+import csv
+import os
+import subprocess
+import sys
+import tempfile
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+def train_losses(command, seed, *extra):
+    """Обучение на 2 эпохи с заданным зерном; возвращает строки --metrics-csv без заголовка"""
+    with tempfile.TemporaryDirectory() as directory:
+        metrics = os.path.join(directory, "metrics.csv")
+        result = subprocess.run(
+            BASHPIC + [command, "--model", os.path.join(directory, "model.pt"), "--epochs", "2",
+                       "--seed", str(seed), "--metrics-csv", metrics, *extra],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        assert f"--seed {seed}" in result.stdout, result.stdout
+        with open(metrics, newline="") as f:
+            return list(csv.reader(f))[1:]
+
+def check_seed(command, *extra):
+    """Одно зерно дает те же потери по эпохам, другое - иные"""
+    first = train_losses(command, 1, *extra)
+    assert first == train_losses(command, 1, *extra), first
+    assert first != train_losses(command, 2, *extra), first
+    print(f"✅ {command} --seed: повторный запуск дает те же потери {first}")
+
+def test_train_seed():
+    check_seed("train")
+
+def test_train_os_error_seed():
+    check_seed("train-os-error", "--input-size", "32")
+
+if __name__ == "__main__":
+    try:
+        test_train_seed()
+        test_train_os_error_seed()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании --seed: {e}")
+        sys.exit(1)
@@ -0,0 +1,70 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+import time
+
+import requests
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+PORT = 5093
+SERVER_URL = f"http://localhost:{PORT}"
+SHARE_TTL = 2
+
+def wait_for_server(server):
+    for _ in range(600):
+        if server.poll() is not None:
+            raise AssertionError(f"Сервер завершился: {server.stderr.read()}")
+        try:
+            requests.get(f"{SERVER_URL}/", timeout=1)
+            return
+        except requests.exceptions.ConnectionError:
+            time.sleep(0.5)
+    raise AssertionError("Сервер не запустился")
+
+def tampered(token):
+    """Токен с продленным сроком действия и прежней подписью"""
+    link_id, expires, signature = token.split(".")
+    return f"{link_id}.{int(expires) + 3600}.{signature}"
+
+def test_signed_expiring_links():
+    """Ссылка открывается до --share-ttl и отвечает 404 после; токен с измененным сроком отклоняется"""
+    env = dict(os.environ)
+    env.pop("BASHPIC_API_KEYS", None)
+    server = subprocess.Popen(
+        BASHPIC + ["server", "--port", str(PORT), "--grpc-port", "50093", "--no-warmup",
+                   "--share-ttl", str(SHARE_TTL)],
+        stdout=subprocess.DEVNULL, stderr=subprocess.PIPE, text=True, env=env,
+    )
+    try:
+        wait_for_server(server)
+        analysis = requests.post(f"{SERVER_URL}/analyze-text", json={"message": "kernel panic"},
+                                 timeout=30).json()['analysis']
+        response = requests.post(f"{SERVER_URL}/share", json=analysis, timeout=30)
+        assert response.status_code == 201, response.text
+        shared = response.json()
+        assert shared['expires_in'] == SHARE_TTL, shared
+
+        page = requests.get(f"{SERVER_URL}/shared/{shared['token']}", timeout=30)
+        assert page.status_code == 200, page.status_code
+        forged = requests.get(f"{SERVER_URL}/shared/{tampered(shared['token'])}", timeout=30)
+        assert forged.status_code == 404, forged.status_code
+        print("✅ Ссылка открывается, токен с измененным сроком отклонен")
+
+        time.sleep(SHARE_TTL + 1)
+        expired = requests.get(f"{SERVER_URL}/shared/{shared['token']}", timeout=30)
+        assert expired.status_code == 404, expired.status_code
+        print(f"✅ Ссылка истекла через {SHARE_TTL} с")
+    finally:
+        server.terminate()
+        server.wait(timeout=60)
+
+if __name__ == "__main__":
+    try:
+        test_signed_expiring_links()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании ссылок на анализы: {e}")
+        sys.exit(1)
@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+
+import json
+import os
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+def test_known_statistics():
+    """Два однотонных изображения дают заранее известные среднее и отклонение каналов"""
+    with tempfile.TemporaryDirectory() as directory:
+        data_dir = os.path.join(directory, "data")
+        os.makedirs(os.path.join(data_dir, "nested"))
+        # R: 1.0 и 0.0, G: 0.2 и 0.6, B: 0.0 и 1.0
+        Image.new('RGB', (64, 48), color=(255, 51, 0)).save(os.path.join(data_dir, "a.png"))
+        Image.new('RGB', (40, 40), color=(0, 153, 255)).save(os.path.join(data_dir, "nested", "b.png"))
+        out = os.path.join(directory, "stats.json")
+
+        result = subprocess.run(
+            BASHPIC + ["stats", "--data-dir", data_dir, "--input-size", "32", "--out", out],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        with open(out) as f:
+            stats = json.load(f)
+        assert stats["images"] == 2, stats
+        assert stats["input_size"] == 32, stats
+        for actual, expected in zip(stats["mean"], [0.5, 0.4, 0.5]):
+            assert abs(actual - expected) < 1e-3, stats
+        for actual, expected in zip(stats["std"], [0.5, 0.2, 0.5]):
+            assert abs(actual - expected) < 1e-3, stats
+        print("✅ Статистика совпадает с ожидаемой")
+
+        result = subprocess.run(
+            BASHPIC + ["train-os-error", "--normalize-from", out, "--input-size", "32", "--dry-run"],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        assert "Ошибка" not in result.stderr, result.stderr
+        print("✅ train-os-error принимает --normalize-from")
+
+if __name__ == "__main__":
+    try:
+        test_known_statistics()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании stats: {e}")
+        sys.exit(1)
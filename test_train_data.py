@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+
+import os
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+ERROR_TYPES = [
+    "blue_screen_of_death", "kernel_panic", "application_crash", "memory_error", "disk_error",
+    "network_error", "permission_denied", "file_not_found", "system_overload", "driver_error",
+]
+OS_TYPES = ["windows", "linux", "macos", "unknown"]
+
+def make_dataset(directory, missing_error_type):
+    """По скриншоту на каждое сочетание типа ошибки и ОС, кроме одного типа ошибки"""
+    for i, error_type in enumerate(ERROR_TYPES):
+        if error_type == missing_error_type:
+            continue
+        for j, os_type in enumerate(OS_TYPES):
+            os_dir = os.path.join(directory, error_type, os_type)
+            os.makedirs(os_dir)
+            Image.new('RGB', (64, 64), color=(20 * i, 60 * j, 128)).save(os.path.join(os_dir, "0.png"))
+
+def train_dry_run(cache, *extra):
+    command = BASHPIC + ["train-os-error", "--tensor-cache", cache, "--input-size", "32", "--dry-run", *extra]
+    return subprocess.run(command, capture_output=True, text=True, check=True)
+
+def test_missing_class_aborts():
+    """Набор без одного типа ошибки: обучение прерывается, а с --force доходит до конца проверки"""
+    missing = "driver_error"
+    with tempfile.TemporaryDirectory() as directory:
+        data_dir = os.path.join(directory, "data")
+        cache = os.path.join(directory, "dataset.pt")
+        make_dataset(data_dir, missing)
+        subprocess.run(BASHPIC + ["preprocess", "--data-dir", data_dir, "--out", cache, "--input-size", "32"],
+                       capture_output=True, text=True, check=True)
+
+        result = train_dry_run(cache)
+        assert f"нет примеров для {missing}" in result.stderr, result.stderr
+        assert "Проверка перед обучением пройдена" not in result.stdout, result.stdout
+        print(f"✅ Без примеров {missing} обучение прервано")
+
+        result = train_dry_run(cache, "--force")
+        assert "Проверка перед обучением пройдена" in result.stdout, (result.stdout, result.stderr)
+        assert missing in result.stderr, result.stderr
+        print("✅ С --force проверка пройдена, пустой класс указан в предупреждении")
+
+if __name__ == "__main__":
+    try:
+        test_missing_class_aborts()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании распределения классов: {e}")
+        sys.exit(1)
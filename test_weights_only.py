@@ -0,0 +1,79 @@
+#!/usr/bin/env python3
+
+import json
+import os
+import subprocess
+import sys
+import tempfile
+
+from PIL import Image, ImageDraw
+
+# Команда запуска утилиты; можно заменить собранным бинарником через BASHPIC_BIN
+BASHPIC = os.environ.get("BASHPIC_BIN", "cargo run -q --").split()
+
+def train(model, *extra):
+    result = subprocess.run(
+        BASHPIC + ["train-os-error", "--model", model, "--epochs", "1", "--input-size", "32", "--seed", "1", *extra],
+        capture_output=True, text=True,
+    )
+    assert result.returncode == 0, (result.stdout, result.stderr)
+
+def predict(model, screenshot):
+    result = subprocess.run(
+        BASHPIC + ["predict-os-error", "--model", model, "--screenshot", screenshot, "--json"],
+        capture_output=True, text=True,
+    )
+    assert result.returncode == 0, (result.stdout, result.stderr)
+    return json.loads(result.stdout)
+
+def test_weights_only_round_trip():
+    """Модель, сохраненная только с весами, предсказывает то же, что и полное сохранение"""
+    with tempfile.TemporaryDirectory() as directory:
+        full = os.path.join(directory, "full.pt")
+        light = os.path.join(directory, "light.pt")
+        train(full)
+        train(light, "--weights-only")
+
+        assert os.path.exists(os.path.join(directory, "full.optim"))
+        assert not os.path.exists(os.path.join(directory, "light.optim"))
+        with open(os.path.join(directory, "light.json")) as f:
+            assert json.load(f).get("weights_only") is True
+        assert os.path.getsize(light) < os.path.getsize(full) + os.path.getsize(os.path.join(directory, "full.optim"))
+        print("✅ --weights-only не сохраняет состояние оптимизатора")
+
+        screenshot = os.path.join(directory, "error.png")
+        image = Image.new('RGB', (200, 120), color='white')
+        ImageDraw.Draw(image).text((10, 50), "Permission denied", fill='black')
+        image.save(screenshot)
+        assert predict(full, screenshot) == predict(light, screenshot)
+        print("✅ Предсказания совпадают с полным сохранением")
+
+def test_missing_weights():
+    """Веса другой архитектуры отклоняются с перечислением недостающих имен"""
+    with tempfile.TemporaryDirectory() as directory:
+        light = os.path.join(directory, "light.pt")
+        train(light, "--weights-only")
+        # Веса простой CNN под метаданными модели ошибок ОС
+        other = os.path.join(directory, "other.pt")
+        result = subprocess.run(BASHPIC + ["train", "--model", other, "--epochs", "1"], capture_output=True, text=True)
+        assert result.returncode == 0, (result.stdout, result.stderr)
+        with open(os.path.join(directory, "light.json")) as src, open(os.path.join(directory, "other.json"), "w") as dst:
+            dst.write(src.read())
+
+        screenshot = os.path.join(directory, "error.png")
+        Image.new('RGB', (64, 64), color='white').save(screenshot)
+        result = subprocess.run(
+            BASHPIC + ["predict-os-error", "--model", other, "--screenshot", screenshot],
+            capture_output=True, text=True,
+        )
+        assert result.returncode == 5, (result.returncode, result.stderr)
+        assert "нет весов" in result.stderr, result.stderr
+        print("✅ Веса другой архитектуры отклоняются с перечислением недостающих")
+
+if __name__ == "__main__":
+    try:
+        test_weights_only_round_trip()
+        test_missing_weights()
+    except Exception as e:
+        print(f"❌ Ошибка при тестировании --weights-only: {e}")
+        sys.exit(1)